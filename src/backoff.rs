@@ -0,0 +1,45 @@
+//! Waiting between retries.
+//!
+//! A [`Backoff`] starts at its lower bound and doubles with every failure,
+//! up to its upper bound. Each wait is a random time between half of the
+//! bound and all of it, so nodes that failed together spread out rather
+//! than retrying in lockstep.
+
+use std::time::Duration;
+
+use rand::Rng;
+
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    bound: Duration,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max,
+            bound: min,
+        }
+    }
+
+    /// How long to wait after another failure; raises the bound for the
+    /// next one.
+    pub fn next_wait(&mut self) -> Duration {
+        let wait = rand::thread_rng().gen_range(self.bound / 2..=self.bound);
+        self.bound = (self.bound * 2).min(self.max);
+        wait
+    }
+
+    /// Waits after another failure. Blocks.
+    pub fn sleep(&mut self) {
+        std::thread::sleep(self.next_wait());
+    }
+
+    /// Back to the lower bound, once a retry succeeded.
+    pub fn reset(&mut self) {
+        self.bound = self.min;
+    }
+}
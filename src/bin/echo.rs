@@ -0,0 +1,37 @@
+//! Gossip Glomers challenge 1 with nothing but the runtime: `init` is
+//! handled by `main_loop`, and `echo` is answered with the same text.
+
+use fly_distributed::{main_loop, message::Init, Message, Node, Runtime};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    Echo { echo: String },
+    EchoOk { echo: String },
+}
+
+struct EchoNode {
+    runtime: Runtime,
+}
+
+impl Node<Payload> for EchoNode {
+    fn from_init(runtime: Runtime, _init: Init) -> anyhow::Result<Self> {
+        Ok(Self { runtime })
+    }
+
+    fn step(&mut self, input: Message<Payload>) -> anyhow::Result<()> {
+        match input.body.payload {
+            Payload::Echo { ref echo } => {
+                let echo = echo.clone();
+                self.runtime.reply(&input, Payload::EchoOk { echo })
+            }
+            Payload::EchoOk { .. } => Ok(()),
+        }
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<EchoNode, Payload>()
+}
@@ -0,0 +1,8 @@
+use fly_distributed::{
+    main_loop,
+    set::{GSetNode, Payload},
+};
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<GSetNode, Payload>()
+}
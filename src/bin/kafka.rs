@@ -0,0 +1,8 @@
+use fly_distributed::{
+    kafka::{KafkaNode, Payload},
+    main_loop,
+};
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<KafkaNode, Payload>()
+}
@@ -0,0 +1,8 @@
+use fly_distributed::{
+    counter::keyed::{KeyedCounterNode, Payload},
+    main_loop,
+};
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<KeyedCounterNode, Payload>()
+}
@@ -0,0 +1,8 @@
+use fly_distributed::{
+    lin_kv::{LinKvNode, Payload},
+    main_loop,
+};
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<LinKvNode, Payload>()
+}
@@ -0,0 +1,8 @@
+use fly_distributed::{
+    lock::{LockNode, Payload},
+    main_loop,
+};
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<LockNode, Payload>()
+}
@@ -0,0 +1,8 @@
+use fly_distributed::{
+    counter::pn::{Payload, PnCounterNode},
+    main_loop,
+};
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<PnCounterNode, Payload>()
+}
@@ -0,0 +1,8 @@
+use fly_distributed::{
+    main_loop,
+    pubsub::{Payload, PubSubNode},
+};
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<PubSubNode, Payload>()
+}
@@ -0,0 +1,8 @@
+use fly_distributed::{
+    main_loop,
+    queue::{Payload, QueueNode},
+};
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<QueueNode, Payload>()
+}
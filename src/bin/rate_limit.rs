@@ -0,0 +1,8 @@
+use fly_distributed::{
+    main_loop,
+    rate_limit::{Payload, RateLimitNode},
+};
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<RateLimitNode, Payload>()
+}
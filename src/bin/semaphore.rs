@@ -0,0 +1,8 @@
+use fly_distributed::{
+    main_loop,
+    semaphore::{Payload, SemaphoreNode},
+};
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<SemaphoreNode, Payload>()
+}
@@ -0,0 +1,8 @@
+use fly_distributed::{
+    main_loop,
+    membership::swim::{Payload, SwimNode},
+};
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<SwimNode, Payload>()
+}
@@ -0,0 +1,8 @@
+use fly_distributed::{
+    main_loop,
+    tob::{Payload, TobNode},
+};
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<TobNode, Payload>()
+}
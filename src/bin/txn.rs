@@ -0,0 +1,8 @@
+use fly_distributed::{
+    main_loop,
+    txn::{Payload, TxnNode},
+};
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<TxnNode, Payload>()
+}
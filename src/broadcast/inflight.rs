@@ -0,0 +1,78 @@
+//! Bounds the anti-entropy frames outstanding to each peer.
+//!
+//! A slow or partitioned peer would otherwise get the same unacknowledged
+//! values re-sent on every tick. Values of frames in flight are not repeated,
+//! and once a peer has `limit` frames in flight further deltas wait until an
+//! ack arrives or a frame times out.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::broadcast::Gossiped;
+
+pub struct Inflight {
+    limit: usize,
+    timeout: Duration,
+    // peer -> frame msg_id -> (sent at, values)
+    frames: HashMap<String, HashMap<usize, (Instant, Gossiped)>>,
+}
+
+impl Inflight {
+    pub fn new(limit: usize, timeout: Duration) -> Self {
+        Self {
+            limit,
+            timeout,
+            frames: HashMap::new(),
+        }
+    }
+
+    /// Gives up on frames that were not acknowledged in time, so their
+    /// values become eligible for retransmission. Returns the peers that
+    /// timed out.
+    pub fn expire(&mut self, now: Instant) -> Vec<String> {
+        let mut silent = Vec::new();
+        for (peer, frames) in self.frames.iter_mut() {
+            let before = frames.len();
+            frames.retain(|_, (sent, _)| now.duration_since(*sent) < self.timeout);
+            if frames.len() < before {
+                silent.push(peer.clone());
+            }
+        }
+        silent
+    }
+
+    pub fn has_room(&self, peer: &str) -> bool {
+        self.frames
+            .get(peer)
+            .is_none_or(|frames| frames.len() < self.limit)
+    }
+
+    /// Values currently travelling to `peer`.
+    pub fn in_flight(&self, peer: &str) -> Gossiped {
+        self.frames
+            .get(peer)
+            .map(|frames| {
+                frames
+                    .values()
+                    .flat_map(|(_, values)| values)
+                    .copied()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn sent(&mut self, peer: &str, frame: usize, values: Gossiped, now: Instant) {
+        self.frames
+            .entry(peer.to_string())
+            .or_default()
+            .insert(frame, (now, values));
+    }
+
+    pub fn acked(&mut self, peer: &str, frame: usize) {
+        if let Some(frames) = self.frames.get_mut(peer) {
+            frames.remove(&frame);
+        }
+    }
+}
@@ -0,0 +1,584 @@
+pub mod inflight;
+pub mod node;
+pub mod primary_backup;
+pub mod topology;
+pub mod tree;
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use inflight::Inflight;
+use serde::{Deserialize, Serialize};
+use topology::TopologyMode;
+use tree::Plumtree;
+
+use crate::{
+    broadcast::node::Payload,
+    config::Config,
+    crdt::GSet,
+    membership::{hyparview::HyParView, MembershipMode},
+    storage::{FileBackend, Journal, StorageBackend},
+};
+
+pub type Gossiped = HashSet<usize>;
+
+/// Every value a node took in or saw expire, as a snapshot and the changes
+/// after it.
+type Values = Journal<Saved, Change>;
+
+/// The values a node holds and the ones that expired, each sorted.
+#[derive(Serialize, Deserialize, Default)]
+struct Saved {
+    values: Vec<usize>,
+    tombstones: Vec<usize>,
+}
+
+/// A value taken in, or one that expired and is never taken in again.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Change {
+    Insert(usize),
+    Bury(usize),
+}
+
+/// How values get to every node.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BroadcastMode {
+    /// Every node spreads what it learns to its neighbors.
+    #[default]
+    Gossip,
+    /// One node orders every value and streams them to the others (see
+    /// [`primary_backup`]).
+    PrimaryBackup,
+}
+
+impl FromStr for BroadcastMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gossip" => Ok(Self::Gossip),
+            "primary-backup" => Ok(Self::PrimaryBackup),
+            _ => bail!("unknown broadcast mode {s}, expected gossip or primary-backup"),
+        }
+    }
+}
+
+/// Who introduced a value to this node.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "kind", content = "from", rename_all = "snake_case")]
+pub enum Origin {
+    Client(String),
+    Peer(String),
+    /// Read back from the node's journal on start.
+    Journal,
+}
+
+#[derive(Clone, Debug)]
+pub struct Provenance {
+    pub origin: Origin,
+    pub at: Instant,
+}
+
+/// One value as reported by the `dump` message.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ValueInfo {
+    pub value: usize,
+    pub origin: Origin,
+    pub age_ms: u128,
+    pub archived: bool,
+}
+
+/// How often a journaled node replaces its journal with a snapshot.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Most values sent in a single anti-entropy frame.
+pub const MAX_FRAME: usize = 512;
+
+#[derive(Clone)]
+pub struct BroadcastStore {
+    // Values still gossiped. Unlike the tombstones, this and `archive` are
+    // plain sets rather than a `GSet`: values leave them, for the archive
+    // once every peer has them and for good once they expire.
+    pub messages: Arc<Mutex<Gossiped>>,
+    pub whoami: Arc<Mutex<String>>,
+    pub topology: Arc<Mutex<HashMap<String, Vec<String>>>>,
+    topology_mode: TopologyMode,
+    // Values each neighbor has acknowledged (or sent to us), so gossip only carries the delta.
+    pub known_by: Arc<Mutex<HashMap<String, Gossiped>>>,
+    // Values inserted since the last gossip tick; they were just pushed over
+    // the tree, so the anti-entropy gossip leaves them alone for one round.
+    pub fresh: Arc<Mutex<Gossiped>>,
+    // Values every peer has acknowledged. They are no longer gossiped and are
+    // only consulted when answering `read`.
+    pub archive: Arc<Mutex<Gossiped>>,
+    pub tree: Arc<Mutex<Plumtree>>,
+    pub inflight: Arc<Mutex<Inflight>>,
+    membership_mode: MembershipMode,
+    // Values a `read` found missing on some neighbor, retransmitted ahead of
+    // everything else on the next tick.
+    urgent: Arc<Mutex<HashMap<String, Gossiped>>>,
+    // Wakes the gossip thread before its interval elapses.
+    wake: Arc<(Mutex<bool>, Condvar)>,
+    // Set at init when gossip targets come from a HyParView active view.
+    pub hyparview: Arc<Mutex<Option<HyParView>>>,
+    // How long a value lives before it expires, if values expire at all.
+    ttl: Option<Duration>,
+    // Where and when each live value was first seen by this node.
+    provenance: Arc<Mutex<HashMap<usize, Provenance>>>,
+    // Expired values. They are never accepted again, so a late gossip frame
+    // cannot resurrect them.
+    tombstones: Arc<Mutex<GSet<usize>>>,
+    // Version of `tombstones` last sent to the neighbors; later ones, ours
+    // or received, go out on the next tick.
+    tombstones_sent: Arc<Mutex<u64>>,
+    // Partition-test mode: new values go to every neighbor at once and the
+    // gossip thread periodically pulls a neighbor's values, on top of the
+    // acked anti-entropy.
+    pub partition_test: bool,
+    // With `--broadcast-dir` set, where every value inserted goes, and
+    // every so often a snapshot of them all.
+    journal: Arc<Mutex<Option<Values>>>,
+}
+
+impl BroadcastStore {
+    pub fn new(
+        ttl: Option<Duration>,
+        topology_mode: TopologyMode,
+        max_inflight: usize,
+        membership_mode: MembershipMode,
+        partition_test: bool,
+    ) -> Self {
+        Self {
+            messages: Default::default(),
+            whoami: Default::default(),
+            topology: Default::default(),
+            topology_mode,
+            known_by: Default::default(),
+            fresh: Default::default(),
+            archive: Default::default(),
+            tree: Arc::new(Mutex::new(Plumtree::new(Duration::from_millis(200)))),
+            inflight: Arc::new(Mutex::new(Inflight::new(
+                max_inflight,
+                Duration::from_millis(1000),
+            ))),
+            membership_mode,
+            urgent: Default::default(),
+            wake: Default::default(),
+            hyparview: Default::default(),
+            ttl,
+            provenance: Default::default(),
+            tombstones: Default::default(),
+            tombstones_sent: Default::default(),
+            partition_test,
+            journal: Default::default(),
+        }
+    }
+
+    /// With `--broadcast-dir` set, journals values to a [`FileBackend`] in
+    /// `<dir>/<node>`; see [`persist_to`](Self::persist_to).
+    pub fn persist(&self, config: &Config, node: &str) -> anyhow::Result<()> {
+        let Some(dir) = config.get("broadcast-dir") else {
+            return Ok(());
+        };
+        let sync = config.parse("wal-sync")?.unwrap_or_default();
+        let backend = FileBackend::open(Path::new(dir).join(node), sync)?;
+        self.persist_to(Arc::new(backend), node)
+    }
+
+    /// Takes back the values an earlier run of `node` journaled to `backend`,
+    /// if any, and journals every new value and every tombstone there from
+    /// now on, replacing the journal with a snapshot of both every
+    /// [`CHECKPOINT_INTERVAL`]. Values that expired before the restart stay
+    /// expired.
+    pub fn persist_to(&self, backend: Arc<dyn StorageBackend>, node: &str) -> anyhow::Result<()> {
+        let started = Instant::now();
+        let (journal, recovered) =
+            Values::open(backend).context("recovering the broadcast values")?;
+        let snapshot = recovered.snapshot.unwrap_or_default();
+        let (saved, journaled) = (snapshot.values.len(), recovered.records.len());
+        eprintln!(
+            "{node}: recovered broadcast values in {:?}: {saved} from the snapshot, \
+             {journaled} changes journaled after it, {} it covered skipped{}",
+            started.elapsed(),
+            recovered.skipped,
+            match recovered.quarantined {
+                true => ", a corrupt last batch quarantined",
+                false => "",
+            }
+        );
+        let (mut values, mut tombstones) = (snapshot.values, snapshot.tombstones);
+        for change in recovered.records {
+            match change {
+                Change::Insert(value) => values.push(value),
+                Change::Bury(value) => tombstones.push(value),
+            }
+        }
+        // Buried first, so the values that expired are not taken in again.
+        self.bury(&tombstones.into_iter().collect());
+        self.insert(values, Origin::Journal);
+        *self.journal.lock().unwrap() = Some(journal);
+        let store = self.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(CHECKPOINT_INTERVAL);
+            if let Err(err) = store.checkpoint() {
+                eprintln!("checkpointing broadcast values failed: {err:#}");
+            }
+        });
+        Ok(())
+    }
+
+    /// Snapshots every value held, if the journal grew since the last time.
+    fn checkpoint(&self) -> anyhow::Result<()> {
+        // In the order `insert` and `bury` take them, so no change journaled
+        // after the snapshot is read slips in between.
+        let archive = self.archive.lock().unwrap();
+        let tombstones = self.tombstones.lock().unwrap();
+        let messages = self.messages.lock().unwrap();
+        let mut journal = self.journal.lock().unwrap();
+        let Some(journal) = journal.as_mut().filter(|journal| journal.pending() > 0) else {
+            return Ok(());
+        };
+        let mut saved = Saved {
+            values: archive.iter().chain(messages.iter()).copied().collect(),
+            tombstones: tombstones.iter().copied().collect(),
+        };
+        saved.values.sort();
+        saved.tombstones.sort();
+        journal.checkpoint(&saved)
+    }
+
+    /// Adds values to the hot set, skipping anything already archived or
+    /// expired. Returns the values that were new to this node.
+    pub fn insert(&self, values: impl IntoIterator<Item = usize>, origin: Origin) -> Gossiped {
+        let archive = self.archive.lock().unwrap();
+        let tombstones = self.tombstones.lock().unwrap();
+        let mut messages = self.messages.lock().unwrap();
+        let inserted: Gossiped = values
+            .into_iter()
+            .filter(|value| {
+                !archive.contains(value) && !tombstones.contains(value) && messages.insert(*value)
+            })
+            .collect();
+        if !inserted.is_empty() {
+            if let Some(journal) = self.journal.lock().unwrap().as_mut() {
+                let changes: Vec<Change> = inserted.iter().copied().map(Change::Insert).collect();
+                if let Err(err) = journal.append(&changes) {
+                    eprintln!("journaling broadcast values failed: {err:#}");
+                }
+            }
+            self.fresh.lock().unwrap().extend(inserted.iter().copied());
+            let at = Instant::now();
+            let mut provenance = self.provenance.lock().unwrap();
+            provenance.extend(inserted.iter().map(|&value| {
+                let origin = origin.clone();
+                (value, Provenance { origin, at })
+            }));
+        }
+        inserted
+    }
+
+    /// Expires every value older than the TTL. The new tombstones are queued
+    /// for the neighbors.
+    pub fn expire(&self, now: Instant) {
+        let Some(ttl) = self.ttl else {
+            return;
+        };
+        let expired: Gossiped = self
+            .provenance
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, seen)| now.duration_since(seen.at) >= ttl)
+            .map(|(&value, _)| value)
+            .collect();
+        if !expired.is_empty() {
+            self.bury(&expired);
+        }
+    }
+
+    /// Applies tombstones, either ours or received from a peer, and
+    /// journals the new ones.
+    pub fn bury(&self, values: &Gossiped) {
+        let mut archive = self.archive.lock().unwrap();
+        let mut tombstones = self.tombstones.lock().unwrap();
+        let mut messages = self.messages.lock().unwrap();
+        let mut known_by = self.known_by.lock().unwrap();
+        let mut provenance = self.provenance.lock().unwrap();
+        for value in values {
+            archive.remove(value);
+            messages.remove(value);
+            provenance.remove(value);
+            for known in known_by.values_mut() {
+                known.remove(value);
+            }
+        }
+        let buried: Vec<Change> = values
+            .iter()
+            .copied()
+            .filter(|value| !tombstones.contains(value))
+            .map(Change::Bury)
+            .collect();
+        tombstones.extend(values.iter().copied());
+        if buried.is_empty() {
+            return;
+        }
+        if let Some(journal) = self.journal.lock().unwrap().as_mut() {
+            if let Err(err) = journal.append(&buried) {
+                eprintln!("journaling broadcast tombstones failed: {err:#}");
+            }
+        }
+    }
+
+    /// Picks at most `limit` values of `delta` to send: `urgent` ones first,
+    /// then values fresh from a client (newest first), then the ones learnt
+    /// from peers.
+    pub fn prioritize(&self, delta: Gossiped, urgent: &Gossiped, limit: usize) -> Gossiped {
+        if delta.len() <= limit {
+            return delta;
+        }
+        let provenance = self.provenance.lock().unwrap();
+        let mut ranked: Vec<(bool, bool, Option<Instant>, usize)> = delta
+            .into_iter()
+            .map(|value| match provenance.get(&value) {
+                Some(seen) => (
+                    urgent.contains(&value),
+                    matches!(seen.origin, Origin::Client(_)),
+                    Some(seen.at),
+                    value,
+                ),
+                None => (urgent.contains(&value), false, None, value),
+            })
+            .collect();
+        ranked.sort_by(|a, b| b.cmp(a));
+        ranked
+            .into_iter()
+            .take(limit)
+            .map(|(_, _, _, value)| value)
+            .collect()
+    }
+
+    /// Compares what we hold against what each neighbor acknowledged and
+    /// wakes the gossip thread to retransmit the gaps right away. Called when
+    /// answering `read`, so other nodes' reads catch up with ours sooner.
+    pub fn schedule_repair(&self) {
+        let neighbors = self.neighbors();
+        let messages = self.messages.lock().unwrap().clone();
+        let mut gaps = HashMap::new();
+        {
+            let known_by = self.known_by.lock().unwrap();
+            for neighbor in neighbors {
+                let missing: Gossiped = match known_by.get(&neighbor) {
+                    Some(known) => messages.difference(known).copied().collect(),
+                    None => messages.clone(),
+                };
+                if !missing.is_empty() {
+                    gaps.insert(neighbor, missing);
+                }
+            }
+        }
+        if gaps.is_empty() {
+            return;
+        }
+        let mut urgent = self.urgent.lock().unwrap();
+        for (neighbor, missing) in gaps {
+            urgent.entry(neighbor).or_default().extend(missing);
+        }
+        drop(urgent);
+        let (woken, wake) = &*self.wake;
+        *woken.lock().unwrap() = true;
+        wake.notify_one();
+    }
+
+    pub fn take_urgent(&self) -> HashMap<String, Gossiped> {
+        std::mem::take(&mut *self.urgent.lock().unwrap())
+    }
+
+    /// Sleeps for `interval` unless `schedule_repair` wakes us earlier.
+    pub fn sleep(&self, interval: Duration) {
+        let (woken, wake) = &*self.wake;
+        let guard = woken.lock().unwrap();
+        let (mut guard, _) = wake
+            .wait_timeout_while(guard, interval, |woken| !*woken)
+            .unwrap();
+        *guard = false;
+    }
+
+    /// Describes every live value, for the `dump` message.
+    pub fn dump(&self, now: Instant) -> Vec<ValueInfo> {
+        let archive = self.archive.lock().unwrap();
+        let provenance = self.provenance.lock().unwrap();
+        let mut values: Vec<ValueInfo> = provenance
+            .iter()
+            .map(|(&value, seen)| ValueInfo {
+                value,
+                origin: seen.origin.clone(),
+                age_ms: now.duration_since(seen.at).as_millis(),
+                archived: archive.contains(&value),
+            })
+            .collect();
+        values.sort_by_key(|info| info.value);
+        values
+    }
+
+    /// Values among `values` that already expired here.
+    pub fn buried(&self, values: &Gossiped) -> Gossiped {
+        let tombstones = self.tombstones.lock().unwrap();
+        values
+            .iter()
+            .filter(|value| tombstones.contains(value))
+            .copied()
+            .collect()
+    }
+
+    /// Tombstones added since the last call, to pass on to the neighbors.
+    pub fn take_unsent_tombstones(&self) -> Gossiped {
+        let tombstones = self.tombstones.lock().unwrap();
+        let mut sent = self.tombstones_sent.lock().unwrap();
+        let unsent = tombstones.delta_since(*sent);
+        *sent = tombstones.version();
+        unsent.into_iter().collect()
+    }
+
+    pub fn contains(&self, value: &usize) -> bool {
+        self.archive.lock().unwrap().contains(value)
+            || self.messages.lock().unwrap().contains(value)
+    }
+
+    /// Every value this node has seen, hot or archived.
+    pub fn all(&self) -> Vec<usize> {
+        let archive = self.archive.lock().unwrap();
+        let messages = self.messages.lock().unwrap();
+        archive.iter().chain(messages.iter()).copied().collect()
+    }
+
+    /// Records that `peer` has the given values.
+    pub fn acknowledge(&self, peer: &str, values: impl IntoIterator<Item = usize>) {
+        let mut known_by = self.known_by.lock().unwrap();
+        known_by.entry(peer.to_string()).or_default().extend(values);
+    }
+
+    /// Builds our own topology at init, when we do not rely on Maelstrom's,
+    /// and joins the HyParView overlay if enabled.
+    pub fn init_topology(&self, node_ids: &[String]) -> Vec<(String, Payload)> {
+        if let TopologyMode::Hubs(groups) = self.topology_mode {
+            *self.topology.lock().unwrap() = topology::hubs(node_ids, groups);
+        }
+        if self.membership_mode != MembershipMode::HyParView {
+            return vec![];
+        }
+        let whoami = self.whoami.lock().unwrap().clone();
+        let mut view = HyParView::new(&whoami, node_ids);
+        let outgoing = view.join();
+        *self.hyparview.lock().unwrap() = Some(view);
+        outgoing
+    }
+
+    /// Stores the topology Maelstrom sent, unless we built our own.
+    pub fn set_topology(&self, topology: HashMap<String, Vec<String>>) {
+        if !matches!(self.topology_mode, TopologyMode::Hubs(_)) {
+            self.topology.lock().unwrap().extend(topology);
+        }
+    }
+
+    /// The nodes we gossip with, never ourselves.
+    pub fn neighbors(&self) -> Vec<String> {
+        if let Some(view) = self.hyparview.lock().unwrap().as_ref() {
+            return view.active();
+        }
+        let whoami = self.whoami.lock().unwrap().clone();
+        let topology = self.topology.lock().unwrap();
+        let mut neighbors = match self.topology_mode {
+            TopologyMode::Full => topology.values().flatten().cloned().collect(),
+            TopologyMode::Maelstrom | TopologyMode::Hubs(_) => {
+                topology.get(&whoami).cloned().unwrap_or_default()
+            }
+        };
+        neighbors.sort();
+        neighbors.dedup();
+        neighbors.retain(|neighbor| neighbor != &whoami);
+        neighbors
+    }
+
+    /// Whose acks make a value stable. Active views change over time, so with
+    /// HyParView that is every node rather than the current neighbors.
+    pub fn stability_peers(&self) -> Vec<String> {
+        let others = self
+            .hyparview
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(HyParView::others);
+        others.unwrap_or_else(|| self.neighbors())
+    }
+
+    /// Moves values acknowledged by every neighbor out of the hot set, and
+    /// forgets them from the per-neighbor bookkeeping.
+    pub fn archive_stable(&self, neighbors: &[String]) {
+        if neighbors.is_empty() {
+            return;
+        }
+        let mut archive = self.archive.lock().unwrap();
+        let mut messages = self.messages.lock().unwrap();
+        let mut known_by = self.known_by.lock().unwrap();
+        let stable: Gossiped = messages
+            .iter()
+            .filter(|value| {
+                neighbors.iter().all(|neighbor| {
+                    known_by
+                        .get(neighbor)
+                        .is_some_and(|known| known.contains(value))
+                })
+            })
+            .copied()
+            .collect();
+        if stable.is_empty() {
+            return;
+        }
+        messages.retain(|value| !stable.contains(value));
+        for known in known_by.values_mut() {
+            // Also drops late acks for values that were archived earlier.
+            known.retain(|value| messages.contains(value));
+        }
+        archive.extend(stable);
+    }
+}
+
+// Gossip tick that stays fast while new values are being disseminated and
+// backs off towards a long idle interval once everything is acknowledged.
+pub struct AdaptiveInterval {
+    current: Duration,
+    fast: Duration,
+    idle: Duration,
+    last_pending: usize,
+}
+
+impl AdaptiveInterval {
+    pub fn new(fast: Duration, idle: Duration) -> Self {
+        Self {
+            current: fast,
+            fast,
+            idle,
+            last_pending: 0,
+        }
+    }
+
+    /// `changed` tells whether new values arrived since the last tick and
+    /// `pending` how many (neighbor, value) pairs are still unacknowledged.
+    pub fn next(&mut self, changed: bool, pending: usize) -> Duration {
+        let progressing = pending > 0 && pending < self.last_pending;
+        if changed || progressing {
+            self.current = self.fast;
+        } else {
+            self.current = (self.current * 2).min(self.idle);
+        }
+        self.last_pending = pending;
+        self.current
+    }
+}
@@ -0,0 +1,602 @@
+//! The node answering Maelstrom's echo, unique-ids and broadcast workloads.
+
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    time::{Duration, Instant},
+};
+
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    broadcast::{
+        primary_backup::PrimaryBackup, AdaptiveInterval, BroadcastMode, BroadcastStore, Gossiped,
+        Origin, ValueInfo, MAX_FRAME,
+    },
+    ids::IdPool,
+    message::{Init, Message},
+    runtime::{Node, Runtime},
+};
+
+/// How often partition-test mode pulls from a neighbor.
+const PULL_INTERVAL: Duration = Duration::from_millis(500);
+const CHECK_TIMEOUT: Duration = Duration::from_millis(1000);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Echo {
+        echo: String,
+    },
+    EchoOk {
+        echo: String,
+    },
+    /// Asks for one id, or for `count` of them at once.
+    Generate {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        count: Option<usize>,
+    },
+    /// `id` answers a plain `generate`, `ids` one with a `count`.
+    GenerateOk {
+        #[serde(rename = "id", default, skip_serializing_if = "Option::is_none")]
+        unq_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ids: Option<Vec<String>>,
+    },
+    Broadcast {
+        message: usize,
+    },
+    BroadcastOk,
+    Read,
+    ReadOk {
+        messages: Vec<usize>,
+    },
+    Topology {
+        topology: HashMap<String, Vec<String>>,
+    },
+    TopologyOk,
+    GossipBroadcast {
+        message: Gossiped,
+    },
+    GossipBroadcastOk {
+        message: Gossiped,
+    },
+    TreePush {
+        message: Gossiped,
+    },
+    #[serde(rename = "ihave")]
+    IHave {
+        message: Gossiped,
+    },
+    Graft {
+        message: Gossiped,
+    },
+    Prune,
+    /// Tombstones for values that expired on the sender.
+    Expire {
+        message: Gossiped,
+    },
+    // HyParView membership.
+    Join,
+    ForwardJoin {
+        joiner: String,
+        ttl: usize,
+    },
+    Neighbor {
+        high_priority: bool,
+    },
+    NeighborOk {
+        accepted: bool,
+    },
+    Disconnect,
+    Shuffle {
+        origin: String,
+        ttl: usize,
+        nodes: Vec<String>,
+    },
+    ShuffleReply {
+        nodes: Vec<String>,
+    },
+    /// Partition-test mode: the sender's values, answered with the ones it
+    /// is missing.
+    Pull {
+        message: Gossiped,
+    },
+    PullOk {
+        message: Gossiped,
+    },
+    /// Primary-backup mode: the primary's log from `start` on, at most
+    /// [`MAX_FRAME`] values of it, and how long it is in all.
+    Replicate {
+        start: usize,
+        messages: Vec<usize>,
+        len: usize,
+    },
+    /// How much of the primary's log the backup holds, and once it holds
+    /// all of it, the values it has that the log lacks.
+    ReplicateOk {
+        len: usize,
+        unsequenced: Gossiped,
+    },
+    /// Self-check: asks this node to read every other node and report the
+    /// values they are missing, e.g. after a partition heals.
+    Check,
+    CheckOk {
+        complete: bool,
+        missing: HashMap<String, Gossiped>,
+        unreachable: Vec<String>,
+    },
+    /// Debugging: lists every live value with where it came from.
+    Dump,
+    DumpOk {
+        values: Vec<ValueInfo>,
+        client_values: usize,
+        peer_values: usize,
+    },
+}
+
+pub struct BroadcastNode {
+    runtime: Runtime,
+    store: BroadcastStore,
+    ids: IdPool,
+    /// Set in primary-backup mode, which then handles the values instead of
+    /// the gossip store.
+    primary_backup: Option<PrimaryBackup>,
+}
+
+impl BroadcastNode {
+    /// Partition-test mode: sends new values straight to every neighbor
+    /// but the one they came from. The acks feed `known_by` like any other
+    /// gossip frame.
+    fn push_eagerly(&self, new: &Gossiped, from: Option<&str>) -> anyhow::Result<()> {
+        if !self.store.partition_test || new.is_empty() {
+            return Ok(());
+        }
+        for neighbor in self.store.neighbors() {
+            if Some(neighbor.as_str()) != from {
+                let payload = Payload::GossipBroadcast {
+                    message: new.clone(),
+                };
+                self.runtime.send(&neighbor, payload)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Primary-backup mode: handles the values and the stream of the
+    /// primary's log, and leaves everything else to the rest of `step`.
+    /// Returns whether it handled `input`.
+    fn step_primary_backup(
+        &self,
+        primary_backup: &PrimaryBackup,
+        input: &Message<Payload>,
+    ) -> anyhow::Result<bool> {
+        let reply = match &input.body.payload {
+            Payload::Broadcast { message } => {
+                primary_backup.broadcast(*message)?;
+                Some(Payload::BroadcastOk)
+            }
+            Payload::Read => Some(Payload::ReadOk {
+                messages: primary_backup.read(),
+            }),
+            Payload::Replicate {
+                start,
+                messages,
+                len,
+            } => primary_backup.on_replicate(&input.src, *start, messages, *len),
+            Payload::ReplicateOk { len, unsequenced } => {
+                primary_backup.on_replicate_ok(&input.src, *len, unsequenced);
+                None
+            }
+            _ => return Ok(false),
+        };
+        if let Some(reply) = reply {
+            self.runtime.reply(input, reply)?;
+        }
+        Ok(true)
+    }
+
+    fn send(&self, outgoing: Vec<(String, Payload)>) -> anyhow::Result<()> {
+        for (dest, payload) in outgoing {
+            self.runtime.send(&dest, payload)?;
+        }
+        Ok(())
+    }
+
+    /// Tells a peer which of the values it sent us already expired here.
+    fn send_tombstones(&self, dest: &str, values: &Gossiped) -> anyhow::Result<()> {
+        let buried = self.store.buried(values);
+        if buried.is_empty() {
+            return Ok(());
+        }
+        self.runtime
+            .send(dest, Payload::Expire { message: buried })?;
+        Ok(())
+    }
+}
+
+impl Node<Payload> for BroadcastNode {
+    fn from_init(runtime: Runtime, init: Init) -> anyhow::Result<Self> {
+        let config = runtime.config().clone();
+        let store = BroadcastStore::new(
+            config.millis("broadcast-ttl")?,
+            config.parse("topology")?.unwrap_or_default(),
+            config
+                .parse("gossip-inflight")?
+                .map_or(3, NonZeroUsize::get),
+            config.parse("membership")?.unwrap_or_default(),
+            config.parse("partition-test")?.unwrap_or(false),
+        );
+        store.whoami.lock().unwrap().push_str(&init.node_id);
+        store.persist(&config, &init.node_id)?;
+        let joins = store.init_topology(&init.node_ids);
+        let neighbors = store.neighbors();
+        store.tree.lock().unwrap().set_peers(&neighbors);
+
+        let mut node_ids = init.node_ids.clone();
+        node_ids.sort();
+        let node_index = node_ids
+            .iter()
+            .position(|id| *id == init.node_id)
+            .unwrap_or_default();
+        let ids = IdPool::new(config.parse("id-scheme")?.unwrap_or_default(), node_index);
+        let primary_backup = match config.parse("broadcast-mode")?.unwrap_or_default() {
+            BroadcastMode::Gossip => None,
+            BroadcastMode::PrimaryBackup => Some(PrimaryBackup::new(runtime.clone())),
+        };
+
+        let node = Self {
+            runtime,
+            store,
+            ids,
+            primary_backup,
+        };
+        match &node.primary_backup {
+            Some(primary_backup) => primary_backup.spawn(),
+            None => {
+                node.send(joins)?;
+                spawn_gossip(node.runtime.clone(), node.store.clone());
+            }
+        }
+        Ok(node)
+    }
+
+    fn step(&mut self, input: Message<Payload>) -> anyhow::Result<()> {
+        if let Some(primary_backup) = &self.primary_backup {
+            if self.step_primary_backup(primary_backup, &input)? {
+                return Ok(());
+            }
+        }
+        let store = &self.store;
+        match input.body.payload {
+            Payload::Echo { ref echo } => {
+                let echo = echo.clone();
+                self.runtime.reply(&input, Payload::EchoOk { echo })?;
+            }
+            Payload::Generate { count } => {
+                let reply = match count {
+                    None => Payload::GenerateOk {
+                        unq_id: self.ids.take(1).pop(),
+                        ids: None,
+                    },
+                    Some(count) => Payload::GenerateOk {
+                        unq_id: None,
+                        ids: Some(self.ids.take(count)),
+                    },
+                };
+                self.runtime.reply(&input, reply)?;
+            }
+            Payload::Broadcast { message } => {
+                let new = store.insert([message], Origin::Client(input.src.clone()));
+                if !new.is_empty() {
+                    let outgoing = store.tree.lock().unwrap().on_new(&new, None);
+                    self.send(outgoing)?;
+                    self.push_eagerly(&new, None)?;
+                }
+                self.runtime.reply(&input, Payload::BroadcastOk)?;
+            }
+            Payload::Read => {
+                store.expire(Instant::now());
+                store.schedule_repair();
+                let messages = store.all();
+                self.runtime.reply(&input, Payload::ReadOk { messages })?;
+            }
+            Payload::Topology { ref topology } => {
+                store.set_topology(topology.clone());
+                let neighbors = store.neighbors();
+                store.tree.lock().unwrap().set_peers(&neighbors);
+                self.runtime.reply(&input, Payload::TopologyOk)?;
+            }
+            Payload::GossipBroadcast { ref message } => {
+                self.send_tombstones(&input.src, message)?;
+                // The sender obviously knows what it gossiped to us.
+                store.acknowledge(&input.src, message.iter().copied());
+                let origin = Origin::Peer(input.src.clone());
+                let new = store.insert(message.iter().copied(), origin);
+                if !new.is_empty() {
+                    // Anti-entropy repaired a gap: keep the tree flowing from here.
+                    let outgoing = store.tree.lock().unwrap().on_new(&new, Some(&input.src));
+                    self.send(outgoing)?;
+                    self.push_eagerly(&new, Some(&input.src))?;
+                }
+                let message = message.clone();
+                self.runtime
+                    .reply(&input, Payload::GossipBroadcastOk { message })?;
+            }
+            Payload::GossipBroadcastOk { message } => {
+                if let Some(frame) = input.body.in_reply_to {
+                    let mut inflight = store.inflight.lock().unwrap();
+                    inflight.acked(&input.src, frame);
+                }
+                if let Some(view) = store.hyparview.lock().unwrap().as_mut() {
+                    view.heard_from(&input.src);
+                }
+                store.acknowledge(&input.src, message);
+            }
+            Payload::TreePush { message } => {
+                self.send_tombstones(&input.src, &message)?;
+                store.acknowledge(&input.src, message.iter().copied());
+                let origin = Origin::Peer(input.src.clone());
+                let new = store.insert(message.iter().copied(), origin);
+                let outgoing = {
+                    let mut tree = store.tree.lock().unwrap();
+                    if new.is_empty() {
+                        tree.on_duplicate(&input.src)
+                    } else {
+                        tree.on_new(&new, Some(&input.src))
+                    }
+                };
+                self.send(outgoing)?;
+                // Ack the push so the anti-entropy gossip does not repeat it.
+                self.runtime
+                    .send(&input.src, Payload::GossipBroadcastOk { message })?;
+            }
+            Payload::IHave { message } => {
+                store.acknowledge(&input.src, message.iter().copied());
+                let buried = store.buried(&message);
+                let (known, unknown): (Gossiped, Gossiped) = message
+                    .into_iter()
+                    .filter(|value| !buried.contains(value))
+                    .partition(|value| store.contains(value));
+                store
+                    .tree
+                    .lock()
+                    .unwrap()
+                    .on_ihave(&input.src, &unknown, Instant::now());
+                if !known.is_empty() {
+                    self.runtime
+                        .send(&input.src, Payload::GossipBroadcastOk { message: known })?;
+                }
+            }
+            Payload::Graft { message } => {
+                let values = message
+                    .into_iter()
+                    .filter(|value| store.contains(value))
+                    .collect();
+                let outgoing = store.tree.lock().unwrap().on_graft(&input.src, values);
+                self.send(outgoing)?;
+            }
+            Payload::Prune => {
+                store.tree.lock().unwrap().on_prune(&input.src);
+            }
+            Payload::Expire { message } => {
+                store.bury(&message);
+            }
+            Payload::Join
+            | Payload::ForwardJoin { .. }
+            | Payload::Neighbor { .. }
+            | Payload::NeighborOk { .. }
+            | Payload::Disconnect
+            | Payload::Shuffle { .. }
+            | Payload::ShuffleReply { .. } => {
+                let outgoing = {
+                    let mut view = store.hyparview.lock().unwrap();
+                    let Some(view) = view.as_mut() else {
+                        // Not using HyParView.
+                        return Ok(());
+                    };
+                    let from = input.src.as_str();
+                    match input.body.payload {
+                        Payload::Join => view.on_join(from),
+                        Payload::ForwardJoin { joiner, ttl } => {
+                            view.on_forward_join(from, joiner, ttl)
+                        }
+                        Payload::Neighbor { high_priority } => {
+                            view.on_neighbor(from, high_priority)
+                        }
+                        Payload::NeighborOk { accepted } => view.on_neighbor_ok(from, accepted),
+                        Payload::Disconnect => {
+                            view.on_disconnect(from);
+                            vec![]
+                        }
+                        Payload::Shuffle { origin, ttl, nodes } => {
+                            view.on_shuffle(from, origin, ttl, nodes)
+                        }
+                        Payload::ShuffleReply { nodes } => {
+                            view.on_shuffle_reply(nodes);
+                            vec![]
+                        }
+                        _ => unreachable!(),
+                    }
+                };
+                self.send(outgoing)?;
+            }
+            Payload::Pull { ref message } => {
+                store.acknowledge(&input.src, message.iter().copied());
+                let new = store.insert(message.iter().copied(), Origin::Peer(input.src.clone()));
+                self.push_eagerly(&new, Some(&input.src))?;
+                let missing = store
+                    .all()
+                    .into_iter()
+                    .filter(|value| !message.contains(value))
+                    .collect();
+                self.runtime
+                    .reply(&input, Payload::PullOk { message: missing })?;
+            }
+            Payload::PullOk { message } => {
+                store.acknowledge(&input.src, message.iter().copied());
+                let new = store.insert(message, Origin::Peer(input.src.clone()));
+                self.push_eagerly(&new, Some(&input.src))?;
+            }
+            Payload::Check => {
+                let runtime = self.runtime.clone();
+                let ours = store.all();
+                std::thread::spawn(move || {
+                    let result = runtime.reply(&input, check(&runtime, ours));
+                    if let Err(err) = result {
+                        eprintln!("check reply failed: {err:#}");
+                    }
+                });
+            }
+            Payload::Dump => {
+                let values = store.dump(Instant::now());
+                let client_values = values
+                    .iter()
+                    .filter(|info| matches!(info.origin, Origin::Client(_)))
+                    .count();
+                let payload = Payload::DumpOk {
+                    client_values,
+                    peer_values: values.len() - client_values,
+                    values,
+                };
+                self.runtime.reply(&input, payload)?;
+            }
+            Payload::EchoOk { .. }
+            | Payload::GenerateOk { .. }
+            | Payload::BroadcastOk
+            | Payload::ReadOk { .. }
+            | Payload::TopologyOk
+            | Payload::CheckOk { .. }
+            | Payload::DumpOk { .. } => {}
+            // Only used in primary-backup mode.
+            Payload::Replicate { .. } | Payload::ReplicateOk { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+/// Reads every other node and lists the values from `ours` each is missing.
+fn check(runtime: &Runtime, ours: Vec<usize>) -> Payload {
+    let mut missing = HashMap::new();
+    let mut unreachable = Vec::new();
+    for peer in runtime.peers() {
+        match runtime.rpc::<_, Payload>(peer, Payload::Read, CHECK_TIMEOUT) {
+            Ok(Payload::ReadOk { messages }) => {
+                let theirs: Gossiped = messages.into_iter().collect();
+                let lacking: Gossiped = ours
+                    .iter()
+                    .filter(|value| !theirs.contains(value))
+                    .copied()
+                    .collect();
+                if !lacking.is_empty() {
+                    missing.insert(peer.clone(), lacking);
+                }
+            }
+            _ => unreachable.push(peer.clone()),
+        }
+    }
+    Payload::CheckOk {
+        complete: missing.is_empty() && unreachable.is_empty(),
+        missing,
+        unreachable,
+    }
+}
+
+/// Background ticks: membership upkeep, tree announcements and grafts,
+/// tombstones, anti-entropy retransmission of unacknowledged values and, in
+/// partition-test mode, pulls from a random neighbor.
+fn spawn_gossip(runtime: Runtime, store: BroadcastStore) {
+    std::thread::spawn(move || -> anyhow::Result<()> {
+        let mut interval =
+            AdaptiveInterval::new(Duration::from_millis(50), Duration::from_millis(2000));
+        let mut last_pull = Instant::now();
+        loop {
+            let neighbors = store.neighbors();
+            store.expire(Instant::now());
+            let tombstones = store.take_unsent_tombstones();
+            let fresh = std::mem::take(&mut *store.fresh.lock().unwrap());
+            let msgs = store.messages.lock().unwrap().clone();
+
+            let mut outgoing = match store.hyparview.lock().unwrap().as_mut() {
+                Some(view) => view.tick(Instant::now()),
+                None => vec![],
+            };
+            outgoing.extend({
+                let mut tree = store.tree.lock().unwrap();
+                tree.set_peers(&neighbors);
+                tree.tick(Instant::now())
+            });
+            if !tombstones.is_empty() {
+                for neighbor in neighbors.iter() {
+                    let payload = Payload::Expire {
+                        message: tombstones.clone(),
+                    };
+                    outgoing.push((neighbor.clone(), payload));
+                }
+            }
+            let mut pending = 0;
+            // Frames are sent while holding the limiter, so an ack can never
+            // be processed before its frame is recorded.
+            let mut inflight = store.inflight.lock().unwrap();
+            let silent = inflight.expire(Instant::now());
+            if let Some(view) = store.hyparview.lock().unwrap().as_mut() {
+                for peer in silent {
+                    outgoing.extend(view.on_failed(&peer));
+                }
+            }
+            let mut urgent = store.take_urgent();
+            for neighbor in neighbors.iter() {
+                // Anti-entropy: repeat what this neighbor has not acknowledged
+                // yet, except values the tree only just pushed (unless a read
+                // found them missing) and values of frames still waiting for
+                // an ack.
+                let travelling = inflight.in_flight(neighbor);
+                let urgent = urgent.remove(neighbor).unwrap_or_default();
+                let delta: Gossiped = {
+                    let known_by = store.known_by.lock().unwrap();
+                    msgs.iter()
+                        .filter(|value| !fresh.contains(value) || urgent.contains(value))
+                        .filter(|value| !travelling.contains(value))
+                        .filter(|value| {
+                            known_by
+                                .get(neighbor)
+                                .is_none_or(|known| !known.contains(value))
+                        })
+                        .copied()
+                        .collect()
+                };
+                if delta.is_empty() {
+                    continue;
+                }
+                pending += delta.len();
+                if !inflight.has_room(neighbor) {
+                    continue;
+                }
+                let delta = store.prioritize(delta, &urgent, MAX_FRAME);
+                let payload = Payload::GossipBroadcast {
+                    message: delta.clone(),
+                };
+                let frame = runtime.send(neighbor, payload)?;
+                inflight.sent(neighbor, frame, delta, Instant::now());
+            }
+            drop(inflight);
+
+            if store.partition_test && last_pull.elapsed() >= PULL_INTERVAL {
+                last_pull = Instant::now();
+                if let Some(neighbor) = neighbors.choose(&mut rand::thread_rng()) {
+                    let message = store.all().into_iter().collect();
+                    outgoing.push((neighbor.clone(), Payload::Pull { message }));
+                }
+            }
+            for (dest, payload) in outgoing {
+                runtime.send(&dest, payload)?;
+            }
+            store.archive_stable(&store.stability_peers());
+
+            let changed = !fresh.is_empty() || store.tree.lock().unwrap().is_waiting();
+            store.sleep(interval.next(changed, pending));
+        }
+    });
+}
@@ -0,0 +1,231 @@
+//! Primary-backup broadcast, a lighter alternative to gossip: one node, the
+//! primary, puts every value in order and streams that log to the others.
+//!
+//! The primary is the lowest node id not suspected of having failed. It
+//! appends each value it learns to its log and, every tick, sends each
+//! backup the part of the log it has not acknowledged yet, or an empty
+//! heartbeat. A backup that hears nothing from the primary for
+//! [`PRIMARY_TIMEOUT`] suspects it and follows the next id, until it hears
+//! from it again.
+//!
+//! Backups pass their clients' values on to the primary. They also keep
+//! every value they hold until it shows up in the primary's log, and list
+//! the ones missing from it with their acks, so nothing is lost when the
+//! primary changes: the new primary starts from the log it had and appends
+//! them. `read` answers with the log in the primary's order, then any value
+//! it does not hold yet.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    broadcast::{node::Payload, Gossiped, MAX_FRAME},
+    runtime::Runtime,
+};
+
+const STREAM_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a backup waits to hear from the primary before moving on.
+pub const PRIMARY_TIMEOUT: Duration = Duration::from_millis(1000);
+
+struct State {
+    /// Values in the order of the primary this node follows, as far as it
+    /// has them; its own order while it is the primary.
+    log: Vec<usize>,
+    /// The values in `log`.
+    sequenced: Gossiped,
+    /// Every value this node holds, in `log` or not.
+    values: Gossiped,
+    /// Whose order `log` is.
+    following: Option<String>,
+    /// How much of the log each backup holds, while this node is primary.
+    acked: HashMap<String, usize>,
+    /// Nodes that stopped streaming, until they are heard from again.
+    suspected: HashSet<String>,
+    heard_from_primary: Instant,
+}
+
+impl State {
+    fn sequence(&mut self, value: usize) {
+        if self.sequenced.insert(value) {
+            self.log.push(value);
+        }
+    }
+
+    fn unsequenced(&self) -> Vec<usize> {
+        let mut values: Vec<usize> = self.values.difference(&self.sequenced).copied().collect();
+        values.sort();
+        values
+    }
+}
+
+#[derive(Clone)]
+pub struct PrimaryBackup {
+    runtime: Runtime,
+    /// Every node, in id order: the order in which they take over.
+    nodes: Vec<String>,
+    state: Arc<Mutex<State>>,
+}
+
+impl PrimaryBackup {
+    pub fn new(runtime: Runtime) -> Self {
+        let mut nodes = runtime.node_ids().to_vec();
+        nodes.sort();
+        Self {
+            runtime,
+            nodes,
+            state: Arc::new(Mutex::new(State {
+                log: Vec::new(),
+                sequenced: Gossiped::new(),
+                values: Gossiped::new(),
+                following: None,
+                acked: HashMap::new(),
+                suspected: HashSet::new(),
+                heard_from_primary: Instant::now(),
+            })),
+        }
+    }
+
+    /// The lowest id this node does not suspect, possibly its own.
+    fn primary(&self, state: &State) -> String {
+        let me = self.runtime.node_id();
+        self.nodes
+            .iter()
+            .find(|node| *node == me || !state.suspected.contains(*node))
+            .cloned()
+            .unwrap_or_else(|| me.to_string())
+    }
+
+    /// Takes a value from a client or a backup: the primary appends it to
+    /// its log, a backup keeps it and passes it on.
+    pub fn broadcast(&self, value: usize) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.values.insert(value) {
+            return Ok(());
+        }
+        let primary = self.primary(&state);
+        if primary == self.runtime.node_id() {
+            state.sequence(value);
+            return Ok(());
+        }
+        // If this is lost, the next ack reports the value instead.
+        self.runtime
+            .send(&primary, Payload::Broadcast { message: value })?;
+        Ok(())
+    }
+
+    /// Every value held, in the primary's order as far as it is known.
+    pub fn read(&self) -> Vec<usize> {
+        let state = self.state.lock().unwrap();
+        let mut values = state.log.clone();
+        values.extend(state.unsequenced());
+        values
+    }
+
+    /// Takes the part of `from`'s log that starts at `start`, if `from` is
+    /// the primary. Returns the ack.
+    pub fn on_replicate(
+        &self,
+        from: &str,
+        start: usize,
+        messages: &[usize],
+        len: usize,
+    ) -> Option<Payload> {
+        let mut state = self.state.lock().unwrap();
+        state.suspected.remove(from);
+        if self.primary(&state) != from {
+            // It will hear from the primary and stand down.
+            return None;
+        }
+        state.heard_from_primary = Instant::now();
+        if state.following.as_deref() != Some(from) {
+            // A new primary: its order starts over, and what this node
+            // held goes back to it until the order covers it.
+            state.following = Some(from.to_string());
+            state.log.clear();
+            state.sequenced.clear();
+        }
+        if start <= state.log.len() {
+            let known = state.log.len() - start;
+            for &value in messages.iter().skip(known) {
+                state.values.insert(value);
+                state.sequence(value);
+            }
+        }
+        // Values the primary lacks can only be told apart once this node
+        // caught up with its whole log.
+        let unsequenced = match state.log.len() == len {
+            true => state.unsequenced().into_iter().collect(),
+            false => Gossiped::new(),
+        };
+        Some(Payload::ReplicateOk {
+            len: state.log.len(),
+            unsequenced,
+        })
+    }
+
+    /// Records how much of the log a backup holds, and appends the values it
+    /// reports the log lacks.
+    pub fn on_replicate_ok(&self, from: &str, len: usize, unsequenced: &Gossiped) {
+        let mut state = self.state.lock().unwrap();
+        if self.primary(&state) != self.runtime.node_id() {
+            return;
+        }
+        state.acked.insert(from.to_string(), len);
+        let mut unsequenced: Vec<usize> = unsequenced.iter().copied().collect();
+        unsequenced.sort();
+        for value in unsequenced {
+            state.values.insert(value);
+            state.sequence(value);
+        }
+    }
+
+    /// Streams the log to the backups while this node is primary, and
+    /// watches the primary otherwise.
+    pub fn spawn(&self) {
+        let node = self.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(STREAM_INTERVAL);
+            if let Err(err) = node.tick() {
+                eprintln!("primary-backup tick failed: {err:#}");
+            }
+        });
+    }
+
+    fn tick(&self) -> anyhow::Result<()> {
+        let me = self.runtime.node_id();
+        let mut state = self.state.lock().unwrap();
+        let primary = self.primary(&state);
+        if primary != me {
+            if state.heard_from_primary.elapsed() >= PRIMARY_TIMEOUT {
+                eprintln!("suspecting primary {primary}");
+                state.suspected.insert(primary);
+                state.heard_from_primary = Instant::now();
+            }
+            return Ok(());
+        }
+        if state.following.as_deref() != Some(me) {
+            // Taking over: keep the order followed so far, then append
+            // whatever it did not cover yet.
+            state.following = Some(me.to_string());
+            state.acked.clear();
+        }
+        for value in state.unsequenced() {
+            state.sequence(value);
+        }
+        for backup in self.nodes.iter().filter(|node| *node != me) {
+            let start = state.acked.get(backup).copied().unwrap_or_default();
+            let start = start.min(state.log.len());
+            let messages = state.log[start..].iter().take(MAX_FRAME).copied().collect();
+            let replicate = Payload::Replicate {
+                start,
+                messages,
+                len: state.log.len(),
+            };
+            self.runtime.send(backup, replicate)?;
+        }
+        Ok(())
+    }
+}
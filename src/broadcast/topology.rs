@@ -0,0 +1,56 @@
+//! Where the neighbor lists used for gossip come from.
+
+use std::{collections::HashMap, str::FromStr};
+
+use anyhow::bail;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TopologyMode {
+    /// Gossip with every node mentioned in Maelstrom's `topology` message.
+    #[default]
+    Full,
+    /// Gossip only with the neighbors Maelstrom assigned to this node.
+    Maelstrom,
+    /// Ignore Maelstrom and build a two-tier layout with this many groups.
+    Hubs(usize),
+}
+
+impl FromStr for TopologyMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "full" => Ok(Self::Full),
+            None if s == "maelstrom" => Ok(Self::Maelstrom),
+            Some(("hubs", groups)) => match groups.parse() {
+                Ok(0) | Err(_) => bail!("hubs needs a positive group count, got {groups}"),
+                Ok(groups) => Ok(Self::Hubs(groups)),
+            },
+            _ => bail!("unknown topology {s}, expected full, maelstrom or hubs:<groups>"),
+        }
+    }
+}
+
+/// Splits the sorted `node_ids` into `groups` contiguous groups whose first
+/// node is the hub. Leaves only talk to their hub and hubs talk to their
+/// leaves and every other hub, so any value crosses at most
+/// leaf → hub → hub → leaf.
+pub fn hubs(node_ids: &[String], groups: usize) -> HashMap<String, Vec<String>> {
+    let mut sorted = node_ids.to_vec();
+    sorted.sort();
+    let size = sorted.len().div_ceil(groups.max(1)).max(1);
+    let groups: Vec<&[String]> = sorted.chunks(size).collect();
+    let hubs: Vec<&String> = groups.iter().map(|group| &group[0]).collect();
+
+    let mut topology = HashMap::new();
+    for group in groups.iter() {
+        let hub = &group[0];
+        let mut neighbors: Vec<String> = group[1..].to_vec();
+        neighbors.extend(hubs.iter().filter(|&&other| other != hub).cloned().cloned());
+        topology.insert(hub.clone(), neighbors);
+        for leaf in &group[1..] {
+            topology.insert(leaf.clone(), vec![hub.clone()]);
+        }
+    }
+    topology
+}
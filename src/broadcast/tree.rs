@@ -0,0 +1,140 @@
+//! Plumtree-style broadcast.
+//!
+//! New values are pushed eagerly along a spanning tree, while the remaining
+//! links only carry lazy `i_have` announcements. A node that hears about a
+//! value through an announcement but not through the tree within
+//! `graft_timeout` grafts the announcing link into the tree, which repairs it
+//! when an eager edge fails. Duplicate eager deliveries prune the link back
+//! to lazy, so the tree converges towards a single path per node.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::{Duration, Instant},
+};
+
+use crate::broadcast::{node::Payload, Gossiped};
+
+pub struct Plumtree {
+    eager: BTreeSet<String>,
+    lazy: BTreeSet<String>,
+    // Values announced to us that the tree has not delivered yet: who
+    // announced them and when we give up waiting and graft.
+    missing: HashMap<usize, (String, Instant)>,
+    // Values delivered since the last tick, to be announced to lazy peers.
+    announce: Gossiped,
+    graft_timeout: Duration,
+}
+
+impl Plumtree {
+    pub fn new(graft_timeout: Duration) -> Self {
+        Self {
+            eager: BTreeSet::new(),
+            lazy: BTreeSet::new(),
+            missing: HashMap::new(),
+            announce: Gossiped::new(),
+            graft_timeout,
+        }
+    }
+
+    /// Syncs the tree with the current neighbor list. Unknown neighbors start
+    /// as eager links; neighbors that went away are forgotten.
+    pub fn set_peers(&mut self, peers: &[String]) {
+        self.eager.retain(|peer| peers.contains(peer));
+        self.lazy.retain(|peer| peers.contains(peer));
+        for peer in peers {
+            if !self.lazy.contains(peer) {
+                self.eager.insert(peer.clone());
+            }
+        }
+    }
+
+    pub fn is_waiting(&self) -> bool {
+        !self.missing.is_empty()
+    }
+
+    /// Values that were not known locally arrived, either from a client
+    /// (`from` is `None`) or over the tree. Pushes them to every other eager
+    /// peer.
+    pub fn on_new(&mut self, values: &Gossiped, from: Option<&str>) -> Vec<(String, Payload)> {
+        for value in values {
+            self.missing.remove(value);
+        }
+        self.announce.extend(values.iter().copied());
+        self.eager
+            .iter()
+            .filter(|&peer| Some(peer.as_str()) != from)
+            .map(|peer| {
+                let payload = Payload::TreePush {
+                    message: values.clone(),
+                };
+                (peer.clone(), payload)
+            })
+            .collect()
+    }
+
+    /// An eager push only carried values we already had: the link is
+    /// redundant, so demote it to lazy and tell the peer to do the same.
+    pub fn on_duplicate(&mut self, from: &str) -> Vec<(String, Payload)> {
+        if self.eager.remove(from) {
+            self.lazy.insert(from.to_string());
+            vec![(from.to_string(), Payload::Prune)]
+        } else {
+            vec![]
+        }
+    }
+
+    /// `from` announced values we have not received yet.
+    pub fn on_ihave(&mut self, from: &str, unknown: &Gossiped, now: Instant) {
+        for &value in unknown {
+            self.missing
+                .entry(value)
+                .or_insert_with(|| (from.to_string(), now + self.graft_timeout));
+        }
+    }
+
+    /// `from` asked to join our eager set, replying with the values it lacks.
+    pub fn on_graft(&mut self, from: &str, values: Gossiped) -> Vec<(String, Payload)> {
+        self.lazy.remove(from);
+        self.eager.insert(from.to_string());
+        if values.is_empty() {
+            return vec![];
+        }
+        vec![(from.to_string(), Payload::TreePush { message: values })]
+    }
+
+    pub fn on_prune(&mut self, from: &str) {
+        if self.eager.remove(from) {
+            self.lazy.insert(from.to_string());
+        }
+    }
+
+    /// Announces recent values to lazy peers and grafts the links whose
+    /// announcements the tree failed to follow up on.
+    pub fn tick(&mut self, now: Instant) -> Vec<(String, Payload)> {
+        let mut outgoing = Vec::new();
+        let announce = std::mem::take(&mut self.announce);
+        if !announce.is_empty() {
+            for peer in self.lazy.iter() {
+                let payload = Payload::IHave {
+                    message: announce.clone(),
+                };
+                outgoing.push((peer.clone(), payload));
+            }
+        }
+
+        let mut grafts: HashMap<String, Gossiped> = HashMap::new();
+        self.missing.retain(|&value, (announcer, deadline)| {
+            if *deadline > now {
+                return true;
+            }
+            grafts.entry(announcer.clone()).or_default().insert(value);
+            false
+        });
+        for (peer, values) in grafts {
+            self.lazy.remove(&peer);
+            self.eager.insert(peer.clone());
+            outgoing.push((peer, Payload::Graft { message: values }));
+        }
+        outgoing
+    }
+}
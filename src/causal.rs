@@ -0,0 +1,81 @@
+//! Causal delivery of broadcast operations.
+//!
+//! Every node stamps the operations it broadcasts with a vector clock of
+//! what it had applied, its own operations included. A receiver holds an
+//! operation back until it has applied everything the clock counts, so
+//! operations are applied after whatever they were based on, on every
+//! node, however the network reorders them. Senders must broadcast every
+//! stamped operation to every node and retry until it arrives, or the
+//! operations after it are held back for good.
+
+use crate::{clock::VectorClock, message::NodeId};
+
+/// One node's delivery state.
+#[derive(Debug)]
+pub struct CausalDelivery<T> {
+    me: NodeId,
+    /// The operations applied here, from every node.
+    delivered: VectorClock,
+    /// Operations received before something they depend on, with their
+    /// sender and clock.
+    pending: Vec<(NodeId, VectorClock, T)>,
+}
+
+impl<T> CausalDelivery<T> {
+    pub fn new(me: &str) -> Self {
+        Self {
+            me: me.to_string(),
+            delivered: VectorClock::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Counts a local operation as applied and returns the clock to
+    /// broadcast it with. Stamp operations in the order they are applied.
+    pub fn stamp(&mut self) -> VectorClock {
+        self.delivered.increment(&self.me);
+        self.delivered.clone()
+    }
+
+    /// Takes in an operation `from` sent at `clock`. Returns the operations
+    /// that can be applied now, in an order respecting causality: this one
+    /// and any it unblocked, or none if it still waits. Duplicates are
+    /// dropped.
+    pub fn receive(&mut self, from: &str, clock: VectorClock, op: T) -> Vec<T> {
+        let counter = clock.get(from);
+        let seen = counter <= self.delivered.get(from)
+            || self
+                .pending
+                .iter()
+                .any(|(sender, pending, _)| sender == from && pending.get(from) == counter);
+        if seen {
+            return Vec::new();
+        }
+        self.pending.push((from.to_string(), clock, op));
+        let mut ready = Vec::new();
+        while let Some(index) = self
+            .pending
+            .iter()
+            .position(|(from, clock, _)| self.deliverable(from, clock))
+        {
+            let (from, _, op) = self.pending.swap_remove(index);
+            self.delivered.increment(&from);
+            ready.push(op);
+        }
+        ready
+    }
+
+    /// Operations received but waiting on others.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Whether an operation is the next one from `from` and everything else
+    /// it saw was applied here.
+    fn deliverable(&self, from: &str, clock: &VectorClock) -> bool {
+        clock.get(from) == self.delivered.get(from) + 1
+            && clock
+                .iter()
+                .all(|(node, counter)| node == from || counter <= self.delivered.get(node))
+    }
+}
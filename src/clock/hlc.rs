@@ -0,0 +1,58 @@
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::clock::now_ms;
+
+/// A hybrid logical time: wall-clock millis, and a counter ordering events
+/// within the same milli or while the wall clock lags behind a time already
+/// seen. Compares by millis, then counter.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HlcTimestamp {
+    pub ms: u64,
+    pub logical: u32,
+}
+
+/// A hybrid logical clock. Its times stay close to the wall clock, yet are
+/// strictly increasing on each node and ahead of every time it observed,
+/// even when the nodes' clocks are skewed or the wall clock steps back.
+#[derive(Debug, Default)]
+pub struct Hlc {
+    last: Mutex<HlcTimestamp>,
+}
+
+impl Hlc {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The time of a local event, such as a write.
+    pub fn tick(&self) -> HlcTimestamp {
+        self.advance(HlcTimestamp::default())
+    }
+
+    /// Catches up with a time received from another node, as the event of
+    /// receiving it. Returns the new time.
+    pub fn observe(&self, remote: HlcTimestamp) -> HlcTimestamp {
+        self.advance(remote)
+    }
+
+    /// The next time after both the last one and `seen`.
+    fn advance(&self, seen: HlcTimestamp) -> HlcTimestamp {
+        let mut last = self.last.lock().unwrap();
+        let wall = now_ms();
+        let latest = (*last).max(seen);
+        *last = if wall > latest.ms {
+            HlcTimestamp {
+                ms: wall,
+                logical: 0,
+            }
+        } else {
+            HlcTimestamp {
+                ms: latest.ms,
+                logical: latest.logical + 1,
+            }
+        };
+        *last
+    }
+}
@@ -0,0 +1,36 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A Lamport clock: ticks on every local event and jumps past every time it
+/// hears of, so an event's time is greater than that of every event that
+/// could have caused it.
+///
+/// The runtime keeps one per node. It ticks on every message sent, stamps
+/// the time on messages to other nodes and observes the stamp on messages
+/// from them, so workloads only need `now` or `tick` to order their events.
+#[derive(Debug, Default)]
+pub struct Lamport {
+    time: AtomicU64,
+}
+
+impl Lamport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The time of the latest event.
+    pub fn now(&self) -> u64 {
+        self.time.load(Ordering::SeqCst)
+    }
+
+    /// Advances the clock for a local event and returns its time.
+    pub fn tick(&self) -> u64 {
+        self.time.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Catches up with a time received from another node, as the event of
+    /// receiving it. Returns the new time.
+    pub fn observe(&self, time: u64) -> u64 {
+        self.time.fetch_max(time, Ordering::SeqCst);
+        self.tick()
+    }
+}
@@ -0,0 +1,22 @@
+//! Logical clocks for ordering events across nodes.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+mod hlc;
+mod lamport;
+mod stability;
+mod vector;
+
+pub use hlc::{Hlc, HlcTimestamp};
+pub use lamport::Lamport;
+pub use stability::Stability;
+pub use vector::{Dot, VectorClock};
+
+/// Wall-clock millis since the Unix epoch, as leases and expiries are
+/// stamped.
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
@@ -0,0 +1,39 @@
+use std::collections::BTreeMap;
+
+use crate::{clock::VectorClock, gossip::Merge, message::NodeId};
+
+/// Tracks the latest clock each node reported, to tell which events are
+/// causally stable: seen by every node, so nothing concurrent with them can
+/// still show up. Metadata kept only to order such events against others,
+/// like tombstones, can then be dropped.
+#[derive(Clone, Debug, Default)]
+pub struct Stability {
+    seen: BTreeMap<NodeId, VectorClock>,
+}
+
+impl Stability {
+    /// A tracker over `node_ids`, none of which reported yet.
+    pub fn new(node_ids: &[NodeId]) -> Self {
+        let seen = node_ids
+            .iter()
+            .map(|id| (id.clone(), VectorClock::new()))
+            .collect();
+        Self { seen }
+    }
+
+    /// `node` has seen the events in `clock`. Reports arriving out of order
+    /// never move a node back.
+    pub fn observe(&mut self, node: &str, clock: VectorClock) {
+        self.seen.entry(node.to_string()).or_default().merge(clock);
+    }
+
+    /// The events every node has seen: the pointwise minimum of the clocks
+    /// reported. Empty until every node reported.
+    pub fn stable(&self) -> VectorClock {
+        let mut clocks = self.seen.values();
+        let Some(first) = clocks.next() else {
+            return VectorClock::new();
+        };
+        clocks.fold(first.clone(), |stable, clock| stable.meet(clock))
+    }
+}
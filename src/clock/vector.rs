@@ -0,0 +1,110 @@
+use std::{cmp::Ordering, collections::BTreeMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{gossip::Merge, message::NodeId};
+
+/// One event: the node it happened on and that node's count of events
+/// up to it.
+pub type Dot = (NodeId, u64);
+
+/// A vector clock: how many events of each node an event has seen, itself
+/// included. Unlike a Lamport time it tells concurrent events apart from
+/// ordered ones: `partial_cmp` is `Less` when `self` happened before
+/// `other`, `Greater` after, and `None` when they are concurrent.
+///
+/// Serializes as a map from node id to counter, so it embeds directly in
+/// gossip payloads.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct VectorClock {
+    counters: BTreeMap<NodeId, u64>,
+}
+
+impl VectorClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, node: &str) -> u64 {
+        self.counters.get(node).copied().unwrap_or_default()
+    }
+
+    /// Records an event on `node`. Returns its counter.
+    pub fn increment(&mut self, node: &str) -> u64 {
+        let counter = self.counters.entry(node.to_string()).or_default();
+        *counter += 1;
+        *counter
+    }
+
+    /// The nodes with events, and their counters.
+    pub fn iter(&self) -> impl Iterator<Item = (&NodeId, u64)> {
+        self.counters.iter().map(|(node, &counter)| (node, counter))
+    }
+
+    /// Whether the event `dot` is among those this clock saw.
+    pub fn contains(&self, (node, counter): &Dot) -> bool {
+        self.get(node) >= *counter
+    }
+
+    /// Raises the counter of `node` to at least `counter`.
+    pub fn advance(&mut self, node: &str, counter: u64) {
+        let entry = self.counters.entry(node.to_string()).or_default();
+        *entry = (*entry).max(counter);
+    }
+
+    /// Pointwise minimum: the events both clocks saw.
+    pub fn meet(&self, other: &Self) -> Self {
+        let counters = self
+            .counters
+            .iter()
+            .map(|(node, &counter)| (node.clone(), counter.min(other.get(node))))
+            .filter(|&(_, counter)| counter > 0)
+            .collect();
+        Self { counters }
+    }
+
+    /// Whether `self` saw every event `other` saw: it happened after
+    /// `other` or is the same clock.
+    pub fn descends(&self, other: &Self) -> bool {
+        other
+            .counters
+            .iter()
+            .all(|(node, &counter)| self.get(node) >= counter)
+    }
+
+    /// Whether every event `self` saw, `other` saw too, and more.
+    pub fn happens_before(&self, other: &Self) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Less)
+    }
+
+    pub fn concurrent(&self, other: &Self) -> bool {
+        self.partial_cmp(other).is_none()
+    }
+}
+
+impl Merge for VectorClock {
+    /// Pointwise maximum: the clock of an event that saw both.
+    fn merge(&mut self, other: Self) {
+        for (node, counter) in other.counters {
+            let entry = self.counters.entry(node).or_default();
+            *entry = (*entry).max(counter);
+        }
+    }
+}
+
+impl PartialOrd for VectorClock {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        let nodes = self.counters.keys().chain(other.counters.keys());
+        let mut ordering = Ordering::Equal;
+        for node in nodes {
+            match (ordering, self.get(node).cmp(&other.get(node))) {
+                (_, Ordering::Equal) => {}
+                (Ordering::Equal, side) => ordering = side,
+                (current, side) if current != side => return None,
+                _ => {}
+            }
+        }
+        Some(ordering)
+    }
+}
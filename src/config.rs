@@ -0,0 +1,77 @@
+use std::{collections::HashMap, str::FromStr, time::Duration};
+
+use anyhow::Context;
+
+/// Command line options given as `--name value`.
+///
+/// Maelstrom starts the binary without arguments, so every option can also be
+/// set through the environment as `FLY_NAME` (upper case, dashes turned into
+/// underscores). Command line values win over the environment.
+#[derive(Default, Clone, Debug)]
+pub struct Config {
+    values: HashMap<String, String>,
+}
+
+impl Config {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut values = HashMap::new();
+        for (key, value) in std::env::vars() {
+            if let Some(name) = key.strip_prefix("FLY_") {
+                values.insert(name.to_lowercase().replace('_', "-"), value);
+            }
+        }
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let name = arg
+                .strip_prefix("--")
+                .with_context(|| format!("unexpected argument {arg}"))?;
+            let value = args
+                .next()
+                .with_context(|| format!("missing value for --{name}"))?;
+            values.insert(name.to_string(), value);
+        }
+        Ok(Self { values })
+    }
+
+    /// These options with `--name value` added, overriding any earlier
+    /// value.
+    pub fn with(mut self, name: &str, value: impl ToString) -> Self {
+        self.values.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+
+    pub fn parse<T>(&self, name: &str) -> anyhow::Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: Into<anyhow::Error>,
+    {
+        self.get(name)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(Into::into)
+                    .with_context(|| format!("invalid --{name} {value}"))
+            })
+            .transpose()
+    }
+
+    /// A comma-separated option, empty when unset.
+    pub fn list(&self, name: &str) -> Vec<String> {
+        self.get(name)
+            .into_iter()
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// An option expressed in milliseconds.
+    pub fn millis(&self, name: &str) -> anyhow::Result<Option<Duration>> {
+        Ok(self.parse::<u64>(name)?.map(Duration::from_millis))
+    }
+}
@@ -0,0 +1,116 @@
+//! What a replicated state machine needs from a consensus protocol, so a
+//! workload can run on any of them.
+//!
+//! Every implementation agrees on one sequence of commands and feeds it, in
+//! order, to a [`StateMachine`] on every node. A client's command goes to
+//! [`propose`](Consensus::propose) on the leader, which answers once the
+//! command is committed and applied; reads go through
+//! [`read_index`](Consensus::read_index), which waits until the leader has
+//! confirmed it still leads and its copy caught up, then
+//! [`read`](Consensus::read) the copy. A server that does not lead refuses
+//! with [`NotLeader`], naming the leader it knows of.
+//!
+//! `--consensus` picks the protocol: `raft` (default, see [`crate::raft`]),
+//! `paxos` (Multi-Paxos, see [`crate::paxos`]) or `vr` (Viewstamped
+//! Replication, see [`crate::vr`]). The last two are written as a bare
+//! [`Protocol`] and share one [`Server`] to mount them.
+
+mod server;
+
+pub use self::server::{Committed, Protocol, Server};
+
+use std::{collections::BTreeSet, fmt, str::FromStr, sync::mpsc::Receiver};
+
+use anyhow::bail;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{config::Config, message::NodeId, runtime::Runtime};
+
+/// What a consensus protocol drives: every server applies the same
+/// commands in the same order, so their state machines go through the
+/// same states. Its [`Snapshot`](crate::crdt::Snapshot) stands in for the
+/// commands a log compacts away.
+pub trait StateMachine: crate::crdt::Snapshot + Send + 'static {
+    type Command: Serialize + DeserializeOwned + Clone + Send + 'static;
+    type Output: Send + 'static;
+
+    fn apply(&mut self, command: Self::Command) -> Self::Output;
+}
+
+/// A proposal was made on a server that does not lead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotLeader {
+    /// The leader this server knows of, to retry with.
+    pub leader: Option<NodeId>,
+}
+
+impl fmt::Display for NotLeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.leader {
+            Some(leader) => write!(f, "not the leader, {leader} is"),
+            None => write!(f, "not the leader, and no leader is known"),
+        }
+    }
+}
+
+impl std::error::Error for NotLeader {}
+
+/// A consensus server mounted on a node, replicating a state machine `S`.
+/// Clones share the server.
+pub trait Consensus<S: StateMachine>: Clone + Send + 'static {
+    /// What the servers send each other.
+    type Message: Serialize + DeserializeOwned + Send + 'static;
+
+    /// Starts a server over every node of the cluster. `wrap` turns the
+    /// protocol's messages into the workload's message, which the peers
+    /// pass to [`receive`](Self::receive).
+    fn mount<P, F>(runtime: Runtime, config: &Config, machine: S, wrap: F) -> anyhow::Result<Self>
+    where
+        P: Serialize,
+        F: Fn(Self::Message) -> P + Send + Sync + 'static;
+
+    /// Handles a message from the peer `from`.
+    fn receive(&self, from: &str, message: Self::Message);
+
+    /// Proposes `command`. The receiver yields the state machine's output
+    /// once the command is committed and applied, and disconnects if
+    /// another command took its place; it may also wait forever if this
+    /// server loses leadership first, so wait on it with a timeout.
+    fn propose(&self, command: S::Command) -> Result<Receiver<S::Output>, NotLeader>;
+
+    /// Starts a linearizable read. The receiver yields once
+    /// [`read`](Self::read) sees every command committed before the call,
+    /// and disconnects if this server stops leading first.
+    fn read_index(&self) -> Result<Receiver<()>, NotLeader>;
+
+    /// Reads the state machine as applied so far.
+    fn read<R>(&self, f: impl FnOnce(&S) -> R) -> R;
+
+    /// Starts moving the cluster to `voters`, for an admin request. The
+    /// receiver yields once the new voters alone are in force; wait on it
+    /// with a timeout. Fails with [`NotLeader`] on a follower, and on
+    /// protocols that run on a fixed membership.
+    fn reconfigure(&self, voters: BTreeSet<NodeId>) -> anyhow::Result<Receiver<()>>;
+}
+
+/// Which consensus protocol to run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Algorithm {
+    #[default]
+    Raft,
+    Paxos,
+    Vr,
+}
+
+impl FromStr for Algorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raft" => Ok(Self::Raft),
+            "paxos" => Ok(Self::Paxos),
+            "vr" => Ok(Self::Vr),
+            _ => bail!("unknown consensus {s}, expected raft, paxos or vr"),
+        }
+    }
+}
@@ -0,0 +1,266 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    config::Config,
+    consensus::{Consensus, NotLeader, StateMachine},
+    message::NodeId,
+    runtime::Runtime,
+};
+
+/// How often timers are checked.
+const TICK: Duration = Duration::from_millis(10);
+
+/// A consensus protocol without I/O or threads, for a [`Server`] to mount:
+/// messages to send pile up in an outbox and committed commands are handed
+/// out in order, as [`Raft`](crate::raft::Raft) does.
+pub trait Protocol: Send + 'static {
+    type Command;
+    type Message: Serialize + DeserializeOwned + Send + 'static;
+
+    fn new(me: &str, node_ids: &[String], now: Instant) -> Self;
+
+    /// Runs whatever timers are due.
+    fn tick(&mut self, now: Instant);
+
+    fn handle(&mut self, from: &str, message: Self::Message, now: Instant);
+
+    /// Proposes `command` if this server leads, returning where it goes in
+    /// the log and a tag that [`Committed::proposal`] matches if it is the
+    /// command committed there.
+    fn propose(&mut self, command: Self::Command) -> Option<(u64, u64)>;
+
+    /// Starts a linearizable read if this server leads, returning its id
+    /// for [`take_reads`](Self::take_reads).
+    fn read_index(&mut self) -> Option<u64>;
+
+    fn is_leader(&self) -> bool;
+
+    /// The leader this server knows of.
+    fn leader(&self) -> Option<&str>;
+
+    /// What committed since the last call, in log order.
+    fn take_committed(&mut self) -> Vec<Committed<Self::Command>>;
+
+    /// The reads that may be served once what committed so far is applied.
+    fn take_reads(&mut self) -> Vec<u64>;
+
+    /// The messages to send, in order.
+    fn take_outbox(&mut self) -> Vec<(NodeId, Self::Message)>;
+}
+
+/// A position in the log, once committed.
+pub struct Committed<C> {
+    pub position: u64,
+    /// The tag [`Protocol::propose`] returned, if this server may have
+    /// proposed what committed here.
+    pub proposal: Option<u64>,
+    /// What the state machine applies, or `None` for a no-op.
+    pub command: Option<C>,
+}
+
+/// Waiters for proposed commands, by position, with their tags.
+type Waiters<O> = HashMap<u64, (u64, Sender<O>)>;
+
+/// Waiters for reads, by the id the protocol gave them.
+type Reads = HashMap<u64, Sender<()>>;
+
+/// What the applier thread is handed, in order.
+enum Apply<C> {
+    Committed(Vec<Committed<C>>),
+    /// Reads that may be served once what came before is applied.
+    Reads(Vec<Sender<()>>),
+}
+
+/// Sends a protocol message to a peer, wrapped in the workload's message.
+type Transport<M> = Arc<dyn Fn(&str, M) + Send + Sync>;
+
+/// A [`Protocol`] mounted on a node, the way
+/// [`RaftServer`](crate::raft::RaftServer) mounts Raft: timers run on their
+/// own thread, messages go out through the runtime, and committed commands
+/// travel down an apply channel to a thread that feeds them to the state
+/// machine. It runs on the nodes given at `init`, in memory only. Clones
+/// share the server.
+pub struct Server<P: Protocol, S: StateMachine> {
+    protocol: Arc<Mutex<P>>,
+    machine: Arc<Mutex<S>>,
+    waiters: Arc<Mutex<Waiters<S::Output>>>,
+    reads: Arc<Mutex<Reads>>,
+    applier: Sender<Apply<S::Command>>,
+    send: Transport<P::Message>,
+}
+
+impl<P: Protocol, S: StateMachine> Clone for Server<P, S> {
+    fn clone(&self) -> Self {
+        Self {
+            protocol: self.protocol.clone(),
+            machine: self.machine.clone(),
+            waiters: self.waiters.clone(),
+            reads: self.reads.clone(),
+            applier: self.applier.clone(),
+            send: self.send.clone(),
+        }
+    }
+}
+
+impl<P, S> Server<P, S>
+where
+    P: Protocol<Command = S::Command>,
+    S: StateMachine,
+{
+    pub fn is_leader(&self) -> bool {
+        self.protocol.lock().unwrap().is_leader()
+    }
+
+    /// Runs `f` on the protocol's state, then sends what it queued and
+    /// hands what it committed to the applier, under the same lock so both
+    /// keep their order.
+    fn with_protocol<R>(&self, f: impl FnOnce(&mut P) -> R) -> R {
+        let mut protocol = self.protocol.lock().unwrap();
+        let result = f(&mut protocol);
+        for (to, message) in protocol.take_outbox() {
+            (self.send)(&to, message);
+        }
+        // The applier only stops with the process.
+        let committed = protocol.take_committed();
+        if !committed.is_empty() {
+            let _ = self.applier.send(Apply::Committed(committed));
+        }
+        let mut reads = self.reads.lock().unwrap();
+        let ready: Vec<_> = protocol
+            .take_reads()
+            .iter()
+            .filter_map(|id| reads.remove(id))
+            .collect();
+        if !ready.is_empty() {
+            let _ = self.applier.send(Apply::Reads(ready));
+        }
+        if !protocol.is_leader() {
+            // Reads still waiting never will be.
+            reads.clear();
+        }
+        result
+    }
+
+    fn spawn_applier(&self, applies: Receiver<Apply<S::Command>>) {
+        let server = self.clone();
+        std::thread::spawn(move || {
+            for apply in applies {
+                match apply {
+                    Apply::Committed(committed) => {
+                        for committed in committed {
+                            server.apply(committed);
+                        }
+                    }
+                    Apply::Reads(ready) => {
+                        for done in ready {
+                            // The reader may have given up waiting.
+                            let _ = done.send(());
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    fn apply(&self, committed: Committed<S::Command>) {
+        let waiter = self.waiters.lock().unwrap().remove(&committed.position);
+        let Some(command) = committed.command else {
+            return;
+        };
+        let output = self.machine.lock().unwrap().apply(command);
+        if let Some((proposal, done)) = waiter {
+            if committed.proposal == Some(proposal) {
+                // The proposer may have given up waiting.
+                let _ = done.send(output);
+            }
+        }
+    }
+}
+
+impl<P, S> Consensus<S> for Server<P, S>
+where
+    P: Protocol<Command = S::Command>,
+    S: StateMachine,
+{
+    type Message = P::Message;
+
+    fn mount<W, F>(runtime: Runtime, _config: &Config, machine: S, wrap: F) -> anyhow::Result<Self>
+    where
+        W: Serialize,
+        F: Fn(Self::Message) -> W + Send + Sync + 'static,
+    {
+        let protocol = P::new(runtime.node_id(), runtime.node_ids(), Instant::now());
+        let (applier, committed) = mpsc::channel();
+        let send_runtime = runtime.clone();
+        let server = Self {
+            protocol: Arc::new(Mutex::new(protocol)),
+            machine: Arc::new(Mutex::new(machine)),
+            waiters: Arc::new(Mutex::new(HashMap::new())),
+            reads: Arc::new(Mutex::new(HashMap::new())),
+            applier,
+            send: Arc::new(move |to, message| {
+                if let Err(err) = send_runtime.send(to, wrap(message)) {
+                    eprintln!("consensus send to {to} failed: {err:#}");
+                }
+            }),
+        };
+        server.spawn_applier(committed);
+        let ticker = server.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(TICK);
+            ticker.with_protocol(|protocol| protocol.tick(Instant::now()));
+        });
+        Ok(server)
+    }
+
+    fn receive(&self, from: &str, message: Self::Message) {
+        self.with_protocol(|protocol| protocol.handle(from, message, Instant::now()));
+    }
+
+    fn propose(&self, command: S::Command) -> Result<Receiver<S::Output>, NotLeader> {
+        let (done, output) = mpsc::channel();
+        self.with_protocol(|protocol| match protocol.propose(command) {
+            Some((position, proposal)) => {
+                self.waiters
+                    .lock()
+                    .unwrap()
+                    .insert(position, (proposal, done));
+                Ok(output)
+            }
+            None => Err(NotLeader {
+                leader: protocol.leader().map(str::to_string),
+            }),
+        })
+    }
+
+    fn read_index(&self) -> Result<Receiver<()>, NotLeader> {
+        let (done, ready) = mpsc::channel();
+        self.with_protocol(|protocol| match protocol.read_index() {
+            Some(id) => {
+                self.reads.lock().unwrap().insert(id, done);
+                Ok(ready)
+            }
+            None => Err(NotLeader {
+                leader: protocol.leader().map(str::to_string),
+            }),
+        })
+    }
+
+    fn read<R>(&self, f: impl FnOnce(&S) -> R) -> R {
+        f(&self.machine.lock().unwrap())
+    }
+
+    fn reconfigure(&self, _voters: BTreeSet<NodeId>) -> anyhow::Result<Receiver<()>> {
+        bail!("this protocol runs on a fixed membership")
+    }
+}
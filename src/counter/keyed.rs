@@ -0,0 +1,411 @@
+//! Keyed counters: `add {key, delta}` and `read {key}` on any number of
+//! named counters. Each key is owned by one node, picked on a consistent
+//! hash ring of the node ids (see [`crate::ring`]); the owner keeps the
+//! total and the other nodes forward requests for that key to it.
+//!
+//! A `reconfigure {nodes}` admin message moves the keys onto a ring of just
+//! those nodes. The node it reaches passes it on to every other node, and
+//! each works out, from the two rings, which nodes it gains keys from and
+//! which it loses keys to. A node streams the totals it holds for keys it
+//! loses to their new owner in batches, each acknowledged before the next,
+//! and serves those keys itself until it sends a final, empty batch saying
+//! it is done. From then on it rejects them, and once that batch arrives the
+//! new owner, which turned them away until that point, starts serving them.
+//! Batches that fail are sent again, backing off between attempts. The admin
+//! gets `reconfigure_ok` once every node's final batches were acknowledged.
+//!
+//! Only keyed counters rebalance this way. The other workloads that place
+//! keys on a ring, the `owned` and `replicated` kafka stores and the
+//! `quorum` mode of `lin_kv`, keep the ring of the nodes given at init.
+//!
+//! A node only forwards requests that come from clients. One that comes
+//! from another node, for a key this node does not serve, is rejected with
+//! error 11: the two disagree on the ring for now, and the client retries.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backoff::Backoff,
+    message::{error_code, Init, Message},
+    ring::Ring,
+    runtime::{Node, Runtime},
+};
+
+const FORWARD_TIMEOUT: Duration = Duration::from_millis(1000);
+const HANDOFF_TIMEOUT: Duration = Duration::from_millis(500);
+/// Bounds on the wait before a failed handoff batch is sent again.
+const MIN_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_BACKOFF: Duration = Duration::from_millis(500);
+/// How long a `reconfigure` waits for every node to hand off its keys.
+const RECONFIGURE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Add {
+        key: String,
+        delta: i64,
+    },
+    AddOk,
+    Read {
+        key: String,
+    },
+    ReadOk {
+        value: i64,
+    },
+    /// Admin: moves the keys onto a ring of `nodes`, all of them given at
+    /// init.
+    Reconfigure {
+        nodes: Vec<String>,
+    },
+    ReconfigureOk,
+    /// Totals of keys the sender loses to the receiver on the ring of
+    /// `nodes`. `seq` grows with every batch a sender sends, so a batch
+    /// sent again is only added once; `done` says the sender has no more.
+    Handoff {
+        nodes: Vec<String>,
+        seq: u64,
+        totals: HashMap<String, i64>,
+        done: bool,
+    },
+    HandoffOk,
+}
+
+struct State {
+    /// The sorted ids the ring is made of.
+    nodes: Vec<String>,
+    ring: Ring,
+    /// While keys move: the ring they move from.
+    previous: Option<Ring>,
+    // Totals of the keys this node serves, or hands off.
+    totals: HashMap<String, i64>,
+    // Totals sent in a handoff batch not acknowledged yet. They still
+    // count towards reads here.
+    in_flight: HashMap<String, i64>,
+    /// Nodes this node loses keys to and has not had its last batch
+    /// acknowledged by yet.
+    handing_to: BTreeSet<String>,
+    /// Of those, the nodes its last batch is on its way to. Their keys are
+    /// rejected here already.
+    closed: BTreeSet<String>,
+    /// Nodes this node gains keys from and has not heard the last batch of.
+    awaiting: BTreeSet<String>,
+    /// The last handoff batch sent by this node.
+    seq: u64,
+    /// The last handoff batch added, by sender.
+    applied: HashMap<String, u64>,
+}
+
+/// Whether this node answers for a key, or who should.
+enum Serve {
+    Here,
+    /// The key is on its way here and cannot be served yet.
+    Arriving,
+    Elsewhere(String),
+}
+
+impl State {
+    fn serve(&self, me: &str, key: &str) -> Serve {
+        let owner = self.ring.owner(key);
+        let before = self.previous.as_ref().map(|ring| ring.owner(key));
+        match before {
+            Some(before) if before == me && owner != me => {
+                match self.handing_to.contains(owner) && !self.closed.contains(owner) {
+                    true => Serve::Here,
+                    false => Serve::Elsewhere(owner.to_string()),
+                }
+            }
+            Some(before) if before != me && owner == me && self.awaiting.contains(before) => {
+                Serve::Arriving
+            }
+            _ if owner == me => Serve::Here,
+            _ => Serve::Elsewhere(owner.to_string()),
+        }
+    }
+
+    fn value(&self, key: &str) -> i64 {
+        let total = self.totals.get(key).copied().unwrap_or_default();
+        total + self.in_flight.get(key).copied().unwrap_or_default()
+    }
+
+    /// Ends the move once nothing is left to send or wait for.
+    fn settle(&mut self) {
+        if self.handing_to.is_empty() && self.awaiting.is_empty() {
+            self.previous = None;
+            self.closed.clear();
+        }
+    }
+}
+
+pub struct KeyedCounterNode {
+    runtime: Runtime,
+    state: Arc<Mutex<State>>,
+}
+
+impl Node<Payload> for KeyedCounterNode {
+    fn from_init(runtime: Runtime, init: Init) -> anyhow::Result<Self> {
+        let mut nodes = init.node_ids;
+        nodes.sort();
+        let state = State {
+            ring: Ring::new(&nodes)?,
+            nodes,
+            previous: None,
+            totals: HashMap::new(),
+            in_flight: HashMap::new(),
+            handing_to: BTreeSet::new(),
+            closed: BTreeSet::new(),
+            awaiting: BTreeSet::new(),
+            seq: 0,
+            applied: HashMap::new(),
+        };
+        Ok(Self {
+            runtime,
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    fn step(&mut self, input: Message<Payload>) -> anyhow::Result<()> {
+        let key = match &input.body.payload {
+            Payload::Add { key, .. } | Payload::Read { key } => key.clone(),
+            Payload::Reconfigure { nodes } => {
+                let nodes = nodes.clone();
+                return self.reconfigure(nodes, input);
+            }
+            Payload::Handoff {
+                nodes,
+                seq,
+                totals,
+                done,
+            } => {
+                if nodes.is_empty() {
+                    let text = "handoff takes a non-empty list of nodes";
+                    return self
+                        .runtime
+                        .reply_error(&input, error_code::MALFORMED_REQUEST, text);
+                }
+                if !self.take_handoff(&input.src, nodes.clone(), *seq, totals, *done)? {
+                    let text = "keys are still moving to another ring";
+                    let code = error_code::TEMPORARILY_UNAVAILABLE;
+                    return self.runtime.reply_error(&input, code, text);
+                }
+                return self.runtime.reply(&input, Payload::HandoffOk);
+            }
+            Payload::AddOk
+            | Payload::ReadOk { .. }
+            | Payload::ReconfigureOk
+            | Payload::HandoffOk => return Ok(()),
+        };
+        let me = self.runtime.node_id();
+        let mut state = self.state.lock().unwrap();
+        match state.serve(me, &key) {
+            Serve::Here => {}
+            Serve::Arriving => {
+                let text = format!("key {key} is still moving here");
+                let code = error_code::TEMPORARILY_UNAVAILABLE;
+                return self.runtime.reply_error(&input, code, text);
+            }
+            Serve::Elsewhere(_) if self.runtime.node_ids().contains(&input.src) => {
+                let text = format!("this node does not serve key {key}");
+                let code = error_code::TEMPORARILY_UNAVAILABLE;
+                return self.runtime.reply_error(&input, code, text);
+            }
+            Serve::Elsewhere(owner) => {
+                drop(state);
+                self.runtime.forward(&owner, input, FORWARD_TIMEOUT);
+                return Ok(());
+            }
+        }
+        match input.body.payload {
+            Payload::Add { delta, .. } => {
+                *state.totals.entry(key).or_default() += delta;
+                self.runtime.reply(&input, Payload::AddOk)
+            }
+            _ => {
+                let value = state.value(&key);
+                self.runtime.reply(&input, Payload::ReadOk { value })
+            }
+        }
+    }
+}
+
+impl KeyedCounterNode {
+    /// Starts moving keys onto the ring of `nodes`, and answers `input` once
+    /// this node, and every other if it came from the admin, handed off
+    /// what it loses.
+    fn reconfigure(&self, mut nodes: Vec<String>, input: Message<Payload>) -> anyhow::Result<()> {
+        nodes.sort();
+        nodes.dedup();
+        let known = nodes
+            .iter()
+            .all(|node| self.runtime.node_ids().contains(node));
+        if nodes.is_empty() || !known {
+            let text = "reconfigure takes a non-empty list of nodes given at init";
+            return self
+                .runtime
+                .reply_error(&input, error_code::MALFORMED_REQUEST, text);
+        }
+        if !self.begin(nodes.clone())? {
+            let text = "keys are still moving to another ring";
+            let code = error_code::TEMPORARILY_UNAVAILABLE;
+            return self.runtime.reply_error(&input, code, text);
+        }
+        let from_admin = !self.runtime.node_ids().contains(&input.src);
+        let runtime = self.runtime.clone();
+        let state = self.state.clone();
+        std::thread::spawn(move || {
+            let started = Instant::now();
+            let mut failed = None;
+            if from_admin {
+                for peer in runtime.peers() {
+                    let request = Payload::Reconfigure {
+                        nodes: nodes.clone(),
+                    };
+                    if let Err(err) = runtime.rpc::<_, Payload>(peer, request, RECONFIGURE_TIMEOUT)
+                    {
+                        failed = Some(format!("{peer} did not move its keys: {err}"));
+                    }
+                }
+            }
+            // This node's own handoffs.
+            while !state.lock().unwrap().handing_to.is_empty() && failed.is_none() {
+                if started.elapsed() >= RECONFIGURE_TIMEOUT {
+                    failed = Some("handing off keys timed out".to_string());
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            let result = match failed {
+                None => runtime.reply(&input, Payload::ReconfigureOk),
+                Some(text) => runtime.reply_error(&input, error_code::TIMEOUT, text),
+            };
+            if let Err(err) = result {
+                eprintln!("keyed counter reply failed: {err:#}");
+            }
+        });
+        Ok(())
+    }
+
+    /// Switches to the ring of `nodes` and starts handing off the keys this
+    /// node loses. Returns false if keys are still moving to another ring.
+    fn begin(&self, nodes: Vec<String>) -> anyhow::Result<bool> {
+        let me = self.runtime.node_id();
+        let mut state = self.state.lock().unwrap();
+        if state.nodes == nodes {
+            return Ok(true);
+        }
+        if state.previous.is_some() {
+            return Ok(false);
+        }
+        let ring = Ring::new(&nodes)?;
+        let moves = state.ring.moves(&ring);
+        state.handing_to = moves
+            .iter()
+            .filter(|(from, _)| from == me)
+            .map(|(_, to)| to.clone())
+            .collect();
+        state.awaiting = moves
+            .iter()
+            .filter(|(_, to)| to == me)
+            .map(|(from, _)| from.clone())
+            .collect();
+        state.previous = Some(std::mem::replace(&mut state.ring, ring));
+        state.nodes = nodes;
+        let targets = state.handing_to.clone();
+        state.settle();
+        drop(state);
+        for target in targets {
+            let (runtime, state) = (self.runtime.clone(), self.state.clone());
+            std::thread::spawn(move || hand_off(&runtime, &state, &target));
+        }
+        Ok(true)
+    }
+
+    /// Adds a batch of totals from `from`, unless it was added before.
+    /// Returns false, adding nothing, if keys are still moving here to
+    /// another ring than the batch's.
+    fn take_handoff(
+        &self,
+        from: &str,
+        nodes: Vec<String>,
+        seq: u64,
+        totals: &HashMap<String, i64>,
+        done: bool,
+    ) -> anyhow::Result<bool> {
+        // The sender may have heard of the new ring first.
+        if !self.begin(nodes)? {
+            return Ok(false);
+        }
+        let mut state = self.state.lock().unwrap();
+        let applied = state.applied.entry(from.to_string()).or_default();
+        if seq <= *applied {
+            return Ok(true);
+        }
+        *applied = seq;
+        for (key, total) in totals {
+            *state.totals.entry(key.clone()).or_default() += total;
+        }
+        if done {
+            state.awaiting.remove(from);
+            state.settle();
+        }
+        Ok(true)
+    }
+}
+
+/// Streams the totals of the keys this node loses to `target` until a
+/// batch finds none left, retrying each batch with growing waits until it
+/// is acknowledged.
+fn hand_off(runtime: &Runtime, state: &Mutex<State>, target: &str) {
+    loop {
+        let request = {
+            let mut state = state.lock().unwrap();
+            let moving: Vec<String> = state
+                .totals
+                .keys()
+                .filter(|key| state.ring.owner(key.as_str()) == target)
+                .cloned()
+                .collect();
+            let mut totals = HashMap::new();
+            for key in moving {
+                let total = state.totals.remove(&key).unwrap_or_default();
+                *state.in_flight.entry(key.clone()).or_default() += total;
+                totals.insert(key, total);
+            }
+            let done = totals.is_empty();
+            if done {
+                // From here on the keys are rejected here.
+                state.closed.insert(target.to_string());
+            }
+            state.seq += 1;
+            Payload::Handoff {
+                nodes: state.nodes.clone(),
+                seq: state.seq,
+                totals,
+                done,
+            }
+        };
+        let mut backoff = Backoff::new(MIN_BACKOFF, MAX_BACKOFF);
+        while let Err(err) = runtime.rpc::<_, Payload>(target, &request, HANDOFF_TIMEOUT) {
+            eprintln!("handing keys off to {target} failed: {err}");
+            backoff.sleep();
+        }
+        let mut state = state.lock().unwrap();
+        let Payload::Handoff { totals, done, .. } = request else {
+            unreachable!("only handoffs are sent");
+        };
+        for key in totals.keys() {
+            state.in_flight.remove(key);
+        }
+        if done {
+            state.handing_to.remove(target);
+            state.settle();
+            return;
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! Grow-only counter (Gossip Glomers challenge 4).
+//!
+//! By default the total lives in `seq-kv` under a single key updated with
+//! cas. With `--counter-impl crdt` nodes skip `seq-kv` altogether: each one
+//! counts its own additions and replicates the per-node map (a G-counter)
+//! through a [`CrdtReplicator`], so the counter stays available under
+//! partitions.
+
+pub mod keyed;
+pub mod pn;
+
+use std::{str::FromStr, time::Duration};
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    crdt::GCounter,
+    message::{error_code, Init, Message},
+    replication::{CrdtReplicator, Gossip},
+    runtime::{Node, Runtime},
+    services::{KvError, SeqKv},
+};
+
+const KEY: &str = "counter";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CounterImpl {
+    #[default]
+    SeqKv,
+    Crdt,
+}
+
+impl FromStr for CounterImpl {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "seq-kv" => Ok(Self::SeqKv),
+            "crdt" => Ok(Self::Crdt),
+            _ => bail!("unknown counter implementation {s}, expected seq-kv or crdt"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Add {
+        delta: i64,
+    },
+    AddOk,
+    Read,
+    ReadOk {
+        value: i64,
+    },
+    Replicate {
+        gossip: Gossip<GCounter>,
+    },
+    /// Debugging: this node's copy, as a snapshot, with `--counter-impl crdt`.
+    Dump,
+    DumpOk {
+        state: Value,
+    },
+}
+
+enum Backend {
+    SeqKv(SeqKv),
+    Crdt(CrdtReplicator<GCounter>),
+}
+
+pub struct CounterNode {
+    runtime: Runtime,
+    backend: Backend,
+}
+
+impl Node<Payload> for CounterNode {
+    fn from_init(runtime: Runtime, _init: Init) -> anyhow::Result<Self> {
+        let config = runtime.config().clone();
+        let backend = match config.parse("counter-impl")?.unwrap_or_default() {
+            CounterImpl::SeqKv => {
+                // Reads are confirmed by cas, which corrects a stale cache.
+                let cache = config.parse("kv-cache")?.unwrap_or_default();
+                let kv = SeqKv::new(runtime.clone()).with_cache(cache);
+                kv.report_cache(Duration::from_secs(10));
+                Backend::SeqKv(kv)
+            }
+            CounterImpl::Crdt => {
+                let counter = CrdtReplicator::mount(
+                    runtime.clone(),
+                    GCounter::default(),
+                    Duration::from_millis(300),
+                    |gossip| Payload::Replicate { gossip },
+                );
+                counter.persist(&config, runtime.node_id())?;
+                Backend::Crdt(counter)
+            }
+        };
+        Ok(Self { runtime, backend })
+    }
+
+    fn step(&mut self, input: Message<Payload>) -> anyhow::Result<()> {
+        let kv = match &self.backend {
+            Backend::SeqKv(kv) => kv.clone(),
+            Backend::Crdt(counter) => return self.step_crdt(counter, input),
+        };
+        let runtime = self.runtime.clone();
+        // Both requests wait on seq-kv, so they run off the input thread.
+        match input.body.payload {
+            Payload::Add { delta } => {
+                std::thread::spawn(move || {
+                    let result = match add(&kv, delta) {
+                        Ok(()) => runtime.reply(&input, Payload::AddOk),
+                        Err(err) => {
+                            runtime.reply_error(&input, error_code::TIMEOUT, err.to_string())
+                        }
+                    };
+                    if let Err(err) = result {
+                        eprintln!("add reply failed: {err:#}");
+                    }
+                });
+            }
+            Payload::Read => {
+                std::thread::spawn(move || {
+                    let result = match read(&kv) {
+                        Ok(value) => runtime.reply(&input, Payload::ReadOk { value }),
+                        Err(err) => {
+                            runtime.reply_error(&input, error_code::TIMEOUT, err.to_string())
+                        }
+                    };
+                    if let Err(err) = result {
+                        eprintln!("read reply failed: {err:#}");
+                    }
+                });
+            }
+            Payload::AddOk
+            | Payload::ReadOk { .. }
+            | Payload::Replicate { .. }
+            | Payload::Dump
+            | Payload::DumpOk { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+impl CounterNode {
+    fn step_crdt(
+        &self,
+        counter: &CrdtReplicator<GCounter>,
+        input: Message<Payload>,
+    ) -> anyhow::Result<()> {
+        match input.body.payload {
+            Payload::Add { delta } if delta < 0 => {
+                let text = "a grow-only counter cannot decrease";
+                self.runtime
+                    .reply_error(&input, error_code::MALFORMED_REQUEST, text)?;
+            }
+            Payload::Add { delta } => {
+                let node_id = self.runtime.node_id();
+                counter.update(|counter| counter.add(node_id, delta as u64));
+                self.runtime.reply(&input, Payload::AddOk)?;
+            }
+            Payload::Read => {
+                let value = counter.read(GCounter::value) as i64;
+                self.runtime.reply(&input, Payload::ReadOk { value })?;
+            }
+            Payload::Replicate { gossip } => {
+                if let Some(gossip) = counter.receive(&input.src, gossip) {
+                    self.runtime
+                        .send(&input.src, Payload::Replicate { gossip })?;
+                }
+            }
+            Payload::Dump => {
+                let state = counter.dump();
+                self.runtime.reply(&input, Payload::DumpOk { state })?;
+            }
+            Payload::AddOk | Payload::ReadOk { .. } | Payload::DumpOk { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+fn read_or_zero(kv: &SeqKv) -> Result<i64, KvError> {
+    match kv.read(KEY) {
+        Err(KvError::KeyDoesNotExist) => Ok(0),
+        other => other,
+    }
+}
+
+fn add(kv: &SeqKv, delta: i64) -> Result<(), KvError> {
+    kv.update(KEY, |current: &i64| current + delta)?;
+    Ok(())
+}
+
+/// seq-kv may serve a stale value to a plain read, so confirm it with a
+/// no-op cas: it only succeeds if the value is current.
+fn read(kv: &SeqKv) -> Result<i64, KvError> {
+    loop {
+        let current = read_or_zero(kv)?;
+        match kv.cas(KEY, current, current, true) {
+            Ok(()) => return Ok(current),
+            Err(KvError::PreconditionFailed) => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
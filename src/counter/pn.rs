@@ -0,0 +1,82 @@
+//! PN-counter workload: `add` accepts negative deltas. Every node counts its
+//! own increments and decrements separately and replicates the counts that
+//! changed through a [`CrdtReplicator`]; merging keeps the per-node maximum,
+//! so the net value converges everywhere.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    crdt::PnCounter,
+    message::{Init, Message},
+    replication::{CrdtReplicator, Gossip},
+    runtime::{Node, Runtime},
+};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Add {
+        delta: i64,
+    },
+    AddOk,
+    Read,
+    ReadOk {
+        value: i64,
+    },
+    Replicate {
+        gossip: Gossip<PnCounter>,
+    },
+    /// Debugging: this node's copy, as a snapshot.
+    Dump,
+    DumpOk {
+        state: Value,
+    },
+}
+
+pub struct PnCounterNode {
+    runtime: Runtime,
+    counter: CrdtReplicator<PnCounter>,
+}
+
+impl Node<Payload> for PnCounterNode {
+    fn from_init(runtime: Runtime, _init: Init) -> anyhow::Result<Self> {
+        let counter = CrdtReplicator::mount(
+            runtime.clone(),
+            PnCounter::default(),
+            Duration::from_millis(300),
+            |gossip| Payload::Replicate { gossip },
+        );
+        counter.persist(runtime.config(), runtime.node_id())?;
+        Ok(Self { runtime, counter })
+    }
+
+    fn step(&mut self, input: Message<Payload>) -> anyhow::Result<()> {
+        match input.body.payload {
+            Payload::Add { delta } => {
+                let node_id = self.runtime.node_id();
+                self.counter.update(|counter| counter.apply(node_id, delta));
+                self.runtime.reply(&input, Payload::AddOk)?;
+            }
+            Payload::Read => {
+                let value = self.counter.read(PnCounter::value);
+                self.runtime.reply(&input, Payload::ReadOk { value })?;
+            }
+            Payload::Replicate { gossip } => {
+                if let Some(gossip) = self.counter.receive(&input.src, gossip) {
+                    self.runtime
+                        .send(&input.src, Payload::Replicate { gossip })?;
+                }
+            }
+            Payload::Dump => {
+                let state = self.counter.dump();
+                self.runtime.reply(&input, Payload::DumpOk { state })?;
+            }
+            Payload::AddOk | Payload::ReadOk { .. } | Payload::DumpOk { .. } => {}
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    clock::{Dot, HlcTimestamp, VectorClock},
+    crdt::Snapshot,
+    gossip::{DeltaCrdt, Merge},
+};
+
+/// One value of a [`DvvSet`], with the write that made it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Sibling<T> {
+    pub dot: Dot,
+    pub time: HlcTimestamp,
+    pub value: T,
+}
+
+/// Multi-value register tracked with dotted version vectors.
+///
+/// Every value carries the dot of the write that made it, and the register
+/// keeps one causal context for all of them: the writes it has seen. A
+/// write names the context its client read, and replaces exactly the
+/// values in that context; values written concurrently, which the client
+/// never saw, stay as siblings. Plain version vectors, one per value, can't
+/// tell a client-side merge of two siblings from a write that saw neither,
+/// so they either drop a concurrent write or report a conflict that isn't
+/// there.
+///
+/// `version` is local bookkeeping for `split_delta` and is not sent.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DvvSet<T> {
+    siblings: Vec<Sibling<T>>,
+    context: VectorClock,
+    #[serde(skip)]
+    version: u64,
+}
+
+impl<T> Default for DvvSet<T> {
+    fn default() -> Self {
+        Self {
+            siblings: Vec::new(),
+            context: VectorClock::new(),
+            version: 0,
+        }
+    }
+}
+
+/// Equal when the siblings and contexts are; versions are ignored.
+impl<T: PartialEq> PartialEq for DvvSet<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.siblings == other.siblings && self.context == other.context
+    }
+}
+
+impl<T: Eq> Eq for DvvSet<T> {}
+
+impl<T> DvvSet<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The concurrent values, in dot order.
+    pub fn siblings(&self) -> &[Sibling<T>] {
+        &self.siblings
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.siblings.is_empty()
+    }
+
+    /// The writes this register has seen, to pass back with a write that
+    /// resolves the current siblings.
+    pub fn context(&self) -> &VectorClock {
+        &self.context
+    }
+
+    /// The sibling written last, ties broken by dot: the value for readers
+    /// that do not deal with siblings.
+    pub fn winner(&self) -> Option<&T> {
+        self.siblings
+            .iter()
+            .max_by(|a, b| (a.time, &a.dot).cmp(&(b.time, &b.dot)))
+            .map(|sibling| &sibling.value)
+    }
+
+    pub fn latest(&self) -> Option<HlcTimestamp> {
+        self.siblings.iter().map(|sibling| sibling.time).max()
+    }
+
+    /// Writes `value` as `node` at `time`, on behalf of a client that had
+    /// read `context`: the siblings in it are replaced, the others kept.
+    /// Returns the dot of the write.
+    pub fn write(
+        &mut self,
+        node: &str,
+        value: T,
+        time: HlcTimestamp,
+        context: &VectorClock,
+    ) -> Dot {
+        let dot = (node.to_string(), self.context.increment(node));
+        self.siblings
+            .retain(|sibling| !context.contains(&sibling.dot));
+        self.siblings.push(Sibling {
+            dot: dot.clone(),
+            time,
+            value,
+        });
+        self.siblings.sort_by(|a, b| a.dot.cmp(&b.dot));
+        self.version += 1;
+        dot
+    }
+}
+
+impl<T: Clone + Send + 'static> Merge for DvvSet<T> {
+    /// Keeps each side's siblings unless the other side saw them and
+    /// replaced them.
+    fn merge(&mut self, other: Self) {
+        let survives = |sibling: &Sibling<T>, context: &VectorClock, siblings: &[Sibling<T>]| {
+            !context.contains(&sibling.dot) || siblings.iter().any(|s| s.dot == sibling.dot)
+        };
+        let mut siblings: Vec<Sibling<T>> = self
+            .siblings
+            .iter()
+            .filter(|sibling| survives(sibling, &other.context, &other.siblings))
+            .cloned()
+            .collect();
+        for sibling in &other.siblings {
+            let new = !siblings.iter().any(|s| s.dot == sibling.dot);
+            if new && survives(sibling, &self.context, &self.siblings) {
+                siblings.push(sibling.clone());
+            }
+        }
+        siblings.sort_by(|a, b| a.dot.cmp(&b.dot));
+        let mut context = self.context.clone();
+        context.merge(other.context);
+        let changed = context != self.context
+            || siblings
+                .iter()
+                .map(|s| &s.dot)
+                .ne(self.siblings.iter().map(|s| &s.dot));
+        if changed {
+            self.siblings = siblings;
+            self.context = context;
+            self.version += 1;
+        }
+    }
+}
+
+impl<T: Clone + Send + 'static> DeltaCrdt for DvvSet<T> {
+    type Version = u64;
+
+    fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// The whole register if it changed since `since`, else nothing.
+    fn split_delta(&self, since: &u64) -> Self {
+        if self.version > *since {
+            self.clone()
+        } else {
+            Self::default()
+        }
+    }
+}
+
+impl<T: Clone + Serialize + DeserializeOwned> Snapshot for DvvSet<T> {
+    /// The siblings and the context.
+    type Snapshot = (Vec<Sibling<T>>, VectorClock);
+
+    fn snapshot(&self) -> Self::Snapshot {
+        (self.siblings.clone(), self.context.clone())
+    }
+
+    fn restore((siblings, context): Self::Snapshot) -> Self {
+        Self {
+            siblings,
+            context,
+            version: 1,
+        }
+    }
+}
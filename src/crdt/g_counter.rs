@@ -0,0 +1,104 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    crdt::Snapshot,
+    gossip::{DeltaCrdt, Merge},
+    message::NodeId,
+};
+
+/// Grow-only counter: each node's own total, summed. Merging keeps the
+/// largest count seen for each node.
+///
+/// Each count also remembers the local version at which it last grew, for
+/// `split_delta`; only the counts are serialized.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(transparent)]
+pub struct GCounter {
+    counts: HashMap<NodeId, u64>,
+    #[serde(skip)]
+    changed: HashMap<NodeId, u64>,
+    #[serde(skip)]
+    version: u64,
+}
+
+impl GCounter {
+    pub fn add(&mut self, node: &str, delta: u64) {
+        let count = self.get(node) + delta;
+        self.set(node.to_string(), count);
+    }
+
+    /// What `node` alone has added.
+    pub fn get(&self, node: &str) -> u64 {
+        self.counts.get(node).copied().unwrap_or_default()
+    }
+
+    pub fn value(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    fn set(&mut self, node: NodeId, count: u64) {
+        self.version += 1;
+        self.changed.insert(node.clone(), self.version);
+        self.counts.insert(node, count);
+    }
+}
+
+/// Equal when the counts are; versions are ignored.
+impl PartialEq for GCounter {
+    fn eq(&self, other: &Self) -> bool {
+        self.counts == other.counts
+    }
+}
+
+impl Eq for GCounter {}
+
+impl Merge for GCounter {
+    fn merge(&mut self, other: Self) {
+        for (node, count) in other.counts {
+            if count > self.get(&node) {
+                self.set(node, count);
+            }
+        }
+    }
+}
+
+impl DeltaCrdt for GCounter {
+    type Version = u64;
+
+    fn version(&self) -> u64 {
+        self.version
+    }
+
+    fn split_delta(&self, since: &u64) -> Self {
+        let mut delta = Self::default();
+        for (node, &changed) in &self.changed {
+            if changed > *since {
+                delta.set(node.clone(), self.get(node));
+            }
+        }
+        delta
+    }
+}
+
+impl Snapshot for GCounter {
+    /// The nonzero counts, by node.
+    type Snapshot = BTreeMap<NodeId, u64>;
+
+    fn snapshot(&self) -> Self::Snapshot {
+        self.counts
+            .iter()
+            .filter(|(_, &count)| count > 0)
+            .map(|(node, &count)| (node.clone(), count))
+            .collect()
+    }
+
+    fn restore(counts: Self::Snapshot) -> Self {
+        let mut counter = Self::default();
+        for (node, count) in counts {
+            counter.set(node, count);
+        }
+        counter
+    }
+}
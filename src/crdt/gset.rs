@@ -0,0 +1,154 @@
+use std::collections::BTreeMap;
+
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    crdt::Snapshot,
+    gossip::{DeltaCrdt, Merge},
+};
+
+/// Grow-only set: elements are only ever added, and merging is union.
+///
+/// Every element also remembers the local version at which this copy
+/// learnt it, so `delta_since` hands out just what arrived after some
+/// earlier `version()`. Versions are local bookkeeping: the set serializes
+/// as a plain list of its elements.
+#[derive(Clone, Debug)]
+pub struct GSet<T: Ord> {
+    elements: BTreeMap<T, u64>,
+    version: u64,
+}
+
+impl<T: Ord> Default for GSet<T> {
+    fn default() -> Self {
+        Self {
+            elements: BTreeMap::new(),
+            version: 0,
+        }
+    }
+}
+
+impl<T: Ord> GSet<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an element. Returns whether it was new.
+    pub fn insert(&mut self, element: T) -> bool {
+        if self.elements.contains_key(&element) {
+            return false;
+        }
+        self.version += 1;
+        self.elements.insert(element, self.version);
+        true
+    }
+
+    pub fn contains(&self, element: &T) -> bool {
+        self.elements.contains_key(element)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.elements.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    /// Grows with every element added; pass it to `delta_since` later.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+}
+
+impl<T: Ord + Clone> GSet<T> {
+    /// The elements added after `version`. Merging it into a copy that has
+    /// everything up to `version` brings that copy up to date.
+    pub fn delta_since(&self, version: u64) -> GSet<T> {
+        self.elements
+            .iter()
+            .filter(|(_, &added)| added > version)
+            .map(|(element, _)| element.clone())
+            .collect()
+    }
+}
+
+/// Equal when the elements are; versions are ignored.
+impl<T: Ord> PartialEq for GSet<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.elements.keys().eq(other.elements.keys())
+    }
+}
+
+impl<T: Ord> Eq for GSet<T> {}
+
+impl<T: Ord> FromIterator<T> for GSet<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut set = Self::new();
+        set.extend(iter);
+        set
+    }
+}
+
+impl<T: Ord> Extend<T> for GSet<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for element in iter {
+            self.insert(element);
+        }
+    }
+}
+
+impl<T: Ord> IntoIterator for GSet<T> {
+    type Item = T;
+    type IntoIter = std::collections::btree_map::IntoKeys<T, u64>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.elements.into_keys()
+    }
+}
+
+impl<T: Ord + Clone + Send + 'static> Merge for GSet<T> {
+    fn merge(&mut self, other: Self) {
+        self.extend(other);
+    }
+}
+
+impl<T: Ord + Serialize> Serialize for GSet<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+impl<'de, T: Ord + Deserialize<'de>> Deserialize<'de> for GSet<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Vec::<T>::deserialize(deserializer)?.into_iter().collect())
+    }
+}
+
+impl<T: Ord + Clone + Send + 'static> DeltaCrdt for GSet<T> {
+    type Version = u64;
+
+    fn version(&self) -> u64 {
+        self.version
+    }
+
+    fn split_delta(&self, since: &u64) -> Self {
+        self.delta_since(*since)
+    }
+}
+
+impl<T: Ord + Clone + Serialize + DeserializeOwned> Snapshot for GSet<T> {
+    type Snapshot = Vec<T>;
+
+    fn snapshot(&self) -> Vec<T> {
+        self.iter().cloned().collect()
+    }
+
+    fn restore(elements: Vec<T>) -> Self {
+        elements.into_iter().collect()
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::BTreeMap;
+
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    clock::{HlcTimestamp, VectorClock},
+    crdt::{LwwRegister, Snapshot},
+    gossip::{DeltaCrdt, Merge},
+    message::NodeId,
+};
+
+/// Map of last-writer-wins registers. A removal writes a tombstone, which
+/// competes with writes to the key like any other write, so a removal and
+/// a concurrent insertion resolve the same way on every copy.
+///
+/// Clocks over a map count wall-clock millis of each node's writes, so
+/// [`gc`](Self::gc) can drop a tombstone once every copy has seen a later
+/// write by its writer. That holds as long as copies are exchanged whole,
+/// so that a copy with a write also has everything its writer had before.
+///
+/// Every key also remembers the local version at which its register last
+/// changed, so `split_delta` hands out just the registers that did.
+///
+/// Serializes as a list of `[key, register]` entries, so keys need not be
+/// strings; the stable clock and versions are not sent.
+#[derive(Clone, Debug)]
+pub struct LwwMap<K: Ord, V> {
+    /// `None` is a tombstone.
+    entries: BTreeMap<K, LwwRegister<Option<V>>>,
+    /// What every copy had seen as of the last collection. A write under it
+    /// to a key this copy lacks lost to a collected tombstone.
+    stable: VectorClock,
+    changed: BTreeMap<K, u64>,
+    version: u64,
+}
+
+impl<K: Ord, V> Default for LwwMap<K, V> {
+    fn default() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            stable: VectorClock::new(),
+            changed: BTreeMap::new(),
+            version: 0,
+        }
+    }
+}
+
+/// Equal when the entries are; the stable clock and versions are local
+/// knowledge.
+impl<K: Ord, V: PartialEq> PartialEq for LwwMap<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.entries == other.entries
+    }
+}
+
+impl<K: Ord, V: Eq> Eq for LwwMap<K, V> {}
+
+impl<K: Ord + Clone, V> LwwMap<K, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries
+            .get(key)
+            .and_then(|register| register.get().as_ref())
+    }
+
+    /// Writes `value` under `key`, as [`LwwRegister::set`] does. Returns
+    /// whether the write took.
+    pub fn insert(&mut self, key: K, value: V, time: HlcTimestamp, node: &str) -> bool {
+        self.write(key, Some(value), time, node)
+    }
+
+    /// Writes a tombstone under `key`. Returns whether it took.
+    pub fn remove(&mut self, key: K, time: HlcTimestamp, node: &str) -> bool {
+        self.write(key, None, time, node)
+    }
+
+    /// The live entries, in key order.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries
+            .iter()
+            .filter_map(|(key, register)| register.get().as_ref().map(|value| (key, value)))
+    }
+
+    /// The latest write of each node this copy holds, in millis, to report
+    /// to the other nodes' [`Stability`](crate::clock::Stability) trackers.
+    pub fn clock(&self) -> VectorClock {
+        let mut clock = self.stable.clone();
+        for register in self.entries.values() {
+            let (time, node) = register.stamp();
+            clock.advance(node, time.ms);
+        }
+        clock
+    }
+
+    /// The latest time a value here was written at.
+    pub fn latest(&self) -> Option<HlcTimestamp> {
+        self.entries
+            .values()
+            .map(|register| register.stamp().0)
+            .max()
+    }
+
+    /// Drops the tombstones every copy has seen, given the clock `stable`
+    /// that all copies reached. Returns how many it dropped.
+    pub fn gc(&mut self, stable: &VectorClock) -> usize {
+        let stable = stable.meet(&self.clock());
+        let before = self.entries.len();
+        self.entries
+            .retain(|_, register| register.get().is_some() || !Self::under(&stable, register));
+        let entries = &self.entries;
+        self.changed.retain(|key, _| entries.contains_key(key));
+        self.stable.merge(stable);
+        before - self.entries.len()
+    }
+
+    pub fn tombstones(&self) -> usize {
+        self.entries
+            .values()
+            .filter(|register| register.get().is_none())
+            .count()
+    }
+
+    /// Whether every node seen up to `stable` has seen `register` too. Only
+    /// earlier millis count, as a later write may share the milli.
+    fn under(stable: &VectorClock, register: &LwwRegister<Option<V>>) -> bool {
+        let (time, node) = register.stamp();
+        time.ms < stable.get(node)
+    }
+
+    fn write(&mut self, key: K, value: Option<V>, time: HlcTimestamp, node: &str) -> bool {
+        let took = match self.entries.get_mut(&key) {
+            Some(register) => register.set(value, time, node),
+            None => {
+                self.entries
+                    .insert(key.clone(), LwwRegister::new(value, time, node));
+                true
+            }
+        };
+        if took {
+            self.touch(key);
+        }
+        took
+    }
+
+    fn touch(&mut self, key: K) {
+        self.version += 1;
+        self.changed.insert(key, self.version);
+    }
+}
+
+impl<K, V> Merge for LwwMap<K, V>
+where
+    K: Ord + Clone + Send + 'static,
+    V: Clone + Send + 'static,
+{
+    fn merge(&mut self, other: Self) {
+        for (key, register) in other.entries {
+            let took = match self.entries.get_mut(&key) {
+                Some(current) => {
+                    let newer = register.stamp() > current.stamp();
+                    current.merge(register);
+                    newer
+                }
+                None if Self::under(&self.stable, &register) => false,
+                None => {
+                    self.entries.insert(key.clone(), register);
+                    true
+                }
+            };
+            if took {
+                self.touch(key);
+            }
+        }
+    }
+}
+
+impl<K, V> DeltaCrdt for LwwMap<K, V>
+where
+    K: Ord + Clone + Send + 'static,
+    V: Clone + Send + 'static,
+{
+    type Version = u64;
+
+    fn version(&self) -> u64 {
+        self.version
+    }
+
+    fn split_delta(&self, since: &u64) -> Self {
+        let entries = self
+            .changed
+            .iter()
+            .filter(|(_, &changed)| changed > *since)
+            .map(|(key, _)| (key.clone(), self.entries[key].clone()))
+            .collect();
+        Self {
+            entries,
+            ..Self::default()
+        }
+    }
+}
+
+impl<K: Ord + Serialize, V: Serialize> Serialize for LwwMap<K, V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(&self.entries)
+    }
+}
+
+impl<'de, K: Ord + Deserialize<'de>, V: Deserialize<'de>> Deserialize<'de> for LwwMap<K, V> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let entries = Vec::<(K, LwwRegister<Option<V>>)>::deserialize(deserializer)?;
+        Ok(Self {
+            entries: entries.into_iter().collect(),
+            ..Self::default()
+        })
+    }
+}
+
+impl<K, V> Snapshot for LwwMap<K, V>
+where
+    K: Ord + Clone + Serialize + DeserializeOwned,
+    V: Clone + Serialize + DeserializeOwned,
+{
+    /// `[key, value, time, node]` per entry, `null` values being
+    /// tombstones, and the stable clock.
+    type Snapshot = (Vec<(K, Option<V>, HlcTimestamp, NodeId)>, VectorClock);
+
+    fn snapshot(&self) -> Self::Snapshot {
+        let entries = self
+            .entries
+            .iter()
+            .map(|(key, register)| {
+                let (time, node) = register.stamp();
+                (key.clone(), register.get().clone(), time, node.to_string())
+            })
+            .collect();
+        (entries, self.stable.clone())
+    }
+
+    fn restore((entries, stable): Self::Snapshot) -> Self {
+        let mut map = Self::default();
+        for (key, value, time, node) in entries {
+            map.write(key, value, time, &node);
+        }
+        map.stable = stable;
+        map
+    }
+}
@@ -0,0 +1,64 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{clock::HlcTimestamp, crdt::Snapshot, gossip::Merge, message::NodeId};
+
+/// Last-writer-wins register: the value of the write with the latest hybrid
+/// logical time, ties between nodes broken by node id, so every copy picks
+/// the same winner among concurrent writes.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LwwRegister<T> {
+    value: T,
+    time: HlcTimestamp,
+    node: NodeId,
+}
+
+impl<T> LwwRegister<T> {
+    pub fn new(value: T, time: HlcTimestamp, node: &str) -> Self {
+        Self {
+            value,
+            time,
+            node: node.to_string(),
+        }
+    }
+
+    pub fn get(&self) -> &T {
+        &self.value
+    }
+
+    /// When and by whom the current value was written.
+    pub fn stamp(&self) -> (HlcTimestamp, &str) {
+        (self.time, &self.node)
+    }
+
+    /// Writes `value` unless a later write is already in. An equal stamp is
+    /// the same node writing again at the same time, and wins, so writes
+    /// sharing a stamp apply in order. Returns whether the write took.
+    pub fn set(&mut self, value: T, time: HlcTimestamp, node: &str) -> bool {
+        if (time, node) < self.stamp() {
+            return false;
+        }
+        *self = Self::new(value, time, node);
+        true
+    }
+}
+
+impl<T: Clone + Send + 'static> Merge for LwwRegister<T> {
+    fn merge(&mut self, other: Self) {
+        if (other.time, other.node.as_str()) > self.stamp() {
+            *self = other;
+        }
+    }
+}
+
+impl<T: Clone + Serialize + DeserializeOwned> Snapshot for LwwRegister<T> {
+    /// `[value, time, node]`.
+    type Snapshot = (T, HlcTimestamp, NodeId);
+
+    fn snapshot(&self) -> Self::Snapshot {
+        (self.value.clone(), self.time, self.node.clone())
+    }
+
+    fn restore((value, time, node): Self::Snapshot) -> Self {
+        Self { value, time, node }
+    }
+}
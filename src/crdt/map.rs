@@ -0,0 +1,128 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    crdt::Snapshot,
+    gossip::{DeltaCrdt, Merge},
+};
+
+/// Map from string keys to CRDTs of one type; merging merges key by key.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct CrdtMap<C> {
+    entries: BTreeMap<String, C>,
+}
+
+impl<C> Default for CrdtMap<C> {
+    fn default() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+        }
+    }
+}
+
+impl<C> CrdtMap<C> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&C> {
+        self.entries.get(key)
+    }
+
+    /// The CRDT under `key`, created empty if missing.
+    pub fn entry(&mut self, key: &str) -> &mut C
+    where
+        C: Default,
+    {
+        self.entries.entry(key.to_string()).or_default()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &C)> {
+        self.entries.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&String, &mut C)> {
+        self.entries.iter_mut()
+    }
+
+    /// The entries whose key `keep` accepts.
+    pub fn select(&self, keep: impl Fn(&str) -> bool) -> Self
+    where
+        C: Clone,
+    {
+        let entries = self
+            .entries
+            .iter()
+            .filter(|(key, _)| keep(key))
+            .map(|(key, crdt)| (key.clone(), crdt.clone()))
+            .collect();
+        Self { entries }
+    }
+}
+
+impl<C> IntoIterator for CrdtMap<C> {
+    type Item = (String, C);
+    type IntoIter = std::collections::btree_map::IntoIter<String, C>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
+
+impl<C: Merge + Default> Merge for CrdtMap<C> {
+    fn merge(&mut self, other: Self) {
+        for (key, crdt) in other.entries {
+            match self.entries.get_mut(&key) {
+                Some(current) => current.merge(crdt),
+                None => {
+                    self.entries.insert(key, crdt);
+                }
+            }
+        }
+    }
+}
+
+impl<C: DeltaCrdt + Default> DeltaCrdt for CrdtMap<C> {
+    /// Each key's version.
+    type Version = BTreeMap<String, C::Version>;
+
+    fn version(&self) -> Self::Version {
+        self.entries
+            .iter()
+            .map(|(key, crdt)| (key.clone(), crdt.version()))
+            .collect()
+    }
+
+    fn split_delta(&self, since: &Self::Version) -> Self {
+        let entries = self
+            .entries
+            .iter()
+            .filter_map(|(key, crdt)| {
+                let since = since.get(key).cloned().unwrap_or_default();
+                (crdt.version() != since).then(|| (key.clone(), crdt.split_delta(&since)))
+            })
+            .collect();
+        Self { entries }
+    }
+}
+
+impl<C: Snapshot> Snapshot for CrdtMap<C> {
+    type Snapshot = BTreeMap<String, C::Snapshot>;
+
+    fn snapshot(&self) -> Self::Snapshot {
+        self.entries
+            .iter()
+            .map(|(key, crdt)| (key.clone(), crdt.snapshot()))
+            .collect()
+    }
+
+    fn restore(entries: Self::Snapshot) -> Self {
+        let entries = entries
+            .into_iter()
+            .map(|(key, snapshot)| (key, C::restore(snapshot)))
+            .collect();
+        Self { entries }
+    }
+}
@@ -0,0 +1,30 @@
+//! Conflict-free replicated data types. Each one implements
+//! [`Merge`](crate::gossip::Merge), so any of them can be replicated with
+//! [`Replicated`](crate::gossip::Replicated) and converges however its
+//! copies are exchanged.
+
+mod dvv_set;
+mod g_counter;
+mod gset;
+mod lww_map;
+mod lww_register;
+mod map;
+mod orset;
+mod orswot;
+mod pn_counter;
+mod rga;
+pub mod snapshot;
+mod two_phase;
+
+pub use dvv_set::{DvvSet, Sibling};
+pub use g_counter::GCounter;
+pub use gset::GSet;
+pub use lww_map::LwwMap;
+pub use lww_register::LwwRegister;
+pub use map::CrdtMap;
+pub use orset::{OrSet, Tag};
+pub use orswot::Orswot;
+pub use pn_counter::PnCounter;
+pub use rga::{Rga, RgaId};
+pub use snapshot::Snapshot;
+pub use two_phase::TwoPhaseSet;
@@ -0,0 +1,178 @@
+use std::collections::BTreeSet;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{clock::VectorClock, crdt::Snapshot, gossip::Merge, message::NodeId};
+
+/// Identifies one insertion: the inserting node and its count of insertions.
+pub type Tag = (NodeId, u64);
+
+/// Observed-remove set: elements can be removed and added again.
+///
+/// Every insertion carries a fresh tag, and a removal deletes the tags of the
+/// element this copy has seen. An element is present while it has a tag not
+/// removed, so an insertion concurrent with a removal survives it (add
+/// wins), while everything the removing node observed is gone for good.
+///
+/// Removals leave tombstones, so merging a copy that still has the removed
+/// insertions does not bring them back. Once a removal is causally stable,
+/// [`gc`](Self::gc) drops its tombstone: every copy has the removal, and a
+/// stale copy arriving later is recognised by its insertions falling under
+/// the stable clock.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OrSet<T: Ord> {
+    /// Live insertions.
+    entries: BTreeSet<(T, Tag)>,
+    /// Tags of removed insertions, each with the tag of its removal.
+    removed: BTreeSet<(Tag, Tag)>,
+    /// Insertions and removals seen, per node; tags are drawn from it.
+    clock: VectorClock,
+    /// What every copy had seen as of the last collection. An insertion
+    /// under it that this copy lacks was removed, even without a tombstone.
+    #[serde(skip)]
+    stable: VectorClock,
+}
+
+impl<T: Ord> Default for OrSet<T> {
+    fn default() -> Self {
+        Self {
+            entries: BTreeSet::new(),
+            removed: BTreeSet::new(),
+            clock: VectorClock::new(),
+            stable: VectorClock::new(),
+        }
+    }
+}
+
+/// Equal when the insertions, removals and clocks are; the stable clock is
+/// local knowledge and ignored.
+impl<T: Ord> PartialEq for OrSet<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.entries == other.entries && self.removed == other.removed && self.clock == other.clock
+    }
+}
+
+impl<T: Ord> Eq for OrSet<T> {}
+
+impl<T: Ord + Clone> OrSet<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `element` as an insertion by `node`, the node owning this copy.
+    pub fn insert(&mut self, node: &str, element: T) {
+        let tag = (node.to_string(), self.clock.increment(node));
+        self.entries.insert((element, tag));
+    }
+
+    /// Removes, as `node`, every insertion of `element` seen so far.
+    /// Returns whether the element was present.
+    pub fn remove(&mut self, node: &str, element: &T) -> bool {
+        let tags: Vec<Tag> = self
+            .entries
+            .iter()
+            .filter(|(candidate, _)| candidate == element)
+            .map(|(_, tag)| tag.clone())
+            .collect();
+        for tag in &tags {
+            self.entries.remove(&(element.clone(), tag.clone()));
+        }
+        if tags.is_empty() {
+            return false;
+        }
+        let removal = (node.to_string(), self.clock.increment(node));
+        self.removed
+            .extend(tags.into_iter().map(|tag| (tag, removal.clone())));
+        true
+    }
+
+    pub fn contains(&self, element: &T) -> bool {
+        self.entries
+            .iter()
+            .any(|(candidate, _)| candidate == element)
+    }
+
+    /// The elements present, in order.
+    pub fn elements(&self) -> Vec<T> {
+        let mut elements: Vec<T> = self
+            .entries
+            .iter()
+            .map(|(element, _)| element.clone())
+            .collect();
+        elements.dedup();
+        elements
+    }
+
+    /// The insertions and removals this copy has seen, to report to the
+    /// other nodes' [`Stability`](crate::clock::Stability) trackers.
+    pub fn clock(&self) -> &VectorClock {
+        &self.clock
+    }
+
+    /// Drops the tombstones of removals every copy has seen, given the
+    /// clock `stable` that all copies reached. Returns how many it dropped.
+    pub fn gc(&mut self, stable: &VectorClock) -> usize {
+        let stable = stable.meet(&self.clock);
+        let before = self.removed.len();
+        self.removed
+            .retain(|(_, (node, counter))| *counter > stable.get(node));
+        self.stable.merge(stable);
+        before - self.removed.len()
+    }
+
+    pub fn tombstones(&self) -> usize {
+        self.removed.len()
+    }
+}
+
+impl<T: Ord + Clone + Send + 'static> Merge for OrSet<T> {
+    fn merge(&mut self, other: Self) {
+        for entry in other.entries {
+            let (node, counter) = &entry.1;
+            let collected = *counter <= self.stable.get(node);
+            if !collected {
+                self.entries.insert(entry);
+            }
+        }
+        self.removed.extend(other.removed);
+        self.clock.merge(other.clock);
+        let removed: BTreeSet<&Tag> = self.removed.iter().map(|(tag, _)| tag).collect();
+        self.entries.retain(|(_, tag)| !removed.contains(tag));
+    }
+}
+
+impl<T: Ord + Clone + Serialize + DeserializeOwned> Snapshot for OrSet<T> {
+    /// Each element with its live tags, the removed tags with their removals,
+    /// the clock and the stable clock.
+    type Snapshot = (
+        Vec<(T, Vec<Tag>)>,
+        Vec<(Tag, Tag)>,
+        VectorClock,
+        VectorClock,
+    );
+
+    fn snapshot(&self) -> Self::Snapshot {
+        let mut entries: Vec<(T, Vec<Tag>)> = Vec::new();
+        for (element, tag) in &self.entries {
+            match entries.last_mut() {
+                Some((last, tags)) if last == element => tags.push(tag.clone()),
+                _ => entries.push((element.clone(), vec![tag.clone()])),
+            }
+        }
+        let removed = self.removed.iter().cloned().collect();
+        (entries, removed, self.clock.clone(), self.stable.clone())
+    }
+
+    fn restore((entries, removed, clock, stable): Self::Snapshot) -> Self {
+        let entries = entries
+            .into_iter()
+            .flat_map(|(element, tags)| tags.into_iter().map(move |tag| (element.clone(), tag)))
+            .collect();
+        Self {
+            entries,
+            removed: removed.into_iter().collect(),
+            clock,
+            stable,
+        }
+    }
+}
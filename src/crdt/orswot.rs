@@ -0,0 +1,129 @@
+use std::collections::BTreeSet;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    clock::{Dot, VectorClock},
+    crdt::Snapshot,
+    gossip::Merge,
+};
+
+/// Observed-remove set without tombstones (ORSWOT): the same add-wins
+/// behaviour as [`OrSet`](super::OrSet) in space bounded by the elements
+/// present, however many have been removed.
+///
+/// Every insertion is a dot drawn from the copy's clock, and an element is
+/// present while it keeps a dot. Removing an element just forgets its dots.
+/// On merge, a dot only one side has survives unless the other side's clock
+/// covers it: then that side saw the insertion and removed it since. The
+/// clock alone stands in for the tombstones, so nothing needs collecting.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Orswot<T: Ord> {
+    /// Present elements with the dots of their insertions not yet removed.
+    entries: BTreeSet<(T, Dot)>,
+    /// Every insertion this copy has seen, removed or not.
+    clock: VectorClock,
+}
+
+impl<T: Ord> Default for Orswot<T> {
+    fn default() -> Self {
+        Self {
+            entries: BTreeSet::new(),
+            clock: VectorClock::new(),
+        }
+    }
+}
+
+impl<T: Ord + Clone> Orswot<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `element` as an insertion by `node`, the node owning this copy.
+    /// The new dot replaces the element's others, which the clock now
+    /// covers.
+    pub fn insert(&mut self, node: &str, element: T) {
+        self.forget(&element);
+        let dot = (node.to_string(), self.clock.increment(node));
+        self.entries.insert((element, dot));
+    }
+
+    /// Removes every insertion of `element` seen so far. Returns whether the
+    /// element was present.
+    pub fn remove(&mut self, element: &T) -> bool {
+        self.forget(element)
+    }
+
+    pub fn contains(&self, element: &T) -> bool {
+        self.entries
+            .iter()
+            .any(|(candidate, _)| candidate == element)
+    }
+
+    /// The elements present, in order.
+    pub fn elements(&self) -> Vec<T> {
+        let mut elements: Vec<T> = self
+            .entries
+            .iter()
+            .map(|(element, _)| element.clone())
+            .collect();
+        elements.dedup();
+        elements
+    }
+
+    /// The insertions this copy has seen.
+    pub fn clock(&self) -> &VectorClock {
+        &self.clock
+    }
+
+    /// Drops the dots of `element`. Returns whether it had any.
+    fn forget(&mut self, element: &T) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|(candidate, _)| candidate != element);
+        self.entries.len() != before
+    }
+}
+
+impl<T: Ord + Clone + Send + 'static> Merge for Orswot<T> {
+    fn merge(&mut self, other: Self) {
+        let ours = std::mem::take(&mut self.entries);
+        // Kept: dots both sides have, and dots one side has that the other
+        // never saw.
+        let kept_ours: Vec<(T, Dot)> = ours
+            .iter()
+            .filter(|entry| other.entries.contains(entry) || !other.clock.contains(&entry.1))
+            .cloned()
+            .collect();
+        let kept_theirs: Vec<(T, Dot)> = other
+            .entries
+            .into_iter()
+            .filter(|entry| !ours.contains(entry) && !self.clock.contains(&entry.1))
+            .collect();
+        self.entries = kept_ours.into_iter().chain(kept_theirs).collect();
+        self.clock.merge(other.clock);
+    }
+}
+
+impl<T: Ord + Clone + Serialize + DeserializeOwned> Snapshot for Orswot<T> {
+    /// Each element with its dots, and the clock.
+    type Snapshot = (Vec<(T, Vec<Dot>)>, VectorClock);
+
+    fn snapshot(&self) -> Self::Snapshot {
+        let mut entries: Vec<(T, Vec<Dot>)> = Vec::new();
+        for (element, dot) in &self.entries {
+            match entries.last_mut() {
+                Some((last, dots)) if last == element => dots.push(dot.clone()),
+                _ => entries.push((element.clone(), vec![dot.clone()])),
+            }
+        }
+        (entries, self.clock.clone())
+    }
+
+    fn restore((entries, clock): Self::Snapshot) -> Self {
+        let entries = entries
+            .into_iter()
+            .flat_map(|(element, dots)| dots.into_iter().map(move |dot| (element.clone(), dot)))
+            .collect();
+        Self { entries, clock }
+    }
+}
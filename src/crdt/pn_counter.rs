@@ -0,0 +1,89 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    crdt::{GCounter, Snapshot},
+    gossip::{DeltaCrdt, Merge},
+    message::NodeId,
+};
+
+/// Counter that goes both ways: increments and decrements are counted
+/// apart, in two grow-only counters.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PnCounter {
+    inc: GCounter,
+    dec: GCounter,
+}
+
+impl PnCounter {
+    pub fn apply(&mut self, node: &str, delta: i64) {
+        let side = if delta >= 0 {
+            &mut self.inc
+        } else {
+            &mut self.dec
+        };
+        side.add(node, delta.unsigned_abs());
+    }
+
+    /// What `node` alone has added.
+    pub fn node_value(&self, node: &str) -> i64 {
+        self.inc.get(node) as i64 - self.dec.get(node) as i64
+    }
+
+    pub fn value(&self) -> i64 {
+        self.inc.value() as i64 - self.dec.value() as i64
+    }
+}
+
+impl Merge for PnCounter {
+    fn merge(&mut self, other: Self) {
+        self.inc.merge(other.inc);
+        self.dec.merge(other.dec);
+    }
+}
+
+impl DeltaCrdt for PnCounter {
+    /// The versions of the increments and of the decrements.
+    type Version = (u64, u64);
+
+    fn version(&self) -> (u64, u64) {
+        (self.inc.version(), self.dec.version())
+    }
+
+    fn split_delta(&self, (inc, dec): &(u64, u64)) -> Self {
+        Self {
+            inc: self.inc.split_delta(inc),
+            dec: self.dec.split_delta(dec),
+        }
+    }
+}
+
+impl Snapshot for PnCounter {
+    /// `[increments, decrements]` by node.
+    type Snapshot = BTreeMap<NodeId, (u64, u64)>;
+
+    fn snapshot(&self) -> Self::Snapshot {
+        let mut counts: Self::Snapshot = BTreeMap::new();
+        for (node, inc) in self.inc.snapshot() {
+            counts.entry(node).or_default().0 = inc;
+        }
+        for (node, dec) in self.dec.snapshot() {
+            counts.entry(node).or_default().1 = dec;
+        }
+        counts
+    }
+
+    fn restore(counts: Self::Snapshot) -> Self {
+        let mut counter = Self::default();
+        for (node, (inc, dec)) in counts {
+            if inc > 0 {
+                counter.inc.add(&node, inc);
+            }
+            if dec > 0 {
+                counter.dec.add(&node, dec);
+            }
+        }
+        counter
+    }
+}
@@ -0,0 +1,192 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    clock::HlcTimestamp,
+    crdt::Snapshot,
+    gossip::{DeltaCrdt, Merge},
+    message::NodeId,
+};
+
+/// Identifies one element of an [`Rga`]: when and where it was inserted,
+/// and its position among the elements inserted together.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RgaId {
+    pub time: HlcTimestamp,
+    pub node: NodeId,
+    pub seq: usize,
+}
+
+/// Replicated growable array: a list every node can insert into without a
+/// sequencer.
+///
+/// Each element is inserted after an existing one (or at the head) and the
+/// elements form a tree of "inserted after" links. The list is that tree
+/// walked depth first, with the elements inserted after the same one ordered
+/// newest id first, so concurrent inserts at the same place land in the same
+/// order on every copy. An element whose predecessor has not arrived yet
+/// stays hidden until it does.
+///
+/// Serializes as a list of `[id, after, value]` entries; the local version
+/// at which each element arrived, for `split_delta`, is not sent.
+#[derive(Clone, Debug)]
+pub struct Rga<T> {
+    /// Each element's predecessor, value and arrival version.
+    elements: BTreeMap<RgaId, (Option<RgaId>, T, u64)>,
+    version: u64,
+}
+
+impl<T> Default for Rga<T> {
+    fn default() -> Self {
+        Self {
+            elements: BTreeMap::new(),
+            version: 0,
+        }
+    }
+}
+
+/// Equal when the elements are; versions are ignored.
+impl<T: PartialEq> PartialEq for Rga<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.elements.len() == other.elements.len()
+            && self.elements.iter().zip(&other.elements).all(
+                |((a, (a_after, a_value, _)), (b, (b_after, b_value, _)))| {
+                    a == b && a_after == b_after && a_value == b_value
+                },
+            )
+    }
+}
+
+impl<T: Eq> Eq for Rga<T> {}
+
+impl<T> Rga<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Inserts `value` as element `id` right after `after`, or at the head.
+    /// Returns whether the element was new.
+    pub fn insert_after(&mut self, id: RgaId, after: Option<RgaId>, value: T) -> bool {
+        if self.elements.contains_key(&id) {
+            return false;
+        }
+        self.version += 1;
+        self.elements.insert(id, (after, value, self.version));
+        true
+    }
+
+    /// Inserts `value` as element `id` at the end of the list. Returns the
+    /// element it went after, which other copies need to insert it too.
+    pub fn append(&mut self, id: RgaId, value: T) -> Option<RgaId> {
+        let after = self.order().last().cloned().cloned();
+        self.insert_after(id, after.clone(), value);
+        after
+    }
+
+    /// The values, in list order.
+    pub fn values(&self) -> impl Iterator<Item = &T> {
+        self.order().into_iter().map(|id| &self.elements[id].1)
+    }
+
+    /// The latest time an element was inserted at.
+    pub fn latest(&self) -> Option<HlcTimestamp> {
+        self.elements.keys().map(|id| id.time).max()
+    }
+
+    /// The element ids in list order: a depth-first walk from the head.
+    fn order(&self) -> Vec<&RgaId> {
+        let mut children: BTreeMap<Option<&RgaId>, Vec<&RgaId>> = BTreeMap::new();
+        for (id, (after, _, _)) in &self.elements {
+            children.entry(after.as_ref()).or_default().push(id);
+        }
+        let mut order = Vec::with_capacity(self.elements.len());
+        // Children are in ascending id order, so pushing them as they come
+        // pops the newest first.
+        let mut stack: Vec<&RgaId> = children.get(&None).cloned().unwrap_or_default();
+        while let Some(id) = stack.pop() {
+            order.push(id);
+            if let Some(next) = children.get(&Some(id)) {
+                stack.extend(next);
+            }
+        }
+        order
+    }
+}
+
+impl<T: Clone + Send + 'static> Merge for Rga<T> {
+    fn merge(&mut self, other: Self) {
+        for (id, (after, value, _)) in other.elements {
+            self.insert_after(id, after, value);
+        }
+    }
+}
+
+impl<T: Clone + Send + 'static> DeltaCrdt for Rga<T> {
+    type Version = u64;
+
+    fn version(&self) -> u64 {
+        self.version
+    }
+
+    fn split_delta(&self, since: &u64) -> Self {
+        let mut delta = Self::new();
+        for (id, (after, value, arrived)) in &self.elements {
+            if arrived > since {
+                delta.insert_after(id.clone(), after.clone(), value.clone());
+            }
+        }
+        delta
+    }
+}
+
+impl<T: Serialize> Serialize for Rga<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            self.elements
+                .iter()
+                .map(|(id, (after, value, _))| (id, after, value)),
+        )
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Rga<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let entries = Vec::<(RgaId, Option<RgaId>, T)>::deserialize(deserializer)?;
+        let mut rga = Self::new();
+        for (id, after, value) in entries {
+            rga.insert_after(id, after, value);
+        }
+        Ok(rga)
+    }
+}
+
+impl<T: Clone + Serialize + DeserializeOwned> Snapshot for Rga<T> {
+    /// `[id, after, value]` per element, in list order, so a restore
+    /// inserts every element after its predecessor.
+    type Snapshot = Vec<(RgaId, Option<RgaId>, T)>;
+
+    fn snapshot(&self) -> Self::Snapshot {
+        let order = self.order();
+        let listed: BTreeSet<&RgaId> = order.iter().copied().collect();
+        // Elements still waiting on their predecessor are kept too.
+        let waiting = self.elements.keys().filter(|id| !listed.contains(id));
+        order
+            .iter()
+            .copied()
+            .chain(waiting)
+            .map(|id| {
+                let (after, value, _) = &self.elements[id];
+                (id.clone(), after.clone(), value.clone())
+            })
+            .collect()
+    }
+
+    fn restore(elements: Self::Snapshot) -> Self {
+        let mut rga = Self::new();
+        for (id, after, value) in elements {
+            rga.insert_after(id, after, value);
+        }
+        rga
+    }
+}
@@ -0,0 +1,70 @@
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Deserializer, Serialize, Serializer};
+
+use crate::storage::StorageBackend;
+
+/// A CRDT's whole state in a compact, self-contained form, for saving it,
+/// handing it to a node that has nothing yet, or dumping it for debugging.
+///
+/// Unlike the wire form, which carries deltas and leaves out what only the
+/// local copy needs, a snapshot keeps everything that decides how the copy
+/// merges from now on, such as the stable clocks behind collected
+/// tombstones, and drops the bookkeeping that is rebuilt on restore.
+pub trait Snapshot: Sized {
+    type Snapshot: Serialize + DeserializeOwned;
+
+    fn snapshot(&self) -> Self::Snapshot;
+
+    /// Rebuilds a copy from a snapshot. Everything restored counts as
+    /// changed, so the copy hands all of it out in its next delta.
+    fn restore(snapshot: Self::Snapshot) -> Self;
+}
+
+impl<C: Snapshot> Snapshot for Box<C> {
+    type Snapshot = C::Snapshot;
+
+    fn snapshot(&self) -> C::Snapshot {
+        self.as_ref().snapshot()
+    }
+
+    fn restore(snapshot: C::Snapshot) -> Self {
+        Box::new(C::restore(snapshot))
+    }
+}
+
+/// Writes `state`'s snapshot under `key` and flushes it, so a crash leaves
+/// the old one or the new one.
+pub fn save<C: Snapshot>(state: &C, backend: &dyn StorageBackend, key: &str) -> anyhow::Result<()> {
+    backend.put(key, &serde_json::to_vec(&state.snapshot())?)?;
+    backend.flush()
+}
+
+/// Reads a copy saved by [`save`], or `None` if there is none.
+pub fn load<C: Snapshot>(backend: &dyn StorageBackend, key: &str) -> anyhow::Result<Option<C>> {
+    let Some(json) = backend.get(key)? else {
+        return Ok(None);
+    };
+    let snapshot =
+        serde_json::from_slice(&json).with_context(|| format!("corrupt snapshot {key}"))?;
+    Ok(Some(C::restore(snapshot)))
+}
+
+/// Serializes a field through its snapshot, for `#[serde(with)]`.
+pub mod as_snapshot {
+    use serde::Deserialize;
+
+    use super::*;
+
+    pub fn serialize<C: Snapshot, S: Serializer>(
+        state: &C,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        state.snapshot().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, C: Snapshot, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<C, D::Error> {
+        C::Snapshot::deserialize(deserializer).map(C::restore)
+    }
+}
@@ -0,0 +1,89 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    crdt::{GSet, Snapshot},
+    gossip::Merge,
+};
+
+/// Two-phase set: a grow-only set of additions and one of removals. Cheaper
+/// than [`OrSet`](super::OrSet), as it keeps no tags, but a removed element
+/// can never be added back, and a removal wins over any addition.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(bound(
+    serialize = "T: Ord + Serialize",
+    deserialize = "T: Ord + Deserialize<'de>"
+))]
+pub struct TwoPhaseSet<T: Ord> {
+    added: GSet<T>,
+    removed: GSet<T>,
+}
+
+impl<T: Ord> Default for TwoPhaseSet<T> {
+    fn default() -> Self {
+        Self {
+            added: GSet::new(),
+            removed: GSet::new(),
+        }
+    }
+}
+
+impl<T: Ord + Clone> TwoPhaseSet<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `element` unless it was removed before. Returns whether it is
+    /// present now.
+    pub fn insert(&mut self, element: T) -> bool {
+        if self.removed.contains(&element) {
+            return false;
+        }
+        self.added.insert(element);
+        true
+    }
+
+    /// Removes `element` for good. Only elements present can be removed;
+    /// returns whether it was.
+    pub fn remove(&mut self, element: &T) -> bool {
+        if !self.contains(element) {
+            return false;
+        }
+        self.removed.insert(element.clone());
+        true
+    }
+
+    pub fn contains(&self, element: &T) -> bool {
+        self.added.contains(element) && !self.removed.contains(element)
+    }
+
+    /// The elements present, in order.
+    pub fn elements(&self) -> Vec<T> {
+        self.added
+            .iter()
+            .filter(|element| !self.removed.contains(element))
+            .cloned()
+            .collect()
+    }
+}
+
+impl<T: Ord + Clone + Send + 'static> Merge for TwoPhaseSet<T> {
+    fn merge(&mut self, other: Self) {
+        self.added.merge(other.added);
+        self.removed.merge(other.removed);
+    }
+}
+
+impl<T: Ord + Clone + Serialize + DeserializeOwned> Snapshot for TwoPhaseSet<T> {
+    /// The elements present and the ones removed, each listed once.
+    type Snapshot = (Vec<T>, Vec<T>);
+
+    fn snapshot(&self) -> Self::Snapshot {
+        (self.elements(), self.removed.snapshot())
+    }
+
+    fn restore((present, removed): Self::Snapshot) -> Self {
+        let removed: GSet<T> = removed.into_iter().collect();
+        let added = present.into_iter().chain(removed.iter().cloned()).collect();
+        Self { added, removed }
+    }
+}
@@ -0,0 +1,127 @@
+//! State-based replication: each node owns a copy of some mergeable state
+//! and periodically pushes what changed in it to every peer, which merges it
+//! into its own.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::runtime::Runtime;
+
+/// State whose copies converge when merged in any order, any number of times.
+pub trait Merge: Clone + Send + 'static {
+    fn merge(&mut self, other: Self);
+}
+
+/// A state that can hand out just what changed since one of its earlier
+/// versions. The delta is a state of the same type, holding only those
+/// changes, and merges into any copy like a full state would.
+pub trait DeltaCrdt: Merge {
+    /// Local bookkeeping identifying how far this copy has come; never sent.
+    type Version: Clone + Default + PartialEq + Send + 'static;
+
+    fn version(&self) -> Self::Version;
+
+    /// What changed after `since`. Merging it into a copy that has
+    /// everything up to `since` brings that copy up to date.
+    fn split_delta(&self, since: &Self::Version) -> Self;
+}
+
+/// Gossip rounds between two full-state rounds.
+const FULL_STATE_EVERY: u64 = 10;
+
+/// A node's copy of a replicated state, shared between the input thread and
+/// the gossip thread.
+#[derive(Clone)]
+pub struct Replicated<S> {
+    state: Arc<Mutex<S>>,
+}
+
+impl<S: Merge> Replicated<S> {
+    pub fn new(initial: S) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(initial)),
+        }
+    }
+
+    pub fn update<R>(&self, f: impl FnOnce(&mut S) -> R) -> R {
+        f(&mut self.state.lock().unwrap())
+    }
+
+    pub fn read<R>(&self, f: impl FnOnce(&S) -> R) -> R {
+        f(&self.state.lock().unwrap())
+    }
+
+    /// Folds in a copy received from a peer.
+    pub fn merge(&self, remote: S) {
+        self.state.lock().unwrap().merge(remote);
+    }
+}
+
+impl<S: DeltaCrdt> Replicated<S> {
+    /// Sends every peer what changed since the previous round, every
+    /// `interval`, wrapped by `wrap` into the workload's gossip message.
+    /// Rounds without changes send nothing. Gossip is not acknowledged, so
+    /// every `FULL_STATE_EVERY` rounds the whole state goes out instead,
+    /// repairing whatever deltas were lost.
+    pub fn spawn_gossip<P, F>(&self, runtime: Runtime, interval: Duration, wrap: F)
+    where
+        P: Serialize,
+        F: Fn(S) -> P + Send + 'static,
+    {
+        self.spawn_rounds(runtime, interval, Some(FULL_STATE_EVERY), wrap);
+    }
+
+    /// Like `spawn_gossip`, but never sends the whole state: for states
+    /// whose lost deltas are repaired otherwise, such as by Merkle
+    /// anti-entropy (see [`crate::merkle`]).
+    pub fn spawn_delta_gossip<P, F>(&self, runtime: Runtime, interval: Duration, wrap: F)
+    where
+        P: Serialize,
+        F: Fn(S) -> P + Send + 'static,
+    {
+        self.spawn_rounds(runtime, interval, None, wrap);
+    }
+
+    fn spawn_rounds<P, F>(
+        &self,
+        runtime: Runtime,
+        interval: Duration,
+        full_state_every: Option<u64>,
+        wrap: F,
+    ) where
+        P: Serialize,
+        F: Fn(S) -> P + Send + 'static,
+    {
+        let state = self.state.clone();
+        std::thread::spawn(move || -> anyhow::Result<()> {
+            let mut sent = S::Version::default();
+            for round in 1.. {
+                std::thread::sleep(interval);
+                let outgoing = {
+                    let state = state.lock().unwrap();
+                    let version = state.version();
+                    let outgoing = if full_state_every.is_some_and(|every| round % every == 0) {
+                        Some(state.clone())
+                    } else if version != sent {
+                        Some(state.split_delta(&sent))
+                    } else {
+                        None
+                    };
+                    sent = version;
+                    outgoing
+                };
+                let Some(outgoing) = outgoing else {
+                    continue;
+                };
+                for peer in runtime.peers() {
+                    runtime.send(peer, wrap(outgoing.clone()))?;
+                }
+            }
+            Ok(())
+        });
+    }
+}
@@ -0,0 +1,92 @@
+//! Unique ids for the `generate` workload (Gossip Glomers challenge 2).
+//!
+//! `--id-scheme ulid` (default) hands out ULIDs. `--id-scheme counter`
+//! needs no randomness at all: an id packs the node's index in the sorted
+//! node ids into the top 16 bits and a per-node counter into the low 48, so
+//! ids sort by node, then by issue order. The counter never falls behind
+//! the wall clock, counted in [`TICKS_PER_MS`] ticks since 2024: like a
+//! ULID's timestamp, that keeps a restarted node from handing out the ids
+//! of its last run, unless that run issued more than one id per tick.
+
+use std::{collections::VecDeque, str::FromStr};
+
+use anyhow::bail;
+use ulid::Ulid;
+
+use crate::clock::now_ms;
+
+/// How many ids a refill generates ahead of demand.
+const BATCH: usize = 256;
+const COUNTER_BITS: u32 = 48;
+/// Counter values per millisecond of the wall clock. With 48 bits that
+/// lasts until about 2093.
+pub const TICKS_PER_MS: u64 = 128;
+/// 2024-01-01, in millis since the Unix epoch.
+const EPOCH_MS: u64 = 1_704_067_200_000;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IdScheme {
+    #[default]
+    Ulid,
+    Counter,
+}
+
+impl FromStr for IdScheme {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ulid" => Ok(Self::Ulid),
+            "counter" => Ok(Self::Counter),
+            _ => bail!("unknown id scheme {s}, expected ulid or counter"),
+        }
+    }
+}
+
+/// Ids generated ahead of time, so a busy node or a large `count` does not
+/// pay for generation on every request.
+#[derive(Debug)]
+pub struct IdPool {
+    scheme: IdScheme,
+    node_index: u64,
+    counter: u64,
+    buffer: VecDeque<String>,
+}
+
+impl IdPool {
+    /// `node_index` is this node's position among the sorted node ids; only
+    /// the counter scheme uses it.
+    pub fn new(scheme: IdScheme, node_index: usize) -> Self {
+        Self {
+            scheme,
+            node_index: node_index as u64,
+            counter: 0,
+            buffer: VecDeque::new(),
+        }
+    }
+
+    pub fn take(&mut self, count: usize) -> Vec<String> {
+        if self.buffer.len() < count {
+            let missing = (count - self.buffer.len()).max(BATCH);
+            for _ in 0..missing {
+                let id = self.generate();
+                self.buffer.push_back(id);
+            }
+        }
+        self.buffer.drain(..count).collect()
+    }
+
+    fn generate(&mut self) -> String {
+        match self.scheme {
+            IdScheme::Ulid => Ulid::new().to_string(),
+            IdScheme::Counter => {
+                let ticks = now_ms().saturating_sub(EPOCH_MS) * TICKS_PER_MS;
+                self.counter = self.counter.max(ticks);
+                let id = self.node_index << COUNTER_BITS | self.counter;
+                self.counter += 1;
+                // Zero-padded, so the strings sort like the numbers.
+                format!("{id:020}")
+            }
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! Logs kept in `lin-kv`, so every node can serve every key (challenge 5b).
+//!
+//! `send` claims an offset and writes the message with one cas that creates
+//! `entry/<key>/<offset>`, trying the next offset while that one is taken,
+//! so an offset is only ever claimed together with its entry and a failed
+//! `send` leaves no hole behind. Each key's `next/<key>` is only a hint of
+//! where to start: once its entry is in place `send` hands raising the hint
+//! to a [`WriteCoalescer`] and returns, so concurrent sends pipeline their
+//! hint writes and those to one key collapse into one. `poll` reads entries
+//! until the first one missing.
+//! Committed offsets of every key live in a single map under `committed`,
+//! so one cas records a whole `commit_offsets` or none of it.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::Context;
+
+use crate::{
+    kafka::log::LogStore,
+    runtime::Runtime,
+    services::{CachePolicy, KvError, LinKv, WriteCoalescer},
+};
+
+const COMMITTED: &str = "committed";
+
+const CACHE_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+pub struct LinKvLogs {
+    kv: LinKv,
+    hints: WriteCoalescer,
+    /// Per key, one past the highest offset this node claimed or read as
+    /// the hint, so hints it writes never go backwards.
+    next: Mutex<HashMap<String, usize>>,
+}
+
+impl LinKvLogs {
+    /// Reads of `lin-kv` are cached as `cache` says. Entries never change
+    /// once written; the `next/<key>` hints and the committed offsets do,
+    /// and a stale copy of them only costs failed cas in `send` and
+    /// `commit_offsets`, but makes `list_committed_offsets` lag behind
+    /// other nodes until it expires.
+    ///
+    /// Up to `window` hint writes are in flight at once, shared by every
+    /// `send` in progress.
+    pub fn new(runtime: Runtime, cache: CachePolicy, window: usize) -> Self {
+        let kv = LinKv::new(runtime).with_cache(cache);
+        kv.report_cache(CACHE_REPORT_INTERVAL);
+        Self {
+            hints: kv.coalescer(window),
+            kv,
+            next: Mutex::default(),
+        }
+    }
+}
+
+impl LogStore for LinKvLogs {
+    fn append(&self, key: &str, msg: usize) -> anyhow::Result<usize> {
+        let hint: usize = self
+            .kv
+            .read_or_default(format!("next/{key}"))
+            .context("read next offset")?;
+        let known = self.next.lock().unwrap().get(key).copied();
+        let mut offset = hint.max(known.unwrap_or_default());
+        // No entry holds null, so the cas only succeeds by creating the entry.
+        loop {
+            match self
+                .kv
+                .cas(format!("entry/{key}/{offset}"), None, Some(msg), true)
+            {
+                Ok(()) => break,
+                Err(KvError::PreconditionFailed) => offset += 1,
+                Err(err) => return Err(err).context("write entry"),
+            }
+        }
+        // The entry is in place; a hint left behind only costs later sends a
+        // few failed cas, so nobody waits for it. Writes are queued under the
+        // lock so the last one queued for a key is the highest.
+        let mut next = self.next.lock().unwrap();
+        let next = next.entry(key.to_string()).or_default();
+        *next = (*next).max(offset + 1);
+        let _ = self.hints.submit(format!("next/{key}"), *next);
+        Ok(offset)
+    }
+
+    fn read_from(
+        &self,
+        key: &str,
+        offset: usize,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(usize, usize)>> {
+        let mut entries = Vec::new();
+        for offset in (offset..).take(limit) {
+            match self.kv.read(format!("entry/{key}/{offset}")) {
+                Ok(msg) => entries.push((offset, msg)),
+                // Offsets are claimed in order, so none follow a missing one.
+                Err(KvError::KeyDoesNotExist) => break,
+                Err(err) => return Err(err).context("read entry"),
+            }
+        }
+        Ok(entries)
+    }
+
+    fn commit(&self, offsets: HashMap<String, usize>) -> anyhow::Result<()> {
+        loop {
+            let current: BTreeMap<String, usize> = self
+                .kv
+                .read_or_default(COMMITTED)
+                .context("read committed offsets")?;
+            let mut next = current.clone();
+            for (key, &offset) in &offsets {
+                let committed = next.entry(key.clone()).or_default();
+                *committed = (*committed).max(offset);
+            }
+            if next == current {
+                return Ok(());
+            }
+            match self.kv.cas(COMMITTED, &current, &next, true) {
+                Ok(()) => return Ok(()),
+                Err(KvError::PreconditionFailed) => {}
+                Err(err) => return Err(err).context("commit offsets"),
+            }
+        }
+    }
+
+    fn committed(&self, keys: &[String]) -> anyhow::Result<HashMap<String, usize>> {
+        let committed: BTreeMap<String, usize> = self
+            .kv
+            .read_or_default(COMMITTED)
+            .context("read committed offsets")?;
+        Ok(keys
+            .iter()
+            .filter_map(|key| Some((key.clone(), *committed.get(key)?)))
+            .collect())
+    }
+}
@@ -0,0 +1,354 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    clock::VectorClock,
+    kafka::segment::{SegmentedLog, SEGMENT_SIZE},
+    storage::StorageBackend,
+};
+
+/// Which node keeps committed offsets is decided by hashing this name, so
+/// every key's offsets live in one place and commits stay atomic.
+pub const COMMITTED_OFFSETS: &str = "committed-offsets";
+
+/// Where a kafka node keeps its logs and committed offsets.
+pub trait LogStore: Send + Sync {
+    /// Appends `msg` to `key`'s log and returns its offset.
+    fn append(&self, key: &str, msg: usize) -> anyhow::Result<usize>;
+
+    /// The run of consecutive entries of `key` starting at `offset`, at
+    /// most `limit` of them. Empty when `offset` was truncated away or has
+    /// not arrived yet.
+    fn read_from(
+        &self,
+        key: &str,
+        offset: usize,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(usize, usize)>>;
+
+    /// Records committed offsets; they never move backwards. All of them
+    /// are recorded or, on error, none: `committed` never observes part of
+    /// a commit.
+    fn commit(&self, offsets: HashMap<String, usize>) -> anyhow::Result<()>;
+
+    fn committed(&self, keys: &[String]) -> anyhow::Result<HashMap<String, usize>>;
+
+    /// Stores an entry replicated from the key's owner, `from`. `clock`
+    /// is set when operations are delivered in causal order.
+    fn replicate(
+        &self,
+        from: &str,
+        key: &str,
+        offset: usize,
+        msg: usize,
+        clock: Option<VectorClock>,
+    ) -> anyhow::Result<()> {
+        let _ = (from, key, offset, msg, clock);
+        bail!("this store does not take replicated entries")
+    }
+
+    /// Records committed offsets replicated from the key's leader, `from`.
+    fn replicate_commit(
+        &self,
+        from: &str,
+        offsets: HashMap<String, usize>,
+        clock: Option<VectorClock>,
+    ) -> anyhow::Result<()> {
+        let _ = (from, offsets, clock);
+        bail!("this store does not take replicated commits")
+    }
+
+    /// A claim by `from` to lead `key` in `term`. Returns the latest term
+    /// this node promised and to whom, `term` and `from` if it granted the
+    /// claim, and one past its last entry of `key`.
+    fn claim(
+        &self,
+        from: &str,
+        key: &str,
+        term: u64,
+    ) -> anyhow::Result<(u64, Option<String>, usize)> {
+        let _ = (from, key, term);
+        bail!("this store has no leaders")
+    }
+
+    /// Drops this node's entries of `key` from `start` on and stores
+    /// `entries` in their place, as `key`'s leader `from` in `term` says.
+    /// Entries below `acked` reached every member.
+    fn sync(
+        &self,
+        from: &str,
+        key: &str,
+        term: u64,
+        start: usize,
+        entries: &[(usize, usize)],
+        acked: usize,
+    ) -> anyhow::Result<()> {
+        let _ = (from, key, term, start, entries, acked);
+        bail!("this store has no leaders")
+    }
+
+    /// Learns from `key`'s leader in `term` that entries below `acked`
+    /// reached every member.
+    fn acked(&self, key: &str, term: u64, acked: usize) -> anyhow::Result<()> {
+        let _ = (key, term, acked);
+        bail!("this store has no leaders")
+    }
+
+    /// Takes back the logs an earlier run kept in `backend` and keeps them
+    /// there from now on; see [`Logs::persist`].
+    fn persist(&self, backend: Arc<dyn StorageBackend>) -> anyhow::Result<()> {
+        let _ = backend;
+        bail!("this store does not keep its logs on this node")
+    }
+}
+
+/// Append-only logs, one per key, with the offsets consumers committed.
+///
+/// Once [`persist`](Self::persist)ed, every entry is also written to a
+/// [`StorageBackend`] under `entry/<key>/<offset>`, the offset zero-padded
+/// to 20 digits, and every committed offset under `committed/<key>`, as
+/// they change. Entries retention drops from memory are deleted there too.
+#[derive(Default, Debug)]
+pub struct Logs {
+    logs: HashMap<String, SegmentedLog>,
+    committed: HashMap<String, usize>,
+    // Entries kept below a key's committed offset; older ones are dropped.
+    // `None` keeps everything.
+    retain: Option<usize>,
+    backend: Option<Persisted>,
+}
+
+struct Persisted {
+    backend: Arc<dyn StorageBackend>,
+    /// Per key, the offset below which no entry is left in the backend.
+    deleted_below: HashMap<String, usize>,
+}
+
+impl fmt::Debug for Persisted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Persisted")
+            .field("deleted_below", &self.deleted_below)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Persisted {
+    fn put(&self, key: &str, value: usize) {
+        if let Err(err) = self.backend.put(key, value.to_string().as_bytes()) {
+            eprintln!("persisting {key} failed: {err:#}");
+        }
+    }
+
+    fn put_entry(&self, key: &str, offset: usize, msg: usize) {
+        self.put(&format!("entry/{key}/{offset:020}"), msg);
+    }
+
+    fn delete(&self, key: &str) {
+        if let Err(err) = self.backend.delete(key) {
+            eprintln!("deleting {key} failed: {err:#}");
+        }
+    }
+
+    /// Deletes `key`'s entries below `offset`.
+    fn delete_below(&mut self, key: &str, offset: usize) {
+        let deleted = self.deleted_below.entry(key.to_string()).or_default();
+        for at in *deleted..offset {
+            let entry = format!("entry/{key}/{at:020}");
+            if let Err(err) = self.backend.delete(&entry) {
+                eprintln!("deleting {entry} failed: {err:#}");
+                return;
+            }
+            *deleted = at + 1;
+        }
+    }
+}
+
+/// A value the backend holds, as written by [`Persisted::put`].
+fn parse(key: &str, value: &[u8]) -> anyhow::Result<usize> {
+    let value = std::str::from_utf8(value)
+        .ok()
+        .and_then(|value| value.parse().ok());
+    value.with_context(|| format!("corrupt {key}"))
+}
+
+impl Logs {
+    /// Logs that drop entries more than `retain` offsets below their key's
+    /// committed offset, if `retain` is set.
+    pub fn with_retention(retain: Option<usize>) -> Self {
+        Self {
+            retain,
+            ..Self::default()
+        }
+    }
+
+    /// Takes back what an earlier run persisted to `backend`, and persists
+    /// every change there from now on. Failed writes are logged and do not
+    /// fail the change in memory.
+    pub fn persist(&mut self, backend: Arc<dyn StorageBackend>) -> anyhow::Result<()> {
+        let mut deleted_below: HashMap<String, usize> = HashMap::new();
+        for (name, value) in backend.scan("entry/")? {
+            let parsed = name["entry/".len()..]
+                .rsplit_once('/')
+                .and_then(|(key, offset)| Some((key, offset.parse().ok()?)));
+            let Some((key, offset)) = parsed else {
+                bail!("bad kafka entry {name}");
+            };
+            self.insert(key, offset, parse(&name, &value)?);
+            let deleted = deleted_below.entry(key.to_string()).or_insert(offset);
+            *deleted = (*deleted).min(offset);
+        }
+        let mut committed = HashMap::new();
+        for (name, value) in backend.scan("committed/")? {
+            let key = name["committed/".len()..].to_string();
+            committed.insert(key, parse(&name, &value)?);
+        }
+        self.backend = Some(Persisted {
+            backend,
+            deleted_below,
+        });
+        self.commit(committed);
+        Ok(())
+    }
+
+    /// Appends `msg` to `key`'s log and returns its offset. Offsets start at
+    /// 0 and grow by one per message within a key.
+    pub fn append(&mut self, key: &str, msg: usize) -> usize {
+        let offset = self.logs.entry(key.to_string()).or_default().append(msg);
+        if let Some(backend) = &self.backend {
+            backend.put_entry(key, offset, msg);
+        }
+        offset
+    }
+
+    /// Stores an entry another node appended. Entries may arrive out of
+    /// order or more than once; those arriving after retention dropped
+    /// their segment are dropped too.
+    pub fn insert(&mut self, key: &str, offset: usize, msg: usize) {
+        let log = self.logs.entry(key.to_string()).or_default();
+        if !log.insert(offset, msg) {
+            return;
+        }
+        if let Some(backend) = &self.backend {
+            backend.put_entry(key, offset, msg);
+        }
+    }
+
+    /// The run of consecutive entries of `key` starting at `offset`, at
+    /// most `limit` of them. Empty when `offset` was truncated, and cut
+    /// short at the first gap left by replicated entries that arrived out
+    /// of order.
+    pub fn read_from(&self, key: &str, offset: usize, limit: usize) -> Vec<(usize, usize)> {
+        self.logs
+            .get(key)
+            .map(|log| log.read_from(offset, limit))
+            .unwrap_or_default()
+    }
+
+    /// One past the highest offset of `key` stored.
+    pub fn next(&self, key: &str) -> usize {
+        self.logs.get(key).map_or(0, SegmentedLog::next)
+    }
+
+    /// Every entry of `key` stored from `from` up to but not including `to`.
+    pub fn range(&self, key: &str, from: usize, to: usize) -> Vec<(usize, usize)> {
+        self.logs
+            .get(key)
+            .map(|log| log.range(from, to))
+            .unwrap_or_default()
+    }
+
+    /// Drops every entry of `key` at `offset` and above.
+    pub fn cut(&mut self, key: &str, offset: usize) {
+        let Some(log) = self.logs.get_mut(key) else {
+            return;
+        };
+        let next = log.next();
+        log.cut(offset);
+        if let Some(backend) = &self.backend {
+            for at in offset..next {
+                backend.delete(&format!("entry/{key}/{at:020}"));
+            }
+        }
+    }
+
+    /// Records committed offsets; they never move backwards. With a
+    /// retention set, segments far enough below the new offsets are dropped.
+    pub fn commit(&mut self, offsets: HashMap<String, usize>) {
+        for (key, offset) in offsets {
+            let committed = self.committed.entry(key.clone()).or_default();
+            let moved = offset > *committed;
+            *committed = (*committed).max(offset);
+            let committed = *committed;
+            if let Some(backend) = self.backend.as_ref().filter(|_| moved) {
+                backend.put(&format!("committed/{key}"), committed);
+            }
+            if let Some(retain) = self.retain {
+                let below = committed.saturating_sub(retain);
+                self.truncate(&key, below);
+            }
+        }
+    }
+
+    fn truncate(&mut self, key: &str, offset: usize) {
+        if let Some(log) = self.logs.get_mut(key) {
+            log.truncate(offset);
+        }
+        if let Some(backend) = &mut self.backend {
+            // What the log dropped: the segments wholly below `offset`.
+            backend.delete_below(key, offset - offset % SEGMENT_SIZE);
+        }
+    }
+
+    pub fn committed(&self, keys: &[String]) -> HashMap<String, usize> {
+        keys.iter()
+            .filter_map(|key| Some((key.clone(), *self.committed.get(key)?)))
+            .collect()
+    }
+}
+
+/// Logs held in this node's memory. Only correct on a single node.
+#[derive(Default)]
+pub struct MemoryLogs {
+    logs: Mutex<Logs>,
+}
+
+impl MemoryLogs {
+    pub fn new(retain: Option<usize>) -> Self {
+        Self {
+            logs: Mutex::new(Logs::with_retention(retain)),
+        }
+    }
+}
+
+impl LogStore for MemoryLogs {
+    fn append(&self, key: &str, msg: usize) -> anyhow::Result<usize> {
+        Ok(self.logs.lock().unwrap().append(key, msg))
+    }
+
+    fn read_from(
+        &self,
+        key: &str,
+        offset: usize,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(usize, usize)>> {
+        Ok(self.logs.lock().unwrap().read_from(key, offset, limit))
+    }
+
+    fn commit(&self, offsets: HashMap<String, usize>) -> anyhow::Result<()> {
+        self.logs.lock().unwrap().commit(offsets);
+        Ok(())
+    }
+
+    fn committed(&self, keys: &[String]) -> anyhow::Result<HashMap<String, usize>> {
+        Ok(self.logs.lock().unwrap().committed(keys))
+    }
+
+    fn persist(&self, backend: Arc<dyn StorageBackend>) -> anyhow::Result<()> {
+        self.logs.lock().unwrap().persist(backend)
+    }
+}
@@ -0,0 +1,272 @@
+//! Kafka-style log workload (Gossip Glomers challenge 5): clients append to
+//! per-key logs, poll them from an offset and commit consumer offsets.
+//!
+//! A single node keeps its logs in memory; a cluster gives every key an owner
+//! node (see [`owned`]). `--kafka-store memory|lin-kv|owned|replicated`
+//! overrides the choice, `lin-kv` keeping everything in Maelstrom's `lin-kv`
+//! service and `replicated` copying each key's log to
+//! `--kafka-replicas` (default 1) followers that take over when the leader
+//! fails (see [`replicated`]). The in-memory stores drop entries more than
+//! `--kafka-retain` offsets below their key's committed offset, if set.
+//! With `--deliver-in-causal-order true` the `owned` store applies what
+//! other nodes replicate to it in causal order (see [`crate::causal`]).
+//!
+//! The `lin-kv` store keeps up to `--kv-write-window` (default 16) writes
+//! of its offset hints in flight at once (see [`lin_kv`]).
+//!
+//! With `--kafka-dir` set, the stores that hold logs on the nodes also keep
+//! them in a [`FileBackend`] there (see [`log::Logs::persist`]).
+//!
+//! `poll` returns at most `--kafka-poll-limit` (default 1000) entries per
+//! key; a client continues from the offset after the last one it got.
+
+pub mod lin_kv;
+pub mod log;
+pub mod owned;
+pub mod replicated;
+pub mod segment;
+
+use std::{collections::HashMap, num::NonZeroUsize, path::Path, str::FromStr, sync::Arc};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::VectorClock,
+    message::{error_code, Init, Message},
+    runtime::{Node, Runtime},
+    storage::FileBackend,
+};
+use lin_kv::LinKvLogs;
+use log::{LogStore, MemoryLogs};
+use owned::OwnedLogs;
+use replicated::ReplicatedLogs;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KafkaStore {
+    Memory,
+    LinKv,
+    Owned,
+    Replicated,
+}
+
+impl FromStr for KafkaStore {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(Self::Memory),
+            "lin-kv" => Ok(Self::LinKv),
+            "owned" => Ok(Self::Owned),
+            "replicated" => Ok(Self::Replicated),
+            _ => bail!("unknown kafka store {s}, expected memory, lin-kv, owned or replicated"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Send {
+        key: String,
+        msg: usize,
+    },
+    SendOk {
+        offset: usize,
+    },
+    Poll {
+        offsets: HashMap<String, usize>,
+    },
+    PollOk {
+        msgs: HashMap<String, Vec<(usize, usize)>>,
+    },
+    CommitOffsets {
+        offsets: HashMap<String, usize>,
+    },
+    CommitOffsetsOk,
+    ListCommittedOffsets {
+        keys: Vec<String>,
+    },
+    ListCommittedOffsetsOk {
+        offsets: HashMap<String, usize>,
+    },
+    /// A key's owner pushing a new entry to the other nodes.
+    Replicate {
+        key: String,
+        offset: usize,
+        msg: usize,
+        /// Set with `--deliver-in-causal-order`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        clock: Option<VectorClock>,
+    },
+    ReplicateOk,
+    /// A key's leader pushing committed offsets to its followers.
+    ReplicateCommit {
+        offsets: HashMap<String, usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        clock: Option<VectorClock>,
+    },
+    ReplicateCommitOk,
+    /// A member of `key`'s replica set taking the lead in `term`.
+    Claim {
+        key: String,
+        term: u64,
+    },
+    /// The latest term the member promised and to whom, the claim's if it
+    /// granted it, and one past its last entry of the key.
+    ClaimOk {
+        term: u64,
+        leader: Option<String>,
+        next: usize,
+    },
+    /// `key`'s leader in `term` bringing a member's log in line with its
+    /// own: the member drops its entries from `from` on and stores
+    /// `entries`. Entries below `acked` reached every member.
+    Sync {
+        key: String,
+        term: u64,
+        from: usize,
+        entries: Vec<(usize, usize)>,
+        acked: usize,
+    },
+    SyncOk,
+    /// `key`'s leader in `term` telling the other members that entries
+    /// below `acked` reached every member. Not answered.
+    Acked {
+        key: String,
+        term: u64,
+        acked: usize,
+    },
+}
+
+pub struct KafkaNode {
+    runtime: Runtime,
+    logs: Arc<dyn LogStore>,
+    poll_limit: usize,
+}
+
+impl Node<Payload> for KafkaNode {
+    fn from_init(runtime: Runtime, init: Init) -> anyhow::Result<Self> {
+        let config = runtime.config().clone();
+        let store = match config.parse("kafka-store")? {
+            Some(store) => store,
+            None if init.node_ids.len() > 1 => KafkaStore::Owned,
+            None => KafkaStore::Memory,
+        };
+        let retain = config.parse("kafka-retain")?;
+        let logs: Arc<dyn LogStore> = match store {
+            KafkaStore::Memory => Arc::new(MemoryLogs::new(retain)),
+            KafkaStore::LinKv => {
+                let cache = config.parse("kv-cache")?.unwrap_or_default();
+                let window = config
+                    .parse("kv-write-window")?
+                    .map_or(16, NonZeroUsize::get);
+                Arc::new(LinKvLogs::new(runtime.clone(), cache, window))
+            }
+            KafkaStore::Owned => {
+                let causal = config.parse("deliver-in-causal-order")?.unwrap_or_default();
+                Arc::new(OwnedLogs::new(runtime.clone(), retain, causal)?)
+            }
+            KafkaStore::Replicated => {
+                let followers = config.parse("kafka-replicas")?.unwrap_or(1);
+                Arc::new(ReplicatedLogs::new(runtime.clone(), followers, retain)?)
+            }
+        };
+        if let Some(dir) = config.get("kafka-dir") {
+            let sync = config.parse("wal-sync")?.unwrap_or_default();
+            let backend = FileBackend::open(Path::new(dir).join(&init.node_id), sync)?;
+            logs.persist(Arc::new(backend))
+                .context("recovering the kafka logs")?;
+        }
+        Ok(Self {
+            runtime,
+            logs,
+            poll_limit: config.parse("kafka-poll-limit")?.unwrap_or(1000),
+        })
+    }
+
+    fn step(&mut self, input: Message<Payload>) -> anyhow::Result<()> {
+        let runtime = self.runtime.clone();
+        let logs = self.logs.clone();
+        let poll_limit = self.poll_limit;
+        // The lin-kv store blocks on RPCs, so every request gets its own thread.
+        std::thread::spawn(move || {
+            let result = match handle(logs.as_ref(), poll_limit, &input) {
+                Ok(Some(reply)) => runtime.reply(&input, reply),
+                Ok(None) => Ok(()),
+                Err(err) => runtime.reply_error(&input, error_code::TIMEOUT, format!("{err:#}")),
+            };
+            if let Err(err) = result {
+                eprintln!("kafka reply failed: {err:#}");
+            }
+        });
+        Ok(())
+    }
+}
+
+fn handle(
+    logs: &dyn LogStore,
+    poll_limit: usize,
+    input: &Message<Payload>,
+) -> anyhow::Result<Option<Payload>> {
+    let reply = match &input.body.payload {
+        Payload::Send { key, msg } => Payload::SendOk {
+            offset: logs.append(key, *msg)?,
+        },
+        Payload::Poll { offsets } => {
+            let msgs = offsets
+                .iter()
+                .map(|(key, &offset)| Ok((key.clone(), logs.read_from(key, offset, poll_limit)?)))
+                .collect::<anyhow::Result<_>>()?;
+            Payload::PollOk { msgs }
+        }
+        Payload::CommitOffsets { offsets } => {
+            logs.commit(offsets.clone())?;
+            Payload::CommitOffsetsOk
+        }
+        Payload::ListCommittedOffsets { keys } => Payload::ListCommittedOffsetsOk {
+            offsets: logs.committed(keys)?,
+        },
+        Payload::Replicate {
+            key,
+            offset,
+            msg,
+            clock,
+        } => {
+            logs.replicate(&input.src, key, *offset, *msg, clock.clone())?;
+            Payload::ReplicateOk
+        }
+        Payload::ReplicateCommit { offsets, clock } => {
+            logs.replicate_commit(&input.src, offsets.clone(), clock.clone())?;
+            Payload::ReplicateCommitOk
+        }
+        Payload::Claim { key, term } => {
+            let (term, leader, next) = logs.claim(&input.src, key, *term)?;
+            Payload::ClaimOk { term, leader, next }
+        }
+        Payload::Sync {
+            key,
+            term,
+            from,
+            entries,
+            acked,
+        } => {
+            logs.sync(&input.src, key, *term, *from, entries, *acked)?;
+            Payload::SyncOk
+        }
+        Payload::Acked { key, term, acked } => {
+            logs.acked(key, *term, *acked)?;
+            return Ok(None);
+        }
+        Payload::SendOk { .. }
+        | Payload::PollOk { .. }
+        | Payload::CommitOffsetsOk
+        | Payload::ListCommittedOffsetsOk { .. }
+        | Payload::ReplicateOk
+        | Payload::ReplicateCommitOk
+        | Payload::ClaimOk { .. }
+        | Payload::SyncOk => return Ok(None),
+    };
+    Ok(Some(reply))
+}
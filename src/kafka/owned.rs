@@ -0,0 +1,208 @@
+//! Per-key leadership (challenge 5c): every key hashes onto one owner node
+//! on a consistent hash ring (see [`crate::ring`]), which assigns its
+//! offsets. Other nodes forward `send` to the owner and
+//! serve `poll` from entries the owner replicates to them. Committed offsets
+//! of all keys are kept by a single node, so a multi-key `commit_offsets`
+//! is applied whole; the others forward `commit_offsets` and
+//! `list_committed_offsets` to it.
+//!
+//! With causal delivery on, replicated entries and commits carry a vector
+//! clock and each node applies them in causal order, so a replica never
+//! holds a commit before the entries it was made after.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+
+use crate::{
+    causal::CausalDelivery,
+    clock::VectorClock,
+    kafka::{
+        log::{LogStore, Logs, COMMITTED_OFFSETS},
+        Payload,
+    },
+    ring::Ring,
+    runtime::Runtime,
+    storage::StorageBackend,
+};
+
+const FORWARD_TIMEOUT: Duration = Duration::from_millis(1000);
+const REPLICATE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// A replicated operation, as held back for causal delivery.
+enum Op {
+    Entry {
+        key: String,
+        offset: usize,
+        msg: usize,
+    },
+    Commit(HashMap<String, usize>),
+}
+
+pub struct OwnedLogs {
+    runtime: Runtime,
+    ring: Ring,
+    logs: Mutex<Logs>,
+    /// Set when replicated operations are delivered in causal order. Locked
+    /// after `logs`, so operations are stamped in the order they apply.
+    causal: Option<Mutex<CausalDelivery<Op>>>,
+}
+
+impl OwnedLogs {
+    pub fn new(runtime: Runtime, retain: Option<usize>, causal: bool) -> anyhow::Result<Self> {
+        let ring = Ring::new(runtime.node_ids())?;
+        let causal = causal.then(|| Mutex::new(CausalDelivery::new(runtime.node_id())));
+        Ok(Self {
+            runtime,
+            ring,
+            logs: Mutex::new(Logs::with_retention(retain)),
+            causal,
+        })
+    }
+
+    /// The clock to replicate a local operation with, if delivering in
+    /// causal order. Call with `logs` locked.
+    fn stamp(&self) -> Option<VectorClock> {
+        self.causal
+            .as_ref()
+            .map(|causal| causal.lock().unwrap().stamp())
+    }
+
+    /// Applies a replicated operation, or holds it back until what it
+    /// depends on arrived when it carries a clock.
+    fn receive(&self, from: &str, op: Op, clock: Option<VectorClock>) {
+        let mut logs = self.logs.lock().unwrap();
+        let ready = match (&self.causal, clock) {
+            (Some(causal), Some(clock)) => causal.lock().unwrap().receive(from, clock, op),
+            _ => vec![op],
+        };
+        for op in ready {
+            match op {
+                Op::Entry { key, offset, msg } => logs.insert(&key, offset, msg),
+                Op::Commit(offsets) => logs.commit(offsets),
+            }
+        }
+    }
+
+    fn owner(&self, key: &str) -> &str {
+        self.ring.owner(key)
+    }
+
+    fn owns(&self, key: &str) -> bool {
+        self.owner(key) == self.runtime.node_id()
+    }
+
+    fn forward(&self, owner: &str, payload: Payload) -> anyhow::Result<Payload> {
+        self.runtime
+            .rpc(owner, payload, FORWARD_TIMEOUT)
+            .with_context(|| format!("forward to {owner}"))
+    }
+}
+
+impl LogStore for OwnedLogs {
+    fn append(&self, key: &str, msg: usize) -> anyhow::Result<usize> {
+        if !self.owns(key) {
+            let send = Payload::Send {
+                key: key.to_string(),
+                msg,
+            };
+            return match self.forward(self.owner(key), send)? {
+                Payload::SendOk { offset } => Ok(offset),
+                reply => Err(anyhow!("unexpected send reply {reply:?}")),
+            };
+        }
+        let (offset, clock) = {
+            let mut logs = self.logs.lock().unwrap();
+            (logs.append(key, msg), self.stamp())
+        };
+        // Delivery retries until each peer acknowledges, so replicas never
+        // keep a gap for long.
+        for peer in self.runtime.peers() {
+            let replicate = Payload::Replicate {
+                key: key.to_string(),
+                offset,
+                msg,
+                clock: clock.clone(),
+            };
+            self.runtime.deliver(peer, replicate, REPLICATE_TIMEOUT);
+        }
+        Ok(offset)
+    }
+
+    fn read_from(
+        &self,
+        key: &str,
+        offset: usize,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(usize, usize)>> {
+        Ok(self.logs.lock().unwrap().read_from(key, offset, limit))
+    }
+
+    fn commit(&self, offsets: HashMap<String, usize>) -> anyhow::Result<()> {
+        if self.owns(COMMITTED_OFFSETS) {
+            let clock = {
+                let mut logs = self.logs.lock().unwrap();
+                logs.commit(offsets.clone());
+                self.stamp()
+            };
+            // Let the key owners truncate their logs too.
+            for peer in self.runtime.peers() {
+                let replicate = Payload::ReplicateCommit {
+                    offsets: offsets.clone(),
+                    clock: clock.clone(),
+                };
+                self.runtime.deliver(peer, replicate, REPLICATE_TIMEOUT);
+            }
+            return Ok(());
+        }
+        self.forward(
+            self.owner(COMMITTED_OFFSETS),
+            Payload::CommitOffsets { offsets },
+        )?;
+        Ok(())
+    }
+
+    fn committed(&self, keys: &[String]) -> anyhow::Result<HashMap<String, usize>> {
+        if self.owns(COMMITTED_OFFSETS) {
+            return Ok(self.logs.lock().unwrap().committed(keys));
+        }
+        let list = Payload::ListCommittedOffsets {
+            keys: keys.to_vec(),
+        };
+        match self.forward(self.owner(COMMITTED_OFFSETS), list)? {
+            Payload::ListCommittedOffsetsOk { offsets } => Ok(offsets),
+            reply => Err(anyhow!("unexpected list_committed_offsets reply {reply:?}")),
+        }
+    }
+
+    fn replicate(
+        &self,
+        from: &str,
+        key: &str,
+        offset: usize,
+        msg: usize,
+        clock: Option<VectorClock>,
+    ) -> anyhow::Result<()> {
+        let key = key.to_string();
+        self.receive(from, Op::Entry { key, offset, msg }, clock);
+        Ok(())
+    }
+
+    fn replicate_commit(
+        &self,
+        from: &str,
+        offsets: HashMap<String, usize>,
+        clock: Option<VectorClock>,
+    ) -> anyhow::Result<()> {
+        self.receive(from, Op::Commit(offsets), clock);
+        Ok(())
+    }
+
+    fn persist(&self, backend: Arc<dyn StorageBackend>) -> anyhow::Result<()> {
+        self.logs.lock().unwrap().persist(backend)
+    }
+}
@@ -0,0 +1,445 @@
+//! Leader-replicated logs: every key has a replica set, its owner followed
+//! by the next `--kafka-replicas` nodes on a consistent hash ring (see
+//! [`crate::ring`]). One member leads the key: it assigns offsets and acks
+//! a `send` only once every other member has stored the entry, so while a
+//! member is unreachable the key takes no sends, and any member that takes
+//! over already has every acknowledged message.
+//!
+//! A member leads after claiming a term higher than any member promised
+//! before. Every member must promise the new term and report where its log
+//! ends; the claimer then brings each one in line with its own log, so
+//! entries past its end, which never reached every member and so were never
+//! acknowledged, are dropped, and those a member lacks are sent. Every entry
+//! a leader replicates carries its term, and members refuse entries from
+//! older terms, so a leader that was taken over from can no longer assign
+//! offsets. The first member this node does not suspect of having failed
+//! claims the key, unless another member claimed it since.
+//!
+//! A leader replicates a key's entries one at a time, in offset order. An
+//! entry that did not reach every member is dropped from the leader's log,
+//! and the leader gives up the lead until it claims the key again.
+//!
+//! Nodes outside the replica set forward everything for the key to its
+//! leader; members serve `poll` locally, up to the last entry they know
+//! reached every member. Committed offsets of all keys are replicated the
+//! same way, as if they were one more key, so a multi-key `commit_offsets`
+//! is applied whole; they only grow, so they need no terms.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail};
+
+use crate::{
+    clock::VectorClock,
+    kafka::{
+        log::{LogStore, Logs, COMMITTED_OFFSETS},
+        Payload,
+    },
+    ring::Ring,
+    runtime::{RpcError, Runtime},
+    storage::StorageBackend,
+};
+
+const FORWARD_TIMEOUT: Duration = Duration::from_millis(1000);
+const REPLICATE_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long a node that timed out stays out of leadership.
+const SUSPICION: Duration = Duration::from_millis(5000);
+
+/// Who leads a key, as far as this node knows.
+#[derive(Default, Debug)]
+struct Lead {
+    /// The latest term this node claimed or promised.
+    term: u64,
+    /// The member that claimed `term`.
+    leader: Option<String>,
+    /// Whether this node's claim to `term` went through.
+    leading: bool,
+    /// Entries below this offset reached every member.
+    acked: usize,
+}
+
+#[derive(Default)]
+struct KeyState {
+    /// Held by the leader for the whole of an append, so entries are
+    /// replicated one at a time and in order.
+    appending: Mutex<()>,
+    /// Locked before `logs` when both are.
+    lead: Mutex<Lead>,
+}
+
+pub struct ReplicatedLogs {
+    runtime: Runtime,
+    ring: Ring,
+    followers: usize,
+    logs: Mutex<Logs>,
+    keys: Mutex<HashMap<String, Arc<KeyState>>>,
+    // Nodes that recently failed to answer, and when.
+    suspected: Mutex<HashMap<String, Instant>>,
+}
+
+impl ReplicatedLogs {
+    pub fn new(runtime: Runtime, followers: usize, retain: Option<usize>) -> anyhow::Result<Self> {
+        let followers = followers.min(runtime.node_ids().len().saturating_sub(1));
+        Ok(Self {
+            ring: Ring::new(runtime.node_ids())?,
+            runtime,
+            followers,
+            logs: Mutex::new(Logs::with_retention(retain)),
+            keys: Mutex::default(),
+            suspected: Mutex::default(),
+        })
+    }
+
+    fn replica_set(&self, key: &str) -> Vec<String> {
+        self.ring.replicas(key, self.followers + 1)
+    }
+
+    fn is_member(&self, key: &str) -> bool {
+        self.replica_set(key)
+            .iter()
+            .any(|node| node == self.runtime.node_id())
+    }
+
+    fn state(&self, key: &str) -> Arc<KeyState> {
+        let mut keys = self.keys.lock().unwrap();
+        keys.entry(key.to_string()).or_default().clone()
+    }
+
+    fn suspect(&self, node: &str) {
+        eprintln!("suspecting {node}");
+        let mut suspected = self.suspected.lock().unwrap();
+        suspected.insert(node.to_string(), Instant::now());
+    }
+
+    /// The key's leader as far as this node can tell: the member that
+    /// claimed it last, or else the first member not suspected.
+    fn leader(&self, key: &str) -> Option<String> {
+        let me = self.runtime.node_id();
+        let claimed = self.state(key).lead.lock().unwrap().leader.clone();
+        let mut suspected = self.suspected.lock().unwrap();
+        suspected.retain(|_, since| since.elapsed() < SUSPICION);
+        let trusted = |node: &String| node == me || !suspected.contains_key(node);
+        if let Some(leader) = claimed.filter(trusted) {
+            return Some(leader);
+        }
+        self.replica_set(key).into_iter().find(trusted)
+    }
+
+    /// Sends a request to the key's leader, moving on to the next member
+    /// whenever one times out.
+    fn forward(&self, key: &str, payload: Payload) -> anyhow::Result<Payload> {
+        for _ in 0..=self.followers {
+            let Some(leader) = self.leader(key) else {
+                break;
+            };
+            match self.runtime.rpc(&leader, &payload, FORWARD_TIMEOUT) {
+                Err(RpcError::Timeout) => self.suspect(&leader),
+                reply => return Ok(reply?),
+            }
+        }
+        bail!("no reachable leader for {key}")
+    }
+
+    fn leads(&self, key: &str) -> bool {
+        self.leader(key).as_deref() == Some(self.runtime.node_id())
+    }
+
+    /// Sends every other member of `key`'s replica set the payload
+    /// `request` makes for it, and waits for all of their replies.
+    fn ask_followers(
+        &self,
+        key: &str,
+        request: impl Fn(&str) -> Payload,
+    ) -> anyhow::Result<Vec<(String, Payload)>> {
+        let me = self.runtime.node_id();
+        let handles: Vec<_> = self
+            .replica_set(key)
+            .into_iter()
+            .filter(|node| node != me)
+            .map(|follower| {
+                let runtime = self.runtime.clone();
+                let payload = request(&follower);
+                std::thread::spawn(move || -> anyhow::Result<_> {
+                    let reply = runtime
+                        .rpc::<_, Payload>(&follower, payload, REPLICATE_TIMEOUT)
+                        .map_err(|err| anyhow!("replicate to {follower}: {err}"))?;
+                    Ok((follower, reply))
+                })
+            })
+            .collect();
+        let mut replies = Vec::new();
+        for handle in handles {
+            let reply = handle
+                .join()
+                .map_err(|_| anyhow!("replication thread panicked"))?;
+            replies.push(reply?);
+        }
+        Ok(replies)
+    }
+
+    /// Claims `key` in a new term and brings every other member's log in
+    /// line with this node's. Returns the term.
+    fn claim_lead(&self, key: &str, state: &KeyState) -> anyhow::Result<u64> {
+        let me = self.runtime.node_id();
+        let term = {
+            let mut lead = state.lead.lock().unwrap();
+            lead.term += 1;
+            lead.leader = Some(me.to_string());
+            lead.leading = false;
+            lead.term
+        };
+        let claim = Payload::Claim {
+            key: key.to_string(),
+            term,
+        };
+        let mut ends = HashMap::new();
+        for (follower, reply) in self.ask_followers(key, |_| claim.clone())? {
+            match reply {
+                Payload::ClaimOk {
+                    term: promised,
+                    leader,
+                    ..
+                } if promised != term || leader.as_deref() != Some(me) => {
+                    let mut lead = state.lead.lock().unwrap();
+                    if promised > lead.term {
+                        lead.term = promised;
+                        lead.leader = None;
+                    }
+                    bail!("{follower} promised term {promised} of {key} to {leader:?}");
+                }
+                Payload::ClaimOk { next, .. } => {
+                    ends.insert(follower, next);
+                }
+                reply => bail!("unexpected claim reply {reply:?}"),
+            }
+        }
+        let syncs: HashMap<String, Payload> = {
+            let logs = self.logs.lock().unwrap();
+            let next = logs.next(key);
+            ends.into_iter()
+                .map(|(follower, end)| {
+                    let from = end.min(next);
+                    let sync = Payload::Sync {
+                        key: key.to_string(),
+                        term,
+                        from,
+                        entries: logs.range(key, from, next),
+                        acked: next,
+                    };
+                    (follower, sync)
+                })
+                .collect()
+        };
+        self.ask_followers(key, |follower| syncs[follower].clone())?;
+        let next = self.logs.lock().unwrap().next(key);
+        let mut lead = state.lead.lock().unwrap();
+        if lead.term != term {
+            bail!("lost {key} to term {} while claiming it", lead.term);
+        }
+        lead.leading = true;
+        lead.acked = lead.acked.max(next);
+        Ok(term)
+    }
+
+    /// Appends `msg` as `key`'s leader, claiming the key first unless this
+    /// node holds it already.
+    fn lead_append(&self, key: &str, msg: usize) -> anyhow::Result<usize> {
+        let state = self.state(key);
+        let _appending = state.appending.lock().unwrap();
+        let (term, acked) = {
+            let lead = state.lead.lock().unwrap();
+            (lead.leading.then_some(lead.term), lead.acked)
+        };
+        let (term, acked) = match term {
+            Some(term) => (term, acked),
+            None => {
+                let term = self.claim_lead(key, &state)?;
+                (term, state.lead.lock().unwrap().acked)
+            }
+        };
+        let offset = self.logs.lock().unwrap().append(key, msg);
+        let sync = Payload::Sync {
+            key: key.to_string(),
+            term,
+            from: offset,
+            entries: vec![(offset, msg)],
+            acked,
+        };
+        if let Err(err) = self.ask_followers(key, |_| sync.clone()) {
+            self.logs.lock().unwrap().cut(key, offset);
+            let mut lead = state.lead.lock().unwrap();
+            if lead.term == term {
+                lead.leading = false;
+            }
+            return Err(err);
+        }
+        let acked = offset + 1;
+        {
+            let mut lead = state.lead.lock().unwrap();
+            lead.acked = lead.acked.max(acked);
+        }
+        let me = self.runtime.node_id();
+        for follower in self.replica_set(key).iter().filter(|node| *node != me) {
+            let notice = Payload::Acked {
+                key: key.to_string(),
+                term,
+                acked,
+            };
+            if let Err(err) = self.runtime.send(follower, notice) {
+                eprintln!("acked to {follower} failed: {err:#}");
+            }
+        }
+        Ok(offset)
+    }
+
+    /// Adopts `term` if it is newer than any this node knows of, with
+    /// `from` as the key's leader.
+    fn adopt(lead: &mut Lead, from: &str, term: u64) {
+        if term > lead.term {
+            lead.term = term;
+            lead.leader = Some(from.to_string());
+            lead.leading = false;
+        }
+    }
+}
+
+impl LogStore for ReplicatedLogs {
+    fn append(&self, key: &str, msg: usize) -> anyhow::Result<usize> {
+        if self.leads(key) {
+            return self.lead_append(key, msg);
+        }
+        let send = Payload::Send {
+            key: key.to_string(),
+            msg,
+        };
+        match self.forward(key, send)? {
+            Payload::SendOk { offset } => Ok(offset),
+            reply => Err(anyhow!("unexpected send reply {reply:?}")),
+        }
+    }
+
+    fn read_from(
+        &self,
+        key: &str,
+        offset: usize,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(usize, usize)>> {
+        if !self.is_member(key) {
+            let poll = Payload::Poll {
+                offsets: HashMap::from([(key.to_string(), offset)]),
+            };
+            return match self.forward(key, poll)? {
+                Payload::PollOk { mut msgs } => Ok(msgs.remove(key).unwrap_or_default()),
+                reply => Err(anyhow!("unexpected poll reply {reply:?}")),
+            };
+        }
+        let state = self.state(key);
+        let lead = state.lead.lock().unwrap();
+        let mut entries = self.logs.lock().unwrap().read_from(key, offset, limit);
+        entries.retain(|&(entry, _)| entry < lead.acked);
+        Ok(entries)
+    }
+
+    fn commit(&self, offsets: HashMap<String, usize>) -> anyhow::Result<()> {
+        if !self.leads(COMMITTED_OFFSETS) {
+            self.forward(COMMITTED_OFFSETS, Payload::CommitOffsets { offsets })?;
+            return Ok(());
+        }
+        self.logs.lock().unwrap().commit(offsets.clone());
+        let replicate = Payload::ReplicateCommit {
+            offsets,
+            clock: None,
+        };
+        self.ask_followers(COMMITTED_OFFSETS, |_| replicate.clone())?;
+        // The other nodes only need the offsets to truncate their logs.
+        let members = self.replica_set(COMMITTED_OFFSETS);
+        for peer in self.runtime.peers() {
+            if !members.contains(peer) {
+                self.runtime
+                    .deliver(peer, replicate.clone(), REPLICATE_TIMEOUT);
+            }
+        }
+        Ok(())
+    }
+
+    fn committed(&self, keys: &[String]) -> anyhow::Result<HashMap<String, usize>> {
+        if self.is_member(COMMITTED_OFFSETS) {
+            return Ok(self.logs.lock().unwrap().committed(keys));
+        }
+        let list = Payload::ListCommittedOffsets {
+            keys: keys.to_vec(),
+        };
+        match self.forward(COMMITTED_OFFSETS, list)? {
+            Payload::ListCommittedOffsetsOk { offsets } => Ok(offsets),
+            reply => bail!("unexpected list_committed_offsets reply {reply:?}"),
+        }
+    }
+
+    fn replicate_commit(
+        &self,
+        _from: &str,
+        offsets: HashMap<String, usize>,
+        _clock: Option<VectorClock>,
+    ) -> anyhow::Result<()> {
+        self.logs.lock().unwrap().commit(offsets);
+        Ok(())
+    }
+
+    fn claim(
+        &self,
+        from: &str,
+        key: &str,
+        term: u64,
+    ) -> anyhow::Result<(u64, Option<String>, usize)> {
+        let state = self.state(key);
+        let mut lead = state.lead.lock().unwrap();
+        Self::adopt(&mut lead, from, term);
+        let next = self.logs.lock().unwrap().next(key);
+        Ok((lead.term, lead.leader.clone(), next))
+    }
+
+    fn sync(
+        &self,
+        from: &str,
+        key: &str,
+        term: u64,
+        start: usize,
+        entries: &[(usize, usize)],
+        acked: usize,
+    ) -> anyhow::Result<()> {
+        let state = self.state(key);
+        let mut lead = state.lead.lock().unwrap();
+        Self::adopt(&mut lead, from, term);
+        if term != lead.term || lead.leader.as_deref() != Some(from) {
+            bail!("{key} is led by {:?} in term {}", lead.leader, lead.term);
+        }
+        let mut logs = self.logs.lock().unwrap();
+        let next = logs.next(key);
+        if start > next {
+            bail!("{key} ends at {next}, short of {start}");
+        }
+        logs.cut(key, start);
+        for &(offset, msg) in entries {
+            logs.insert(key, offset, msg);
+        }
+        lead.acked = lead.acked.max(acked);
+        Ok(())
+    }
+
+    fn acked(&self, key: &str, term: u64, acked: usize) -> anyhow::Result<()> {
+        let state = self.state(key);
+        let mut lead = state.lead.lock().unwrap();
+        if term == lead.term {
+            lead.acked = lead.acked.max(acked);
+        }
+        Ok(())
+    }
+
+    fn persist(&self, backend: Arc<dyn StorageBackend>) -> anyhow::Result<()> {
+        self.logs.lock().unwrap().persist(backend)
+    }
+}
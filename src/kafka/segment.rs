@@ -0,0 +1,118 @@
+//! One key's log in memory, split into segments that each cover a fixed
+//! range of [`SEGMENT_SIZE`] offsets. Finding an offset looks up its
+//! segment in an index, then searches that segment alone, and truncating
+//! drops whole segments instead of shifting the entries left behind.
+
+use std::collections::BTreeMap;
+
+/// Offsets each segment covers.
+pub const SEGMENT_SIZE: usize = 1024;
+
+#[derive(Default, Debug)]
+pub struct SegmentedLog {
+    /// Segments by the first offset they cover, a multiple of
+    /// `SEGMENT_SIZE`. Each holds its entries in offset order, with gaps
+    /// where replicated entries have not arrived yet.
+    segments: BTreeMap<usize, Vec<(usize, usize)>>,
+    /// One past the highest offset stored, truncated or not, and never
+    /// below `start`.
+    next: usize,
+    /// The first offset truncation left; entries below it are not stored.
+    start: usize,
+}
+
+impl SegmentedLog {
+    /// Appends `msg` after the highest offset stored and returns its offset.
+    pub fn append(&mut self, msg: usize) -> usize {
+        let offset = self.next;
+        self.insert(offset, msg);
+        offset
+    }
+
+    /// Stores the entry at `offset`, unless one is there already or
+    /// truncation has passed it. Returns whether it is stored.
+    pub fn insert(&mut self, offset: usize, msg: usize) -> bool {
+        if offset < self.start {
+            return false;
+        }
+        let segment = self.segments.entry(base(offset)).or_default();
+        if let Err(at) = segment.binary_search_by_key(&offset, |&(entry, _)| entry) {
+            segment.insert(at, (offset, msg));
+        }
+        self.next = self.next.max(offset + 1);
+        true
+    }
+
+    /// The run of consecutive entries starting at `offset`, at most `limit`
+    /// of them.
+    pub fn read_from(&self, offset: usize, limit: usize) -> Vec<(usize, usize)> {
+        let mut entries = Vec::new();
+        let mut expected = offset;
+        for segment in self
+            .segments
+            .range(base(offset)..)
+            .map(|(_, segment)| segment)
+        {
+            let start = segment.partition_point(|&(entry, _)| entry < expected);
+            for &(entry, msg) in &segment[start..] {
+                if entry != expected || entries.len() == limit {
+                    return entries;
+                }
+                entries.push((entry, msg));
+                expected += 1;
+            }
+            if !expected.is_multiple_of(SEGMENT_SIZE) {
+                // The segment ended short of the next one: a gap.
+                break;
+            }
+        }
+        entries
+    }
+
+    /// One past the highest offset stored.
+    pub fn next(&self) -> usize {
+        self.next
+    }
+
+    /// Every entry stored from `from` up to but not including `to`, gaps
+    /// and all.
+    pub fn range(&self, from: usize, to: usize) -> Vec<(usize, usize)> {
+        self.segments
+            .range(base(from)..)
+            .flat_map(|(_, segment)| segment)
+            .skip_while(|&&(entry, _)| entry < from)
+            .take_while(|&&(entry, _)| entry < to)
+            .copied()
+            .collect()
+    }
+
+    /// Drops every entry at `offset` and above, so the next append takes
+    /// `offset` again.
+    pub fn cut(&mut self, offset: usize) {
+        if offset >= self.next {
+            return;
+        }
+        let mut above = self.segments.split_off(&base(offset));
+        if let Some(mut segment) = above.remove(&base(offset)) {
+            segment.retain(|&(entry, _)| entry < offset);
+            if !segment.is_empty() {
+                self.segments.insert(base(offset), segment);
+            }
+        }
+        self.next = offset;
+    }
+
+    /// Drops the segments that lie wholly below `offset`, and keeps entries
+    /// arriving for them later out. Entries below it in the segment
+    /// `offset` falls in stay until the next truncation passes that segment.
+    pub fn truncate(&mut self, offset: usize) {
+        self.start = self.start.max(base(offset));
+        self.next = self.next.max(self.start);
+        self.segments = self.segments.split_off(&self.start);
+    }
+}
+
+/// The first offset of the segment covering `offset`.
+fn base(offset: usize) -> usize {
+    offset - offset % SEGMENT_SIZE
+}
@@ -0,0 +1,35 @@
+pub mod backoff;
+pub mod broadcast;
+pub mod causal;
+pub mod clock;
+pub mod config;
+pub mod consensus;
+pub mod counter;
+pub mod crdt;
+pub mod gossip;
+pub mod ids;
+pub mod kafka;
+pub mod lin_kv;
+pub mod lock;
+pub mod membership;
+pub mod merkle;
+pub mod message;
+pub mod paxos;
+pub mod pubsub;
+pub mod queue;
+pub mod raft;
+pub mod rate_limit;
+pub mod replication;
+pub mod ring;
+pub mod runtime;
+pub mod semaphore;
+pub mod services;
+pub mod set;
+pub mod storage;
+pub mod tob;
+pub mod transport;
+pub mod txn;
+pub mod vr;
+
+pub use message::{Message, MessageBody};
+pub use runtime::{main_loop, main_loop_on, Node, Runtime};
@@ -0,0 +1,307 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    consensus::StateMachine,
+    lin_kv::{
+        store::{KvCommand, KvStore},
+        written, Payload,
+    },
+    message::{Message, NodeId},
+    runtime::Runtime,
+};
+
+/// How often updates not yet acknowledged by the tail are sent again.
+const RESEND_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Messages between the nodes of a chain. Each carries the nodes the sender
+/// knows were marked failed, so the others learn of it too.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ChainMessage {
+    /// Applies update number `seq` and passes it on down the chain.
+    Update {
+        seq: u64,
+        command: KvCommand,
+        failed: BTreeSet<NodeId>,
+    },
+    /// Every update up to `seq` reached the tail.
+    Ack { seq: u64, failed: BTreeSet<NodeId> },
+    /// Tells every node who left the chain.
+    Failed { failed: BTreeSet<NodeId> },
+}
+
+struct State {
+    store: KvStore,
+    failed: BTreeSet<NodeId>,
+    /// The last update applied here.
+    applied: u64,
+    /// The last update known to have reached the tail.
+    acked: u64,
+    /// Updates passed down the chain and not acknowledged yet, to send again
+    /// to whoever follows this node.
+    sent: BTreeMap<u64, KvCommand>,
+    /// At the head: the requests waiting for their update to reach the
+    /// tail, by update, with the version it brought their key to.
+    waiting: BTreeMap<u64, (Message<Payload>, u64)>,
+}
+
+/// A node of the chain in `chain` mode.
+///
+/// The nodes form a chain in id order, leaving out those marked failed.
+/// The head orders every write and cas: it checks a cas against its own
+/// copy, applies it, and passes it down the chain, each node applying it in
+/// turn. Once it reaches the tail it is committed; the acknowledgement
+/// travels back up, and the head answers the client. Reads go to the tail,
+/// whose copy holds exactly the committed updates, so they are
+/// linearizable without any round of messages.
+///
+/// A node marked failed leaves the chain, and its neighbors repair it: the
+/// node before it sends everything the tail has not acknowledged yet to the
+/// node after it, which skips what it already applied. A new tail commits
+/// everything it applied, and a new head orders writes from where it is.
+/// Requests waiting at a head that failed are never answered. Nodes are
+/// expected to fail by stopping: one marked failed that does not hear of it
+/// goes on with what it has, and a failed node never rejoins.
+#[derive(Clone)]
+pub struct Chain {
+    runtime: Runtime,
+    /// Every node, in chain order.
+    nodes: Vec<NodeId>,
+    state: Arc<Mutex<State>>,
+}
+
+impl Chain {
+    /// Starts this node's part of the chain.
+    pub fn mount(runtime: Runtime) -> Self {
+        let mut nodes = runtime.node_ids().to_vec();
+        nodes.sort();
+        let chain = Self {
+            runtime,
+            nodes,
+            state: Arc::new(Mutex::new(State {
+                store: KvStore::default(),
+                failed: BTreeSet::new(),
+                applied: 0,
+                acked: 0,
+                sent: BTreeMap::new(),
+                waiting: BTreeMap::new(),
+            })),
+        };
+        let resender = chain.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(RESEND_INTERVAL);
+            let state = resender.state.lock().unwrap();
+            resender.resend(&state);
+        });
+        chain
+    }
+
+    pub fn head(&self) -> NodeId {
+        let state = self.state.lock().unwrap();
+        self.live(&state).first().cloned().unwrap_or_default()
+    }
+
+    pub fn tail(&self) -> NodeId {
+        let state = self.state.lock().unwrap();
+        self.live(&state).last().cloned().unwrap_or_default()
+    }
+
+    /// Reads this node's copy, which is the committed state at the tail.
+    pub fn read<R>(&self, f: impl FnOnce(&KvStore) -> R) -> R {
+        f(&self.state.lock().unwrap().store)
+    }
+
+    /// Orders a write or cas at the head. `input` is answered once the
+    /// update reached the tail, or right away if the cas fails.
+    pub fn propose(&self, command: KvCommand, input: Message<Payload>) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let key_version = match state.store.apply(command.clone()) {
+            Ok(key_version) => key_version,
+            Err((code, text)) => return self.runtime.reply_error(&input, code, text),
+        };
+        state.applied += 1;
+        let seq = state.applied;
+        state.waiting.insert(seq, (input, key_version));
+        self.pass_on(&mut state, seq, command);
+        Ok(())
+    }
+
+    /// Has the head order a sweep of the keys expired by `now`, if any are
+    /// due, so every node evicts them at the same update.
+    pub fn sweep(&self, now: u64) {
+        let mut state = self.state.lock().unwrap();
+        if self.live(&state).first().map(String::as_str) != Some(self.runtime.node_id())
+            || !state.store.due(now)
+        {
+            return;
+        }
+        let command = KvCommand::Sweep { at: now };
+        let _ = state.store.apply(command.clone());
+        state.applied += 1;
+        let seq = state.applied;
+        self.pass_on(&mut state, seq, command);
+    }
+
+    /// Marks `node` failed, for an admin request, and tells every node.
+    pub fn mark_failed(&self, node: &str) {
+        let mut state = self.state.lock().unwrap();
+        let failed = BTreeSet::from([node.to_string()]);
+        self.learn_failed(&mut state, &failed);
+        for peer in self.runtime.peers() {
+            let failed = state.failed.clone();
+            self.send(peer, ChainMessage::Failed { failed });
+        }
+    }
+
+    pub fn receive(&self, from: &str, message: ChainMessage) {
+        let mut state = self.state.lock().unwrap();
+        match message {
+            ChainMessage::Update {
+                seq,
+                command,
+                failed,
+            } => {
+                self.learn_failed(&mut state, &failed);
+                if self.predecessor(&state).as_deref() != Some(from) {
+                    return;
+                }
+                if seq <= state.applied {
+                    // Sent again: the ack may have been lost on the way.
+                    if state.acked > 0 {
+                        let ack = ChainMessage::Ack {
+                            seq: state.acked,
+                            failed: state.failed.clone(),
+                        };
+                        self.send(from, ack);
+                    }
+                    return;
+                }
+                if seq != state.applied + 1 {
+                    // Missed one; it comes again with the rest.
+                    return;
+                }
+                // The head already checked it against the same state.
+                let _ = state.store.apply(command.clone());
+                state.applied = seq;
+                self.pass_on(&mut state, seq, command);
+            }
+            ChainMessage::Ack { seq, failed } => {
+                self.learn_failed(&mut state, &failed);
+                if self.successor(&state).as_deref() == Some(from) {
+                    self.commit(&mut state, seq);
+                }
+            }
+            ChainMessage::Failed { failed } => self.learn_failed(&mut state, &failed),
+        }
+    }
+
+    /// The nodes still in the chain, in order.
+    fn live(&self, state: &State) -> Vec<NodeId> {
+        self.nodes
+            .iter()
+            .filter(|node| !state.failed.contains(*node))
+            .cloned()
+            .collect()
+    }
+
+    fn predecessor(&self, state: &State) -> Option<NodeId> {
+        let live = self.live(state);
+        let at = live
+            .iter()
+            .position(|node| node == self.runtime.node_id())?;
+        at.checked_sub(1).map(|at| live[at].clone())
+    }
+
+    fn successor(&self, state: &State) -> Option<NodeId> {
+        let live = self.live(state);
+        let at = live
+            .iter()
+            .position(|node| node == self.runtime.node_id())?;
+        live.get(at + 1).cloned()
+    }
+
+    /// Sends update `seq`, applied here, to the next node, or commits it at
+    /// the tail.
+    fn pass_on(&self, state: &mut State, seq: u64, command: KvCommand) {
+        let Some(successor) = self.successor(state) else {
+            self.commit(state, seq);
+            return;
+        };
+        state.sent.insert(seq, command.clone());
+        let update = ChainMessage::Update {
+            seq,
+            command,
+            failed: state.failed.clone(),
+        };
+        self.send(&successor, update);
+    }
+
+    /// Records that updates up to `seq` reached the tail: forgets them,
+    /// and answers their clients at the head or passes the ack up.
+    fn commit(&self, state: &mut State, seq: u64) {
+        if seq <= state.acked {
+            return;
+        }
+        state.acked = seq;
+        state.sent.retain(|&sent, _| sent > seq);
+        match self.predecessor(state) {
+            Some(predecessor) => {
+                let failed = state.failed.clone();
+                self.send(&predecessor, ChainMessage::Ack { seq, failed });
+            }
+            None => {
+                let rest = state.waiting.split_off(&(seq + 1));
+                for (_, (input, key_version)) in std::mem::replace(&mut state.waiting, rest) {
+                    let reply = written(&input.body.payload, key_version);
+                    if let Err(err) = self.runtime.reply(&input, reply) {
+                        eprintln!("lin-kv reply failed: {err:#}");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Takes the nodes in `failed` out of the chain and repairs it around
+    /// them.
+    fn learn_failed(&self, state: &mut State, failed: &BTreeSet<NodeId>) {
+        let before = state.failed.len();
+        state.failed.extend(failed.iter().cloned());
+        if state.failed.len() == before || state.failed.contains(self.runtime.node_id()) {
+            // Nothing changed, or this node is out and takes no more part.
+            return;
+        }
+        match self.successor(state) {
+            // The node after may have missed what the failed one had.
+            Some(_) => self.resend(state),
+            // This node is the tail now: what it applied is committed.
+            None => self.commit(state, state.applied),
+        }
+    }
+
+    /// Sends the updates not acknowledged yet to the next node, in order.
+    fn resend(&self, state: &State) {
+        let Some(successor) = self.successor(state) else {
+            return;
+        };
+        for (&seq, command) in &state.sent {
+            let update = ChainMessage::Update {
+                seq,
+                command: command.clone(),
+                failed: state.failed.clone(),
+            };
+            self.send(&successor, update);
+        }
+    }
+
+    fn send(&self, to: &str, message: ChainMessage) {
+        if let Err(err) = self.runtime.send(to, Payload::Chain { message }) {
+            eprintln!("chain send to {to} failed: {err:#}");
+        }
+    }
+}
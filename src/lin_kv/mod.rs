@@ -0,0 +1,1082 @@
+//! A node that serves Maelstrom's `lin-kv` protocol itself (`-w lin-kv`), so
+//! the crate can be tested as the service and not only as its client.
+//!
+//! `--kv-mode primary` (default) has one node hold the data: the node with
+//! the lowest id serves every request and the others forward to it. That is
+//! linearizable but does not survive losing that node.
+//!
+//! `--kv-mode replicated` trades linearizability for availability: every
+//! node serves requests from its own copy and the copies converge
+//! last-writer-wins (see [`replicated`]). Replies then carry a `version`
+//! vector, and a `read` given one waits until the node's copy is at least
+//! that fresh, so a client passing along the versions it saw reads causally
+//! consistent values from any node.
+//!
+//! Writes made concurrently on different nodes are kept as siblings, told
+//! apart with dotted version vectors. `read` answers with the one written
+//! last; with `--kv-siblings true` it also returns all of them and the
+//! `context` they were read at, and a `write` passing that context back
+//! replaces exactly those siblings.
+//!
+//! `--kv-mode consensus` is linearizable and survives losing a minority of
+//! the nodes: every node keeps a copy of the store, driven by a replicated
+//! log (see [`crate::consensus`]), Raft's unless `--consensus` picks
+//! another. The leader proposes each write and cas and replies only once
+//! it is committed and applied; it answers reads from its copy once it
+//! confirmed it still leads with a round of heartbeats, or, on Raft with
+//! `--raft-leases true`, right away while its lease lasts. Other nodes
+//! forward requests to the leader they know of and relay its answer, or
+//! refuse with error 11 while they know of none. On Raft, a `reconfigure`
+//! message naming the `voters` changes who takes part, and is answered
+//! with `reconfigure_ok` once the change is in force. `--kv-mode raft` is
+//! the older name of this mode.
+//!
+//! `--kv-mode chain` replicates by chain replication (see [`chain`]): the
+//! nodes form a chain in id order, writes and cas enter at its head and
+//! are answered once they reached the tail, and reads are served at the
+//! tail. Other nodes forward requests to the right end. A `mark_failed`
+//! message naming a `node` takes it out of the chain, and the nodes on
+//! either side of it repair the chain around it.
+//!
+//! `--kv-mode quorum` replicates Dynamo-style, without consensus (see
+//! [`quorum`]): each key lives on `--kv-n` replicas, and whichever node a
+//! client asks writes to all of them, answering once `--kv-w` stored the
+//! write, and reads from all of them, answering with the newest value among
+//! the first `--kv-r` to reply. A request's own `r` and `w` override them.
+//! Writes a replica missed are handed off to it once it answers again, and
+//! a read that finds replicas behind puts the newest version back on them.
+//! In the background, replicas compare Merkle trees over the keys they
+//! share and swap what differs, so keys nobody touches converge too.
+//!
+//! In `primary`, `consensus` and `chain` mode a `write` may carry a `ttl` in
+//! milliseconds, after which the key reads as missing (error 20). Each
+//! command in the log carries its proposer's clock and first evicts what
+//! expired by then, so the expiry replicates with the value and every copy
+//! evicts the same keys; while nothing is written, the primary, leader or
+//! head sweeps on a timer. The other modes refuse a `ttl` with error 10.
+//!
+//! The same modes answer `scan {from, to, limit}` with the `pairs` of keys
+//! from `from` up to but not including `to`, in key order (see
+//! [`store::Key`]), read like a `read`. The other modes hold no one copy
+//! of every key to scan, and refuse it with error 10.
+//!
+//! They also keep a version for every key, which each write and cas bumps,
+//! and return it as `key_version` in `read_ok`, `write_ok` and `cas_ok`.
+//! `write_if_version` writes a key only while it is still at the
+//! `key_version` given, 0 for a key that must not exist yet, and fails with
+//! error 22 otherwise: a cas that compares a number instead of the whole
+//! value.
+
+pub mod chain;
+pub mod quorum;
+pub mod replicated;
+pub mod store;
+
+use std::{
+    collections::BTreeSet,
+    str::FromStr,
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    clock::{now_ms, Hlc, VectorClock},
+    consensus::{Algorithm, Consensus, NotLeader, StateMachine},
+    crdt::{CrdtMap, Snapshot},
+    merkle::SyncStep,
+    message::{error_code, Init, Message, NodeId},
+    paxos::{PaxosMessage, PaxosServer},
+    raft::{RaftMessage, RaftServer},
+    replication::{CrdtReplicator, Gossip},
+    runtime::{Node, Runtime},
+    vr::{VrMessage, VrServer},
+};
+use chain::{Chain, ChainMessage};
+use quorum::{Quorum, Versioned};
+use replicated::Replica;
+use store::{KvCommand, KvStore};
+
+const FORWARD_TIMEOUT: Duration = Duration::from_millis(1000);
+const REPLICATE_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a `read` waits for the copy to reach the version it asked for.
+const FRESHNESS_TIMEOUT: Duration = Duration::from_millis(1000);
+const FRESHNESS_POLL: Duration = Duration::from_millis(10);
+/// How long a request waits for its entry to be applied, and a
+/// `reconfigure` for the change to be in force.
+const COMMIT_TIMEOUT: Duration = Duration::from_millis(1000);
+/// How often expired keys are evicted from a store nobody writes to.
+const SWEEP_INTERVAL: Duration = Duration::from_millis(1000);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KvMode {
+    #[default]
+    Primary,
+    Replicated,
+    Consensus,
+    Chain,
+    Quorum,
+}
+
+impl FromStr for KvMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "primary" => Ok(Self::Primary),
+            "replicated" => Ok(Self::Replicated),
+            "consensus" | "raft" => Ok(Self::Consensus),
+            "chain" => Ok(Self::Chain),
+            "quorum" => Ok(Self::Quorum),
+            _ => bail!(
+                "unknown kv mode {s}, expected primary, replicated, consensus, chain or quorum"
+            ),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Read {
+        key: Value,
+        /// Read at least this fresh.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<VectorClock>,
+        /// Replicas to read from, in `quorum` mode.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        r: Option<usize>,
+    },
+    ReadOk {
+        value: Value,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<VectorClock>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        siblings: Option<Vec<Value>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        context: Option<VectorClock>,
+        /// This key's version, in `primary`, `consensus` and `chain` mode.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key_version: Option<u64>,
+    },
+    Write {
+        key: Value,
+        value: Value,
+        /// The `context` of the read this write resolves.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        context: Option<VectorClock>,
+        /// Replicas to write to, in `quorum` mode.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        w: Option<usize>,
+        /// Milliseconds until the key expires, in `primary`, `consensus`
+        /// and `chain` mode.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl: Option<u64>,
+    },
+    WriteOk {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<VectorClock>,
+        /// This key's version, in `primary`, `consensus` and `chain` mode.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key_version: Option<u64>,
+    },
+    /// Writes `key` only if it is still at `key_version`, 0 meaning it must
+    /// not exist, in `primary`, `consensus` and `chain` mode.
+    WriteIfVersion {
+        key: Value,
+        value: Value,
+        key_version: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl: Option<u64>,
+    },
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default)]
+        create_if_not_exists: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        r: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        w: Option<usize>,
+    },
+    CasOk {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<VectorClock>,
+        /// This key's version, in `primary`, `consensus` and `chain` mode.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key_version: Option<u64>,
+    },
+    Replicate {
+        gossip: Gossip<Replica>,
+    },
+    Raft {
+        message: RaftMessage<KvCommand>,
+    },
+    Paxos {
+        message: PaxosMessage<KvCommand>,
+    },
+    Vr {
+        message: VrMessage<KvCommand>,
+    },
+    Chain {
+        message: ChainMessage,
+    },
+    /// From a coordinator to the replicas of `key`, in `quorum` mode.
+    Get {
+        key: Value,
+    },
+    GetOk {
+        versioned: Versioned,
+    },
+    Put {
+        key: Value,
+        versioned: Versioned,
+    },
+    PutOk,
+    /// Anti-entropy between two replicas, in `quorum` mode.
+    Sync {
+        sync: SyncStep<Versioned>,
+    },
+    SyncOk {
+        sync: SyncStep<Versioned>,
+    },
+    /// Writes a coordinator could not put on this replica in time.
+    Handoff {
+        hints: CrdtMap<Versioned>,
+    },
+    HandoffOk,
+    /// Admin: moves the Raft cluster to these voters, in `consensus` mode.
+    Reconfigure {
+        voters: BTreeSet<NodeId>,
+    },
+    ReconfigureOk,
+    /// Admin: takes this node out of the chain, in `chain` mode.
+    MarkFailed {
+        node: NodeId,
+    },
+    MarkFailedOk,
+    /// The pairs from key `from` on, up to but not including `to`, in key
+    /// order, in `primary`, `consensus` and `chain` mode. Either end may be
+    /// left open.
+    Scan {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        from: Option<Value>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        to: Option<Value>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<usize>,
+    },
+    ScanOk {
+        pairs: Vec<(Value, Value)>,
+    },
+    /// Debugging: this node's copy, as a snapshot, in every mode but
+    /// `primary`.
+    Dump,
+    DumpOk {
+        state: Value,
+    },
+}
+
+enum Backend {
+    Primary {
+        primary: String,
+        store: Arc<Mutex<KvStore>>,
+    },
+    Replicated {
+        replica: CrdtReplicator<Replica>,
+        /// Stamps local writes, never behind a replicated one.
+        clock: Hlc,
+        /// Whether reads return siblings.
+        siblings: bool,
+    },
+    Raft {
+        server: RaftServer<KvStore>,
+    },
+    Paxos {
+        server: PaxosServer<KvStore>,
+    },
+    Vr {
+        server: VrServer<KvStore>,
+    },
+    Chain {
+        chain: Chain,
+    },
+    Quorum {
+        quorum: Quorum,
+    },
+}
+
+pub struct LinKvNode {
+    runtime: Runtime,
+    backend: Backend,
+}
+
+impl Node<Payload> for LinKvNode {
+    fn from_init(runtime: Runtime, init: Init) -> anyhow::Result<Self> {
+        let config = runtime.config().clone();
+        let backend = match config.parse("kv-mode")?.unwrap_or_default() {
+            KvMode::Primary => Backend::Primary {
+                primary: init.node_ids.iter().min().cloned().unwrap_or(init.node_id),
+                store: Arc::default(),
+            },
+            KvMode::Replicated => {
+                let replica = CrdtReplicator::mount(
+                    runtime.clone(),
+                    Replica::default(),
+                    REPLICATE_INTERVAL,
+                    |gossip| Payload::Replicate { gossip },
+                );
+                replica.persist(&config, runtime.node_id())?;
+                let clock = Hlc::new();
+                if let Some(time) = replica.read(Replica::latest) {
+                    clock.observe(time);
+                }
+                Backend::Replicated {
+                    replica,
+                    clock,
+                    siblings: config.parse("kv-siblings")?.unwrap_or_default(),
+                }
+            }
+            KvMode::Consensus => match config.parse("consensus")?.unwrap_or_default() {
+                Algorithm::Raft => Backend::Raft {
+                    server: RaftServer::mount(
+                        runtime.clone(),
+                        &config,
+                        KvStore::default(),
+                        |message| Payload::Raft { message },
+                    )?,
+                },
+                Algorithm::Paxos => Backend::Paxos {
+                    server: PaxosServer::mount(
+                        runtime.clone(),
+                        &config,
+                        KvStore::default(),
+                        |message| Payload::Paxos { message },
+                    )?,
+                },
+                Algorithm::Vr => Backend::Vr {
+                    server: VrServer::mount(
+                        runtime.clone(),
+                        &config,
+                        KvStore::default(),
+                        |message| Payload::Vr { message },
+                    )?,
+                },
+            },
+            KvMode::Chain => Backend::Chain {
+                chain: Chain::mount(runtime.clone()),
+            },
+            KvMode::Quorum => Backend::Quorum {
+                quorum: Quorum::mount(runtime.clone(), &config)?,
+            },
+        };
+        match &backend {
+            Backend::Primary { store, .. } => {
+                let store = store.clone();
+                spawn_sweeper(move |now| {
+                    store.lock().unwrap().sweep(now);
+                });
+            }
+            Backend::Raft { server } => sweep_log(server.clone()),
+            Backend::Paxos { server } => sweep_log(server.clone()),
+            Backend::Vr { server } => sweep_log(server.clone()),
+            Backend::Chain { chain } => {
+                let chain = chain.clone();
+                spawn_sweeper(move |now| chain.sweep(now));
+            }
+            Backend::Replicated { .. } | Backend::Quorum { .. } => {}
+        }
+        Ok(Self { runtime, backend })
+    }
+
+    fn step(&mut self, input: Message<Payload>) -> anyhow::Result<()> {
+        match &self.backend {
+            Backend::Primary { primary, .. } if primary != self.runtime.node_id() => {
+                self.runtime.forward(primary, input, FORWARD_TIMEOUT);
+                Ok(())
+            }
+            Backend::Primary { .. } => self.step_primary(input),
+            Backend::Replicated {
+                replica,
+                clock,
+                siblings,
+            } => self.step_replicated(replica, clock, *siblings, input),
+            Backend::Raft { server } => match &input.body.payload {
+                Payload::Raft { message } => {
+                    server.receive(&input.src, message.clone());
+                    Ok(())
+                }
+                _ => self.step_consensus(server, input),
+            },
+            Backend::Paxos { server } => match &input.body.payload {
+                Payload::Paxos { message } => {
+                    server.receive(&input.src, message.clone());
+                    Ok(())
+                }
+                _ => self.step_consensus(server, input),
+            },
+            Backend::Vr { server } => match &input.body.payload {
+                Payload::Vr { message } => {
+                    server.receive(&input.src, message.clone());
+                    Ok(())
+                }
+                _ => self.step_consensus(server, input),
+            },
+            Backend::Chain { chain } => self.step_chain(chain, input),
+            Backend::Quorum { quorum } => self.step_quorum(quorum, input),
+        }
+    }
+}
+
+impl LinKvNode {
+    fn step_primary(&mut self, input: Message<Payload>) -> anyhow::Result<()> {
+        let Backend::Primary { store, .. } = &self.backend else {
+            return Ok(());
+        };
+        let now = now_ms();
+        let mut store = store.lock().unwrap();
+        store.sweep(now);
+        let result = match input.body.payload.clone() {
+            Payload::Read { .. } | Payload::Scan { .. } => serve_read(&store, &input.body.payload),
+            payload @ (Payload::Write { .. }
+            | Payload::Cas { .. }
+            | Payload::WriteIfVersion { .. }) => store
+                .apply(command(payload))
+                .map(|key_version| written(&input.body.payload, key_version)),
+            Payload::ReadOk { .. }
+            | Payload::WriteOk { .. }
+            | Payload::CasOk { .. }
+            | Payload::Replicate { .. }
+            | Payload::Raft { .. }
+            | Payload::Paxos { .. }
+            | Payload::Vr { .. }
+            | Payload::Chain { .. }
+            | Payload::Get { .. }
+            | Payload::GetOk { .. }
+            | Payload::Put { .. }
+            | Payload::PutOk
+            | Payload::Handoff { .. }
+            | Payload::HandoffOk
+            | Payload::Sync { .. }
+            | Payload::SyncOk { .. }
+            | Payload::MarkFailed { .. }
+            | Payload::MarkFailedOk
+            | Payload::Reconfigure { .. }
+            | Payload::ReconfigureOk
+            | Payload::Dump
+            | Payload::ScanOk { .. }
+            | Payload::DumpOk { .. } => return Ok(()),
+        };
+        match result {
+            Ok(reply) => self.runtime.reply(&input, reply),
+            Err((code, text)) => self.runtime.reply_error(&input, code, text),
+        }
+    }
+
+    /// Serves from this node's copy; writes reply with the version they
+    /// brought the copy to.
+    fn step_replicated(
+        &self,
+        replica: &CrdtReplicator<Replica>,
+        clock: &Hlc,
+        siblings: bool,
+        input: Message<Payload>,
+    ) -> anyhow::Result<()> {
+        let node = self.runtime.node_id();
+        let result = match input.body.payload {
+            Payload::Read { ref version, .. } => {
+                let version = version.clone().unwrap_or_default();
+                let (runtime, replica) = (self.runtime.clone(), replica.clone());
+                read_when_fresh(runtime, replica, version, siblings, input);
+                return Ok(());
+            }
+            Payload::Write { ttl: Some(_), .. } => Err(ttl_not_supported()),
+            Payload::Scan { .. } => Err(scan_not_supported()),
+            Payload::WriteIfVersion { .. } => Err(versions_not_supported()),
+            Payload::Write {
+                ref key,
+                ref value,
+                ref context,
+                ..
+            } => {
+                let time = clock.tick();
+                replica.update(|replica| {
+                    replica.write(key, value.clone(), context.as_ref(), time, node);
+                    Ok(Payload::WriteOk {
+                        version: Some(replica.clock().clone()),
+                        key_version: None,
+                    })
+                })
+            }
+            Payload::Cas {
+                ref key,
+                ref from,
+                ref to,
+                create_if_not_exists,
+                ..
+            } => {
+                let time = clock.tick();
+                replica.update(|replica| {
+                    replica
+                        .cas(key, from, to.clone(), create_if_not_exists, time, node)
+                        .map(|()| Payload::CasOk {
+                            version: Some(replica.clock().clone()),
+                            key_version: None,
+                        })
+                })
+            }
+            Payload::Replicate { gossip } => {
+                if let Some(time) = gossip.state().and_then(Replica::latest) {
+                    clock.observe(time);
+                }
+                if let Some(gossip) = replica.receive(&input.src, gossip) {
+                    self.runtime
+                        .send(&input.src, Payload::Replicate { gossip })?;
+                }
+                return Ok(());
+            }
+            Payload::Dump => Ok(Payload::DumpOk {
+                state: replica.dump(),
+            }),
+            Payload::ReadOk { .. }
+            | Payload::WriteOk { .. }
+            | Payload::CasOk { .. }
+            | Payload::Raft { .. }
+            | Payload::Paxos { .. }
+            | Payload::Vr { .. }
+            | Payload::Chain { .. }
+            | Payload::Get { .. }
+            | Payload::GetOk { .. }
+            | Payload::Put { .. }
+            | Payload::PutOk
+            | Payload::Handoff { .. }
+            | Payload::HandoffOk
+            | Payload::Sync { .. }
+            | Payload::SyncOk { .. }
+            | Payload::MarkFailed { .. }
+            | Payload::MarkFailedOk
+            | Payload::Reconfigure { .. }
+            | Payload::ReconfigureOk
+            | Payload::ScanOk { .. }
+            | Payload::DumpOk { .. } => return Ok(()),
+        };
+        match result {
+            Ok(reply) => self.runtime.reply(&input, reply),
+            Err((code, text)) => self.runtime.reply_error(&input, code, text),
+        }
+    }
+
+    /// Proposes requests to the replicated log, answering each once it is
+    /// applied, off the input thread.
+    fn step_consensus<C: Consensus<KvStore>>(
+        &self,
+        server: &C,
+        input: Message<Payload>,
+    ) -> anyhow::Result<()> {
+        let command = match input.body.payload.clone() {
+            Payload::Read { .. } | Payload::Scan { .. } => {
+                return match server.read_index() {
+                    Ok(ready) => {
+                        let (runtime, server) = (self.runtime.clone(), server.clone());
+                        std::thread::spawn(move || {
+                            reply_read_index(&runtime, &server, ready, &input)
+                        });
+                        Ok(())
+                    }
+                    Err(not_leader) => self.not_leader(not_leader, input),
+                };
+            }
+            payload @ (Payload::Write { .. }
+            | Payload::Cas { .. }
+            | Payload::WriteIfVersion { .. }) => command(payload),
+            Payload::Reconfigure { voters } => {
+                let changed = server.reconfigure(voters);
+                reply_reconfigured(self.runtime.clone(), changed, input);
+                return Ok(());
+            }
+            Payload::Dump => {
+                let state = server.read(|store| serde_json::to_value(store.snapshot()));
+                let state = state.unwrap_or_default();
+                return self.runtime.reply(&input, Payload::DumpOk { state });
+            }
+            Payload::ReadOk { .. }
+            | Payload::WriteOk { .. }
+            | Payload::CasOk { .. }
+            | Payload::Replicate { .. }
+            | Payload::Raft { .. }
+            | Payload::Paxos { .. }
+            | Payload::Vr { .. }
+            | Payload::Chain { .. }
+            | Payload::Get { .. }
+            | Payload::GetOk { .. }
+            | Payload::Put { .. }
+            | Payload::PutOk
+            | Payload::Handoff { .. }
+            | Payload::HandoffOk
+            | Payload::Sync { .. }
+            | Payload::SyncOk { .. }
+            | Payload::MarkFailed { .. }
+            | Payload::MarkFailedOk
+            | Payload::ReconfigureOk
+            | Payload::ScanOk { .. }
+            | Payload::DumpOk { .. } => return Ok(()),
+        };
+        match server.propose(command) {
+            Ok(applied) => {
+                let runtime = self.runtime.clone();
+                std::thread::spawn(move || reply_applied(&runtime, applied, &input));
+                Ok(())
+            }
+            Err(not_leader) => self.not_leader(not_leader, input),
+        }
+    }
+
+    /// Sends writes to the head of the chain and reads to its tail, and
+    /// serves them there.
+    fn step_chain(&self, chain: &Chain, input: Message<Payload>) -> anyhow::Result<()> {
+        let me = self.runtime.node_id();
+        let command = match input.body.payload.clone() {
+            Payload::Chain { message } => {
+                chain.receive(&input.src, message);
+                return Ok(());
+            }
+            Payload::Read { .. } | Payload::Scan { .. } => {
+                let tail = chain.tail();
+                if tail != me {
+                    return self.forward_once(&tail, "the tail", input);
+                }
+                return match chain.read(|store| serve_read(store, &input.body.payload)) {
+                    Ok(reply) => self.runtime.reply(&input, reply),
+                    Err((code, text)) => self.runtime.reply_error(&input, code, text),
+                };
+            }
+            payload @ (Payload::Write { .. }
+            | Payload::Cas { .. }
+            | Payload::WriteIfVersion { .. }) => command(payload),
+            Payload::MarkFailed { node } => {
+                chain.mark_failed(&node);
+                return self.runtime.reply(&input, Payload::MarkFailedOk);
+            }
+            Payload::Dump => {
+                let state = chain.read(|store| serde_json::to_value(store.snapshot()));
+                let state = state.unwrap_or_default();
+                return self.runtime.reply(&input, Payload::DumpOk { state });
+            }
+            Payload::ReadOk { .. }
+            | Payload::WriteOk { .. }
+            | Payload::CasOk { .. }
+            | Payload::Replicate { .. }
+            | Payload::Raft { .. }
+            | Payload::Paxos { .. }
+            | Payload::Vr { .. }
+            | Payload::Get { .. }
+            | Payload::GetOk { .. }
+            | Payload::Put { .. }
+            | Payload::PutOk
+            | Payload::Handoff { .. }
+            | Payload::HandoffOk
+            | Payload::Sync { .. }
+            | Payload::SyncOk { .. }
+            | Payload::Reconfigure { .. }
+            | Payload::ReconfigureOk
+            | Payload::MarkFailedOk
+            | Payload::ScanOk { .. }
+            | Payload::DumpOk { .. } => return Ok(()),
+        };
+        let head = chain.head();
+        match head == me {
+            true => chain.propose(command, input),
+            false => self.forward_once(&head, "the head", input),
+        }
+    }
+
+    /// Coordinates clients' requests, each on its own thread as it waits on
+    /// the replicas, and serves the replicas' part for other coordinators.
+    fn step_quorum(&self, quorum: &Quorum, input: Message<Payload>) -> anyhow::Result<()> {
+        let payload = input.body.payload.clone();
+        match payload {
+            Payload::Get { .. } | Payload::Put { .. } | Payload::Handoff { .. } => {
+                if let Some(reply) = quorum.serve(payload) {
+                    self.runtime.reply(&input, reply)?;
+                }
+                return Ok(());
+            }
+            Payload::Sync { sync } => {
+                if let Some(sync) = quorum.sync(&input.src, sync) {
+                    self.runtime.reply(&input, Payload::SyncOk { sync })?;
+                }
+                return Ok(());
+            }
+            Payload::Dump => {
+                let state = quorum.dump();
+                return self.runtime.reply(&input, Payload::DumpOk { state });
+            }
+            Payload::Write { ttl: Some(_), .. } => {
+                let (code, text) = ttl_not_supported();
+                return self.runtime.reply_error(&input, code, text);
+            }
+            Payload::Scan { .. } => {
+                let (code, text) = scan_not_supported();
+                return self.runtime.reply_error(&input, code, text);
+            }
+            Payload::WriteIfVersion { .. } => {
+                let (code, text) = versions_not_supported();
+                return self.runtime.reply_error(&input, code, text);
+            }
+            Payload::Read { .. } | Payload::Write { .. } | Payload::Cas { .. } => {}
+            _ => return Ok(()),
+        }
+        let (runtime, quorum) = (self.runtime.clone(), quorum.clone());
+        std::thread::spawn(move || {
+            let reply = match input.body.payload.clone() {
+                Payload::Read { key, r, .. } => quorum.read(&key, r).map(|value| Payload::ReadOk {
+                    value,
+                    version: None,
+                    siblings: None,
+                    context: None,
+                    key_version: None,
+                }),
+                Payload::Write { key, value, w, .. } => {
+                    quorum.write(&key, value, w).map(|()| Payload::WriteOk {
+                        version: None,
+                        key_version: None,
+                    })
+                }
+                Payload::Cas {
+                    key,
+                    from,
+                    to,
+                    create_if_not_exists,
+                    r,
+                    w,
+                } => quorum
+                    .cas(&key, &from, to, create_if_not_exists, (r, w))
+                    .map(|()| Payload::CasOk {
+                        version: None,
+                        key_version: None,
+                    }),
+                _ => return,
+            };
+            let result = match reply {
+                Ok(reply) => runtime.reply(&input, reply),
+                Err((code, text)) => runtime.reply_error(&input, code, text),
+            };
+            if let Err(err) = result {
+                eprintln!("lin-kv reply failed: {err:#}");
+            }
+        });
+        Ok(())
+    }
+
+    /// Forwards a request a client sent to `to`, which serves it as `role`;
+    /// one another node forwarded is refused instead, as the chain changed
+    /// in between.
+    fn forward_once(&self, to: &str, role: &str, input: Message<Payload>) -> anyhow::Result<()> {
+        if self.runtime.node_ids().contains(&input.src) {
+            let code = error_code::TEMPORARILY_UNAVAILABLE;
+            return self
+                .runtime
+                .reply_error(&input, code, format!("not {role} any more"));
+        }
+        self.runtime.forward(to, input, FORWARD_TIMEOUT);
+        Ok(())
+    }
+
+    /// Forwards a request a follower got to the leader, or refuses it when
+    /// no leader is known.
+    fn not_leader(&self, not_leader: NotLeader, input: Message<Payload>) -> anyhow::Result<()> {
+        match not_leader.leader {
+            // Forward once at most: a leader that lost the lead since the
+            // request came from another node is asked again by the client,
+            // not chased around the cluster.
+            Some(leader) if !self.runtime.node_ids().contains(&input.src) => {
+                self.runtime.forward(&leader, input, FORWARD_TIMEOUT);
+                Ok(())
+            }
+            leader => {
+                let code = error_code::TEMPORARILY_UNAVAILABLE;
+                let text = match leader {
+                    Some(_) => "not the leader any more",
+                    None => "no leader is known",
+                };
+                self.runtime.reply_error(&input, code, text)
+            }
+        }
+    }
+}
+
+/// Answers a read once the copy descends `version`, waiting off the input
+/// thread for replication to catch up when it does not yet.
+fn read_when_fresh(
+    runtime: Runtime,
+    replica: CrdtReplicator<Replica>,
+    version: VectorClock,
+    siblings: bool,
+    input: Message<Payload>,
+) {
+    if is_fresh(&replica, &version) {
+        reply_read(&runtime, &replica, siblings, &input);
+        return;
+    }
+    std::thread::spawn(move || {
+        let started = Instant::now();
+        while !is_fresh(&replica, &version) {
+            if started.elapsed() >= FRESHNESS_TIMEOUT {
+                let text = "this node has not caught up with the version read yet";
+                let code = error_code::TEMPORARILY_UNAVAILABLE;
+                if let Err(err) = runtime.reply_error(&input, code, text) {
+                    eprintln!("lin-kv reply failed: {err:#}");
+                }
+                return;
+            }
+            std::thread::sleep(FRESHNESS_POLL);
+        }
+        reply_read(&runtime, &replica, siblings, &input);
+    });
+}
+
+fn is_fresh(replica: &CrdtReplicator<Replica>, version: &VectorClock) -> bool {
+    replica.read(|copy| copy.clock().descends(version))
+}
+
+fn reply_read(
+    runtime: &Runtime,
+    replica: &CrdtReplicator<Replica>,
+    siblings: bool,
+    input: &Message<Payload>,
+) {
+    let Payload::Read { ref key, .. } = input.body.payload else {
+        return;
+    };
+    let read = replica.read(|copy| {
+        let register = copy.register(key)?;
+        let (siblings, context) = match siblings {
+            true => {
+                let values = register.siblings().iter().map(|s| s.value.clone());
+                (Some(values.collect()), Some(register.context().clone()))
+            }
+            false => (None, None),
+        };
+        Ok(Payload::ReadOk {
+            value: register.winner().cloned().unwrap_or_default(),
+            version: Some(copy.clock().clone()),
+            siblings,
+            context,
+            key_version: None,
+        })
+    });
+    let result = match read {
+        Ok(reply) => runtime.reply(input, reply),
+        Err((code, text)) => runtime.reply_error(input, code, text),
+    };
+    if let Err(err) = result {
+        eprintln!("lin-kv reply failed: {err:#}");
+    }
+}
+
+/// Answers a request proposed to the log once the state machine applied
+/// it.
+fn reply_applied(
+    runtime: &Runtime,
+    applied: Receiver<<KvStore as StateMachine>::Output>,
+    input: &Message<Payload>,
+) {
+    let reply = match applied.recv_timeout(COMMIT_TIMEOUT) {
+        Ok(Ok(key_version)) => Ok(written(&input.body.payload, key_version)),
+        Ok(Err(err)) => Err(err),
+        // Leadership changed and the entry may yet commit under the next
+        // leader, or not: the outcome is unknown.
+        Err(RecvTimeoutError::Timeout) => Err((
+            error_code::TIMEOUT,
+            "not committed in time, and may still be".to_string(),
+        )),
+        Err(RecvTimeoutError::Disconnected) => Err((
+            error_code::TEMPORARILY_UNAVAILABLE,
+            "another leader's entry took its place".to_string(),
+        )),
+    };
+    let result = match reply {
+        Ok(reply) => runtime.reply(input, reply),
+        Err((code, text)) => runtime.reply_error(input, code, text),
+    };
+    if let Err(err) = result {
+        eprintln!("lin-kv reply failed: {err:#}");
+    }
+}
+
+/// Answers a read from the state machine once the leader confirmed it
+/// still leads and the writes committed before it are applied.
+fn reply_read_index<C: Consensus<KvStore>>(
+    runtime: &Runtime,
+    server: &C,
+    ready: Receiver<()>,
+    input: &Message<Payload>,
+) {
+    let read = match ready.recv_timeout(COMMIT_TIMEOUT) {
+        Ok(()) => server.read(|store| serve_read(store, &input.body.payload)),
+        // A read changes nothing, so failing it is always safe.
+        Err(_) => Err((
+            error_code::TEMPORARILY_UNAVAILABLE,
+            "could not confirm this node still leads".to_string(),
+        )),
+    };
+    let result = match read {
+        Ok(reply) => runtime.reply(input, reply),
+        Err((code, text)) => runtime.reply_error(input, code, text),
+    };
+    if let Err(err) = result {
+        eprintln!("lin-kv reply failed: {err:#}");
+    }
+}
+
+/// Answers a `read` or `scan` from a copy of the store.
+fn serve_read(store: &KvStore, payload: &Payload) -> Result<Payload, (usize, String)> {
+    let now = now_ms();
+    match payload {
+        Payload::Scan { from, to, limit } => Ok(Payload::ScanOk {
+            pairs: store.scan(from.as_ref(), to.as_ref(), *limit, now),
+        }),
+        Payload::Read { key, .. } => {
+            store
+                .read(key, now)
+                .map(|(value, key_version)| Payload::ReadOk {
+                    value,
+                    version: None,
+                    siblings: None,
+                    context: None,
+                    key_version: Some(key_version),
+                })
+        }
+        _ => Err((error_code::NOT_SUPPORTED, "not a read".to_string())),
+    }
+}
+
+/// Answers a `reconfigure` once the change it started is in force.
+fn reply_reconfigured(
+    runtime: Runtime,
+    changed: anyhow::Result<Receiver<()>>,
+    input: Message<Payload>,
+) {
+    std::thread::spawn(move || {
+        let result = match changed.map(|changed| changed.recv_timeout(COMMIT_TIMEOUT)) {
+            Ok(Ok(())) => runtime.reply(&input, Payload::ReconfigureOk),
+            Ok(Err(_)) => {
+                let text = "the change did not take effect in time, and may still";
+                runtime.reply_error(&input, error_code::TIMEOUT, text)
+            }
+            Err(err) if err.is::<NotLeader>() => {
+                let code = error_code::TEMPORARILY_UNAVAILABLE;
+                runtime.reply_error(&input, code, err.to_string())
+            }
+            Err(err) => {
+                let code = error_code::PRECONDITION_FAILED;
+                runtime.reply_error(&input, code, format!("{err:#}"))
+            }
+        };
+        if let Err(err) = result {
+            eprintln!("lin-kv reply failed: {err:#}");
+        }
+    });
+}
+
+/// The reply to `request`, a write or cas that brought its key to
+/// `key_version`.
+fn written(request: &Payload, key_version: u64) -> Payload {
+    let key_version = Some(key_version);
+    match request {
+        Payload::Cas { .. } => Payload::CasOk {
+            version: None,
+            key_version,
+        },
+        _ => Payload::WriteOk {
+            version: None,
+            key_version,
+        },
+    }
+}
+
+/// The log command for a `write`, `write_if_version` or `cas`, stamped with
+/// this node's clock.
+fn command(payload: Payload) -> KvCommand {
+    let at = now_ms();
+    match payload {
+        Payload::Write {
+            key, value, ttl, ..
+        } => KvCommand::Write {
+            key,
+            value,
+            expires: ttl.map(|ttl| at + ttl),
+            at,
+        },
+        Payload::WriteIfVersion {
+            key,
+            value,
+            key_version,
+            ttl,
+        } => KvCommand::WriteIfVersion {
+            key,
+            value,
+            version: key_version,
+            expires: ttl.map(|ttl| at + ttl),
+            at,
+        },
+        Payload::Cas {
+            key,
+            from,
+            to,
+            create_if_not_exists,
+            ..
+        } => KvCommand::Cas {
+            key,
+            from,
+            to,
+            create_if_not_exists,
+            at,
+        },
+        // Nothing else is proposed.
+        _ => KvCommand::Sweep { at },
+    }
+}
+
+fn versions_not_supported() -> (usize, String) {
+    let text = "key versions are only kept in primary, consensus and chain mode";
+    (error_code::NOT_SUPPORTED, text.to_string())
+}
+
+fn scan_not_supported() -> (usize, String) {
+    let text = "scan is only supported in primary, consensus and chain mode";
+    (error_code::NOT_SUPPORTED, text.to_string())
+}
+
+fn ttl_not_supported() -> (usize, String) {
+    let text = "ttl is only supported in primary, consensus and chain mode";
+    (error_code::NOT_SUPPORTED, text.to_string())
+}
+
+/// Calls `sweep` with the time every [`SWEEP_INTERVAL`], so expired keys
+/// are evicted even when no write comes to do it.
+fn spawn_sweeper(sweep: impl Fn(u64) + Send + 'static) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(SWEEP_INTERVAL);
+        sweep(now_ms());
+    });
+}
+
+/// Has the leader propose a sweep whenever keys are due to expire, so
+/// every copy evicts them at the same point of the log.
+fn sweep_log<C: Consensus<KvStore>>(server: C) {
+    spawn_sweeper(move |now| {
+        if server.read(|store| store.due(now)) {
+            // Followers refuse it, and their copies follow the leader's.
+            let _ = server.propose(KvCommand::Sweep { at: now });
+        }
+    });
+}
@@ -0,0 +1,448 @@
+use std::{
+    collections::HashMap,
+    sync::{mpsc, Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::bail;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    clock::{Hlc, HlcTimestamp},
+    config::Config,
+    crdt::{CrdtMap, LwwRegister},
+    gossip::{Merge, Replicated},
+    lin_kv::Payload,
+    merkle::SyncStep,
+    message::error_code,
+    ring::Ring,
+    runtime::Runtime,
+};
+
+/// How long a coordinator waits for each replica to answer.
+const REPLICA_TIMEOUT: Duration = Duration::from_millis(500);
+/// How often hints are offered to the replicas they are for.
+const HANDOFF_INTERVAL: Duration = Duration::from_millis(500);
+/// How often a replica reconciles the keys it shares with another.
+const ANTI_ENTROPY_INTERVAL: Duration = Duration::from_millis(1000);
+
+/// A key's value on one replica, with the stamp of the write that set it;
+/// empty until the key is written. Copies merge last-writer-wins.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Versioned(Option<LwwRegister<Value>>);
+
+impl Versioned {
+    pub fn new(value: Value, time: HlcTimestamp, node: &str) -> Self {
+        Self(Some(LwwRegister::new(value, time, node)))
+    }
+
+    pub fn value(&self) -> Option<&Value> {
+        self.0.as_ref().map(LwwRegister::get)
+    }
+
+    pub fn time(&self) -> Option<HlcTimestamp> {
+        self.0.as_ref().map(|register| register.stamp().0)
+    }
+}
+
+impl Merge for Versioned {
+    fn merge(&mut self, other: Self) {
+        match (&mut self.0, other.0) {
+            (Some(ours), Some(theirs)) => ours.merge(theirs),
+            (ours @ None, theirs) => *ours = theirs,
+            (Some(_), None) => {}
+        }
+    }
+}
+
+/// Writes replicas missed, by replica, held by the coordinator until it
+/// can hand them off. Only the newest write per key is kept.
+#[derive(Clone, Default)]
+struct Hints(Arc<Mutex<HashMap<String, CrdtMap<Versioned>>>>);
+
+impl Hints {
+    fn hold(&self, replica: &str, key: &Value, versioned: Versioned) {
+        let mut hints = self.0.lock().unwrap();
+        let held = hints.entry(replica.to_string()).or_default();
+        held.entry(&key.to_string()).merge(versioned);
+    }
+
+    fn held(&self) -> Vec<(String, CrdtMap<Versioned>)> {
+        let hints = self.0.lock().unwrap();
+        hints
+            .iter()
+            .map(|(replica, held)| (replica.clone(), held.clone()))
+            .collect()
+    }
+
+    /// Forgets the hints `replica` stored, unless a newer write for the
+    /// same key was missed since.
+    fn handed_off(&self, replica: &str, sent: &CrdtMap<Versioned>) {
+        let mut hints = self.0.lock().unwrap();
+        let Some(held) = hints.get_mut(replica) else {
+            return;
+        };
+        *held = held.select(|key| held.get(key) != sent.get(key));
+        if held.iter().next().is_none() {
+            hints.remove(replica);
+        }
+    }
+}
+
+/// A node in `quorum` mode: a replica of some keys, and the coordinator of
+/// the requests clients send it.
+///
+/// Every key lives on `n` replicas, its owner and the next nodes on a
+/// consistent hash ring (see [`crate::ring`]). Whichever node a client asks coordinates
+/// the request: a write is stamped with the coordinator's hybrid logical
+/// clock and sent to every replica, and answered once `w` of them stored
+/// it; a read asks every replica and answers with the newest version among
+/// the first `r` to reply. With `r + w > n` every read quorum overlaps every
+/// write quorum, so a read sees the last acknowledged write. A request may
+/// pick its own `r` and `w`, trading consistency for latency.
+///
+/// A cas reads at `r`, compares, and writes at `w`; it is not atomic, so
+/// two concurrent cas on the same value may both succeed, the later stamp
+/// winning on every replica.
+///
+/// A read that finds replicas disagreeing repairs them once it answered:
+/// those that had an older version get the newest one.
+///
+/// A replica that does not acknowledge a write in time gets it later by
+/// hinted handoff: the coordinator keeps the write as a hint and offers its
+/// hints to the replica every [`HANDOFF_INTERVAL`] until it takes them, so
+/// a partition leaves no replica behind once it heals. Hints do not count
+/// towards `w`, and live only in the coordinator's memory.
+///
+/// Keys nobody reads or writes again converge by anti-entropy: every
+/// [`ANTI_ENTROPY_INTERVAL`] each replica picks another that shares some of
+/// its keys, and the two compare Merkle trees over just those keys (see
+/// [`crate::merkle`]) and swap the entries that differ.
+#[derive(Clone)]
+pub struct Quorum {
+    runtime: Runtime,
+    ring: Arc<Ring>,
+    n: usize,
+    r: usize,
+    w: usize,
+    /// Stamps writes coordinated here, never behind a stamp seen.
+    clock: Arc<Hlc>,
+    store: Replicated<CrdtMap<Versioned>>,
+    hints: Hints,
+}
+
+impl Quorum {
+    /// Reads `--kv-n`, `--kv-r` and `--kv-w`; `r` and `w` default to a
+    /// majority of `n`.
+    pub fn mount(runtime: Runtime, config: &Config) -> anyhow::Result<Self> {
+        let n = config
+            .parse("kv-n")?
+            .unwrap_or(3)
+            .min(runtime.node_ids().len());
+        let r = config.parse("kv-r")?.unwrap_or(n / 2 + 1);
+        let w = config.parse("kv-w")?.unwrap_or(n / 2 + 1);
+        if !(1..=n).contains(&r) || !(1..=n).contains(&w) {
+            bail!("kv-r and kv-w must be between 1 and {n}, got {r} and {w}");
+        }
+        let quorum = Self {
+            ring: Arc::new(Ring::new(runtime.node_ids())?),
+            runtime,
+            n,
+            r,
+            w,
+            clock: Arc::new(Hlc::new()),
+            store: Replicated::new(CrdtMap::new()),
+            hints: Hints::default(),
+        };
+        let handoff = quorum.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(HANDOFF_INTERVAL);
+            handoff.hand_off();
+        });
+        let anti_entropy = quorum.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(ANTI_ENTROPY_INTERVAL);
+            anti_entropy.reconcile();
+        });
+        Ok(quorum)
+    }
+
+    /// The replicas of `key`.
+    pub fn replicas(&self, key: &Value) -> Vec<String> {
+        self.ring.replicas(&key.to_string(), self.n)
+    }
+
+    /// Answers a step of the anti-entropy exchange `from` started, over the
+    /// keys both hold.
+    pub fn sync(&self, from: &str, step: SyncStep<Versioned>) -> Option<SyncStep<Versioned>> {
+        if let SyncStep::Exchange { entries, .. } = &step {
+            if let Some(time) = entries.iter().filter_map(|(_, v)| v.time()).max() {
+                self.clock.observe(time);
+            }
+        }
+        self.store.sync_within(step, &|key| self.shares(from, key))
+    }
+
+    /// This replica's copy, as a snapshot.
+    pub fn dump(&self) -> Value {
+        self.store
+            .read(|store| serde_json::to_value(store))
+            .unwrap_or_default()
+    }
+
+    /// Answers a coordinator's `get`, `put` or `handoff` from this replica's
+    /// copy.
+    pub fn serve(&self, request: Payload) -> Option<Payload> {
+        match request {
+            Payload::Get { key } => {
+                let versioned = self
+                    .store
+                    .read(|store| store.get(&key.to_string()).cloned());
+                Some(Payload::GetOk {
+                    versioned: versioned.unwrap_or_default(),
+                })
+            }
+            Payload::Put { key, versioned } => {
+                if let Some(time) = versioned.time() {
+                    self.clock.observe(time);
+                }
+                self.store
+                    .update(|store| store.entry(&key.to_string()).merge(versioned));
+                Some(Payload::PutOk)
+            }
+            Payload::Handoff { hints } => {
+                if let Some(time) = hints.iter().filter_map(|(_, v)| v.time()).max() {
+                    self.clock.observe(time);
+                }
+                self.store.merge(hints);
+                Some(Payload::HandoffOk)
+            }
+            _ => None,
+        }
+    }
+
+    /// The newest value of `key` among `r` replicas. Blocks.
+    pub fn read(&self, key: &Value, r: Option<usize>) -> Result<Value, (usize, String)> {
+        let newest = self.read_versioned(key, r)?;
+        newest.value().cloned().ok_or_else(|| {
+            (
+                error_code::KEY_DOES_NOT_EXIST,
+                format!("key {key} does not exist"),
+            )
+        })
+    }
+
+    /// Writes `value` under `key` on `w` replicas. Blocks.
+    pub fn write(
+        &self,
+        key: &Value,
+        value: Value,
+        w: Option<usize>,
+    ) -> Result<(), (usize, String)> {
+        let w = self.size(w, self.w)?;
+        let versioned = Versioned::new(value, self.clock.tick(), self.runtime.node_id());
+        self.put(key, versioned, w)
+    }
+
+    /// Writes `to` under `key` if a read at `r` finds `from` there. Blocks.
+    pub fn cas(
+        &self,
+        key: &Value,
+        from: &Value,
+        to: Value,
+        create_if_not_exists: bool,
+        (r, w): (Option<usize>, Option<usize>),
+    ) -> Result<(), (usize, String)> {
+        let w = self.size(w, self.w)?;
+        let newest = self.read_versioned(key, r)?;
+        match newest.value() {
+            Some(current) if current == from => {}
+            Some(current) => {
+                return Err((
+                    error_code::PRECONDITION_FAILED,
+                    format!("expected {from}, but had {current}"),
+                ))
+            }
+            None if create_if_not_exists => {}
+            None => {
+                return Err((
+                    error_code::KEY_DOES_NOT_EXIST,
+                    format!("key {key} does not exist"),
+                ))
+            }
+        }
+        // Stamped after the value it replaces, wherever that was written.
+        if let Some(time) = newest.time() {
+            self.clock.observe(time);
+        }
+        let versioned = Versioned::new(to, self.clock.tick(), self.runtime.node_id());
+        self.put(key, versioned, w)
+    }
+
+    /// The newest version of `key` among the first `r` replicas to answer
+    /// with their copy.
+    fn read_versioned(&self, key: &Value, r: Option<usize>) -> Result<Versioned, (usize, String)> {
+        let r = self.size(r, self.r)?;
+        let answers = self.ask(key, Payload::Get { key: key.clone() });
+        // A reply that is not a copy does not count towards `r`.
+        let seen: Vec<_> = answers.iter().filter_map(versioned_of).take(r).collect();
+        if seen.len() < r {
+            return Err((
+                error_code::TEMPORARILY_UNAVAILABLE,
+                format!("only {} of {r} replicas answered", seen.len()),
+            ));
+        }
+        let newest = newest_of(&seen);
+        self.read_repair(key.clone(), seen, answers);
+        Ok(newest)
+    }
+
+    /// In the background, once the other replicas answered or timed out,
+    /// puts the newest version any of them had on those that had an older
+    /// one.
+    fn read_repair(
+        &self,
+        key: Value,
+        mut seen: Vec<(String, Versioned)>,
+        rest: mpsc::Receiver<(String, Payload)>,
+    ) {
+        let quorum = self.clone();
+        std::thread::spawn(move || {
+            seen.extend(rest.iter().filter_map(versioned_of));
+            let newest = newest_of(&seen);
+            for (replica, versioned) in seen {
+                if versioned == newest {
+                    continue;
+                }
+                let put = Payload::Put {
+                    key: key.clone(),
+                    versioned: newest.clone(),
+                };
+                if replica == quorum.runtime.node_id() {
+                    quorum.serve(put);
+                } else if let Err(err) = quorum.runtime.send(&replica, put) {
+                    eprintln!("read repair of {replica} failed: {err:#}");
+                }
+            }
+        });
+    }
+
+    fn put(&self, key: &Value, versioned: Versioned, w: usize) -> Result<(), (usize, String)> {
+        let put = Payload::Put {
+            key: key.clone(),
+            versioned,
+        };
+        let acks = self
+            .ask(key, put)
+            .iter()
+            .filter(|(_, reply)| matches!(reply, Payload::PutOk))
+            .take(w)
+            .count();
+        match acks < w {
+            // Stored on some replicas, it may yet be read.
+            true => Err((
+                error_code::TIMEOUT,
+                format!("only {acks} of {w} replicas acknowledged, and more may still"),
+            )),
+            false => Ok(()),
+        }
+    }
+
+    /// `asked` if given, else `default`, as long as it is a quorum of the
+    /// `n` replicas.
+    fn size(&self, asked: Option<usize>, default: usize) -> Result<usize, (usize, String)> {
+        let size = asked.unwrap_or(default);
+        match (1..=self.n).contains(&size) {
+            true => Ok(size),
+            false => Err((
+                error_code::MALFORMED_REQUEST,
+                format!("a quorum must be between 1 and {}, got {size}", self.n),
+            )),
+        }
+    }
+
+    /// Sends `request` to every replica of `key`. Their answers come in as
+    /// they arrive, and the channel closes once the others timed out.
+    fn ask(&self, key: &Value, request: Payload) -> mpsc::Receiver<(String, Payload)> {
+        let (tx, rx) = mpsc::channel();
+        for replica in self.replicas(key) {
+            if replica == self.runtime.node_id() {
+                if let Some(reply) = self.serve(request.clone()) {
+                    let _ = tx.send((replica, reply));
+                }
+                continue;
+            }
+            let (runtime, request, tx) = (self.runtime.clone(), request.clone(), tx.clone());
+            let hints = self.hints.clone();
+            std::thread::spawn(move || {
+                match runtime.rpc::<_, Payload>(&replica, request.clone(), REPLICA_TIMEOUT) {
+                    // The coordinator may have moved on with enough answers.
+                    Ok(reply) => {
+                        let _ = tx.send((replica, reply));
+                    }
+                    Err(_) => {
+                        if let Payload::Put { key, versioned } = request {
+                            hints.hold(&replica, &key, versioned);
+                        }
+                    }
+                }
+            });
+        }
+        rx
+    }
+
+    /// Whether `key` lives on both this node and `peer`.
+    fn shares(&self, peer: &str, key: &str) -> bool {
+        let replicas = self.ring.replicas(key, self.n);
+        let me = self.runtime.node_id();
+        replicas.iter().any(|node| node == me) && replicas.iter().any(|node| node == peer)
+    }
+
+    /// Reconciles with a random replica among those that share keys with
+    /// this one.
+    fn reconcile(&self) {
+        let neighbors = self.ring.neighbors(self.runtime.node_id(), self.n);
+        let Some(peer) = neighbors.choose(&mut rand::thread_rng()) else {
+            return;
+        };
+        let within = |key: &str| self.shares(peer, key);
+        let wrap = |sync| Payload::Sync { sync };
+        if let Err(err) = self.store.reconcile(&self.runtime, peer, &within, &wrap) {
+            eprintln!("anti-entropy with {peer} failed: {err:#}");
+        }
+    }
+
+    /// Offers every replica the writes it missed.
+    fn hand_off(&self) {
+        for (replica, hints) in self.hints.held() {
+            let handoff = Payload::Handoff {
+                hints: hints.clone(),
+            };
+            // Still unreachable, it is offered them again next round.
+            let sent = self
+                .runtime
+                .rpc::<_, Payload>(&replica, handoff, REPLICA_TIMEOUT);
+            if sent.is_ok() {
+                self.hints.handed_off(&replica, &hints);
+            }
+        }
+    }
+}
+
+fn versioned_of((replica, reply): (String, Payload)) -> Option<(String, Versioned)> {
+    match reply {
+        Payload::GetOk { versioned } => Some((replica, versioned)),
+        _ => None,
+    }
+}
+
+fn newest_of(seen: &[(String, Versioned)]) -> Versioned {
+    let mut newest = Versioned::default();
+    for (_, versioned) in seen {
+        newest.merge(versioned.clone());
+    }
+    newest
+}
@@ -0,0 +1,148 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    clock::{HlcTimestamp, VectorClock},
+    crdt::{CrdtMap, DvvSet, Snapshot},
+    gossip::{DeltaCrdt, Merge},
+    message::error_code,
+};
+
+/// One node's copy of the key/value map in `replicated` mode: every node
+/// takes writes and the copies converge.
+///
+/// Each key is a [`DvvSet`], so writes made concurrently on different nodes
+/// are kept as siblings rather than one silently overwriting the other.
+/// Readers that do not deal with siblings see the one written last.
+///
+/// `clock` counts the writes each node made that this copy has merged. A
+/// copy whose clock descends a version has every write that version saw, so
+/// clients pass the versions they got back to read at least that fresh.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct Replica {
+    // Keys are arbitrary JSON, so they are indexed by their serialization.
+    values: CrdtMap<DvvSet<Value>>,
+    clock: VectorClock,
+}
+
+impl Replica {
+    pub fn clock(&self) -> &VectorClock {
+        &self.clock
+    }
+
+    /// The latest time a value here was written at.
+    pub fn latest(&self) -> Option<HlcTimestamp> {
+        self.values
+            .iter()
+            .filter_map(|(_, register)| register.latest())
+            .max()
+    }
+
+    /// The register under `key`, with all its siblings.
+    pub fn register(&self, key: &Value) -> Result<&DvvSet<Value>, (usize, String)> {
+        self.values
+            .get(&key.to_string())
+            .filter(|register| !register.is_empty())
+            .ok_or_else(|| {
+                (
+                    error_code::KEY_DOES_NOT_EXIST,
+                    format!("key {key} does not exist"),
+                )
+            })
+    }
+
+    /// The value written last under `key`.
+    pub fn read(&self, key: &Value) -> Result<Value, (usize, String)> {
+        let register = self.register(key)?;
+        Ok(register.winner().cloned().unwrap_or_default())
+    }
+
+    /// Writes as `node` at `time` for a client that had read `context`,
+    /// keeping the siblings it did not see. Without a context the write
+    /// replaces every value this copy has.
+    pub fn write(
+        &mut self,
+        key: &Value,
+        value: Value,
+        context: Option<&VectorClock>,
+        time: HlcTimestamp,
+        node: &str,
+    ) {
+        let register = self.values.entry(&key.to_string());
+        let context = context.unwrap_or(register.context()).clone();
+        register.write(node, value, time, &context);
+        self.clock.increment(node);
+    }
+
+    /// Compares against this copy only: concurrent cas on other nodes may
+    /// both succeed, and the later one wins.
+    pub fn cas(
+        &mut self,
+        key: &Value,
+        from: &Value,
+        to: Value,
+        create_if_not_exists: bool,
+        time: HlcTimestamp,
+        node: &str,
+    ) -> Result<(), (usize, String)> {
+        match self.read(key).ok() {
+            Some(current) if current == *from => {}
+            Some(current) => {
+                return Err((
+                    error_code::PRECONDITION_FAILED,
+                    format!("expected {from}, but had {current}"),
+                ))
+            }
+            None if create_if_not_exists => {}
+            None => {
+                return Err((
+                    error_code::KEY_DOES_NOT_EXIST,
+                    format!("key {key} does not exist"),
+                ))
+            }
+        }
+        self.write(key, to, None, time, node);
+        Ok(())
+    }
+}
+
+impl Merge for Replica {
+    fn merge(&mut self, other: Self) {
+        self.values.merge(other.values);
+        self.clock.merge(other.clock);
+    }
+}
+
+impl DeltaCrdt for Replica {
+    type Version = (BTreeMap<String, u64>, VectorClock);
+
+    fn version(&self) -> Self::Version {
+        (self.values.version(), self.clock.clone())
+    }
+
+    /// The registers that changed, with the whole clock: it is small, and a
+    /// copy merging the delta has every write the clock counts.
+    fn split_delta(&self, (values, _): &Self::Version) -> Self {
+        Self {
+            values: self.values.split_delta(values),
+            clock: self.clock.clone(),
+        }
+    }
+}
+
+impl Snapshot for Replica {
+    type Snapshot = (<CrdtMap<DvvSet<Value>> as Snapshot>::Snapshot, VectorClock);
+
+    fn snapshot(&self) -> Self::Snapshot {
+        (self.values.snapshot(), self.clock.clone())
+    }
+
+    fn restore((values, clock): Self::Snapshot) -> Self {
+        Self {
+            values: CrdtMap::restore(values),
+            clock,
+        }
+    }
+}
@@ -0,0 +1,380 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ops::Bound,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{consensus::StateMachine, crdt::Snapshot, message::error_code};
+
+/// A JSON key, ordered by value: `null`, then booleans, numbers, strings,
+/// arrays and objects, each among themselves in their natural order, so
+/// integer keys scan in numeric order.
+#[derive(Clone, Debug)]
+pub struct Key(pub Value);
+
+fn rank(value: &Value) -> u8 {
+    match value {
+        Value::Null => 0,
+        Value::Bool(_) => 1,
+        Value::Number(_) => 2,
+        Value::String(_) => 3,
+        Value::Array(_) => 4,
+        Value::Object(_) => 5,
+    }
+}
+
+fn compare(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        (Value::Number(x), Value::Number(y)) => match (x.as_i64(), y.as_i64()) {
+            (Some(x), Some(y)) => x.cmp(&y),
+            _ => match (x.as_u64(), y.as_u64()) {
+                (Some(x), Some(y)) => x.cmp(&y),
+                _ => {
+                    let (x, y) = (
+                        x.as_f64().unwrap_or(f64::NAN),
+                        y.as_f64().unwrap_or(f64::NAN),
+                    );
+                    // `1` and `1.0` are still different keys.
+                    x.total_cmp(&y)
+                        .then_with(|| a.to_string().cmp(&b.to_string()))
+                }
+            },
+        },
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        (Value::Array(a), Value::Array(b)) => {
+            let first = a
+                .iter()
+                .zip(b)
+                .map(|(a, b)| compare(a, b))
+                .find(|order| order.is_ne());
+            first.unwrap_or_else(|| a.len().cmp(&b.len()))
+        }
+        (Value::Object(_), Value::Object(_)) => a.to_string().cmp(&b.to_string()),
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+impl Ord for Key {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare(&self.0, &other.0)
+    }
+}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for Key {}
+
+/// A key/value map with Maelstrom's `read`/`write`/`cas` semantics, plus
+/// range scans in key order. Errors are the Maelstrom error code and text
+/// to reply with.
+///
+/// A key written with an expiry, in milliseconds since the Unix epoch, reads
+/// as missing from then on. It is evicted by the first [`sweep`](Self::sweep)
+/// at or after that time; a replicated copy sweeps at the time each command
+/// carries, so every copy evicts the same keys at the same point of its log.
+///
+/// Every key also has a version, which each write or cas of it bumps; it is
+/// 1 once the key is created, and a missing key's is 0. A key written back
+/// after it expired starts over from 1.
+#[derive(Default, Debug)]
+pub struct KvStore {
+    values: BTreeMap<Key, Value>,
+    versions: BTreeMap<Key, u64>,
+    expires: BTreeMap<Key, u64>,
+    /// `expires`, by time.
+    deadlines: BTreeSet<(u64, Key)>,
+}
+
+impl KvStore {
+    /// Reads `key` and its version as of `now`.
+    pub fn read(&self, key: &Value, now: u64) -> Result<(Value, u64), (usize, String)> {
+        let key = Key(key.clone());
+        match self.values.get(&key) {
+            Some(value) if !self.expired(&key, now) => Ok((value.clone(), self.version(&key))),
+            _ => Err((
+                error_code::KEY_DOES_NOT_EXIST,
+                format!("key {} does not exist", key.0),
+            )),
+        }
+    }
+
+    /// The pairs from key `from` on, up to but not including `to`, in key
+    /// order, as of `now`; at most `limit` of them if given.
+    pub fn scan(
+        &self,
+        from: Option<&Value>,
+        to: Option<&Value>,
+        limit: Option<usize>,
+        now: u64,
+    ) -> Vec<(Value, Value)> {
+        let bound = |key: Option<&Value>, bound: fn(Key) -> Bound<Key>| match key {
+            Some(key) => bound(Key(key.clone())),
+            None => Bound::Unbounded,
+        };
+        let range = (bound(from, Bound::Included), bound(to, Bound::Excluded));
+        if let (Bound::Included(from), Bound::Excluded(to)) = &range {
+            if from > to {
+                // BTreeMap::range panics on those.
+                return Vec::new();
+            }
+        }
+        self.values
+            .range(range)
+            .filter(|(key, _)| !self.expired(key, now))
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|(key, value)| (key.0.clone(), value.clone()))
+            .collect()
+    }
+
+    fn expired(&self, key: &Key, now: u64) -> bool {
+        self.expires.get(key).is_some_and(|&at| at <= now)
+    }
+
+    fn version(&self, key: &Key) -> u64 {
+        self.versions.get(key).copied().unwrap_or_default()
+    }
+
+    /// Bumps `key`'s version, returning the new one.
+    fn bump(&mut self, key: &Key) -> u64 {
+        let version = self.versions.entry(key.clone()).or_default();
+        *version += 1;
+        *version
+    }
+
+    /// Writes `key`, to expire at `expires` if given, and never otherwise.
+    /// Returns its new version.
+    pub fn write(&mut self, key: &Value, value: Value, expires: Option<u64>) -> u64 {
+        let key = Key(key.clone());
+        if let Some(at) = self.expires.remove(&key) {
+            self.deadlines.remove(&(at, key.clone()));
+        }
+        if let Some(at) = expires {
+            self.expires.insert(key.clone(), at);
+            self.deadlines.insert((at, key.clone()));
+        }
+        self.values.insert(key.clone(), value);
+        self.bump(&key)
+    }
+
+    /// Writes `key` if it is still at `version`, 0 meaning it must not
+    /// exist. Returns its new version.
+    pub fn write_if_version(
+        &mut self,
+        key: &Value,
+        value: Value,
+        version: u64,
+        expires: Option<u64>,
+    ) -> Result<u64, (usize, String)> {
+        let current = self.version(&Key(key.clone()));
+        if current != version {
+            return Err((
+                error_code::PRECONDITION_FAILED,
+                format!("expected version {version} of {key}, but had {current}"),
+            ));
+        }
+        Ok(self.write(key, value, expires))
+    }
+
+    /// Swaps `key`'s value; the key keeps its expiry. Returns its new
+    /// version.
+    pub fn cas(
+        &mut self,
+        key: &Value,
+        from: &Value,
+        to: Value,
+        create_if_not_exists: bool,
+    ) -> Result<u64, (usize, String)> {
+        let key_at = Key(key.clone());
+        match self.values.get_mut(&key_at) {
+            Some(current) if current == from => {
+                *current = to;
+                Ok(self.bump(&key_at))
+            }
+            Some(current) => Err((
+                error_code::PRECONDITION_FAILED,
+                format!("expected {from}, but had {current}"),
+            )),
+            None if create_if_not_exists => Ok(self.write(key, to, None)),
+            None => Err((
+                error_code::KEY_DOES_NOT_EXIST,
+                format!("key {key} does not exist"),
+            )),
+        }
+    }
+
+    /// Whether a key expired by `now` is still held.
+    pub fn due(&self, now: u64) -> bool {
+        self.deadlines.first().is_some_and(|&(at, _)| at <= now)
+    }
+
+    /// Evicts the keys expired by `now`, returning how many.
+    pub fn sweep(&mut self, now: u64) -> usize {
+        let mut evicted = 0;
+        while self.due(now) {
+            let (_, key) = self.deadlines.pop_first().unwrap();
+            self.expires.remove(&key);
+            self.versions.remove(&key);
+            self.values.remove(&key);
+            evicted += 1;
+        }
+        evicted
+    }
+}
+
+/// A `lin-kv` write, as a command in a replicated log. Reads do not need
+/// the log: see [`Consensus::read_index`](crate::consensus::Consensus::read_index).
+///
+/// `at` is the proposer's clock, in milliseconds since the Unix epoch: each
+/// command first sweeps the keys expired by then.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum KvCommand {
+    Write {
+        key: Value,
+        value: Value,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires: Option<u64>,
+        #[serde(default)]
+        at: u64,
+    },
+    WriteIfVersion {
+        key: Value,
+        value: Value,
+        version: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires: Option<u64>,
+        at: u64,
+    },
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        create_if_not_exists: bool,
+        #[serde(default)]
+        at: u64,
+    },
+    /// Only sweeps, for stores nobody writes to.
+    Sweep { at: u64 },
+}
+
+impl StateMachine for KvStore {
+    type Command = KvCommand;
+    /// The version the key was brought to, 0 for a sweep, or the error to
+    /// reply with.
+    type Output = Result<u64, (usize, String)>;
+
+    fn apply(&mut self, command: KvCommand) -> Self::Output {
+        match command {
+            KvCommand::Write {
+                key,
+                value,
+                expires,
+                at,
+            } => {
+                self.sweep(at);
+                Ok(self.write(&key, value, expires))
+            }
+            KvCommand::WriteIfVersion {
+                key,
+                value,
+                version,
+                expires,
+                at,
+            } => {
+                self.sweep(at);
+                self.write_if_version(&key, value, version, expires)
+            }
+            KvCommand::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+                at,
+            } => {
+                self.sweep(at);
+                self.cas(&key, &from, to, create_if_not_exists)
+            }
+            KvCommand::Sweep { at } => {
+                self.sweep(at);
+                Ok(0)
+            }
+        }
+    }
+}
+
+/// A [`KvStore`] as JSON, whose object keys are strings: each key appears
+/// serialized.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct KvSnapshot {
+    pub values: HashMap<String, Value>,
+    #[serde(default)]
+    pub versions: HashMap<String, u64>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub expires: HashMap<String, u64>,
+}
+
+/// A key back from its serialization in a [`KvSnapshot`].
+fn parse(key: String) -> Key {
+    Key(serde_json::from_str(&key).unwrap_or(Value::String(key)))
+}
+
+impl Snapshot for KvStore {
+    type Snapshot = KvSnapshot;
+
+    fn snapshot(&self) -> Self::Snapshot {
+        let serialized = |key: &Key| key.0.to_string();
+        KvSnapshot {
+            values: self
+                .values
+                .iter()
+                .map(|(key, value)| (serialized(key), value.clone()))
+                .collect(),
+            versions: self
+                .versions
+                .iter()
+                .map(|(key, &version)| (serialized(key), version))
+                .collect(),
+            expires: self
+                .expires
+                .iter()
+                .map(|(key, &at)| (serialized(key), at))
+                .collect(),
+        }
+    }
+
+    fn restore(snapshot: Self::Snapshot) -> Self {
+        let expires: BTreeMap<Key, u64> = snapshot
+            .expires
+            .into_iter()
+            .map(|(key, at)| (parse(key), at))
+            .collect();
+        Self {
+            values: snapshot
+                .values
+                .into_iter()
+                .map(|(key, value)| (parse(key), value))
+                .collect(),
+            versions: snapshot
+                .versions
+                .into_iter()
+                .map(|(key, version)| (parse(key), version))
+                .collect(),
+            deadlines: expires.iter().map(|(key, &at)| (at, key.clone())).collect(),
+            expires,
+        }
+    }
+}
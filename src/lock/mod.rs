@@ -0,0 +1,219 @@
+//! Lock service: `lock {key}` grants `key` to the requesting client, or
+//! fails with `temporarily-unavailable` while someone else holds it, and
+//! `unlock {key, token}` releases it.
+//!
+//! Lock state lives in `lin-kv` under `lock/<key>` and only changes through
+//! cas, so every node can serve every key. Each grant bumps the key's token,
+//! which the holder must present to unlock. Grants are leases: once
+//! `--lock-lease` (default 2000ms) has passed, the lock can be taken over,
+//! so a crashed client does not hold it forever.
+//!
+//! The token is also a fencing token. Each lock guards a value, kept in
+//! `lock/<key>` with the lock itself: `write {key, value, token}` goes
+//! through only while `token` is the current grant's, checked and written
+//! by the same cas, so a client that paused past its lease cannot overwrite
+//! what the next holder wrote. `read {key}` returns the value.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    clock::now_ms,
+    message::{error_code, Init, Message},
+    runtime::{Node, RpcError, Runtime},
+    services::Kv,
+};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Lock {
+        key: String,
+    },
+    LockOk {
+        token: u64,
+    },
+    Unlock {
+        key: String,
+        token: u64,
+    },
+    UnlockOk,
+    /// Writes the value `key` guards, fenced by the grant's `token`.
+    Write {
+        key: String,
+        value: Value,
+        token: u64,
+    },
+    WriteOk,
+    Read {
+        key: String,
+    },
+    ReadOk {
+        value: Value,
+    },
+}
+
+/// What `lin-kv` holds for one lock.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+struct LockState {
+    holder: Option<String>,
+    /// Incremented by every grant; never reset.
+    token: u64,
+    /// Wall-clock millis after which the grant lapses.
+    expires_ms: u64,
+    /// The value the lock guards, once written.
+    value: Option<Value>,
+}
+
+pub struct LockNode {
+    runtime: Runtime,
+    kv: Kv,
+    lease: Duration,
+}
+
+impl Node<Payload> for LockNode {
+    fn from_init(runtime: Runtime, _init: Init) -> anyhow::Result<Self> {
+        let config = runtime.config().clone();
+        Ok(Self {
+            kv: Kv::new(runtime.clone(), "lin-kv"),
+            runtime,
+            lease: config
+                .millis("lock-lease")?
+                .unwrap_or(Duration::from_millis(2000)),
+        })
+    }
+
+    fn step(&mut self, input: Message<Payload>) -> anyhow::Result<()> {
+        let runtime = self.runtime.clone();
+        let kv = self.kv.clone();
+        let lease = self.lease;
+        std::thread::spawn(move || {
+            let client = input.src.as_str();
+            let result = match input.body.payload {
+                Payload::Lock { ref key } => {
+                    lock(&kv, key, client, lease).map(|token| Some(Payload::LockOk { token }))
+                }
+                Payload::Unlock { ref key, token } => {
+                    unlock(&kv, key, client, token).map(|()| Some(Payload::UnlockOk))
+                }
+                Payload::Write {
+                    ref key,
+                    ref value,
+                    token,
+                } => write(&kv, key, value, token).map(|()| Some(Payload::WriteOk)),
+                Payload::Read { ref key } => {
+                    read(&kv, key).map(|value| Some(Payload::ReadOk { value }))
+                }
+                Payload::LockOk { .. }
+                | Payload::UnlockOk
+                | Payload::WriteOk
+                | Payload::ReadOk { .. } => Ok(None),
+            };
+            let result = match result {
+                Ok(Some(reply)) => runtime.reply(&input, reply),
+                Ok(None) => Ok(()),
+                Err(LockError::Refused { code, text }) => runtime.reply_error(&input, code, text),
+                Err(LockError::Kv(err)) => {
+                    runtime.reply_error(&input, error_code::TIMEOUT, err.to_string())
+                }
+            };
+            if let Err(err) = result {
+                eprintln!("lock reply failed: {err:#}");
+            }
+        });
+        Ok(())
+    }
+}
+
+enum LockError {
+    /// The request itself cannot succeed right now.
+    Refused {
+        code: usize,
+        text: String,
+    },
+    Kv(RpcError),
+}
+
+impl From<RpcError> for LockError {
+    fn from(err: RpcError) -> Self {
+        LockError::Kv(err)
+    }
+}
+
+/// The lock under `key`, never granted if `lin-kv` does not have it.
+fn read_state(kv: &Kv, key: &str) -> Result<LockState, RpcError> {
+    match kv.read(key) {
+        Ok(state) => Ok(state),
+        Err(RpcError::Remote { code, .. }) if code == error_code::KEY_DOES_NOT_EXIST => {
+            Ok(LockState::default())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+fn lock(kv: &Kv, key: &str, client: &str, lease: Duration) -> Result<u64, LockError> {
+    let granted = kv.try_update(format!("lock/{key}"), |current: &LockState| {
+        let now = now_ms();
+        if let Some(holder) = &current.holder {
+            if current.expires_ms > now {
+                return Err(LockError::Refused {
+                    code: error_code::TEMPORARILY_UNAVAILABLE,
+                    text: format!("{key} is held by {holder}"),
+                });
+            }
+        }
+        Ok(LockState {
+            holder: Some(client.to_string()),
+            token: current.token + 1,
+            expires_ms: now + lease.as_millis() as u64,
+            value: current.value.clone(),
+        })
+    })?;
+    Ok(granted.token)
+}
+
+fn unlock(kv: &Kv, key: &str, client: &str, token: u64) -> Result<(), LockError> {
+    kv.try_update(format!("lock/{key}"), |current: &LockState| {
+        if current.holder.as_deref() != Some(client) || current.token != token {
+            return Err(LockError::Refused {
+                code: error_code::PRECONDITION_FAILED,
+                text: format!("{client} does not hold {key} with token {token}"),
+            });
+        }
+        Ok(LockState {
+            holder: None,
+            token: current.token,
+            expires_ms: 0,
+            value: current.value.clone(),
+        })
+    })?;
+    Ok(())
+}
+
+/// Writes the value `key` guards, unless `token` is not the current grant's.
+fn write(kv: &Kv, key: &str, value: &Value, token: u64) -> Result<(), LockError> {
+    kv.try_update(format!("lock/{key}"), |current: &LockState| {
+        if current.holder.is_none() || current.token != token {
+            return Err(LockError::Refused {
+                code: error_code::PRECONDITION_FAILED,
+                text: format!("token {token} for {key} is not the current grant"),
+            });
+        }
+        Ok(LockState {
+            value: Some(value.clone()),
+            ..current.clone()
+        })
+    })?;
+    Ok(())
+}
+
+fn read(kv: &Kv, key: &str) -> Result<Value, LockError> {
+    let lock = read_state(kv, &format!("lock/{key}"))?;
+    lock.value.ok_or_else(|| LockError::Refused {
+        code: error_code::KEY_DOES_NOT_EXIST,
+        text: format!("nothing was written under {key}"),
+    })
+}
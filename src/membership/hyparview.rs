@@ -0,0 +1,325 @@
+//! HyParView partial membership.
+//!
+//! Each node keeps a small symmetric *active* view it gossips with and a
+//! larger *passive* view of backups. Nodes join through a contact node whose
+//! `forward_join` random walks spread the newcomer, periodic shuffles keep
+//! the passive views fresh, and an active peer that leaves several frames
+//! in a row unacknowledged is replaced by a passive one, so the overlay
+//! stays connected with bounded fan-out.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::StdRng, seq::IteratorRandom, SeedableRng};
+
+use crate::broadcast::node::Payload;
+
+/// Active random walk length for `forward_join`.
+const ACTIVE_WALK: usize = 6;
+/// Remaining walk length at which a `forward_join` also lands in the passive view.
+const PASSIVE_WALK: usize = 3;
+const SHUFFLE_EVERY: Duration = Duration::from_secs(1);
+const SHUFFLE_ACTIVE: usize = 3;
+const SHUFFLE_PASSIVE: usize = 4;
+/// Ack timeouts in a row after which an active peer is taken for failed;
+/// one lost frame is not enough.
+const MAX_FAILURES: usize = 3;
+
+pub struct HyParView {
+    me: String,
+    node_ids: Vec<String>,
+    active: BTreeSet<String>,
+    passive: BTreeSet<String>,
+    /// Active peers' ack timeouts since they last acknowledged a frame.
+    failures: HashMap<String, usize>,
+    active_size: usize,
+    passive_size: usize,
+    last_shuffle: Instant,
+    rng: StdRng,
+}
+
+impl HyParView {
+    /// View sizes follow the usual `log2(n) + 1` active and 6x passive.
+    pub fn new(me: &str, node_ids: &[String]) -> Self {
+        let n = node_ids.len().max(2) as f64;
+        let active_size = (n.log2().ceil() as usize + 1).max(2);
+        Self {
+            me: me.to_string(),
+            node_ids: node_ids.to_vec(),
+            active: BTreeSet::new(),
+            passive: BTreeSet::new(),
+            failures: HashMap::new(),
+            active_size,
+            passive_size: active_size * 6,
+            last_shuffle: Instant::now(),
+            rng: StdRng::from_entropy(),
+        }
+    }
+
+    pub fn active(&self) -> Vec<String> {
+        self.active.iter().cloned().collect()
+    }
+
+    /// Every node in the cluster but us.
+    pub fn others(&self) -> Vec<String> {
+        self.node_ids
+            .iter()
+            .filter(|&id| id != &self.me)
+            .cloned()
+            .collect()
+    }
+
+    /// Joins the overlay through the lowest node id, the one contact every
+    /// node agrees on without coordination.
+    pub fn join(&mut self) -> Vec<(String, Payload)> {
+        let contact = self.node_ids.iter().filter(|&id| id != &self.me).min();
+        let Some(contact) = contact.cloned() else {
+            return vec![];
+        };
+        let mut outgoing = self.add_active(&contact);
+        outgoing.push((contact, Payload::Join));
+        outgoing
+    }
+
+    pub fn on_join(&mut self, from: &str) -> Vec<(String, Payload)> {
+        let mut outgoing = self.add_active(from);
+        for peer in self.active.iter().filter(|&peer| peer != from) {
+            let payload = Payload::ForwardJoin {
+                joiner: from.to_string(),
+                ttl: ACTIVE_WALK,
+            };
+            outgoing.push((peer.clone(), payload));
+        }
+        outgoing
+    }
+
+    pub fn on_forward_join(
+        &mut self,
+        from: &str,
+        joiner: String,
+        ttl: usize,
+    ) -> Vec<(String, Payload)> {
+        if joiner == self.me {
+            return vec![];
+        }
+        if ttl == 0 || self.active.len() <= 1 {
+            let mut outgoing = self.add_active(&joiner);
+            outgoing.push((
+                joiner,
+                Payload::Neighbor {
+                    high_priority: true,
+                },
+            ));
+            return outgoing;
+        }
+        if ttl == PASSIVE_WALK {
+            self.add_passive(&joiner);
+        }
+        let next = self
+            .active
+            .iter()
+            .filter(|&peer| peer != from && peer != &joiner)
+            .choose(&mut self.rng)
+            .cloned();
+        match next {
+            Some(next) => vec![(
+                next,
+                Payload::ForwardJoin {
+                    joiner,
+                    ttl: ttl - 1,
+                },
+            )],
+            None => {
+                let mut outgoing = self.add_active(&joiner);
+                outgoing.push((
+                    joiner,
+                    Payload::Neighbor {
+                        high_priority: true,
+                    },
+                ));
+                outgoing
+            }
+        }
+    }
+
+    /// A node asks to enter our active view. High priority requests come
+    /// from nodes with an empty active view and are always accepted.
+    pub fn on_neighbor(&mut self, from: &str, high_priority: bool) -> Vec<(String, Payload)> {
+        if self.active.contains(from) {
+            return vec![(from.to_string(), Payload::NeighborOk { accepted: true })];
+        }
+        if high_priority || self.active.len() < self.active_size {
+            let mut outgoing = self.add_active(from);
+            outgoing.push((from.to_string(), Payload::NeighborOk { accepted: true }));
+            outgoing
+        } else {
+            vec![(from.to_string(), Payload::NeighborOk { accepted: false })]
+        }
+    }
+
+    pub fn on_neighbor_ok(&mut self, from: &str, accepted: bool) -> Vec<(String, Payload)> {
+        if accepted {
+            self.add_active(from)
+        } else {
+            vec![]
+        }
+    }
+
+    pub fn on_disconnect(&mut self, from: &str) {
+        self.failures.remove(from);
+        if self.active.remove(from) {
+            self.add_passive(from);
+        }
+    }
+
+    pub fn on_shuffle(
+        &mut self,
+        from: &str,
+        origin: String,
+        ttl: usize,
+        nodes: Vec<String>,
+    ) -> Vec<(String, Payload)> {
+        let next = self
+            .active
+            .iter()
+            .filter(|&peer| peer != from && peer != &origin)
+            .choose(&mut self.rng)
+            .cloned();
+        match next {
+            Some(next) if ttl > 0 => {
+                let payload = Payload::Shuffle {
+                    origin,
+                    ttl: ttl - 1,
+                    nodes,
+                };
+                vec![(next, payload)]
+            }
+            _ => {
+                let reply = self
+                    .passive
+                    .iter()
+                    .choose_multiple(&mut self.rng, nodes.len())
+                    .into_iter()
+                    .cloned()
+                    .collect();
+                self.integrate(nodes);
+                vec![(origin, Payload::ShuffleReply { nodes: reply })]
+            }
+        }
+    }
+
+    pub fn on_shuffle_reply(&mut self, nodes: Vec<String>) {
+        self.integrate(nodes);
+    }
+
+    /// A peer acknowledged a frame, so its earlier misses are forgiven.
+    pub fn heard_from(&mut self, peer: &str) {
+        self.failures.remove(peer);
+    }
+
+    /// A frame to `peer` timed out unacknowledged. Once an active peer misses
+    /// [`MAX_FAILURES`] in a row, demote it and ask a passive node to take
+    /// its place.
+    pub fn on_failed(&mut self, peer: &str) -> Vec<(String, Payload)> {
+        if !self.active.contains(peer) {
+            return vec![];
+        }
+        let failures = self.failures.entry(peer.to_string()).or_default();
+        *failures += 1;
+        if *failures < MAX_FAILURES {
+            return vec![];
+        }
+        self.failures.remove(peer);
+        self.active.remove(peer);
+        self.add_passive(peer);
+        self.promote()
+    }
+
+    /// Periodic upkeep: rejoin when isolated, refill the active view from
+    /// the passive one and shuffle.
+    pub fn tick(&mut self, now: Instant) -> Vec<(String, Payload)> {
+        if self.active.is_empty() && self.passive.is_empty() {
+            return self.join();
+        }
+        let mut outgoing = self.promote();
+        if now.duration_since(self.last_shuffle) >= SHUFFLE_EVERY {
+            self.last_shuffle = now;
+            if let Some(peer) = self.active.iter().choose(&mut self.rng).cloned() {
+                let mut nodes = vec![self.me.clone()];
+                nodes.extend(
+                    self.active
+                        .iter()
+                        .filter(|&active| active != &peer)
+                        .choose_multiple(&mut self.rng, SHUFFLE_ACTIVE)
+                        .into_iter()
+                        .cloned(),
+                );
+                nodes.extend(
+                    self.passive
+                        .iter()
+                        .choose_multiple(&mut self.rng, SHUFFLE_PASSIVE)
+                        .into_iter()
+                        .cloned(),
+                );
+                let payload = Payload::Shuffle {
+                    origin: self.me.clone(),
+                    ttl: PASSIVE_WALK,
+                    nodes,
+                };
+                outgoing.push((peer, payload));
+            }
+        }
+        outgoing
+    }
+
+    fn promote(&mut self) -> Vec<(String, Payload)> {
+        if self.active.len() >= self.active_size {
+            return vec![];
+        }
+        let Some(candidate) = self.passive.iter().choose(&mut self.rng).cloned() else {
+            return vec![];
+        };
+        let high_priority = self.active.is_empty();
+        vec![(candidate, Payload::Neighbor { high_priority })]
+    }
+
+    /// Adds a peer to the active view, evicting a random member to the
+    /// passive view (and telling it) when full.
+    fn add_active(&mut self, peer: &str) -> Vec<(String, Payload)> {
+        if peer == self.me || self.active.contains(peer) {
+            return vec![];
+        }
+        let mut outgoing = Vec::new();
+        if self.active.len() >= self.active_size {
+            if let Some(evicted) = self.active.iter().choose(&mut self.rng).cloned() {
+                self.active.remove(&evicted);
+                self.failures.remove(&evicted);
+                self.add_passive(&evicted);
+                outgoing.push((evicted, Payload::Disconnect));
+            }
+        }
+        self.passive.remove(peer);
+        self.active.insert(peer.to_string());
+        outgoing
+    }
+
+    fn add_passive(&mut self, peer: &str) {
+        if peer == self.me || self.active.contains(peer) || self.passive.contains(peer) {
+            return;
+        }
+        if self.passive.len() >= self.passive_size {
+            if let Some(evicted) = self.passive.iter().choose(&mut self.rng).cloned() {
+                self.passive.remove(&evicted);
+            }
+        }
+        self.passive.insert(peer.to_string());
+    }
+
+    fn integrate(&mut self, nodes: Vec<String>) {
+        for node in nodes {
+            self.add_passive(&node);
+        }
+    }
+}
@@ -0,0 +1,28 @@
+pub mod hyparview;
+pub mod swim;
+
+use std::str::FromStr;
+
+use anyhow::bail;
+
+/// How a node picks the peers it gossips with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MembershipMode {
+    /// Everyone the topology names.
+    #[default]
+    Full,
+    /// A small active view maintained by HyParView.
+    HyParView,
+}
+
+impl FromStr for MembershipMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(Self::Full),
+            "hyparview" => Ok(Self::HyParView),
+            _ => bail!("unknown membership {s}, expected full or hyparview"),
+        }
+    }
+}
@@ -0,0 +1,419 @@
+//! SWIM failure detection.
+//!
+//! Every protocol period a node pings one member, cycling through them in a
+//! shuffled order. When the ping goes unanswered it asks a few other members
+//! to `ping_req` the target on its behalf, and only when none of them gets an
+//! ack either does it suspect the target. A suspect that does not refute the
+//! suspicion within `SUSPICION_TIMEOUT` is declared dead.
+//!
+//! State changes are not broadcast separately: they ride on the `ping`,
+//! `ping_req` and `ack` messages the detector sends anyway, each one a
+//! bounded number of times. Incarnation numbers order the claims about a
+//! node, and only the node itself bumps its own, to refute a suspicion.
+//! A node that acks while held suspect or dead has the claim sent back to it
+//! until it refutes it, so a node declared dead by mistake comes back.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    message::{Init, Message},
+    runtime::{Node, Runtime},
+};
+
+const PROTOCOL_PERIOD: Duration = Duration::from_millis(500);
+const PING_TIMEOUT: Duration = Duration::from_millis(150);
+/// Members asked to probe a target that missed its direct ping.
+const INDIRECT_PROBES: usize = 3;
+const SUSPICION_TIMEOUT: Duration = Duration::from_secs(3);
+/// Most updates piggybacked on a single message.
+const MAX_PIGGYBACK: usize = 8;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+/// A claim about one member, as disseminated.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Update {
+    pub node: String,
+    pub status: Status,
+    pub incarnation: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Ping {
+        updates: Vec<Update>,
+    },
+    PingReq {
+        target: String,
+        updates: Vec<Update>,
+    },
+    /// Answers `ping` and, once the target acked, `ping_req`.
+    Ack {
+        incarnation: u64,
+        updates: Vec<Update>,
+    },
+    Members,
+    MembersOk {
+        members: BTreeMap<String, Status>,
+    },
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Member {
+    status: Status,
+    incarnation: u64,
+    /// When the member became suspect.
+    since: Instant,
+}
+
+/// The detector's view of the cluster.
+pub struct Swim {
+    me: String,
+    incarnation: u64,
+    members: HashMap<String, Member>,
+    /// Updates still to piggyback, with how many times each was sent.
+    pending: HashMap<String, (Update, usize)>,
+    /// How many times each update is piggybacked, about `3 log2(n)`.
+    retransmits: usize,
+    probe_order: Vec<String>,
+    rng: StdRng,
+}
+
+impl Swim {
+    pub fn new(me: &str, node_ids: &[String]) -> Self {
+        let now = Instant::now();
+        let members = node_ids
+            .iter()
+            .filter(|&id| id != me)
+            .map(|id| {
+                let member = Member {
+                    status: Status::Alive,
+                    incarnation: 0,
+                    since: now,
+                };
+                (id.clone(), member)
+            })
+            .collect();
+        let n = node_ids.len().max(2) as f64;
+        Self {
+            me: me.to_string(),
+            incarnation: 0,
+            members,
+            pending: HashMap::new(),
+            retransmits: 3 * n.log2().ceil() as usize,
+            probe_order: Vec::new(),
+            rng: StdRng::from_entropy(),
+        }
+    }
+
+    /// The current view, us included.
+    pub fn view(&self) -> BTreeMap<String, Status> {
+        let mut view: BTreeMap<_, _> = self
+            .members
+            .iter()
+            .map(|(id, member)| (id.clone(), member.status))
+            .collect();
+        view.insert(self.me.clone(), Status::Alive);
+        view
+    }
+
+    pub fn incarnation(&self) -> u64 {
+        self.incarnation
+    }
+
+    /// The next member to ping. Dead members are probed too, so that a node
+    /// cut off by a partition learns it was declared dead and refutes it.
+    pub fn next_target(&mut self) -> Option<String> {
+        if self.probe_order.is_empty() {
+            self.probe_order = self.members.keys().cloned().collect();
+            self.probe_order.shuffle(&mut self.rng);
+        }
+        self.probe_order.pop()
+    }
+
+    /// Up to `INDIRECT_PROBES` live members other than `target`.
+    pub fn helpers(&mut self, target: &str) -> Vec<String> {
+        let mut helpers: Vec<String> = self
+            .members
+            .iter()
+            .filter(|(id, member)| *id != target && member.status != Status::Dead)
+            .map(|(id, _)| id.clone())
+            .collect();
+        helpers.shuffle(&mut self.rng);
+        helpers.truncate(INDIRECT_PROBES);
+        helpers
+    }
+
+    /// Updates to piggyback on an outgoing message.
+    pub fn piggyback(&mut self) -> Vec<Update> {
+        let mut pending: Vec<_> = self.pending.values_mut().collect();
+        pending.sort_by_key(|(_, sent)| *sent);
+        let updates: Vec<Update> = pending
+            .into_iter()
+            .take(MAX_PIGGYBACK)
+            .map(|(update, sent)| {
+                *sent += 1;
+                update.clone()
+            })
+            .collect();
+        let retransmits = self.retransmits;
+        self.pending.retain(|_, (_, sent)| *sent < retransmits);
+        updates
+    }
+
+    /// `node` answered a probe while at `incarnation`. That does not clear a
+    /// suspicion or death at the same incarnation, which only the node can
+    /// refute: the claim is passed on again instead, so that the node hears
+    /// of it with our next message and answers with a fresh incarnation.
+    pub fn on_ack(&mut self, node: &str, incarnation: u64) {
+        if let Some(member) = self.members.get(node) {
+            if member.status != Status::Alive && member.incarnation >= incarnation {
+                let claim = Update {
+                    node: node.to_string(),
+                    status: member.status,
+                    incarnation: member.incarnation,
+                };
+                self.disseminate(claim);
+                return;
+            }
+        }
+        self.apply(Update {
+            node: node.to_string(),
+            status: Status::Alive,
+            incarnation,
+        });
+    }
+
+    /// Neither `node` nor the helpers answered.
+    pub fn on_probe_failed(&mut self, node: &str) {
+        let Some(member) = self.members.get(node) else {
+            return;
+        };
+        if member.status == Status::Alive {
+            let incarnation = member.incarnation;
+            self.apply(Update {
+                node: node.to_string(),
+                status: Status::Suspect,
+                incarnation,
+            });
+        }
+    }
+
+    /// Declares dead the suspects whose suspicion outlived the timeout.
+    pub fn expire(&mut self, now: Instant) {
+        let expired: Vec<Update> = self
+            .members
+            .iter()
+            .filter(|(_, member)| {
+                member.status == Status::Suspect
+                    && now.duration_since(member.since) >= SUSPICION_TIMEOUT
+            })
+            .map(|(id, member)| Update {
+                node: id.clone(),
+                status: Status::Dead,
+                incarnation: member.incarnation,
+            })
+            .collect();
+        for update in expired {
+            self.apply(update);
+        }
+    }
+
+    pub fn apply_all(&mut self, updates: Vec<Update>) {
+        for update in updates {
+            self.apply(update);
+        }
+    }
+
+    /// Takes in a claim if it is newer than what we know, and passes it on.
+    fn apply(&mut self, update: Update) {
+        if update.node == self.me {
+            // Refute any suspicion about us with a fresh incarnation.
+            if update.status != Status::Alive && update.incarnation >= self.incarnation {
+                self.incarnation = update.incarnation + 1;
+                self.disseminate(Update {
+                    node: self.me.clone(),
+                    status: Status::Alive,
+                    incarnation: self.incarnation,
+                });
+            }
+            return;
+        }
+        let Some(member) = self.members.get_mut(&update.node) else {
+            return;
+        };
+        let newer = match update.status {
+            Status::Alive => update.incarnation > member.incarnation,
+            Status::Suspect => {
+                update.incarnation > member.incarnation
+                    || (update.incarnation == member.incarnation && member.status == Status::Alive)
+            }
+            Status::Dead => {
+                update.incarnation >= member.incarnation && member.status != Status::Dead
+            }
+        };
+        if !newer {
+            return;
+        }
+        if update.status == Status::Suspect && member.status != Status::Suspect {
+            member.since = Instant::now();
+        }
+        member.status = update.status;
+        member.incarnation = update.incarnation;
+        self.disseminate(update);
+    }
+
+    fn disseminate(&mut self, update: Update) {
+        self.pending.insert(update.node.clone(), (update, 0));
+    }
+}
+
+pub struct SwimNode {
+    runtime: Runtime,
+    swim: Arc<Mutex<Swim>>,
+}
+
+impl Node<Payload> for SwimNode {
+    fn from_init(runtime: Runtime, init: Init) -> anyhow::Result<Self> {
+        let swim = Arc::new(Mutex::new(Swim::new(&init.node_id, &init.node_ids)));
+        spawn_protocol(runtime.clone(), swim.clone());
+        Ok(Self { runtime, swim })
+    }
+
+    fn step(&mut self, input: Message<Payload>) -> anyhow::Result<()> {
+        match input.body.payload {
+            Payload::Ping { ref updates } => {
+                let mut swim = self.swim.lock().unwrap();
+                swim.apply_all(updates.clone());
+                let ack = Payload::Ack {
+                    incarnation: swim.incarnation(),
+                    updates: swim.piggyback(),
+                };
+                drop(swim);
+                self.runtime.reply(&input, ack)?;
+            }
+            Payload::PingReq {
+                ref target,
+                ref updates,
+            } => {
+                let ping = {
+                    let mut swim = self.swim.lock().unwrap();
+                    swim.apply_all(updates.clone());
+                    Payload::Ping {
+                        updates: swim.piggyback(),
+                    }
+                };
+                let runtime = self.runtime.clone();
+                let swim = self.swim.clone();
+                let target = target.clone();
+                std::thread::spawn(move || {
+                    // No ack from the target means no answer at all; the
+                    // requester times out.
+                    let Some(incarnation) = probe(&runtime, &swim, &target, ping) else {
+                        return;
+                    };
+                    let ack = Payload::Ack {
+                        incarnation,
+                        updates: swim.lock().unwrap().piggyback(),
+                    };
+                    if let Err(err) = runtime.reply(&input, ack) {
+                        eprintln!("ping_req reply failed: {err:#}");
+                    }
+                });
+            }
+            Payload::Members => {
+                let members = self.swim.lock().unwrap().view();
+                self.runtime.reply(&input, Payload::MembersOk { members })?;
+            }
+            Payload::Ack { .. } | Payload::MembersOk { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+/// Sends `request` to `dest` and folds in the ack. Returns the incarnation
+/// the ack reported for `target`, or `None` without an ack in time.
+fn probe(runtime: &Runtime, swim: &Mutex<Swim>, target: &str, request: Payload) -> Option<u64> {
+    probe_via(runtime, swim, target, target, request, PING_TIMEOUT)
+}
+
+fn probe_via(
+    runtime: &Runtime,
+    swim: &Mutex<Swim>,
+    dest: &str,
+    target: &str,
+    request: Payload,
+    timeout: Duration,
+) -> Option<u64> {
+    let Ok(Payload::Ack {
+        incarnation,
+        updates,
+    }) = runtime.rpc(dest, request, timeout)
+    else {
+        return None;
+    };
+    let mut swim = swim.lock().unwrap();
+    swim.apply_all(updates);
+    swim.on_ack(target, incarnation);
+    Some(incarnation)
+}
+
+/// Runs one probe per protocol period: a direct ping, then indirect ones
+/// through helpers, then suspicion.
+fn spawn_protocol(runtime: Runtime, swim: Arc<Mutex<Swim>>) {
+    std::thread::spawn(move || loop {
+        let started = Instant::now();
+        let (target, ping) = {
+            let mut swim = swim.lock().unwrap();
+            swim.expire(started);
+            let target = swim.next_target();
+            let ping = Payload::Ping {
+                updates: swim.piggyback(),
+            };
+            (target, ping)
+        };
+        if let Some(target) = target {
+            if probe(&runtime, &swim, &target, ping).is_none() {
+                let helpers = swim.lock().unwrap().helpers(&target);
+                let acked = std::thread::scope(|scope| {
+                    let probes: Vec<_> = helpers
+                        .iter()
+                        .map(|helper| {
+                            let request = Payload::PingReq {
+                                target: target.clone(),
+                                updates: swim.lock().unwrap().piggyback(),
+                            };
+                            let (runtime, swim, target) = (&runtime, &swim, &target);
+                            scope.spawn(move || {
+                                probe_via(runtime, swim, helper, target, request, 2 * PING_TIMEOUT)
+                                    .is_some()
+                            })
+                        })
+                        .collect();
+                    probes
+                        .into_iter()
+                        .any(|probe| probe.join().unwrap_or(false))
+                });
+                if !acked {
+                    swim.lock().unwrap().on_probe_failed(&target);
+                }
+            }
+        }
+        std::thread::sleep(PROTOCOL_PERIOD.saturating_sub(started.elapsed()));
+    });
+}
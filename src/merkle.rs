@@ -0,0 +1,225 @@
+//! Merkle-tree anti-entropy for keyed CRDT state.
+//!
+//! Keys hash into `LEAVES` buckets under a binary tree whose every node
+//! hashes its children. Two nodes find out where their copies differ by
+//! comparing the tree top down, one level per round trip, and then swap
+//! only the entries of the leaves that differ: `DEPTH + 2` messages each
+//! way, however large the state, instead of shipping all of it.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    time::Duration,
+};
+
+use rand::seq::IteratorRandom;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    crdt::CrdtMap,
+    gossip::{Merge, Replicated},
+    runtime::Runtime,
+};
+
+/// Levels below the root; the leaves are at this level.
+pub const DEPTH: usize = 6;
+pub const LEAVES: usize = 1 << DEPTH;
+
+const SYNC_TIMEOUT: Duration = Duration::from_millis(500);
+
+fn hash_of(value: impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The leaf `key` falls in.
+pub fn leaf_of(key: &str) -> usize {
+    hash_of(key) as usize % LEAVES
+}
+
+/// Hashes of every node of the tree, level by level from the root.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MerkleTree {
+    levels: Vec<Vec<u64>>,
+}
+
+impl MerkleTree {
+    /// Builds the tree over `(key, value hash)` pairs.
+    pub fn build<'a>(entries: impl IntoIterator<Item = (&'a str, u64)>) -> Self {
+        let mut leaves: Vec<Vec<(&str, u64)>> = vec![Vec::new(); LEAVES];
+        for (key, value) in entries {
+            leaves[leaf_of(key)].push((key, value));
+        }
+        let mut level: Vec<u64> = leaves
+            .into_iter()
+            .map(|mut entries| {
+                entries.sort();
+                hash_of(entries)
+            })
+            .collect();
+        let mut levels = vec![level.clone()];
+        while level.len() > 1 {
+            level = level.chunks(2).map(hash_of).collect();
+            levels.push(level.clone());
+        }
+        levels.reverse();
+        Self { levels }
+    }
+
+    pub fn hash(&self, level: usize, index: usize) -> u64 {
+        self.levels[level][index]
+    }
+
+    pub fn children(index: usize) -> [usize; 2] {
+        [2 * index, 2 * index + 1]
+    }
+}
+
+/// One step of an anti-entropy exchange, sent by the initiating node
+/// (`compare`, `exchange`) or answered by the other one.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "phase", rename_all = "snake_case")]
+pub enum SyncStep<C> {
+    /// The initiator's hashes of some tree nodes at `level`.
+    Compare {
+        level: usize,
+        hashes: Vec<(usize, u64)>,
+    },
+    /// Which of them differ from the responder's.
+    CompareOk { differ: Vec<usize> },
+    /// The initiator's entries in the leaves that differ.
+    Exchange {
+        leaves: Vec<usize>,
+        entries: CrdtMap<C>,
+    },
+    /// The responder's entries in the same leaves.
+    ExchangeOk { entries: CrdtMap<C> },
+}
+
+/// How a workload's reply carries a `SyncStep` answer: in a `sync` field.
+#[derive(Deserialize)]
+struct SyncReply<C> {
+    sync: SyncStep<C>,
+}
+
+impl<C> Replicated<CrdtMap<C>>
+where
+    C: Merge + Default + Serialize + DeserializeOwned,
+{
+    /// The tree over this copy, with values hashed through their JSON form.
+    pub fn tree(&self) -> MerkleTree {
+        self.tree_within(&|_| true)
+    }
+
+    /// The tree over the keys `within` accepts.
+    pub fn tree_within(&self, within: &impl Fn(&str) -> bool) -> MerkleTree {
+        self.read(|map| {
+            let values: Vec<(&str, u64)> = map
+                .iter()
+                .filter(|(key, _)| within(key))
+                .map(|(key, crdt)| {
+                    let json = serde_json::to_value(crdt).unwrap_or_default();
+                    (key.as_str(), hash_of(json.to_string()))
+                })
+                .collect();
+            MerkleTree::build(values)
+        })
+    }
+
+    /// Answers a step of an exchange another node started.
+    pub fn sync(&self, request: SyncStep<C>) -> Option<SyncStep<C>> {
+        self.sync_within(request, &|_| true)
+    }
+
+    /// Answers a step of an exchange over the keys `within` accepts, which
+    /// must be the keys the other node compares.
+    pub fn sync_within(
+        &self,
+        request: SyncStep<C>,
+        within: &impl Fn(&str) -> bool,
+    ) -> Option<SyncStep<C>> {
+        match request {
+            SyncStep::Compare { level, hashes } => {
+                let tree = self.tree_within(within);
+                let differ = hashes
+                    .into_iter()
+                    .filter(|&(index, hash)| tree.hash(level, index) != hash)
+                    .map(|(index, _)| index)
+                    .collect();
+                Some(SyncStep::CompareOk { differ })
+            }
+            SyncStep::Exchange { leaves, entries } => {
+                let ours = self
+                    .read(|map| map.select(|key| within(key) && leaves.contains(&leaf_of(key))));
+                self.merge(entries);
+                Some(SyncStep::ExchangeOk { entries: ours })
+            }
+            SyncStep::CompareOk { .. } | SyncStep::ExchangeOk { .. } => None,
+        }
+    }
+
+    /// Every `interval`, reconciles with a random peer: walks down the
+    /// trees while they differ, then swaps the differing leaves. `wrap`
+    /// turns a step into the workload's message, whose reply must hold the
+    /// answer in a `sync` field.
+    pub fn spawn_anti_entropy<P, F>(&self, runtime: Runtime, interval: Duration, wrap: F)
+    where
+        C: Send + 'static,
+        P: Serialize,
+        F: Fn(SyncStep<C>) -> P + Send + 'static,
+    {
+        let replicated = self.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let peer = runtime.peers().choose(&mut rand::thread_rng()).cloned();
+            let Some(peer) = peer else {
+                continue;
+            };
+            if let Err(err) = replicated.reconcile(&runtime, &peer, &|_| true, &wrap) {
+                eprintln!("anti-entropy with {peer} failed: {err}");
+            }
+        });
+    }
+
+    /// Reconciles the keys `within` accepts with `peer`, which must answer
+    /// over the same keys.
+    pub fn reconcile<P: Serialize>(
+        &self,
+        runtime: &Runtime,
+        peer: &str,
+        within: &impl Fn(&str) -> bool,
+        wrap: &impl Fn(SyncStep<C>) -> P,
+    ) -> anyhow::Result<()> {
+        let tree = self.tree_within(within);
+        let mut indexes = vec![0];
+        for level in 0..=DEPTH {
+            let hashes = indexes
+                .iter()
+                .map(|&index| (index, tree.hash(level, index)))
+                .collect();
+            let request = wrap(SyncStep::Compare { level, hashes });
+            let reply: SyncReply<C> = runtime.rpc(peer, request, SYNC_TIMEOUT)?;
+            let SyncStep::CompareOk { differ } = reply.sync else {
+                anyhow::bail!("unexpected answer to compare");
+            };
+            if differ.is_empty() {
+                return Ok(());
+            }
+            indexes = match level {
+                DEPTH => differ,
+                _ => differ.into_iter().flat_map(MerkleTree::children).collect(),
+            };
+        }
+        let leaves = indexes;
+        let entries =
+            self.read(|map| map.select(|key| within(key) && leaves.contains(&leaf_of(key))));
+        let request = wrap(SyncStep::Exchange { leaves, entries });
+        let reply: SyncReply<C> = runtime.rpc(peer, request, SYNC_TIMEOUT)?;
+        let SyncStep::ExchangeOk { entries } = reply.sync else {
+            anyhow::bail!("unexpected answer to exchange");
+        };
+        self.merge(entries);
+        Ok(())
+    }
+}
@@ -0,0 +1,62 @@
+use serde::{Deserialize, Serialize};
+
+/// A node's id as Maelstrom names it, such as `n1`.
+pub type NodeId = String;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Message<P> {
+    pub src: String,
+    pub dest: String,
+    pub body: MessageBody<P>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MessageBody<P> {
+    pub msg_id: Option<usize>,
+    pub in_reply_to: Option<usize>,
+    /// The sender's Lamport time, on messages between nodes only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lamport: Option<u64>,
+    #[serde(flatten)]
+    pub payload: P,
+}
+
+/// A message whose payload has not been matched to a workload yet.
+pub type RawMessage = Message<serde_json::Value>;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Init {
+    pub node_id: String,
+    pub node_ids: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum InitPayload {
+    Init(Init),
+    InitOk,
+}
+
+/// Maelstrom's error reply, shared by every workload.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorPayload {
+    Error { code: usize, text: String },
+}
+
+/// Maelstrom's standard error codes.
+pub mod error_code {
+    pub const TIMEOUT: usize = 0;
+    pub const NODE_NOT_FOUND: usize = 1;
+    pub const NOT_SUPPORTED: usize = 10;
+    pub const TEMPORARILY_UNAVAILABLE: usize = 11;
+    pub const MALFORMED_REQUEST: usize = 12;
+    pub const CRASH: usize = 13;
+    pub const ABORT: usize = 14;
+    pub const KEY_DOES_NOT_EXIST: usize = 20;
+    pub const KEY_ALREADY_EXISTS: usize = 21;
+    pub const PRECONDITION_FAILED: usize = 22;
+    pub const TXN_CONFLICT: usize = 30;
+}
@@ -0,0 +1,682 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    consensus::{Committed, Protocol},
+    message::NodeId,
+};
+
+const ELECTION_TIMEOUT_MIN: Duration = Duration::from_millis(300);
+const ELECTION_TIMEOUT_MAX: Duration = Duration::from_millis(600);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+/// Most decided slots sent to a lagging peer at once.
+const MAX_DECIDED: usize = 100;
+
+/// Orders proposers: a higher round wins, and the node id breaks ties, so
+/// no two proposers ever share a ballot.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Ballot {
+    pub round: u64,
+    pub leader: NodeId,
+}
+
+/// What a slot holds once decided.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Decree<C> {
+    /// Fills a slot a new leader found no value for; the state machine
+    /// never sees it.
+    Noop,
+    /// A client's command, with who proposed it and its number there, so
+    /// the proposer can tell its own command from one that took the slot.
+    Apply {
+        proposer: NodeId,
+        seq: u64,
+        command: C,
+    },
+}
+
+/// Messages between Paxos servers.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PaxosMessage<C> {
+    /// Phase 1a: asks the acceptor to ignore lower ballots and report
+    /// what it accepted or knows decided from `from_slot` on.
+    Prepare { ballot: Ballot, from_slot: u64 },
+    /// Phase 1b.
+    Promise {
+        ballot: Ballot,
+        accepted: Vec<(u64, Ballot, Decree<C>)>,
+        decided: Vec<(u64, Decree<C>)>,
+    },
+    /// Phase 2a.
+    Accept {
+        ballot: Ballot,
+        slot: u64,
+        decree: Decree<C>,
+    },
+    /// Phase 2b.
+    Accepted { ballot: Ballot, slot: u64 },
+    /// Refuses `ballot` for having promised `promised`.
+    Nack { ballot: Ballot, promised: Ballot },
+    /// Decrees chosen, for the learners.
+    Decided { decided: Vec<(u64, Decree<C>)> },
+    /// The leader is alive. `round` confirms it still leads, for reads.
+    Heartbeat { ballot: Ballot, round: u64 },
+    /// Echoes a heartbeat, with how far this server has learned, so the
+    /// leader sends what it misses.
+    HeartbeatOk {
+        ballot: Ballot,
+        round: u64,
+        decided: u64,
+    },
+}
+
+#[derive(Clone, Debug)]
+enum Role<C> {
+    Follower,
+    /// Running phase 1 for the ballot promised.
+    Candidate {
+        /// The highest ballot promised to another server before standing.
+        /// A leader that is not below it still leads, safely: this server
+        /// promised it nothing else, and gives up on its own ballot.
+        previous: Ballot,
+        promises: BTreeSet<NodeId>,
+        /// The highest-ballot value each acceptor that promised reported,
+        /// by slot.
+        accepted: BTreeMap<u64, (Ballot, Decree<C>)>,
+    },
+    Leader {
+        next_slot: u64,
+        /// Slots proposed and not chosen yet, with who accepted them.
+        proposed: BTreeMap<u64, (Decree<C>, BTreeSet<NodeId>)>,
+        /// Slots up to this one were recovered from phase 1; reads wait
+        /// until they are decided.
+        recovered: u64,
+        /// The last heartbeat round sent.
+        round: u64,
+        /// The last round each follower answered.
+        answered: HashMap<NodeId, u64>,
+        /// Reads waiting for a round to be confirmed, by id.
+        reads: Vec<(u64, u64)>,
+    },
+}
+
+/// One Multi-Paxos server: proposer, acceptor and learner in one, without
+/// I/O, in the manner of [`Raft`](crate::raft::Raft).
+///
+/// Slots are decided one Paxos instance each. A server that hears from no
+/// leader for an election timeout runs phase 1 once for every slot not
+/// decided yet, under a ballot above any it saw. With a majority of
+/// promises it leads: it proposes again whatever the acceptors reported
+/// for those slots, fills gaps with no-ops, and from then on proposes each
+/// command with phase 2 alone, until a higher ballot turns it away.
+///
+/// A slot is chosen once a majority accepted it; the leader then tells
+/// every learner. Heartbeats carry how far each server learned, so one
+/// that missed decisions gets them again. Reads wait for a heartbeat
+/// round a majority answered, as with Raft's read index.
+///
+/// Everything is kept in memory and the slots are never compacted.
+pub struct Paxos<C> {
+    me: NodeId,
+    nodes: Vec<NodeId>,
+    /// The highest ballot promised, which is also this server's own while
+    /// it is a candidate or leads.
+    promised: Ballot,
+    /// The value last accepted in each slot not yet known decided.
+    accepted: BTreeMap<u64, (Ballot, Decree<C>)>,
+    decided: BTreeMap<u64, Decree<C>>,
+    /// Every slot up to this one is decided.
+    decided_upto: u64,
+    last_applied: u64,
+    role: Role<C>,
+    leader: Option<NodeId>,
+    election_deadline: Instant,
+    heartbeat_due: Instant,
+    heard_from_leader: Instant,
+    next_seq: u64,
+    next_read: u64,
+    ready_reads: Vec<u64>,
+    outbox: Vec<(NodeId, PaxosMessage<C>)>,
+}
+
+impl<C: Clone> Paxos<C> {
+    pub fn new(me: &str, node_ids: &[String], now: Instant) -> Self {
+        Self {
+            me: me.to_string(),
+            nodes: node_ids.to_vec(),
+            promised: Ballot::default(),
+            accepted: BTreeMap::new(),
+            decided: BTreeMap::new(),
+            decided_upto: 0,
+            last_applied: 0,
+            role: Role::Follower,
+            leader: None,
+            election_deadline: now + election_timeout(),
+            heartbeat_due: now,
+            heard_from_leader: now,
+            next_seq: 0,
+            next_read: 0,
+            ready_reads: Vec::new(),
+            outbox: Vec::new(),
+        }
+    }
+
+    pub fn ballot(&self) -> &Ballot {
+        &self.promised
+    }
+
+    pub fn is_leader(&self) -> bool {
+        matches!(self.role, Role::Leader { .. })
+    }
+
+    /// The leader of the highest ballot this server knows of.
+    pub fn leader(&self) -> Option<&str> {
+        self.leader.as_deref()
+    }
+
+    /// Every slot up to this one is decided and known here.
+    pub fn decided_upto(&self) -> u64 {
+        self.decided_upto
+    }
+
+    /// Proposes `command` in the next slot if this server leads. Returns
+    /// the slot and the command's number: it was applied if the decree
+    /// applied in that slot is this server's with that number.
+    pub fn propose(&mut self, command: C) -> Option<(u64, u64)> {
+        let Role::Leader { next_slot, .. } = &mut self.role else {
+            return None;
+        };
+        let slot = *next_slot;
+        *next_slot += 1;
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let decree = Decree::Apply {
+            proposer: self.me.clone(),
+            seq,
+            command,
+        };
+        self.start_accept(slot, decree);
+        Some((slot, seq))
+    }
+
+    /// Starts a linearizable read if this server leads, returning its id.
+    /// [`take_reads`](Self::take_reads) hands the id out once a majority
+    /// confirmed this server still leads and every slot it recovered is
+    /// decided. A read still waiting when it stops leading is never handed
+    /// out.
+    pub fn read_index(&mut self) -> Option<u64> {
+        let Role::Leader { round, reads, .. } = &mut self.role else {
+            return None;
+        };
+        let id = self.next_read;
+        self.next_read += 1;
+        reads.push((id, *round + 1));
+        self.broadcast_heartbeat();
+        self.confirm_reads();
+        Some(id)
+    }
+
+    /// Starts phase 1 or sends heartbeats when they are due.
+    pub fn tick(&mut self, now: Instant) {
+        match self.role {
+            Role::Leader { .. } if now >= self.heartbeat_due => {
+                self.heartbeat_due = now + HEARTBEAT_INTERVAL;
+                self.broadcast_heartbeat();
+                self.retry_accepts();
+            }
+            Role::Leader { .. } => {}
+            Role::Follower | Role::Candidate { .. } if now >= self.election_deadline => {
+                self.start_prepare(now);
+            }
+            Role::Follower | Role::Candidate { .. } => {}
+        }
+    }
+
+    pub fn handle(&mut self, from: &str, message: PaxosMessage<C>, now: Instant) {
+        match message {
+            PaxosMessage::Prepare { ballot, from_slot } => {
+                let alive = self.leader.as_deref().is_some_and(|leader| leader != from)
+                    && now < self.heard_from_leader + ELECTION_TIMEOUT_MIN;
+                if self.is_leader() || alive {
+                    // Keep the leader this server hears from in place.
+                    return;
+                }
+                if ballot <= self.promised {
+                    let promised = self.promised.clone();
+                    self.send(from, PaxosMessage::Nack { ballot, promised });
+                    return;
+                }
+                self.follow(ballot.clone(), now);
+                let accepted = self
+                    .accepted
+                    .range(from_slot..)
+                    .map(|(&slot, (ballot, decree))| (slot, ballot.clone(), decree.clone()))
+                    .collect();
+                let decided = self.decided_from(from_slot, usize::MAX);
+                let promise = PaxosMessage::Promise {
+                    ballot,
+                    accepted,
+                    decided,
+                };
+                self.send(from, promise);
+            }
+            PaxosMessage::Promise {
+                ballot,
+                accepted,
+                decided,
+            } => {
+                self.learn(decided);
+                if ballot != self.promised {
+                    return;
+                }
+                let Role::Candidate {
+                    promises,
+                    accepted: reported,
+                    ..
+                } = &mut self.role
+                else {
+                    return;
+                };
+                promises.insert(from.to_string());
+                for (slot, ballot, decree) in accepted {
+                    if reported.get(&slot).is_none_or(|(seen, _)| *seen < ballot) {
+                        reported.insert(slot, (ballot, decree));
+                    }
+                }
+                if promises.len() > self.nodes.len() / 2 {
+                    self.become_leader(now);
+                }
+            }
+            PaxosMessage::Accept {
+                ballot,
+                slot,
+                decree,
+            } => {
+                if !self.admits(&ballot) {
+                    let promised = self.promised.clone();
+                    self.send(from, PaxosMessage::Nack { ballot, promised });
+                    return;
+                }
+                self.follow(ballot.clone(), now);
+                if !self.decided.contains_key(&slot) {
+                    self.accepted.insert(slot, (ballot.clone(), decree));
+                }
+                self.send(from, PaxosMessage::Accepted { ballot, slot });
+            }
+            PaxosMessage::Accepted { ballot, slot } => {
+                if ballot != self.promised {
+                    return;
+                }
+                let Role::Leader { proposed, .. } = &mut self.role else {
+                    return;
+                };
+                let Some((decree, accepted)) = proposed.get_mut(&slot) else {
+                    return;
+                };
+                accepted.insert(from.to_string());
+                if accepted.len() > self.nodes.len() / 2 {
+                    let decree = decree.clone();
+                    proposed.remove(&slot);
+                    self.choose(slot, decree);
+                }
+            }
+            PaxosMessage::Nack { ballot, promised } => {
+                if ballot == self.promised && promised > self.promised {
+                    // A higher ballot is out there: stand back and let it
+                    // lead, or outbid it at the next timeout.
+                    self.follow(promised, now);
+                }
+            }
+            PaxosMessage::Decided { decided } => self.learn(decided),
+            PaxosMessage::Heartbeat { ballot, round } => {
+                if !self.admits(&ballot) {
+                    let promised = self.promised.clone();
+                    self.send(from, PaxosMessage::Nack { ballot, promised });
+                    return;
+                }
+                self.follow(ballot.clone(), now);
+                let decided = self.decided_upto;
+                let ok = PaxosMessage::HeartbeatOk {
+                    ballot,
+                    round,
+                    decided,
+                };
+                self.send(from, ok);
+            }
+            PaxosMessage::HeartbeatOk {
+                ballot,
+                round,
+                decided,
+            } => {
+                if ballot != self.promised {
+                    return;
+                }
+                let Role::Leader { answered, .. } = &mut self.role else {
+                    return;
+                };
+                let last = answered.entry(from.to_string()).or_default();
+                *last = (*last).max(round);
+                if decided < self.decided_upto {
+                    let decided = self.decided_from(decided + 1, MAX_DECIDED);
+                    self.send(from, PaxosMessage::Decided { decided });
+                }
+                self.confirm_reads();
+            }
+        }
+    }
+
+    /// The decrees decided since the last call, in slot order, with their
+    /// slots.
+    pub fn take_decided(&mut self) -> Vec<(u64, Decree<C>)> {
+        let decided = (self.last_applied + 1..=self.decided_upto)
+            .filter_map(|slot| self.decided.get(&slot).map(|decree| (slot, decree.clone())))
+            .collect();
+        self.last_applied = self.decided_upto;
+        decided
+    }
+
+    /// The reads started with [`read_index`](Self::read_index) that may
+    /// be served since the last call.
+    pub fn take_reads(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.ready_reads)
+    }
+
+    /// The messages to send, in order.
+    pub fn take_outbox(&mut self) -> Vec<(NodeId, PaxosMessage<C>)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// Runs phase 1 for every slot not decided yet, under a ballot above
+    /// the highest promised.
+    fn start_prepare(&mut self, now: Instant) {
+        self.election_deadline = now + election_timeout();
+        let previous = match &self.role {
+            Role::Candidate { previous, .. } => previous.clone(),
+            Role::Follower | Role::Leader { .. } => self.promised.clone(),
+        };
+        self.promised = Ballot {
+            round: self.promised.round + 1,
+            leader: self.me.clone(),
+        };
+        self.leader = None;
+        let from_slot = self.decided_upto + 1;
+        let accepted = self.accepted.range(from_slot..);
+        self.role = Role::Candidate {
+            previous,
+            promises: BTreeSet::from([self.me.clone()]),
+            accepted: accepted
+                .map(|(&slot, value)| (slot, value.clone()))
+                .collect(),
+        };
+        if self.nodes.len() / 2 == 0 {
+            self.become_leader(now);
+            return;
+        }
+        let prepare = PaxosMessage::Prepare {
+            ballot: self.promised.clone(),
+            from_slot,
+        };
+        self.broadcast(prepare);
+    }
+
+    /// Takes the lead with a majority of promises: proposes again what the
+    /// acceptors reported, and no-ops in the slots between.
+    fn become_leader(&mut self, now: Instant) {
+        let Role::Candidate { accepted, .. } = std::mem::replace(&mut self.role, Role::Follower)
+        else {
+            return;
+        };
+        let recovered = accepted
+            .keys()
+            .chain(self.decided.keys())
+            .max()
+            .copied()
+            .unwrap_or_default()
+            .max(self.decided_upto);
+        self.role = Role::Leader {
+            next_slot: recovered + 1,
+            proposed: BTreeMap::new(),
+            recovered,
+            round: 0,
+            answered: HashMap::new(),
+            reads: Vec::new(),
+        };
+        self.leader = Some(self.me.clone());
+        for slot in self.decided_upto + 1..=recovered {
+            if self.decided.contains_key(&slot) {
+                continue;
+            }
+            let decree = match accepted.get(&slot) {
+                Some((_, decree)) => decree.clone(),
+                None => Decree::Noop,
+            };
+            self.start_accept(slot, decree);
+        }
+        self.heartbeat_due = now + HEARTBEAT_INTERVAL;
+        self.broadcast_heartbeat();
+    }
+
+    /// Whether a leader at `ballot` may still lead.
+    fn admits(&self, ballot: &Ballot) -> bool {
+        match &self.role {
+            Role::Candidate { previous, .. } => ballot >= previous,
+            Role::Follower | Role::Leader { .. } => *ballot >= self.promised,
+        }
+    }
+
+    /// Goes back to following `ballot`'s leader.
+    fn follow(&mut self, ballot: Ballot, now: Instant) {
+        self.role = Role::Follower;
+        self.leader = Some(ballot.leader.clone());
+        self.promised = ballot;
+        self.heard_from_leader = now;
+        self.election_deadline = now + election_timeout();
+    }
+
+    /// Accepts `decree` in `slot` here and asks every acceptor to.
+    fn start_accept(&mut self, slot: u64, decree: Decree<C>) {
+        let Role::Leader { proposed, .. } = &mut self.role else {
+            return;
+        };
+        let ballot = self.promised.clone();
+        self.accepted.insert(slot, (ballot.clone(), decree.clone()));
+        proposed.insert(slot, (decree.clone(), BTreeSet::from([self.me.clone()])));
+        if self.nodes.len() / 2 == 0 {
+            proposed.remove(&slot);
+            self.choose(slot, decree);
+            return;
+        }
+        self.broadcast(PaxosMessage::Accept {
+            ballot,
+            slot,
+            decree,
+        });
+    }
+
+    /// Sends the slots still waiting for a majority again, to the
+    /// acceptors that have not accepted them, in case the messages were
+    /// lost.
+    fn retry_accepts(&mut self) {
+        let Role::Leader { proposed, .. } = &self.role else {
+            return;
+        };
+        let mut retries = Vec::new();
+        for (&slot, (decree, accepted)) in proposed {
+            for peer in self.nodes.iter().filter(|peer| !accepted.contains(*peer)) {
+                let accept = PaxosMessage::Accept {
+                    ballot: self.promised.clone(),
+                    slot,
+                    decree: decree.clone(),
+                };
+                retries.push((peer.clone(), accept));
+            }
+        }
+        self.outbox.extend(retries);
+    }
+
+    /// Records `decree` as chosen in `slot` and tells every learner.
+    fn choose(&mut self, slot: u64, decree: Decree<C>) {
+        let decided = vec![(slot, decree)];
+        self.broadcast(PaxosMessage::Decided {
+            decided: decided.clone(),
+        });
+        self.learn(decided);
+    }
+
+    fn learn(&mut self, decided: Vec<(u64, Decree<C>)>) {
+        for (slot, decree) in decided {
+            if slot > self.decided_upto {
+                self.accepted.remove(&slot);
+                self.decided.insert(slot, decree);
+            }
+        }
+        while self.decided.contains_key(&(self.decided_upto + 1)) {
+            self.decided_upto += 1;
+        }
+        self.confirm_reads();
+    }
+
+    /// Up to `limit` decided slots from `from_slot` on, as far as they run
+    /// without a gap.
+    fn decided_from(&self, from_slot: u64, limit: usize) -> Vec<(u64, Decree<C>)> {
+        self.decided
+            .range(from_slot..=self.decided_upto)
+            .take(limit)
+            .map(|(&slot, decree)| (slot, decree.clone()))
+            .collect()
+    }
+
+    fn broadcast_heartbeat(&mut self) {
+        let Role::Leader { round, .. } = &mut self.role else {
+            return;
+        };
+        *round += 1;
+        let heartbeat = PaxosMessage::Heartbeat {
+            ballot: self.promised.clone(),
+            round: *round,
+        };
+        self.broadcast(heartbeat);
+    }
+
+    /// Readies the reads whose round a majority answered, once every slot
+    /// recovered from phase 1 is decided.
+    fn confirm_reads(&mut self) {
+        let Role::Leader {
+            recovered,
+            round,
+            answered,
+            reads,
+            ..
+        } = &mut self.role
+        else {
+            return;
+        };
+        if self.decided_upto < *recovered {
+            return;
+        }
+        let mut rounds: Vec<u64> = self
+            .nodes
+            .iter()
+            .map(|id| match *id == self.me {
+                true => *round,
+                false => answered.get(id).copied().unwrap_or_default(),
+            })
+            .collect();
+        rounds.sort_unstable_by(|a, b| b.cmp(a));
+        let confirmed = rounds[self.nodes.len() / 2];
+        reads.retain(|&(id, round)| {
+            let ready = round <= confirmed;
+            if ready {
+                self.ready_reads.push(id);
+            }
+            !ready
+        });
+    }
+
+    fn broadcast(&mut self, message: PaxosMessage<C>) {
+        for peer in self.nodes.iter().filter(|&peer| *peer != self.me) {
+            self.outbox.push((peer.clone(), message.clone()));
+        }
+    }
+
+    fn send(&mut self, to: &str, message: PaxosMessage<C>) {
+        self.outbox.push((to.to_string(), message));
+    }
+}
+
+impl<C> Protocol for Paxos<C>
+where
+    C: Serialize + DeserializeOwned + Clone + Send + 'static,
+{
+    type Command = C;
+    type Message = PaxosMessage<C>;
+
+    fn new(me: &str, node_ids: &[String], now: Instant) -> Self {
+        Paxos::new(me, node_ids, now)
+    }
+
+    fn tick(&mut self, now: Instant) {
+        Paxos::tick(self, now)
+    }
+
+    fn handle(&mut self, from: &str, message: PaxosMessage<C>, now: Instant) {
+        Paxos::handle(self, from, message, now)
+    }
+
+    fn propose(&mut self, command: C) -> Option<(u64, u64)> {
+        Paxos::propose(self, command)
+    }
+
+    fn read_index(&mut self) -> Option<u64> {
+        Paxos::read_index(self)
+    }
+
+    fn is_leader(&self) -> bool {
+        Paxos::is_leader(self)
+    }
+
+    fn leader(&self) -> Option<&str> {
+        Paxos::leader(self)
+    }
+
+    fn take_committed(&mut self) -> Vec<Committed<C>> {
+        self.take_decided()
+            .into_iter()
+            .map(|(slot, decree)| match decree {
+                Decree::Noop => Committed {
+                    position: slot,
+                    proposal: None,
+                    command: None,
+                },
+                Decree::Apply {
+                    proposer,
+                    seq,
+                    command,
+                } => Committed {
+                    position: slot,
+                    proposal: (proposer == self.me).then_some(seq),
+                    command: Some(command),
+                },
+            })
+            .collect()
+    }
+
+    fn take_reads(&mut self) -> Vec<u64> {
+        Paxos::take_reads(self)
+    }
+
+    fn take_outbox(&mut self) -> Vec<(NodeId, PaxosMessage<C>)> {
+        Paxos::take_outbox(self)
+    }
+}
+
+fn election_timeout() -> Duration {
+    rand::thread_rng().gen_range(ELECTION_TIMEOUT_MIN..=ELECTION_TIMEOUT_MAX)
+}
@@ -0,0 +1,25 @@
+//! Multi-Paxos: the same replicated log as [`crate::raft`], reached one
+//! Paxos instance per slot instead of by replicating a leader's log.
+//!
+//! Each slot is decided by the classic two phases: a proposer gets a
+//! majority of acceptors to promise its ballot, then to accept its value.
+//! A stable leader runs phase 1 once for all the slots to come and then
+//! needs only phase 2 per command, so in the common case it costs one
+//! round trip to a majority, like Raft. Unlike Raft, a new leader need not
+//! have the longest log: phase 1 tells it what every slot may hold, and
+//! slots decide independently, so a command can be chosen while an
+//! earlier slot is still open.
+//!
+//! [`Paxos`] is the protocol alone, without I/O or threads; [`PaxosServer`]
+//! mounts it on a node as a [`Consensus`](crate::consensus::Consensus)
+//! implementation, through the [`Server`](crate::consensus::Server) it
+//! shares with [`crate::vr`]. It runs on the cluster's nodes as given at `init`, in
+//! memory only.
+
+mod core;
+
+pub use self::core::{Ballot, Decree, Paxos, PaxosMessage};
+
+/// [`Paxos`] mounted on a node.
+pub type PaxosServer<S> =
+    crate::consensus::Server<Paxos<<S as crate::consensus::StateMachine>::Command>, S>;
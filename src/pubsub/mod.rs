@@ -0,0 +1,207 @@
+//! Publish/subscribe: a client `subscribe {topic}`s through some node and
+//! from then on receives a `deliver {topic, msg}` for every message
+//! `publish`ed to the topic through any node.
+//!
+//! Each node keeps its own subscribers. Published messages are replicated
+//! state: a grow-only set of messages per topic, each with a unique id,
+//! gossiped with the shared gossip layer. Whenever a node learns of messages, by a local
+//! `publish` or by merging a peer's state, it delivers them to its
+//! subscribers of that topic.
+
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use ulid::Ulid;
+
+use crate::{
+    crdt::GSet,
+    gossip::{DeltaCrdt, Merge, Replicated},
+    message::{Init, Message},
+    runtime::{Node, Runtime},
+};
+
+/// A published message. Ids are `<node>-<run>-<n>`: the publishing node,
+/// a ULID it drew when it started, so that a restarted node does not reuse
+/// the ids of messages it published before, and a counter. Messages compare
+/// by id alone.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Published {
+    id: String,
+    msg: Value,
+}
+
+impl PartialEq for Published {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Published {}
+
+impl PartialOrd for Published {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Published {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.id.cmp(&other.id)
+    }
+}
+
+/// Every topic's messages.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(transparent)]
+pub struct Topics {
+    topics: HashMap<String, GSet<Published>>,
+}
+
+impl Merge for Topics {
+    fn merge(&mut self, other: Self) {
+        for (topic, messages) in other.topics {
+            self.topics.entry(topic).or_default().merge(messages);
+        }
+    }
+}
+
+impl DeltaCrdt for Topics {
+    /// Each topic's version.
+    type Version = HashMap<String, u64>;
+
+    fn version(&self) -> Self::Version {
+        self.topics
+            .iter()
+            .map(|(topic, messages)| (topic.clone(), messages.version()))
+            .collect()
+    }
+
+    fn split_delta(&self, since: &Self::Version) -> Self {
+        let topics = self
+            .topics
+            .iter()
+            .filter_map(|(topic, messages)| {
+                let since = since.get(topic).copied().unwrap_or_default();
+                let delta = messages.split_delta(&since);
+                (!delta.is_empty()).then(|| (topic.clone(), delta))
+            })
+            .collect();
+        Self { topics }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Subscribe {
+        topic: String,
+    },
+    SubscribeOk,
+    Publish {
+        topic: String,
+        msg: Value,
+    },
+    PublishOk,
+    /// Pushed to subscribers.
+    Deliver {
+        topic: String,
+        msg: Value,
+    },
+    Replicate {
+        topics: Topics,
+    },
+}
+
+pub struct PubSubNode {
+    runtime: Runtime,
+    topics: Replicated<Topics>,
+    // Clients subscribed through this node, by topic.
+    subscribers: HashMap<String, HashSet<String>>,
+    run: Ulid,
+    published: usize,
+}
+
+impl Node<Payload> for PubSubNode {
+    fn from_init(runtime: Runtime, _init: Init) -> anyhow::Result<Self> {
+        let topics = Replicated::new(Topics::default());
+        topics.spawn_gossip(runtime.clone(), Duration::from_millis(200), |topics| {
+            Payload::Replicate { topics }
+        });
+        Ok(Self {
+            runtime,
+            topics,
+            subscribers: HashMap::new(),
+            run: Ulid::new(),
+            published: 0,
+        })
+    }
+
+    fn step(&mut self, input: Message<Payload>) -> anyhow::Result<()> {
+        match input.body.payload {
+            Payload::Subscribe { ref topic } => {
+                self.subscribers
+                    .entry(topic.clone())
+                    .or_default()
+                    .insert(input.src.clone());
+                self.runtime.reply(&input, Payload::SubscribeOk)?;
+            }
+            Payload::Publish { ref topic, ref msg } => {
+                let id = format!("{}-{}-{}", self.runtime.node_id(), self.run, self.published);
+                self.published += 1;
+                let mut published = Topics::default();
+                published
+                    .topics
+                    .entry(topic.clone())
+                    .or_default()
+                    .insert(Published {
+                        id,
+                        msg: msg.clone(),
+                    });
+                self.learn(published)?;
+                self.runtime.reply(&input, Payload::PublishOk)?;
+            }
+            Payload::Replicate { topics } => {
+                self.learn(topics)?;
+            }
+            Payload::SubscribeOk | Payload::PublishOk | Payload::Deliver { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+impl PubSubNode {
+    /// Merges in messages and delivers the ones that are new here to this
+    /// node's subscribers.
+    fn learn(&self, incoming: Topics) -> anyhow::Result<()> {
+        let mut deliveries = Vec::new();
+        self.topics.update(|topics| {
+            for (topic, messages) in incoming.topics {
+                let known = topics.topics.entry(topic.clone()).or_default();
+                for published in messages {
+                    if known.insert(published.clone()) {
+                        deliveries.push((topic.clone(), published.msg));
+                    }
+                }
+            }
+        });
+        for (topic, msg) in deliveries {
+            let Some(subscribers) = self.subscribers.get(&topic) else {
+                continue;
+            };
+            for client in subscribers {
+                let deliver = Payload::Deliver {
+                    topic: topic.clone(),
+                    msg: msg.clone(),
+                };
+                self.runtime.send(client, deliver)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+//! Distributed queue: `enqueue {msg}` from any node, and `dequeue` hands
+//! each message to exactly one consumer, which confirms it with `ack {id}`.
+//!
+//! Everything lives in `lin-kv`. `enqueue` claims the next index by cas on
+//! `queue/tail` and stores the message under `queue/msg/<id>`. `dequeue`
+//! walks the indexes and claims the first free message by creating
+//! `queue/claim/<id>` with cas, which only one consumer can win. A claim
+//! that is not acked within `--queue-visibility` (default 5000ms) lapses and
+//! the message can be claimed again, so a crashed consumer does not lose it.
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    clock::now_ms,
+    message::{error_code, Init, Message},
+    runtime::{Node, RpcError, Runtime},
+    services::Kv,
+};
+
+const TAIL: &str = "queue/tail";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Enqueue { msg: Value },
+    EnqueueOk { id: usize },
+    Dequeue,
+    DequeueOk { id: usize, msg: Value },
+    Ack { id: usize },
+    AckOk,
+}
+
+/// Who holds a message, stored under `queue/claim/<id>`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+struct Claim {
+    consumer: String,
+    /// Wall-clock millis after which an unacked claim lapses.
+    expires_ms: u64,
+    acked: bool,
+}
+
+#[derive(Clone)]
+struct Queue {
+    kv: Kv,
+    visibility: Duration,
+    // Every message below this index is known to be acked, so `dequeue`
+    // starts here. Only ever a hint: other nodes move on independently.
+    head: Arc<AtomicUsize>,
+}
+
+pub struct QueueNode {
+    runtime: Runtime,
+    queue: Queue,
+}
+
+impl Node<Payload> for QueueNode {
+    fn from_init(runtime: Runtime, _init: Init) -> anyhow::Result<Self> {
+        let config = runtime.config().clone();
+        let queue = Queue {
+            kv: Kv::new(runtime.clone(), "lin-kv"),
+            visibility: config
+                .millis("queue-visibility")?
+                .unwrap_or(Duration::from_millis(5000)),
+            head: Arc::default(),
+        };
+        Ok(Self { runtime, queue })
+    }
+
+    fn step(&mut self, input: Message<Payload>) -> anyhow::Result<()> {
+        let runtime = self.runtime.clone();
+        let queue = self.queue.clone();
+        std::thread::spawn(move || {
+            let consumer = input.src.as_str();
+            let result = match input.body.payload {
+                Payload::Enqueue { ref msg } => {
+                    queue.enqueue(msg).map(|id| Some(Payload::EnqueueOk { id }))
+                }
+                Payload::Dequeue => queue
+                    .dequeue(consumer)
+                    .map(|claimed| claimed.map(|(id, msg)| Payload::DequeueOk { id, msg })),
+                Payload::Ack { id } => queue.ack(id, consumer).map(|()| Some(Payload::AckOk)),
+                Payload::EnqueueOk { .. } | Payload::DequeueOk { .. } | Payload::AckOk => {
+                    return;
+                }
+            };
+            let result = match result {
+                Ok(Some(reply)) => runtime.reply(&input, reply),
+                Ok(None) => runtime.reply_error(
+                    &input,
+                    error_code::KEY_DOES_NOT_EXIST,
+                    "no message available",
+                ),
+                Err(RpcError::Remote { code, text }) => runtime.reply_error(&input, code, text),
+                Err(err) => runtime.reply_error(&input, error_code::TIMEOUT, err.to_string()),
+            };
+            if let Err(err) = result {
+                eprintln!("queue reply failed: {err:#}");
+            }
+        });
+        Ok(())
+    }
+}
+
+fn missing(err: &RpcError) -> bool {
+    matches!(err, RpcError::Remote { code, .. } if *code == error_code::KEY_DOES_NOT_EXIST)
+}
+
+fn lost_race(err: &RpcError) -> bool {
+    matches!(err, RpcError::Remote { code, .. } if *code == error_code::PRECONDITION_FAILED)
+}
+
+impl Queue {
+    fn enqueue(&self, msg: &Value) -> Result<usize, RpcError> {
+        let id = self.kv.update(TAIL, |tail: &usize| tail + 1)? - 1;
+        self.kv.write(format!("queue/msg/{id}"), msg)?;
+        Ok(id)
+    }
+
+    /// Claims the oldest message nobody holds, if there is one.
+    fn dequeue(&self, consumer: &str) -> Result<Option<(usize, Value)>, RpcError> {
+        let tail: usize = match self.kv.read(TAIL) {
+            Err(err) if missing(&err) => return Ok(None),
+            other => other?,
+        };
+        let mut all_acked = true;
+        for id in self.head.load(Ordering::Relaxed)..tail {
+            let claim_key = format!("queue/claim/{id}");
+            let claim: Option<Claim> = match self.kv.read(&claim_key) {
+                Ok(claim) => Some(claim),
+                Err(err) if missing(&err) => None,
+                Err(err) => return Err(err),
+            };
+            if claim.as_ref().is_some_and(|claim| claim.acked) {
+                if all_acked {
+                    self.head.fetch_max(id + 1, Ordering::Relaxed);
+                }
+                continue;
+            }
+            all_acked = false;
+            if claim
+                .as_ref()
+                .is_some_and(|claim| claim.expires_ms > now_ms())
+            {
+                continue;
+            }
+            // Enqueued but not written yet: leave it for a later dequeue.
+            let msg: Value = match self.kv.read(format!("queue/msg/{id}")) {
+                Ok(msg) => msg,
+                Err(err) if missing(&err) => continue,
+                Err(err) => return Err(err),
+            };
+            let next = Claim {
+                consumer: consumer.to_string(),
+                expires_ms: now_ms() + self.visibility.as_millis() as u64,
+                acked: false,
+            };
+            match self.kv.cas(&claim_key, &claim, &Some(next), true) {
+                Ok(()) => return Ok(Some((id, msg))),
+                Err(err) if lost_race(&err) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Marks a message the consumer holds as done for good.
+    fn ack(&self, id: usize, consumer: &str) -> Result<(), RpcError> {
+        let claim_key = format!("queue/claim/{id}");
+        let claim: Claim = self.kv.read(&claim_key)?;
+        if claim.consumer != consumer {
+            return Err(RpcError::Remote {
+                code: error_code::PRECONDITION_FAILED,
+                text: format!("message {id} is claimed by {}", claim.consumer),
+            });
+        }
+        let acked = Claim {
+            acked: true,
+            ..claim.clone()
+        };
+        self.kv.cas(&claim_key, &claim, &acked, false)
+    }
+}
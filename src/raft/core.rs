@@ -0,0 +1,1210 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    message::NodeId,
+    raft::{
+        log::{Command, Entry, Log, Snapshot},
+        membership::Membership,
+        storage::{Durable, Record},
+    },
+};
+
+/// Fewest heartbeats a leader sends within the shortest election timeout,
+/// so one or two lost on the way do not set off an election.
+const HEARTBEATS_PER_TIMEOUT: u32 = 3;
+/// Most entries sent in one `append_entries`.
+const MAX_ENTRIES: usize = 100;
+/// Most snapshot bytes sent in one `install_snapshot`.
+const SNAPSHOT_CHUNK: usize = 8 * 1024;
+
+/// How long followers wait to hear from a leader before standing, picked
+/// at random between the two election timeouts each time, and how often
+/// the leader makes itself heard.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timing {
+    election_timeout_min: Duration,
+    election_timeout_max: Duration,
+    heartbeat_interval: Duration,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Self {
+            election_timeout_min: Duration::from_millis(300),
+            election_timeout_max: Duration::from_millis(600),
+            heartbeat_interval: Duration::from_millis(100),
+        }
+    }
+}
+
+impl Timing {
+    /// Fails unless the election timeouts make a range and the leader
+    /// heartbeats several times within the shortest of them.
+    pub fn new(
+        election_timeout_min: Duration,
+        election_timeout_max: Duration,
+        heartbeat_interval: Duration,
+    ) -> anyhow::Result<Self> {
+        if heartbeat_interval.is_zero() {
+            bail!("the heartbeat interval must be positive");
+        }
+        if election_timeout_max < election_timeout_min {
+            bail!(
+                "the election timeout ranges from {election_timeout_min:?} \
+                 down to {election_timeout_max:?}"
+            );
+        }
+        if heartbeat_interval * HEARTBEATS_PER_TIMEOUT > election_timeout_min {
+            bail!(
+                "a heartbeat interval of {heartbeat_interval:?} fits fewer than \
+                 {HEARTBEATS_PER_TIMEOUT} times in the shortest election timeout \
+                 of {election_timeout_min:?}"
+            );
+        }
+        Ok(Self {
+            election_timeout_min,
+            election_timeout_max,
+            heartbeat_interval,
+        })
+    }
+
+    pub fn election_timeout_min(&self) -> Duration {
+        self.election_timeout_min
+    }
+
+    pub fn election_timeout_max(&self) -> Duration {
+        self.election_timeout_max
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        self.heartbeat_interval
+    }
+
+    fn election_timeout(&self) -> Duration {
+        rand::thread_rng().gen_range(self.election_timeout_min..=self.election_timeout_max)
+    }
+
+    /// How long a leader's lease lasts from when the round that confirmed
+    /// it went out: the shortest election timeout, less a margin for
+    /// clocks that run at slightly different rates.
+    fn lease_duration(&self) -> Duration {
+        self.election_timeout_min - self.election_timeout_min / 10
+    }
+}
+
+/// Messages between Raft servers. Every one carries the sender's term,
+/// except that a pre-vote asks about the term its sender would stand in.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RaftMessage<C> {
+    /// Whether the receiver would vote for the sender in `term`, asked
+    /// before standing, without either of them moving to that term.
+    RequestPreVote {
+        term: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    /// Grants a pre-vote for `term`, or refuses it giving the voter's own
+    /// term, which a sender that fell behind on terms catches up to.
+    PreVote {
+        term: u64,
+        granted: bool,
+    },
+    RequestVote {
+        term: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    /// Entries to store after `prev_log_index`, which the follower must
+    /// hold at `prev_log_term`; empty as a heartbeat.
+    AppendEntries {
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry<C>>,
+        leader_commit: u64,
+        /// The heartbeat round this belongs to, echoed back so the leader
+        /// learns it still led when the round went out.
+        #[serde(default)]
+        round: u64,
+    },
+    /// On success the follower's log matches the leader's up to
+    /// `match_index`. On failure `next_index` is where the leader should
+    /// retry from: past the follower's log, or at the start of the term
+    /// that conflicted.
+    AppendEntriesOk {
+        term: u64,
+        success: bool,
+        match_index: u64,
+        next_index: u64,
+        #[serde(default)]
+        round: u64,
+    },
+    /// A piece of the leader's snapshot for a follower that needs entries
+    /// the leader compacted away: `data` goes at byte `offset`, and `done`
+    /// marks the last piece.
+    InstallSnapshot {
+        term: u64,
+        last_included_index: u64,
+        last_included_term: u64,
+        membership: Membership,
+        offset: usize,
+        data: String,
+        done: bool,
+    },
+    /// The follower holds the first `offset` bytes of the snapshot, so the
+    /// leader goes on from there, or the whole of it once `done`.
+    InstallSnapshotOk {
+        term: u64,
+        last_included_index: u64,
+        offset: usize,
+        done: bool,
+    },
+}
+
+#[derive(Clone, Debug)]
+enum Role {
+    Follower,
+    /// Asking for pre-votes: a follower that timed out, still in its term.
+    PreCandidate {
+        votes: BTreeSet<NodeId>,
+    },
+    Candidate {
+        votes: BTreeSet<NodeId>,
+    },
+    Leader {
+        /// The next index to send each follower.
+        next_index: HashMap<NodeId, u64>,
+        /// The highest index known to be on each follower.
+        match_index: HashMap<NodeId, u64>,
+        /// How far each follower that is sent a snapshot got, with the
+        /// index of the snapshot it is sent.
+        sending: HashMap<NodeId, (u64, usize)>,
+        rounds: Box<Rounds>,
+    },
+}
+
+/// The heartbeat rounds a leader confirms its leadership by.
+#[derive(Clone, Debug, Default)]
+struct Rounds {
+    /// The last round sent.
+    round: u64,
+    /// When each round not confirmed yet went out.
+    sent: BTreeMap<u64, Instant>,
+    /// The last round each follower answered.
+    answered: HashMap<NodeId, u64>,
+    /// The last round a quorum answered: this server still led when it
+    /// went out.
+    confirmed: u64,
+    /// Reads waiting for a round to be confirmed, by id.
+    reads: Vec<(u64, u64)>,
+    /// Until when no other server can have been elected.
+    lease: Option<Instant>,
+}
+
+/// One Raft server's state: elections, log replication and commitment.
+///
+/// It does no I/O. Messages to send pile up in an outbox and committed
+/// entries are handed out by [`take_committed`](Self::take_committed), so
+/// the caller decides how they travel and how they are applied.
+///
+/// The caller also compacts the log, handing over a snapshot of what it
+/// applied with [`compact`](Self::compact). Followers the leader can no
+/// longer send entries to get that snapshot in chunks instead, and hand it
+/// out through [`take_installed`](Self::take_installed).
+///
+/// Who votes changes through [`reconfigure`](Self::reconfigure), by joint
+/// consensus: the leader appends a `configure` entry holding both the old
+/// and the new voters, under which every decision needs a majority of
+/// each, and once that commits, one holding the new voters alone. Servers
+/// go by the latest `configure` entry in their log, committed or not, so
+/// at no point can the old and the new voters each elect a leader.
+///
+/// Reads skip the log with [`read_index`](Self::read_index): the leader
+/// notes its commit index and confirms it still leads with a round of
+/// heartbeats, after which the state machine may answer once it applied
+/// that far. With leases on, a leader that heard from a quorum less than
+/// an election timeout ago answers without a round, since followers that
+/// heard from it that recently ignore candidates.
+///
+/// A server that times out first asks for pre-votes for the next term, and
+/// only stands once a quorum would vote for it. One cut off from the rest,
+/// or behind on the log, never gets them, so it keeps its term and cannot
+/// depose a healthy leader by rejoining with a higher one.
+pub struct Raft<C> {
+    me: NodeId,
+    /// Who votes, as the latest `configure` entry in the log says, or else
+    /// the snapshot, or else the cluster the node started in.
+    membership: Membership,
+    /// The index of the entry `membership` comes from.
+    membership_index: u64,
+    /// Who the membership before it had and it does not, still sent
+    /// entries until it commits so they learn they are out.
+    leaving: BTreeSet<NodeId>,
+    /// The membership before any `configure` entry.
+    initial: Membership,
+    term: u64,
+    voted_for: Option<NodeId>,
+    log: Log<C>,
+    commit_index: u64,
+    last_applied: u64,
+    role: Role,
+    leader: Option<NodeId>,
+    /// When a follower or candidate next starts an election.
+    election_deadline: Instant,
+    /// When a leader next sends heartbeats.
+    heartbeat_due: Instant,
+    timing: Timing,
+    /// The snapshot the log was last compacted to.
+    snapshot: Option<Snapshot>,
+    /// A snapshot being received from the leader, so far.
+    incoming: Option<Snapshot>,
+    /// A snapshot received whole, for the state machine to restore.
+    installed: Option<Snapshot>,
+    /// Whether the leader serves reads on its lease.
+    leases: bool,
+    /// When the leader was last heard from.
+    heard_from_leader: Instant,
+    /// When `tick`, `handle` or `read_index` last ran, to time rounds by.
+    now: Instant,
+    next_read: u64,
+    /// Reads that may be served, by id.
+    ready_reads: Vec<u64>,
+    outbox: Vec<(NodeId, RaftMessage<C>)>,
+    /// Changes to the term, vote and log, to make durable before any
+    /// message in the outbox goes out.
+    journal: Vec<Record<C>>,
+}
+
+impl<C: Clone> Raft<C> {
+    pub fn new(me: &str, node_ids: &[String], now: Instant) -> Self {
+        Self::recover(me, node_ids, now, Durable::default())
+    }
+
+    /// Starts a server from the term, vote, snapshot and log it had before
+    /// a restart. Past the snapshot, which the caller restores its state
+    /// machine from, nothing counts as committed until a leader says so.
+    pub fn recover(me: &str, node_ids: &[String], now: Instant, durable: Durable<C>) -> Self {
+        let initial = Membership::new(node_ids.iter().cloned());
+        let applied = durable
+            .snapshot
+            .as_ref()
+            .map_or(0, |snapshot| snapshot.index);
+        let mut raft = Self {
+            me: me.to_string(),
+            membership: initial.clone(),
+            membership_index: 0,
+            leaving: BTreeSet::new(),
+            initial,
+            term: durable.term,
+            voted_for: durable.voted_for,
+            log: durable.log,
+            commit_index: applied,
+            last_applied: applied,
+            role: Role::Follower,
+            leader: None,
+            election_deadline: now + Timing::default().election_timeout(),
+            heartbeat_due: now,
+            timing: Timing::default(),
+            snapshot: durable.snapshot,
+            incoming: None,
+            installed: None,
+            leases: false,
+            heard_from_leader: now,
+            now,
+            next_read: 0,
+            ready_reads: Vec::new(),
+            outbox: Vec::new(),
+            journal: Vec::new(),
+        };
+        raft.refresh_membership();
+        raft
+    }
+
+    /// Serves reads on the leader's lease, trusting clocks to run at
+    /// about the same rate on every server.
+    pub fn with_leases(mut self, leases: bool) -> Self {
+        self.leases = leases;
+        self
+    }
+
+    /// Starts the cluster with `learners`, which do not vote, and
+    /// `witnesses`, which vote but hold no data, rather than with every
+    /// node voting; see [`Membership`]. Fails if they do not fit the
+    /// cluster.
+    pub fn with_roles(
+        mut self,
+        node_ids: &[String],
+        learners: BTreeSet<NodeId>,
+        witnesses: BTreeSet<NodeId>,
+    ) -> anyhow::Result<Self> {
+        self.initial = Membership::with_roles(node_ids, learners, witnesses)?;
+        self.refresh_membership();
+        Ok(self)
+    }
+
+    pub fn with_timing(mut self, timing: Timing) -> Self {
+        self.timing = timing;
+        self.election_deadline = self.now + timing.election_timeout();
+        self
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub fn is_leader(&self) -> bool {
+        matches!(self.role, Role::Leader { .. })
+    }
+
+    /// The leader of the current term, if known.
+    pub fn leader(&self) -> Option<&str> {
+        self.leader.as_deref()
+    }
+
+    pub fn log(&self) -> &Log<C> {
+        &self.log
+    }
+
+    pub fn commit_index(&self) -> u64 {
+        self.commit_index
+    }
+
+    pub fn membership(&self) -> &Membership {
+        &self.membership
+    }
+
+    /// Appends `command` to the log if this server leads, and starts
+    /// replicating it. Returns its index and term: it was applied if the
+    /// entry applied at that index has that term.
+    pub fn propose(&mut self, command: C) -> Option<(u64, u64)> {
+        if !self.is_leader() {
+            return None;
+        }
+        let entry = Entry {
+            term: self.term,
+            command: Command::Apply { command },
+        };
+        let index = self.append(entry);
+        self.advance_commit();
+        self.broadcast_append();
+        Some((index, self.term))
+    }
+
+    /// Starts moving the cluster to `voters`, if this server leads and no
+    /// other change is under way. The change is done once a `configure`
+    /// entry without `next` commits; a leader that is not among `voters`
+    /// then steps down.
+    pub fn reconfigure(&mut self, voters: BTreeSet<NodeId>) -> anyhow::Result<()> {
+        if !self.is_leader() {
+            bail!("not the leader");
+        }
+        if voters.is_empty() {
+            bail!("a cluster needs at least one voter");
+        }
+        if self.membership.is_joint() || self.membership_index > self.commit_index {
+            bail!("a membership change is already under way");
+        }
+        let membership = self.membership.moving_to(voters);
+        self.append(Entry {
+            term: self.term,
+            command: Command::Configure { membership },
+        });
+        self.advance_commit();
+        self.broadcast_append();
+        Ok(())
+    }
+
+    /// Starts a linearizable read if this server leads, returning its id.
+    /// [`take_reads`](Self::take_reads) hands the id out once the state
+    /// machine may answer the read, having applied what was committed.
+    /// A read still waiting when the server steps down is never handed out.
+    pub fn read_index(&mut self, now: Instant) -> Option<u64> {
+        self.now = now;
+        let committed_in_term = self.log.term_at(self.commit_index) == Some(self.term);
+        let Role::Leader { rounds, .. } = &mut self.role else {
+            return None;
+        };
+        let id = self.next_read;
+        self.next_read += 1;
+        if self.leases && committed_in_term && rounds.lease.is_some_and(|until| now < until) {
+            self.ready_reads.push(id);
+            return Some(id);
+        }
+        rounds.reads.push((id, rounds.round + 1));
+        self.broadcast_append();
+        self.confirm_reads();
+        Some(id)
+    }
+
+    /// Starts an election or sends heartbeats when they are due.
+    pub fn tick(&mut self, now: Instant) {
+        self.now = now;
+        match self.role {
+            Role::Leader { .. } if now >= self.heartbeat_due => {
+                self.heartbeat_due = now + self.timing.heartbeat_interval;
+                self.broadcast_append();
+            }
+            Role::Leader { .. } => {}
+            Role::Follower | Role::PreCandidate { .. } | Role::Candidate { .. }
+                if now >= self.election_deadline =>
+            {
+                self.start_pre_vote(now);
+            }
+            Role::Follower | Role::PreCandidate { .. } | Role::Candidate { .. } => {}
+        }
+    }
+
+    pub fn handle(&mut self, from: &str, message: RaftMessage<C>, now: Instant) {
+        let term = match &message {
+            // Asking about a term, or answering about one, moves no one
+            // to it.
+            RaftMessage::RequestPreVote { .. } | RaftMessage::PreVote { .. } => self.term,
+            RaftMessage::RequestVote { term, .. }
+            | RaftMessage::Vote { term, .. }
+            | RaftMessage::AppendEntries { term, .. }
+            | RaftMessage::AppendEntriesOk { term, .. }
+            | RaftMessage::InstallSnapshot { term, .. }
+            | RaftMessage::InstallSnapshotOk { term, .. } => *term,
+        };
+        self.now = now;
+        if matches!(message, RaftMessage::RequestVote { .. }) && self.leader_alive(now) {
+            // A leader may serve reads on its lease until then: neither
+            // vote nor let the candidate's term depose it.
+            return;
+        }
+        if term > self.term {
+            self.become_follower(term, None);
+        }
+        match message {
+            RaftMessage::RequestPreVote {
+                term,
+                last_log_index,
+                last_log_term,
+            } => {
+                let granted = term > self.term
+                    && !self.is_leader()
+                    && !self.leader_alive(now)
+                    && self.log.up_to_date(last_log_term, last_log_index);
+                let term = if granted { term } else { self.term };
+                self.send(from, RaftMessage::PreVote { term, granted });
+            }
+            RaftMessage::PreVote { term, granted } => {
+                if !granted && term > self.term {
+                    self.become_follower(term, None);
+                    return;
+                }
+                let Role::PreCandidate { votes } = &mut self.role else {
+                    return;
+                };
+                if term == self.term + 1 && granted {
+                    votes.insert(from.to_string());
+                    if self.membership.quorum(|id| votes.contains(id)) {
+                        self.start_election(now);
+                    }
+                }
+            }
+            RaftMessage::RequestVote {
+                term,
+                last_log_index,
+                last_log_term,
+            } => {
+                let granted = term == self.term
+                    && self.voted_for.as_deref().is_none_or(|voted| voted == from)
+                    && self.log.up_to_date(last_log_term, last_log_index);
+                if granted {
+                    self.set_state(self.term, Some(from.to_string()));
+                    self.election_deadline = now + self.timing.election_timeout();
+                }
+                let term = self.term;
+                self.send(from, RaftMessage::Vote { term, granted });
+            }
+            RaftMessage::Vote { term, granted } => {
+                let Role::Candidate { votes } = &mut self.role else {
+                    return;
+                };
+                if term == self.term && granted {
+                    votes.insert(from.to_string());
+                    if self.membership.quorum(|id| votes.contains(id)) {
+                        self.become_leader(now);
+                    }
+                }
+            }
+            append @ RaftMessage::AppendEntries { .. } => {
+                let reply = self.append_entries(from, append, now);
+                self.send(from, reply);
+            }
+            RaftMessage::AppendEntriesOk {
+                term,
+                success,
+                match_index,
+                next_index,
+                round,
+            } => {
+                if term != self.term {
+                    return;
+                }
+                let Role::Leader {
+                    next_index: next,
+                    match_index: matched,
+                    rounds,
+                    ..
+                } = &mut self.role
+                else {
+                    return;
+                };
+                let last = rounds.answered.entry(from.to_string()).or_default();
+                *last = (*last).max(round);
+                if success {
+                    let known = matched.entry(from.to_string()).or_default();
+                    *known = (*known).max(match_index);
+                    next.insert(from.to_string(), *known + 1);
+                    let behind = *known < self.log.last_index();
+                    self.advance_commit();
+                    if behind {
+                        self.send_append(from);
+                    }
+                } else {
+                    // Walk back to where the follower said our logs may
+                    // match, and repair it from there.
+                    let current = next.get(from).copied().unwrap_or(1);
+                    next.insert(from.to_string(), next_index.clamp(1, current));
+                    self.send_append(from);
+                }
+                self.confirm_reads();
+            }
+            install @ RaftMessage::InstallSnapshot { .. } => {
+                let reply = self.install_snapshot(from, install, now);
+                self.send(from, reply);
+            }
+            RaftMessage::InstallSnapshotOk {
+                term,
+                last_included_index,
+                offset,
+                done,
+            } => {
+                if term != self.term {
+                    return;
+                }
+                let Role::Leader {
+                    next_index,
+                    match_index,
+                    sending,
+                    ..
+                } = &mut self.role
+                else {
+                    return;
+                };
+                if !done {
+                    sending.insert(from.to_string(), (last_included_index, offset));
+                    self.send_append(from);
+                    return;
+                }
+                sending.remove(from);
+                let known = match_index.entry(from.to_string()).or_default();
+                *known = (*known).max(last_included_index);
+                next_index.insert(from.to_string(), *known + 1);
+                let behind = *known < self.log.last_index();
+                self.advance_commit();
+                if behind {
+                    self.send_append(from);
+                }
+            }
+        }
+    }
+
+    /// Compacts the log up to `index`, which must have been applied, into
+    /// `data`, the state machine's snapshot once it applied that entry.
+    pub fn compact(&mut self, index: u64, data: String) {
+        if index > self.last_applied || index <= self.log.snapshot_index() {
+            return;
+        }
+        let Some(term) = self.log.term_at(index) else {
+            return;
+        };
+        let (_, membership) = self.membership_at(index);
+        self.log.compact(index, term);
+        let snapshot = Snapshot {
+            index,
+            term,
+            membership,
+            data,
+        };
+        self.journal.push(Record::Snapshot {
+            snapshot: snapshot.clone(),
+        });
+        self.snapshot = Some(snapshot);
+    }
+
+    /// The snapshot the log was last compacted to, if any.
+    pub fn snapshot(&self) -> Option<&Snapshot> {
+        self.snapshot.as_ref()
+    }
+
+    /// The entries committed since the last call, in log order, with their
+    /// indexes.
+    pub fn take_committed(&mut self) -> Vec<(u64, Entry<C>)> {
+        let committed = (self.last_applied + 1..=self.commit_index)
+            .filter_map(|index| self.log.get(index).map(|entry| (index, entry.clone())))
+            .collect();
+        self.last_applied = self.last_applied.max(self.commit_index);
+        committed
+    }
+
+    /// A snapshot received from the leader since the last call, which
+    /// replaces the state machine. Entries committed after it come out of
+    /// [`take_committed`](Self::take_committed) as usual.
+    pub fn take_installed(&mut self) -> Option<Snapshot> {
+        self.installed.take()
+    }
+
+    /// The reads started with [`read_index`](Self::read_index) that may
+    /// be served since the last call.
+    pub fn take_reads(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.ready_reads)
+    }
+
+    /// The messages to send, in order.
+    pub fn take_outbox(&mut self) -> Vec<(NodeId, RaftMessage<C>)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// The changes to persist, in order, before sending the outbox.
+    pub fn take_journal(&mut self) -> Vec<Record<C>> {
+        std::mem::take(&mut self.journal)
+    }
+
+    /// The records that rebuild the current term, vote, snapshot and log
+    /// on their own, to replace a journal that compaction left behind.
+    pub fn records(&self) -> Vec<Record<C>> {
+        let mut records = vec![Record::State {
+            term: self.term,
+            voted_for: self.voted_for.clone(),
+        }];
+        if let Some(snapshot) = &self.snapshot {
+            records.push(Record::Snapshot {
+                snapshot: snapshot.clone(),
+            });
+        }
+        for index in self.log.snapshot_index() + 1..=self.log.last_index() {
+            if let Some(entry) = self.log.get(index) {
+                records.push(Record::Append {
+                    index,
+                    entry: entry.clone(),
+                });
+            }
+        }
+        records
+    }
+
+    /// Answers an `append_entries` from `from`.
+    fn append_entries(
+        &mut self,
+        from: &str,
+        append: RaftMessage<C>,
+        now: Instant,
+    ) -> RaftMessage<C> {
+        let RaftMessage::AppendEntries {
+            term,
+            mut prev_log_index,
+            mut prev_log_term,
+            mut entries,
+            leader_commit,
+            round,
+        } = append
+        else {
+            unreachable!("only called with append_entries");
+        };
+        let reject = |raft: &Self, next_index| RaftMessage::AppendEntriesOk {
+            term: raft.term,
+            success: false,
+            match_index: 0,
+            next_index,
+            round,
+        };
+        if term < self.term {
+            return reject(self, prev_log_index + 1);
+        }
+        if !matches!(self.role, Role::Follower) {
+            self.become_follower(term, self.voted_for.clone());
+        }
+        self.leader = Some(from.to_string());
+        self.heard_from_leader = now;
+        self.election_deadline = now + self.timing.election_timeout();
+
+        let compacted = self.log.snapshot_index();
+        if prev_log_index < compacted {
+            // What the snapshot covers is committed, so the leader holds
+            // the same: skip past it.
+            let skip = (compacted - prev_log_index) as usize;
+            entries.drain(..skip.min(entries.len()));
+            prev_log_index = compacted;
+            prev_log_term = self.log.term_at(compacted).unwrap_or_default();
+        }
+        match self.log.term_at(prev_log_index) {
+            None => return reject(self, self.log.last_index() + 1),
+            Some(found) if found != prev_log_term => {
+                let next_index = self.log.first_of_term(found, prev_log_index);
+                return reject(self, next_index);
+            }
+            Some(_) => {}
+        }
+        let match_index = prev_log_index + entries.len() as u64;
+        for (index, entry) in (prev_log_index + 1..).zip(entries) {
+            match self.log.term_at(index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    // A conflicting suffix never committed: replace it.
+                    self.truncate(index);
+                    self.append(entry);
+                }
+                None => {
+                    self.append(entry);
+                }
+            }
+        }
+        if leader_commit > self.commit_index {
+            // A delayed append may cover less of the log than this node
+            // already committed: never move the commit index back.
+            self.commit_index = self.commit_index.max(leader_commit.min(match_index));
+        }
+        RaftMessage::AppendEntriesOk {
+            term: self.term,
+            success: true,
+            match_index,
+            next_index: match_index + 1,
+            round,
+        }
+    }
+
+    /// Answers an `install_snapshot` from `from`, restoring the snapshot
+    /// once the last piece of it arrives.
+    fn install_snapshot(
+        &mut self,
+        from: &str,
+        install: RaftMessage<C>,
+        now: Instant,
+    ) -> RaftMessage<C> {
+        let RaftMessage::InstallSnapshot {
+            term,
+            last_included_index,
+            last_included_term,
+            membership,
+            offset,
+            data,
+            done,
+        } = install
+        else {
+            unreachable!("only called with install_snapshot");
+        };
+        let reply = |raft: &Self, offset, done| RaftMessage::InstallSnapshotOk {
+            term: raft.term,
+            last_included_index,
+            offset,
+            done,
+        };
+        if term < self.term {
+            return reply(self, 0, false);
+        }
+        if !matches!(self.role, Role::Follower) {
+            self.become_follower(term, self.voted_for.clone());
+        }
+        self.leader = Some(from.to_string());
+        self.heard_from_leader = now;
+        self.election_deadline = now + self.timing.election_timeout();
+        if last_included_index <= self.commit_index {
+            return reply(self, offset + data.len(), true);
+        }
+
+        let mut incoming = match self.incoming.take() {
+            Some(incoming) if incoming.index == last_included_index => incoming,
+            _ => Snapshot {
+                index: last_included_index,
+                term: last_included_term,
+                membership,
+                data: String::new(),
+            },
+        };
+        // Pieces arrive again or out of order when replies are lost: keep
+        // only the one that goes on from what is here.
+        let fits = offset == incoming.data.len();
+        if fits {
+            incoming.data.push_str(&data);
+        }
+        if !(fits && done) {
+            let received = incoming.data.len();
+            self.incoming = Some(incoming);
+            return reply(self, received, false);
+        }
+
+        let received = incoming.data.len();
+        self.log.compact(incoming.index, incoming.term);
+        self.commit_index = incoming.index;
+        self.last_applied = incoming.index;
+        self.journal.push(Record::Snapshot {
+            snapshot: incoming.clone(),
+        });
+        self.snapshot = Some(incoming.clone());
+        self.refresh_membership();
+        if !self.membership.is_witness(&self.me) {
+            self.installed = Some(incoming);
+        }
+        reply(self, received, true)
+    }
+
+    /// Asks every peer whether it would vote for this server in the next
+    /// term, standing right away if it needs no one else's vote.
+    fn start_pre_vote(&mut self, now: Instant) {
+        self.election_deadline = now + self.timing.election_timeout();
+        if !self.membership.contains(&self.me) || self.membership.is_witness(&self.me) {
+            // Removed, not added yet, a learner or a witness: only voters
+            // with the data stand.
+            return;
+        }
+        let votes = BTreeSet::from([self.me.clone()]);
+        if self.membership.quorum(|id| votes.contains(id)) {
+            self.start_election(now);
+            return;
+        }
+        self.role = Role::PreCandidate { votes };
+        let request = RaftMessage::RequestPreVote {
+            term: self.term + 1,
+            last_log_index: self.log.last_index(),
+            last_log_term: self.log.last_term(),
+        };
+        for peer in self.voting_peers() {
+            self.send(&peer, request.clone());
+        }
+    }
+
+    fn start_election(&mut self, now: Instant) {
+        self.election_deadline = now + self.timing.election_timeout();
+        self.set_state(self.term + 1, Some(self.me.clone()));
+        self.leader = None;
+        self.role = Role::Candidate {
+            votes: BTreeSet::from([self.me.clone()]),
+        };
+        if self.membership.quorum(|id| id == self.me) {
+            self.become_leader(now);
+            return;
+        }
+        let request = RaftMessage::RequestVote {
+            term: self.term,
+            last_log_index: self.log.last_index(),
+            last_log_term: self.log.last_term(),
+        };
+        for peer in self.voting_peers() {
+            self.send(&peer, request.clone());
+        }
+    }
+
+    fn become_follower(&mut self, term: u64, voted_for: Option<NodeId>) {
+        if term > self.term {
+            self.leader = None;
+        }
+        if matches!(self.role, Role::Leader { .. }) {
+            // Its deadline lapsed while it led: give the leader that
+            // deposed it a timeout to be heard from before campaigning.
+            self.election_deadline = self.now + self.timing.election_timeout();
+        }
+        self.set_state(term, voted_for);
+        self.role = Role::Follower;
+    }
+
+    /// Takes over and appends a no-op, which commits whatever earlier
+    /// terms left uncommitted once a majority has it.
+    fn become_leader(&mut self, now: Instant) {
+        let next = self.log.last_index() + 1;
+        let peers = self.peers();
+        self.role = Role::Leader {
+            next_index: peers.iter().map(|peer| (peer.clone(), next)).collect(),
+            match_index: peers.iter().map(|peer| (peer.clone(), 0)).collect(),
+            sending: HashMap::new(),
+            rounds: Box::default(),
+        };
+        self.leader = Some(self.me.clone());
+        self.append(Entry {
+            term: self.term,
+            command: Command::Noop,
+        });
+        self.advance_commit();
+        self.heartbeat_due = now + self.timing.heartbeat_interval;
+        self.broadcast_append();
+    }
+
+    /// Commits the highest entry of the current term a majority stores.
+    /// Entries of earlier terms commit along with it, never by counting.
+    fn advance_commit(&mut self) {
+        let Role::Leader { match_index, .. } = &self.role else {
+            return;
+        };
+        let mut index = self.log.last_index();
+        while index > self.commit_index && self.log.term_at(index) == Some(self.term) {
+            let stored = |id: &str| {
+                id == self.me || match_index.get(id).is_some_and(|&matched| matched >= index)
+            };
+            if self.membership.quorum(stored) {
+                self.commit_index = index;
+                self.finish_reconfiguration();
+                self.confirm_reads();
+                return;
+            }
+            index -= 1;
+        }
+    }
+
+    /// Moves a committed change on: from the joint membership to the new
+    /// voters alone, and once that commits, out of the lead if this server
+    /// is not among them.
+    fn finish_reconfiguration(&mut self) {
+        if self.membership_index > self.commit_index {
+            return;
+        }
+        match self.membership.next {
+            Some(_) => {
+                self.append(Entry {
+                    term: self.term,
+                    command: Command::Configure {
+                        membership: self.membership.settled(),
+                    },
+                });
+                self.advance_commit();
+                self.broadcast_append();
+            }
+            None if !self.membership.voters.contains(&self.me) => {
+                // Let the new voters learn the change committed first.
+                self.broadcast_append();
+                self.role = Role::Follower;
+                self.leader = None;
+            }
+            None => {}
+        }
+    }
+
+    /// Sends every peer what it is missing, or a heartbeat, as a new
+    /// round.
+    fn broadcast_append(&mut self) {
+        if let Role::Leader { rounds, .. } = &mut self.role {
+            rounds.round += 1;
+            rounds.sent.insert(rounds.round, self.now);
+        }
+        for peer in self.peers() {
+            self.send_append(&peer);
+        }
+    }
+
+    /// Takes the newest round a quorum answered as confirmed, extending
+    /// the lease from when it went out, and readies the reads waiting on
+    /// it once an entry of this term is committed.
+    fn confirm_reads(&mut self) {
+        let committed_in_term = self.log.term_at(self.commit_index) == Some(self.term);
+        let Role::Leader { rounds, .. } = &mut self.role else {
+            return;
+        };
+        let Rounds {
+            sent,
+            answered,
+            confirmed,
+            reads,
+            lease,
+            ..
+        } = rounds.as_mut();
+        let newest = sent.keys().rev().copied().find(|&round| {
+            self.membership.quorum(|id| {
+                id == self.me || answered.get(id).is_some_and(|&answer| answer >= round)
+            })
+        });
+        if let Some(round) = newest {
+            let at = sent[&round];
+            *sent = sent.split_off(&(round + 1));
+            *confirmed = round;
+            *lease = Some(at + self.timing.lease_duration());
+        }
+        if committed_in_term {
+            reads.retain(|&(id, round)| {
+                let ready = round <= *confirmed;
+                if ready {
+                    self.ready_reads.push(id);
+                }
+                !ready
+            });
+        }
+    }
+
+    /// Whether a leader may still hold a lease: this one, or the one this
+    /// server heard from less than the shortest election timeout ago.
+    fn leader_alive(&self, now: Instant) -> bool {
+        match &self.role {
+            Role::Leader { rounds, .. } => rounds.lease.is_some_and(|until| now < until),
+            Role::Follower | Role::PreCandidate { .. } => {
+                self.leader.is_some()
+                    && now < self.heard_from_leader + self.timing.election_timeout_min
+            }
+            Role::Candidate { .. } => false,
+        }
+    }
+
+    fn send_append(&mut self, peer: &str) {
+        let Role::Leader {
+            next_index, rounds, ..
+        } = &self.role
+        else {
+            return;
+        };
+        let round = rounds.round;
+        let next = next_index.get(peer).copied().unwrap_or(1);
+        let prev_log_index = next - 1;
+        let Some(prev_log_term) = self.log.term_at(prev_log_index) else {
+            // The entries the peer needs were compacted away.
+            self.send_snapshot(peer);
+            return;
+        };
+        let mut entries = self.log.slice(next, MAX_ENTRIES);
+        if self.membership.is_witness(peer) {
+            // Only the terms matter to a witness's votes.
+            for entry in &mut entries {
+                if let Command::Apply { .. } = entry.command {
+                    entry.command = Command::Noop;
+                }
+            }
+        }
+        let message = RaftMessage::AppendEntries {
+            term: self.term,
+            prev_log_index,
+            prev_log_term,
+            entries,
+            leader_commit: self.commit_index,
+            round,
+        };
+        self.send(peer, message);
+    }
+
+    fn set_state(&mut self, term: u64, voted_for: Option<NodeId>) {
+        if (term, &voted_for) == (self.term, &self.voted_for) {
+            return;
+        }
+        self.term = term;
+        self.voted_for = voted_for.clone();
+        self.journal.push(Record::State { term, voted_for });
+    }
+
+    fn append(&mut self, entry: Entry<C>) -> u64 {
+        let configures = matches!(entry.command, Command::Configure { .. });
+        let index = self.log.append(entry.clone());
+        self.journal.push(Record::Append { index, entry });
+        if configures {
+            self.refresh_membership();
+        }
+        index
+    }
+
+    fn truncate(&mut self, index: u64) {
+        self.log.truncate(index);
+        self.journal.push(Record::Truncate { index });
+        if self.membership_index >= index {
+            self.refresh_membership();
+        }
+    }
+
+    /// Goes by the latest membership in the log, and as leader starts
+    /// replicating to the servers it adds.
+    fn refresh_membership(&mut self) {
+        (self.membership_index, self.membership) = self.membership_at(self.log.last_index());
+        let (_, previous) = self.membership_at(self.membership_index.saturating_sub(1));
+        self.leaving = &previous.members() - &self.membership.members();
+        let next = self.log.last_index() + 1;
+        let peers = self.peers();
+        if let Role::Leader {
+            next_index,
+            match_index,
+            ..
+        } = &mut self.role
+        {
+            for peer in peers {
+                next_index.entry(peer.clone()).or_insert(next);
+                match_index.entry(peer).or_insert(0);
+            }
+        }
+    }
+
+    /// The membership in force at `index`, with the index it comes from.
+    fn membership_at(&self, index: u64) -> (u64, Membership) {
+        for at in (self.log.snapshot_index() + 1..=index).rev() {
+            if let Some(Entry {
+                command: Command::Configure { membership },
+                ..
+            }) = self.log.get(at)
+            {
+                return (at, membership.clone());
+            }
+        }
+        match &self.snapshot {
+            Some(snapshot) => (snapshot.index, snapshot.membership.clone()),
+            None => (0, self.initial.clone()),
+        }
+    }
+
+    /// Every other member, voting now or about to, and those leaving
+    /// until they are gone for good.
+    fn peers(&self) -> Vec<NodeId> {
+        let mut peers = self.membership.members();
+        if self.membership_index > self.commit_index {
+            peers.extend(self.leaving.iter().cloned());
+        }
+        peers.remove(&self.me);
+        peers.into_iter().collect()
+    }
+
+    /// The peers whose votes count.
+    fn voting_peers(&self) -> Vec<NodeId> {
+        let mut peers = self.peers();
+        peers.retain(|peer| self.membership.contains(peer));
+        peers
+    }
+
+    /// Sends `peer` the next piece of the snapshot.
+    fn send_snapshot(&mut self, peer: &str) {
+        let (Some(snapshot), Role::Leader { sending, .. }) = (&self.snapshot, &self.role) else {
+            return;
+        };
+        let offset = match sending.get(peer) {
+            Some(&(index, offset)) if index == snapshot.index => offset.min(snapshot.data.len()),
+            _ => 0,
+        };
+        // A witness gets where the log starts and none of the data.
+        let data = match self.membership.is_witness(peer) {
+            true => "",
+            false => &snapshot.data,
+        };
+        let offset = offset.min(data.len());
+        let mut end = (offset + SNAPSHOT_CHUNK).min(data.len());
+        while !data.is_char_boundary(end) {
+            end -= 1;
+        }
+        let message = RaftMessage::InstallSnapshot {
+            term: self.term,
+            last_included_index: snapshot.index,
+            last_included_term: snapshot.term,
+            membership: snapshot.membership.clone(),
+            offset,
+            data: data[offset..end].to_string(),
+            done: end == data.len(),
+        };
+        self.send(peer, message);
+    }
+
+    fn send(&mut self, to: &str, message: RaftMessage<C>) {
+        self.outbox.push((to.to_string(), message));
+    }
+}
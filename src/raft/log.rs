@@ -0,0 +1,152 @@
+use serde::{Deserialize, Serialize};
+
+use crate::raft::membership::Membership;
+
+/// What an entry asks the state machine to do.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Command<C> {
+    /// Appended by a new leader, so entries of earlier terms commit with
+    /// it; the state machine never sees it.
+    Noop,
+    Apply {
+        command: C,
+    },
+    /// Changes who votes, from the moment a server appends it; the state
+    /// machine never sees it either.
+    Configure {
+        membership: Membership,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Entry<C> {
+    pub term: u64,
+    pub command: Command<C>,
+}
+
+/// The replicated log. Indexes start at 1; index 0 stands for the empty
+/// prefix, at term 0.
+///
+/// Once compacted, the log starts after the last entry a snapshot covers,
+/// and remembers that entry's index and term in its place.
+#[derive(Clone, Debug)]
+pub struct Log<C> {
+    snapshot_index: u64,
+    snapshot_term: u64,
+    entries: Vec<Entry<C>>,
+}
+
+impl<C> Default for Log<C> {
+    fn default() -> Self {
+        Self {
+            snapshot_index: 0,
+            snapshot_term: 0,
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl<C: Clone> Log<C> {
+    pub fn last_index(&self) -> u64 {
+        self.snapshot_index + self.entries.len() as u64
+    }
+
+    pub fn last_term(&self) -> u64 {
+        self.entries
+            .last()
+            .map_or(self.snapshot_term, |entry| entry.term)
+    }
+
+    /// The index of the last entry compacted into a snapshot, 0 if none is.
+    pub fn snapshot_index(&self) -> u64 {
+        self.snapshot_index
+    }
+
+    /// The term of the entry at `index`, if the log reaches it and it was
+    /// not compacted away.
+    pub fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            return Some(self.snapshot_term);
+        }
+        self.get(index).map(|entry| entry.term)
+    }
+
+    pub fn get(&self, index: u64) -> Option<&Entry<C>> {
+        self.position(index)
+            .and_then(|position| self.entries.get(position))
+    }
+
+    /// Up to `limit` entries from `index` on, which must not be compacted.
+    pub fn slice(&self, index: u64, limit: usize) -> Vec<Entry<C>> {
+        let start = self.position(index.max(1)).unwrap_or_default();
+        self.entries
+            .iter()
+            .skip(start)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Appends an entry, returning its index.
+    pub fn append(&mut self, entry: Entry<C>) -> u64 {
+        self.entries.push(entry);
+        self.last_index()
+    }
+
+    /// Drops the entry at `index` and every one after it.
+    pub fn truncate(&mut self, index: u64) {
+        let keep = index.max(self.snapshot_index + 1) - self.snapshot_index - 1;
+        self.entries.truncate(keep as usize);
+    }
+
+    /// Drops the entries up to `index`, which a snapshot taken at `term`
+    /// now covers. Entries after it stay if the log holds `index` at
+    /// `term`; otherwise they disagree with the snapshot and go too.
+    pub fn compact(&mut self, index: u64, term: u64) {
+        if index <= self.snapshot_index {
+            return;
+        }
+        if self.term_at(index) == Some(term) {
+            let drop = (index - self.snapshot_index) as usize;
+            self.entries.drain(..drop);
+        } else {
+            self.entries.clear();
+        }
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+    }
+
+    /// The first index holding an entry of `term`, at or before `index`.
+    pub fn first_of_term(&self, term: u64, index: u64) -> u64 {
+        let mut first = index;
+        while first > self.snapshot_index + 1 && self.term_at(first - 1) == Some(term) {
+            first -= 1;
+        }
+        first
+    }
+
+    /// Whether a log ending at `(last_term, last_index)` is at least as up
+    /// to date as this one, so its owner may get our vote.
+    pub fn up_to_date(&self, last_term: u64, last_index: u64) -> bool {
+        (last_term, last_index) >= (self.last_term(), self.last_index())
+    }
+
+    /// Where the entry at `index` sits in `entries`, if after the snapshot.
+    fn position(&self, index: u64) -> Option<usize> {
+        index
+            .checked_sub(self.snapshot_index + 1)
+            .map(|position| position as usize)
+    }
+}
+
+/// A state machine's state once it applied every entry up to `index`, in
+/// the JSON the state machine snapshots itself to, with the membership in
+/// force at that entry.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    pub index: u64,
+    pub term: u64,
+    pub membership: Membership,
+    pub data: String,
+}
@@ -0,0 +1,119 @@
+use std::collections::BTreeSet;
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+use crate::message::NodeId;
+
+/// The servers whose votes count. While `next` is set the cluster is moving
+/// from `voters` to `next`, and every election and commitment needs a
+/// majority of both, so neither side can decide anything alone.
+///
+/// Learners are sent the log like voters but do not vote, so they can
+/// catch up before they are made voters, or serve as read replicas.
+/// Witnesses vote and acknowledge entries like any voter, but are sent
+/// entries without their commands and never stand, so they hold no data.
+/// A witness stays one for good: its log could not be led from.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Membership {
+    pub voters: BTreeSet<NodeId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next: Option<BTreeSet<NodeId>>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub learners: BTreeSet<NodeId>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub witnesses: BTreeSet<NodeId>,
+}
+
+impl Membership {
+    pub fn new(voters: impl IntoIterator<Item = NodeId>) -> Self {
+        Self {
+            voters: voters.into_iter().collect(),
+            next: None,
+            learners: BTreeSet::new(),
+            witnesses: BTreeSet::new(),
+        }
+    }
+
+    /// The servers in `nodes`, all voting but the `learners`, with the
+    /// `witnesses` among the voters.
+    pub fn with_roles(
+        nodes: &[NodeId],
+        learners: BTreeSet<NodeId>,
+        witnesses: BTreeSet<NodeId>,
+    ) -> anyhow::Result<Self> {
+        if let Some(stranger) = learners.union(&witnesses).find(|id| !nodes.contains(id)) {
+            bail!("{stranger} is not in the cluster");
+        }
+        if let Some(both) = learners.intersection(&witnesses).next() {
+            bail!("{both} cannot be both a learner and a witness");
+        }
+        let voters: BTreeSet<NodeId> = nodes
+            .iter()
+            .filter(|id| !learners.contains(*id))
+            .cloned()
+            .collect();
+        if voters.is_subset(&witnesses) {
+            bail!("at least one voter must not be a witness");
+        }
+        Ok(Self {
+            voters,
+            next: None,
+            learners,
+            witnesses,
+        })
+    }
+
+    /// Starts moving to `voters`. Learners among them will vote; the
+    /// others stay learners.
+    pub fn moving_to(&self, voters: BTreeSet<NodeId>) -> Self {
+        Self {
+            voters: self.voters.clone(),
+            learners: &self.learners - &voters,
+            next: Some(voters),
+            witnesses: self.witnesses.clone(),
+        }
+    }
+
+    /// The membership once the change under way is done.
+    pub fn settled(&self) -> Self {
+        Self {
+            voters: self.next.clone().unwrap_or_else(|| self.voters.clone()),
+            next: None,
+            learners: self.learners.clone(),
+            witnesses: self.witnesses.clone(),
+        }
+    }
+
+    /// Whether a change is under way.
+    pub fn is_joint(&self) -> bool {
+        self.next.is_some()
+    }
+
+    /// Every server in either configuration, and the learners.
+    pub fn members(&self) -> BTreeSet<NodeId> {
+        let mut members = self.voters.clone();
+        members.extend(self.next.iter().flatten().cloned());
+        members.extend(self.learners.iter().cloned());
+        members
+    }
+
+    pub fn is_witness(&self, id: &str) -> bool {
+        self.witnesses.contains(id)
+    }
+
+    /// Whether `id` votes, in either configuration.
+    pub fn contains(&self, id: &str) -> bool {
+        self.voters.contains(id) || self.next.as_ref().is_some_and(|next| next.contains(id))
+    }
+
+    /// Whether the servers `agree` picks form a majority of each
+    /// configuration.
+    pub fn quorum(&self, agree: impl Fn(&str) -> bool) -> bool {
+        let majority = |servers: &BTreeSet<NodeId>| {
+            let agreeing = servers.iter().filter(|id| agree(id)).count();
+            agreeing > servers.len() / 2
+        };
+        majority(&self.voters) && self.next.as_ref().is_none_or(majority)
+    }
+}
@@ -0,0 +1,38 @@
+//! Raft consensus: a replicated log that a majority of nodes agrees on,
+//! feeding the same commands in the same order to a state machine on every
+//! node.
+//!
+//! Servers elect a leader per term with randomized election timeouts. The
+//! leader appends client commands to its log and replicates them with
+//! `append_entries`, which carries the index and term of the entry before
+//! them: a follower whose log does not hold that entry refuses, and the
+//! leader walks back until the logs match, overwriting whatever the
+//! follower had past that point. An entry is committed once a majority
+//! stores it and it belongs to the leader's current term, and every server
+//! applies committed entries in index order.
+//!
+//! [`Raft`] is the protocol alone, without I/O or threads; [`RaftServer`]
+//! mounts it on a node as a [`Consensus`](crate::consensus::Consensus)
+//! implementation, applying committed commands to a
+//! [`StateMachine`](crate::consensus::StateMachine). With `--raft-dir`
+//! set, the server keeps its term, vote and log in a [`Storage`] file
+//! there, synced before any message that depends on them goes out, so a
+//! restarted node never votes twice in a term or forgets an entry it
+//! acknowledged.
+//!
+//! The log does not grow forever: the server snapshots its state machine
+//! every so often and drops the entries the snapshot covers. A follower that
+//! falls behind what the leader still has gets the snapshot instead, in
+//! `install_snapshot` pieces small enough for one Maelstrom message each.
+
+mod core;
+mod log;
+mod membership;
+mod server;
+mod storage;
+
+pub use self::core::{Raft, RaftMessage, Timing};
+pub use self::log::{Command, Entry, Log, Snapshot};
+pub use self::membership::Membership;
+pub use self::server::RaftServer;
+pub use self::storage::{Durable, Record, Storage};
@@ -0,0 +1,366 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::Path,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::{
+    config::Config,
+    consensus::{Consensus, NotLeader, StateMachine},
+    message::NodeId,
+    raft::{
+        core::{Raft, RaftMessage, Timing},
+        log::{Command, Entry, Snapshot},
+        storage::{Record, Storage},
+    },
+    runtime::Runtime,
+};
+
+/// How often timers are checked.
+const TICK: Duration = Duration::from_millis(10);
+/// How many entries are applied between snapshots of the state machine.
+const SNAPSHOT_EVERY: u64 = 1000;
+
+/// Waiters for proposed entries, by index, with the term they were
+/// proposed in.
+type Waiters<O> = HashMap<u64, (u64, Sender<O>)>;
+
+/// Waiters for membership changes, with the voters each moves to.
+type Changes = Vec<(BTreeSet<NodeId>, Sender<()>)>;
+
+/// Waiters for reads, by the id Raft gave them.
+type Reads = HashMap<u64, Sender<()>>;
+
+/// What the applier thread is handed, in order.
+enum Apply<C> {
+    /// Committed entries, with their indexes.
+    Entries(Vec<(u64, Entry<C>)>),
+    /// A snapshot from the leader that replaces the state machine.
+    Snapshot(Snapshot),
+    /// Reads that may be served once what came before is applied.
+    Reads(Vec<Sender<()>>),
+}
+
+/// Sends a Raft message to a peer, wrapped in the workload's message.
+type Transport<C> = Arc<dyn Fn(&str, RaftMessage<C>) + Send + Sync>;
+
+/// A [`Raft`] server mounted on a node: timers run on their own thread,
+/// messages go out through the runtime, and committed entries travel down
+/// an apply channel to a thread that feeds them to the state machine.
+/// Every [`SNAPSHOT_EVERY`] entries that thread snapshots the state machine
+/// and compacts the log into it. Clones share the server.
+pub struct RaftServer<S: StateMachine> {
+    raft: Arc<Mutex<Raft<S::Command>>>,
+    machine: Arc<Mutex<S>>,
+    waiters: Arc<Mutex<Waiters<S::Output>>>,
+    changes: Arc<Mutex<Changes>>,
+    reads: Arc<Mutex<Reads>>,
+    applier: Sender<Apply<S::Command>>,
+    send: Transport<S::Command>,
+    storage: Option<Arc<Mutex<Storage>>>,
+}
+
+impl<S: StateMachine> Clone for RaftServer<S> {
+    fn clone(&self) -> Self {
+        Self {
+            raft: self.raft.clone(),
+            machine: self.machine.clone(),
+            waiters: self.waiters.clone(),
+            changes: self.changes.clone(),
+            reads: self.reads.clone(),
+            applier: self.applier.clone(),
+            send: self.send.clone(),
+            storage: self.storage.clone(),
+        }
+    }
+}
+
+impl<S: StateMachine> RaftServer<S> {
+    pub fn is_leader(&self) -> bool {
+        self.raft.lock().unwrap().is_leader()
+    }
+
+    /// Runs `f` on the Raft state, then persists what it changed, sends
+    /// what it queued and hands what it installed and committed to the
+    /// applier, under the same lock so all keep their order.
+    ///
+    /// If the changes cannot be made durable the process exits before any
+    /// message goes out: a vote or acknowledgement the disk does not back
+    /// could be contradicted later, while a restart recovers from whatever
+    /// did reach the disk.
+    fn with_raft<R>(&self, f: impl FnOnce(&mut Raft<S::Command>) -> R) -> R {
+        let mut raft = self.raft.lock().unwrap();
+        let result = f(&mut raft);
+        let journal = raft.take_journal();
+        if let Some(storage) = &self.storage {
+            let mut storage = storage.lock().unwrap();
+            let compacted = journal
+                .iter()
+                .any(|record| matches!(record, Record::Snapshot { .. }));
+            let written = if compacted {
+                storage.rewrite(&raft.records())
+            } else {
+                storage.write(&journal)
+            };
+            if let Err(err) = written {
+                eprintln!("persisting raft state failed: {err:#}");
+                std::process::exit(1);
+            }
+        }
+        for (to, message) in raft.take_outbox() {
+            (self.send)(&to, message);
+        }
+        // The applier only stops with the process.
+        if let Some(snapshot) = raft.take_installed() {
+            let _ = self.applier.send(Apply::Snapshot(snapshot));
+        }
+        let committed = raft.take_committed();
+        if !committed.is_empty() {
+            let _ = self.applier.send(Apply::Entries(committed));
+        }
+        let mut reads = self.reads.lock().unwrap();
+        let ready: Vec<_> = raft
+            .take_reads()
+            .iter()
+            .filter_map(|id| reads.remove(id))
+            .collect();
+        if !ready.is_empty() {
+            let _ = self.applier.send(Apply::Reads(ready));
+        }
+        if !raft.is_leader() {
+            // Reads still waiting never will be.
+            reads.clear();
+        }
+        result
+    }
+
+    fn spawn_applier(&self, applies: Receiver<Apply<S::Command>>, mut snapshot_index: u64) {
+        let server = self.clone();
+        std::thread::spawn(move || {
+            for apply in applies {
+                match apply {
+                    Apply::Entries(entries) => {
+                        let Some(&(last, _)) = entries.last() else {
+                            continue;
+                        };
+                        for (index, entry) in entries {
+                            server.apply(index, entry);
+                        }
+                        if last - snapshot_index >= SNAPSHOT_EVERY {
+                            server.compact(last);
+                            snapshot_index = last;
+                        }
+                    }
+                    Apply::Reads(ready) => {
+                        for done in ready {
+                            // The reader may have given up waiting.
+                            let _ = done.send(());
+                        }
+                    }
+                    Apply::Snapshot(snapshot) => {
+                        match restore(&snapshot) {
+                            Ok(machine) => *server.machine.lock().unwrap() = machine,
+                            Err(err) => eprintln!("restoring a raft snapshot failed: {err:#}"),
+                        }
+                        // What was proposed before it is settled, one way
+                        // or the other, and can no longer be answered.
+                        let mut waiters = server.waiters.lock().unwrap();
+                        waiters.retain(|&index, _| index > snapshot.index);
+                        snapshot_index = snapshot.index;
+                    }
+                }
+            }
+        });
+    }
+
+    fn apply(&self, index: u64, entry: Entry<S::Command>) {
+        let output = match entry.command {
+            Command::Noop => None,
+            Command::Apply { command } => Some(self.machine.lock().unwrap().apply(command)),
+            Command::Configure { membership } => {
+                if !membership.is_joint() {
+                    let mut changes = self.changes.lock().unwrap();
+                    changes.retain(|(voters, done)| {
+                        // The admin may have given up waiting.
+                        let finished = *voters == membership.voters;
+                        if finished {
+                            let _ = done.send(());
+                        }
+                        !finished
+                    });
+                }
+                None
+            }
+        };
+        let waiter = self.waiters.lock().unwrap().remove(&index);
+        if let (Some((term, done)), Some(output)) = (waiter, output) {
+            if term == entry.term {
+                // The proposer may have given up waiting.
+                let _ = done.send(output);
+            }
+        }
+    }
+
+    /// Compacts the log up to `index`, the last entry applied.
+    fn compact(&self, index: u64) {
+        let data = serde_json::to_string(&self.machine.lock().unwrap().snapshot());
+        match data {
+            Ok(data) => self.with_raft(|raft| raft.compact(index, data)),
+            Err(err) => eprintln!("snapshotting the state machine failed: {err:#}"),
+        }
+    }
+}
+
+impl<S: StateMachine> Consensus<S> for RaftServer<S> {
+    type Message = RaftMessage<S::Command>;
+
+    /// With `--raft-dir` set, the server first recovers what an earlier run
+    /// of this node stored there, and fails to start if that is corrupt;
+    /// `--wal-sync` sets when its writes reach the disk.
+    /// With `--raft-leases true` the leader serves reads on its lease.
+    /// `--raft-learners` and `--raft-witnesses`, comma-separated node ids,
+    /// start those nodes as learners and witnesses.
+    /// `--raft-election-timeout-min`, `--raft-election-timeout-max` and
+    /// `--raft-heartbeat-interval`, in milliseconds, override the
+    /// [`Timing`] defaults, and the server fails to start if they do not
+    /// fit together.
+    fn mount<P, F>(runtime: Runtime, config: &Config, machine: S, wrap: F) -> anyhow::Result<Self>
+    where
+        P: Serialize,
+        F: Fn(Self::Message) -> P + Send + Sync + 'static,
+    {
+        let (me, node_ids, now) = (runtime.node_id(), runtime.node_ids(), Instant::now());
+        let (raft, storage) = match config.get("raft-dir") {
+            Some(dir) => {
+                let path = Path::new(dir).join(format!("{me}.raft"));
+                let sync = config.parse("wal-sync")?.unwrap_or_default();
+                let started = Instant::now();
+                let (storage, durable) =
+                    Storage::open(&path, sync).context("recovering the raft state")?;
+                eprintln!(
+                    "{me}: recovered raft term {}, vote {:?}, snapshot through {} \
+                     and log through {}, in {:?}",
+                    durable.term,
+                    durable.voted_for,
+                    durable.log.snapshot_index(),
+                    durable.log.last_index(),
+                    started.elapsed()
+                );
+                (Raft::recover(me, node_ids, now, durable), Some(storage))
+            }
+            None => (Raft::new(me, node_ids, now), None),
+        };
+        let learners = config.list("raft-learners").into_iter().collect();
+        let witnesses = config.list("raft-witnesses").into_iter().collect();
+        let raft = raft
+            .with_roles(node_ids, learners, witnesses)
+            .context("invalid raft roles")?;
+        let mut machine = machine;
+        match raft.snapshot() {
+            // A witness's snapshots only say where its log starts.
+            Some(_) if raft.membership().is_witness(me) => {}
+            Some(snapshot) => {
+                machine = restore(snapshot).context("restoring the raft snapshot")?;
+            }
+            None => {}
+        }
+        let default = Timing::default();
+        let timing = Timing::new(
+            config
+                .millis("raft-election-timeout-min")?
+                .unwrap_or(default.election_timeout_min()),
+            config
+                .millis("raft-election-timeout-max")?
+                .unwrap_or(default.election_timeout_max()),
+            config
+                .millis("raft-heartbeat-interval")?
+                .unwrap_or(default.heartbeat_interval()),
+        )
+        .context("invalid raft timing")?;
+        let raft = raft
+            .with_leases(config.parse("raft-leases")?.unwrap_or_default())
+            .with_timing(timing);
+        let snapshot_index = raft.log().snapshot_index();
+        let (applier, committed) = mpsc::channel();
+        let send_runtime = runtime.clone();
+        let server = Self {
+            raft: Arc::new(Mutex::new(raft)),
+            machine: Arc::new(Mutex::new(machine)),
+            waiters: Arc::new(Mutex::new(HashMap::new())),
+            changes: Arc::new(Mutex::new(Vec::new())),
+            reads: Arc::new(Mutex::new(HashMap::new())),
+            applier,
+            send: Arc::new(move |to, message| {
+                if let Err(err) = send_runtime.send(to, wrap(message)) {
+                    eprintln!("raft send to {to} failed: {err:#}");
+                }
+            }),
+            storage: storage.map(|storage| Arc::new(Mutex::new(storage))),
+        };
+        server.spawn_applier(committed, snapshot_index);
+        let ticker = server.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(TICK);
+            ticker.with_raft(|raft| raft.tick(Instant::now()));
+        });
+        Ok(server)
+    }
+
+    fn receive(&self, from: &str, message: Self::Message) {
+        self.with_raft(|raft| raft.handle(from, message, Instant::now()));
+    }
+
+    fn propose(&self, command: S::Command) -> Result<Receiver<S::Output>, NotLeader> {
+        let (done, output) = mpsc::channel();
+        self.with_raft(|raft| match raft.propose(command) {
+            Some((index, term)) => {
+                self.waiters.lock().unwrap().insert(index, (term, done));
+                Ok(output)
+            }
+            None => Err(NotLeader {
+                leader: raft.leader().map(str::to_string),
+            }),
+        })
+    }
+
+    fn reconfigure(&self, voters: BTreeSet<NodeId>) -> anyhow::Result<Receiver<()>> {
+        let (done, finished) = mpsc::channel();
+        self.with_raft(|raft| {
+            if !raft.is_leader() {
+                let leader = raft.leader().map(str::to_string);
+                return Err(NotLeader { leader }.into());
+            }
+            raft.reconfigure(voters.clone())?;
+            self.changes.lock().unwrap().push((voters, done));
+            Ok(finished)
+        })
+    }
+
+    fn read_index(&self) -> Result<Receiver<()>, NotLeader> {
+        let (done, ready) = mpsc::channel();
+        self.with_raft(|raft| match raft.read_index(Instant::now()) {
+            Some(id) => {
+                self.reads.lock().unwrap().insert(id, done);
+                Ok(ready)
+            }
+            None => Err(NotLeader {
+                leader: raft.leader().map(str::to_string),
+            }),
+        })
+    }
+
+    fn read<R>(&self, f: impl FnOnce(&S) -> R) -> R {
+        f(&self.machine.lock().unwrap())
+    }
+}
+
+fn restore<S: StateMachine>(snapshot: &Snapshot) -> anyhow::Result<S> {
+    Ok(S::restore(serde_json::from_str(&snapshot.data)?))
+}
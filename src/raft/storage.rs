@@ -0,0 +1,109 @@
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    message::NodeId,
+    raft::log::{Entry, Log, Snapshot},
+    storage::{SyncPolicy, Wal},
+};
+
+/// A change to the state a Raft server must not forget across restarts.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "record", rename_all = "snake_case")]
+pub enum Record<C> {
+    /// The current term and the vote cast in it.
+    State {
+        term: u64,
+        voted_for: Option<NodeId>,
+    },
+    Append {
+        index: u64,
+        entry: Entry<C>,
+    },
+    /// Drops the entry at `index` and every one after it.
+    Truncate {
+        index: u64,
+    },
+    /// Compacts the log into `snapshot`.
+    Snapshot {
+        snapshot: Snapshot,
+    },
+}
+
+/// What a server recovers on startup.
+#[derive(Clone, Debug)]
+pub struct Durable<C> {
+    pub term: u64,
+    pub voted_for: Option<NodeId>,
+    pub snapshot: Option<Snapshot>,
+    pub log: Log<C>,
+}
+
+impl<C> Default for Durable<C> {
+    fn default() -> Self {
+        Self {
+            term: 0,
+            voted_for: None,
+            snapshot: None,
+            log: Log::default(),
+        }
+    }
+}
+
+/// A [`Wal`] of [`Record`]s, replayed on open. With the default
+/// [`SyncPolicy`] each [`write`](Self::write) is on disk before it returns.
+///
+/// Compaction leaves the records before a snapshot behind, so the file is
+/// then [`rewrite`](Self::rewrite)n from the current state instead.
+pub struct Storage {
+    wal: Wal,
+}
+
+impl Storage {
+    pub fn open<C>(path: &Path, sync: SyncPolicy) -> anyhow::Result<(Self, Durable<C>)>
+    where
+        C: Clone + DeserializeOwned,
+    {
+        let (wal, records) = Wal::open(path, sync)?;
+        let mut durable = Durable::default();
+        for (number, record) in records.enumerate() {
+            replay(&mut durable, record?)
+                .with_context(|| format!("{} record {}", path.display(), number + 1))?;
+        }
+        Ok((Self { wal }, durable))
+    }
+
+    /// Appends `records`, synced as the policy says.
+    pub fn write<C: Serialize>(&mut self, records: &[Record<C>]) -> anyhow::Result<()> {
+        self.wal.append(records)
+    }
+
+    /// Replaces the file with `records`; see [`Wal::rewrite`].
+    pub fn rewrite<C: Serialize>(&mut self, records: &[Record<C>]) -> anyhow::Result<()> {
+        self.wal.rewrite(records)
+    }
+}
+
+fn replay<C: Clone>(durable: &mut Durable<C>, record: Record<C>) -> anyhow::Result<()> {
+    match record {
+        Record::State { term, voted_for } => {
+            durable.term = term;
+            durable.voted_for = voted_for;
+        }
+        Record::Append { index, entry } => {
+            let expected = durable.log.last_index() + 1;
+            if index != expected {
+                bail!("entry {index} appended where {expected} was expected");
+            }
+            durable.log.append(entry);
+        }
+        Record::Truncate { index } => durable.log.truncate(index),
+        Record::Snapshot { snapshot } => {
+            durable.log.compact(snapshot.index, snapshot.term);
+            durable.snapshot = Some(snapshot);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,148 @@
+//! Rate limiter: `acquire {key, tokens}` takes `tokens` from `key`'s token
+//! bucket and answers whether they were granted.
+//!
+//! Each bucket is a PN-counter of the tokens in use, replicated with the same
+//! delta gossip as the PN-counter workload, so every node can answer for
+//! every key. Granting adds to the counter and refilling subtracts from it.
+//! A bucket holds `--rate-limit-capacity` tokens (default 10) and refills
+//! at `--rate-limit-refill` tokens per second (default 10), whichever nodes
+//! granted them: each key's owner on a [`Ring`] of the nodes refills the
+//! whole bucket, at most down to empty as far as it has heard. While the
+//! owner is cut off from the others, their grants of the key do not refill.
+//!
+//! Like any gossiped state this is eventually consistent: nodes granting at
+//! the same time can hand out more than the capacity until they hear from
+//! each other. Deltas go out every `--rate-limit-gossip` milliseconds
+//! (default 100); the ones lost are repaired by Merkle anti-entropy with a
+//! random peer every second, which only ships the buckets that differ.
+
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    crdt::{CrdtMap, PnCounter},
+    gossip::Replicated,
+    merkle::SyncStep,
+    message::{error_code, Init, Message},
+    ring::Ring,
+    runtime::{Node, Runtime},
+};
+
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(100);
+const REFILL_INTERVAL: Duration = Duration::from_millis(100);
+const ANTI_ENTROPY_INTERVAL: Duration = Duration::from_millis(1000);
+
+/// Tokens in use per key.
+pub type Buckets = CrdtMap<PnCounter>;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Acquire { key: String, tokens: u64 },
+    AcquireOk { granted: bool },
+    Replicate { buckets: Buckets },
+    Sync { sync: SyncStep<PnCounter> },
+    SyncOk { sync: SyncStep<PnCounter> },
+}
+
+pub struct RateLimitNode {
+    runtime: Runtime,
+    buckets: Replicated<Buckets>,
+    capacity: u64,
+}
+
+impl Node<Payload> for RateLimitNode {
+    fn from_init(runtime: Runtime, _init: Init) -> anyhow::Result<Self> {
+        let config = runtime.config().clone();
+        let capacity = config.parse("rate-limit-capacity")?.unwrap_or(10);
+        let refill: f64 = config.parse("rate-limit-refill")?.unwrap_or(10.0);
+        let gossip = config
+            .millis("rate-limit-gossip")?
+            .unwrap_or(GOSSIP_INTERVAL);
+        let buckets = Replicated::new(Buckets::default());
+        buckets.spawn_delta_gossip(runtime.clone(), gossip, |buckets| Payload::Replicate {
+            buckets,
+        });
+        buckets.spawn_anti_entropy(runtime.clone(), ANTI_ENTROPY_INTERVAL, |sync| {
+            Payload::Sync { sync }
+        });
+        let ring = Ring::new(runtime.node_ids())?;
+        spawn_refill(runtime.clone(), ring, buckets.clone(), refill);
+        Ok(Self {
+            runtime,
+            buckets,
+            capacity,
+        })
+    }
+
+    fn step(&mut self, input: Message<Payload>) -> anyhow::Result<()> {
+        match input.body.payload {
+            Payload::Acquire { ref key, tokens } => {
+                let Ok(delta) = i64::try_from(tokens) else {
+                    let text = format!("cannot count {tokens} tokens");
+                    self.runtime
+                        .reply_error(&input, error_code::MALFORMED_REQUEST, text)?;
+                    return Ok(());
+                };
+                let node_id = self.runtime.node_id();
+                let granted = self.buckets.update(|buckets| {
+                    let counter = buckets.entry(key);
+                    let in_use = counter.value().max(0) as u64;
+                    let granted = in_use
+                        .checked_add(tokens)
+                        .is_some_and(|total| total <= self.capacity);
+                    if granted {
+                        counter.apply(node_id, delta);
+                    }
+                    granted
+                });
+                self.runtime.reply(&input, Payload::AcquireOk { granted })?;
+            }
+            Payload::Replicate { buckets } => {
+                self.buckets.merge(buckets);
+            }
+            Payload::Sync { ref sync } => {
+                if let Some(sync) = self.buckets.sync(sync.clone()) {
+                    self.runtime.reply(&input, Payload::SyncOk { sync })?;
+                }
+            }
+            Payload::AcquireOk { .. } | Payload::SyncOk { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+/// Gives back up to `per_second` tokens to each bucket this node owns on
+/// `ring`.
+fn spawn_refill(runtime: Runtime, ring: Ring, buckets: Replicated<Buckets>, per_second: f64) {
+    std::thread::spawn(move || {
+        let node_id = runtime.node_id();
+        let mut last = Instant::now();
+        // Fractions of a token owed by earlier rounds.
+        let mut carry = 0.0;
+        loop {
+            std::thread::sleep(REFILL_INTERVAL);
+            let now = Instant::now();
+            carry += per_second * now.duration_since(last).as_secs_f64();
+            last = now;
+            let whole = carry.floor();
+            if whole < 1.0 {
+                continue;
+            }
+            carry -= whole;
+            buckets.update(|buckets| {
+                for (key, counter) in buckets.iter_mut() {
+                    if ring.owner(key.as_str()) != node_id {
+                        continue;
+                    }
+                    let refund = counter.value().min(whole as i64);
+                    if refund > 0 {
+                        counter.apply(node_id, -refund);
+                    }
+                }
+            });
+        }
+    });
+}
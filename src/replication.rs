@@ -0,0 +1,271 @@
+//! Acknowledged delta replication for CRDT-backed workloads.
+//!
+//! A [`CrdtReplicator`] owns a node's copy of some [`DeltaCrdt`] and keeps
+//! every peer up to date with it. Each round it sends a peer what changed
+//! since the last version that peer acknowledged, so a lost delta is simply
+//! part of the next one, and an ack arriving rounds late still counts.
+//! Once a peer has acknowledged everything, rounds send it a digest of the
+//! state instead every `DIGEST_EVERY` rounds; a peer whose own state hashes
+//! differently answers with its full state, and the two resynchronise from
+//! scratch. A peer that has acknowledged nothing
+//! yet, such as a node that just joined, gets a [`Snapshot`] of the whole
+//! state rather than a delta.
+//!
+//! With `--snapshot-dir` set, [`persist`](CrdtReplicator::persist) also
+//! saves the copy there now and then and restores it when the node starts
+//! again.
+//!
+//! Workloads mount one replicator per state, carry [`Gossip`] in one of
+//! their messages, and hand what they receive to
+//! [`receive`](CrdtReplicator::receive).
+
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    config::Config,
+    crdt::{
+        snapshot::{self, as_snapshot},
+        Snapshot,
+    },
+    gossip::{DeltaCrdt, Replicated},
+    message::NodeId,
+    runtime::Runtime,
+    storage::{FileBackend, StorageBackend, SyncPolicy},
+};
+
+/// Rounds between two digests sent to a peer that is up to date.
+const DIGEST_EVERY: u64 = 10;
+/// Unacknowledged deltas remembered per peer; an ack for an older one is
+/// ignored.
+const MAX_IN_FLIGHT: usize = 16;
+/// How often a persisted copy is saved.
+const SAVE_INTERVAL: Duration = Duration::from_millis(1000);
+
+/// States are boxed, keeping the workload messages that carry gossip small.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+#[serde(bound(
+    serialize = "C: Snapshot + Serialize",
+    deserialize = "C: Snapshot + DeserializeOwned"
+))]
+pub enum Gossip<C> {
+    /// What changed since the last state the receiver acknowledged.
+    Delta { seq: u64, state: Box<C> },
+    /// The whole state, for a receiver that has acknowledged nothing.
+    Snapshot {
+        seq: u64,
+        #[serde(with = "as_snapshot")]
+        state: Box<C>,
+    },
+    /// The receiver merged delta `seq`.
+    Ack { seq: u64 },
+    /// A hash of the sender's state, which it believes the receiver has.
+    Digest { digest: u64 },
+    /// The receiver's whole state, answering a digest it did not match.
+    Full { state: Box<C> },
+}
+
+impl<C> Gossip<C> {
+    /// The state carried, if any.
+    pub fn state(&self) -> Option<&C> {
+        match self {
+            Gossip::Delta { state, .. }
+            | Gossip::Snapshot { state, .. }
+            | Gossip::Full { state } => Some(state.as_ref()),
+            Gossip::Ack { .. } | Gossip::Digest { .. } => None,
+        }
+    }
+}
+
+/// What one peer has of our state.
+struct Peer<V> {
+    /// The last version it acknowledged.
+    acked: V,
+    /// The deltas on their way, by seq, with the version each brings the
+    /// peer to.
+    in_flight: BTreeMap<u64, V>,
+}
+
+impl<V: Default> Default for Peer<V> {
+    fn default() -> Self {
+        Self {
+            acked: V::default(),
+            in_flight: BTreeMap::new(),
+        }
+    }
+}
+
+impl<V> Peer<V> {
+    fn sent(&mut self, seq: u64, version: V) {
+        self.in_flight.insert(seq, version);
+        if self.in_flight.len() > MAX_IN_FLIGHT {
+            self.in_flight.pop_first();
+        }
+    }
+
+    /// The peer merged delta `seq`, and so everything sent before it.
+    fn acked(&mut self, seq: u64) {
+        if let Some(version) = self.in_flight.remove(&seq) {
+            self.acked = version;
+            self.in_flight.retain(|&sent, _| sent > seq);
+        }
+    }
+}
+
+/// A node's copy of a replicated CRDT, with the bookkeeping to disseminate
+/// it. Clones share the copy.
+pub struct CrdtReplicator<C: DeltaCrdt> {
+    state: Replicated<C>,
+    peers: Arc<Mutex<HashMap<NodeId, Peer<C::Version>>>>,
+}
+
+impl<C: DeltaCrdt> Clone for CrdtReplicator<C> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+            peers: self.peers.clone(),
+        }
+    }
+}
+
+impl<C: DeltaCrdt + Snapshot + Serialize> CrdtReplicator<C> {
+    /// Starts replicating `initial` to every peer, one round per
+    /// `interval`. `wrap` turns gossip into the workload's message, which
+    /// the peers pass to [`receive`](Self::receive).
+    pub fn mount<P, F>(runtime: Runtime, initial: C, interval: Duration, wrap: F) -> Self
+    where
+        P: Serialize,
+        F: Fn(Gossip<C>) -> P + Send + 'static,
+    {
+        let peers = runtime
+            .peers()
+            .map(|peer| (peer.clone(), Peer::default()))
+            .collect();
+        let replicator = Self {
+            state: Replicated::new(initial),
+            peers: Arc::new(Mutex::new(peers)),
+        };
+        let rounds = replicator.clone();
+        std::thread::spawn(move || -> anyhow::Result<()> {
+            for round in 1.. {
+                std::thread::sleep(interval);
+                for (peer, gossip) in rounds.round(round) {
+                    runtime.send(&peer, wrap(gossip))?;
+                }
+            }
+            Ok(())
+        });
+        replicator
+    }
+
+    pub fn update<R>(&self, f: impl FnOnce(&mut C) -> R) -> R {
+        self.state.update(f)
+    }
+
+    pub fn read<R>(&self, f: impl FnOnce(&C) -> R) -> R {
+        self.state.read(f)
+    }
+
+    /// With `--snapshot-dir` set, keeps the copy in a [`FileBackend`] there,
+    /// as `<node>.json`; see [`persist_to`](Self::persist_to).
+    pub fn persist(&self, config: &Config, node: &str) -> anyhow::Result<()> {
+        let Some(dir) = config.get("snapshot-dir") else {
+            return Ok(());
+        };
+        let backend = FileBackend::open(dir, SyncPolicy::Always)?;
+        self.persist_to(Arc::new(backend), format!("{node}.json"))
+    }
+
+    /// Merges in the copy an earlier run saved in `backend` under `key`, if
+    /// any, and saves the copy there from now on.
+    pub fn persist_to(&self, backend: Arc<dyn StorageBackend>, key: String) -> anyhow::Result<()> {
+        if let Some(saved) = snapshot::load(backend.as_ref(), &key)? {
+            self.state.merge(saved);
+        }
+        let state = self.state.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(SAVE_INTERVAL);
+            if let Err(err) = state.read(|state| snapshot::save(state, backend.as_ref(), &key)) {
+                eprintln!("saving {key} failed: {err:#}");
+            }
+        });
+        Ok(())
+    }
+
+    /// The copy's snapshot, for debug dumps.
+    pub fn dump(&self) -> Value {
+        self.state
+            .read(|state| serde_json::to_value(state.snapshot()))
+            .unwrap_or_default()
+    }
+
+    /// Handles gossip from `from`. Returns the answer to send back, if any.
+    pub fn receive(&self, from: &str, gossip: Gossip<C>) -> Option<Gossip<C>> {
+        match gossip {
+            Gossip::Delta { seq, state } | Gossip::Snapshot { seq, state } => {
+                self.state.merge(*state);
+                Some(Gossip::Ack { seq })
+            }
+            Gossip::Ack { seq } => {
+                let mut peers = self.peers.lock().unwrap();
+                peers.entry(from.to_string()).or_default().acked(seq);
+                None
+            }
+            Gossip::Digest { digest } => self.state.read(|state| {
+                (digest_of(state) != digest).then(|| Gossip::Full {
+                    state: Box::new(state.clone()),
+                })
+            }),
+            Gossip::Full { state } => {
+                self.state.merge(*state);
+                // Whatever we thought `from` had, it hashed differently:
+                // send it everything again.
+                let mut peers = self.peers.lock().unwrap();
+                peers.insert(from.to_string(), Peer::default());
+                None
+            }
+        }
+    }
+
+    /// The gossip of round `round`: a delta to every peer behind, and a
+    /// digest now and then to those that are not.
+    fn round(&self, round: u64) -> Vec<(NodeId, Gossip<C>)> {
+        self.state.read(|state| {
+            let version = state.version();
+            let mut peers = self.peers.lock().unwrap();
+            let mut outgoing = Vec::new();
+            for (id, peer) in peers.iter_mut() {
+                if peer.acked == C::Version::default() && version != peer.acked {
+                    let state = Box::new(state.clone());
+                    peer.sent(round, version.clone());
+                    outgoing.push((id.clone(), Gossip::Snapshot { seq: round, state }));
+                } else if peer.acked != version {
+                    let state = Box::new(state.split_delta(&peer.acked));
+                    peer.sent(round, version.clone());
+                    outgoing.push((id.clone(), Gossip::Delta { seq: round, state }));
+                } else if round.is_multiple_of(DIGEST_EVERY) {
+                    let digest = digest_of(state);
+                    outgoing.push((id.clone(), Gossip::Digest { digest }));
+                }
+            }
+            outgoing
+        })
+    }
+}
+
+/// Hashes the JSON form of `state`, whose maps serialize in key order, so
+/// equal states hash alike on every node.
+fn digest_of(state: &impl Serialize) -> u64 {
+    let json = serde_json::to_value(state).unwrap_or_default();
+    let mut hasher = DefaultHasher::new();
+    json.to_string().hash(&mut hasher);
+    hasher.finish()
+}
@@ -0,0 +1,114 @@
+//! Assigning keys to nodes by consistent hashing.
+//!
+//! Every node is hashed onto a ring at [`VIRTUAL_NODES`] points, and a key
+//! belongs to the node at the first point at or after the key's own hash,
+//! wrapping around. Adding or removing a node only moves the keys next to
+//! its points, and the many points per node even out how many keys each
+//! one gets.
+
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeSet},
+    hash::{Hash, Hasher},
+};
+
+use anyhow::bail;
+
+/// Points on the ring per node.
+pub const VIRTUAL_NODES: usize = 64;
+
+#[derive(Clone, Debug)]
+pub struct Ring {
+    /// Every point, in hash order, with the node it stands for.
+    points: Vec<(u64, String)>,
+    /// How many distinct nodes the ring holds.
+    nodes: usize,
+}
+
+impl Ring {
+    /// Every node builds the same ring from the same set of ids, in
+    /// whatever order. A ring with no nodes would have no owner for any
+    /// key, so it is refused.
+    pub fn new(nodes: &[String]) -> anyhow::Result<Self> {
+        if nodes.is_empty() {
+            bail!("a hash ring needs at least one node");
+        }
+        let mut points: Vec<(u64, String)> = nodes
+            .iter()
+            .flat_map(|node| {
+                (0..VIRTUAL_NODES).map(move |point| (hash(&(node, point)), node.clone()))
+            })
+            .collect();
+        points.sort();
+        let nodes = nodes.iter().collect::<BTreeSet<_>>().len();
+        Ok(Self { points, nodes })
+    }
+
+    /// The node that owns `key`.
+    pub fn owner<K: Hash + ?Sized>(&self, key: &K) -> &str {
+        self.at(hash(key))
+    }
+
+    /// The `count` nodes that hold `key`: its owner, then the next distinct
+    /// nodes round the ring. Fewer if there are not that many nodes.
+    pub fn replicas<K: Hash + ?Sized>(&self, key: &K, count: usize) -> Vec<String> {
+        self.walk(self.first(key), count)
+    }
+
+    /// The other nodes that hold some key along with `node` when every key
+    /// has `count` replicas.
+    pub fn neighbors(&self, node: &str, count: usize) -> Vec<String> {
+        let mut neighbors = BTreeSet::new();
+        for at in 0..self.points.len() {
+            let replicas = self.walk(at, count);
+            if replicas.iter().any(|replica| replica == node) {
+                neighbors.extend(replicas.into_iter().filter(|replica| replica != node));
+            }
+        }
+        neighbors.into_iter().collect()
+    }
+
+    /// The pairs of nodes `(from, to)` such that some keys `from` owns on
+    /// this ring belong to `to` on `next`.
+    pub fn moves(&self, next: &Ring) -> BTreeSet<(String, String)> {
+        // Owners only change at the points of either ring.
+        let points = self.points.iter().chain(&next.points);
+        points
+            .map(|(point, _)| (self.at(*point), next.at(*point)))
+            .filter(|(from, to)| from != to)
+            .map(|(from, to)| (from.to_string(), to.to_string()))
+            .collect()
+    }
+
+    /// The node owning the keys that hash to `hash`.
+    fn at(&self, hash: u64) -> &str {
+        let at = self.points.partition_point(|(point, _)| *point < hash) % self.points.len();
+        &self.points[at].1
+    }
+
+    /// Where the ring first reaches `key`'s hash.
+    fn first<K: Hash + ?Sized>(&self, key: &K) -> usize {
+        let hash = hash(key);
+        self.points.partition_point(|(point, _)| *point < hash) % self.points.len()
+    }
+
+    /// The first `count` distinct nodes from point `at` on.
+    fn walk(&self, at: usize, count: usize) -> Vec<String> {
+        let count = count.min(self.nodes);
+        let mut nodes: Vec<String> = Vec::with_capacity(count);
+        for (_, node) in self.points[at..].iter().chain(&self.points[..at]) {
+            if nodes.len() == count {
+                break;
+            }
+            if !nodes.contains(node) {
+                nodes.push(node.clone());
+            }
+        }
+        nodes
+    }
+}
+
+fn hash<K: Hash + ?Sized>(key: &K) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
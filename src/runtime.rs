@@ -0,0 +1,400 @@
+//! Plumbing shared by every node: the init handshake, writing messages,
+//! and request/response RPCs to other nodes and Maelstrom services.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    backoff::Backoff,
+    clock::Lamport,
+    config::Config,
+    message::{error_code, ErrorPayload, Init, InitPayload, Message, MessageBody, RawMessage},
+    transport::{Stdio, Tcp, Transport, TransportMode},
+};
+
+/// A workload. `main_loop` builds it from the init message and feeds it
+/// every message that is not a reply to one of its RPCs.
+pub trait Node<P>: Sized {
+    fn from_init(runtime: Runtime, init: Init) -> anyhow::Result<Self>;
+
+    fn step(&mut self, input: Message<P>) -> anyhow::Result<()>;
+}
+
+#[derive(Debug)]
+pub enum RpcError {
+    /// No reply arrived in time. The request may or may not have been applied.
+    Timeout,
+    /// The destination answered with a Maelstrom error.
+    Remote {
+        code: usize,
+        text: String,
+    },
+    Other(anyhow::Error),
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::Timeout => write!(f, "rpc timed out"),
+            RpcError::Remote { code, text } => write!(f, "remote error {code}: {text}"),
+            RpcError::Other(err) => write!(f, "{err:#}"),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(err: anyhow::Error) -> Self {
+        RpcError::Other(err)
+    }
+}
+
+/// Bounds on the pause before [`Runtime::deliver`] resends a request that
+/// timed out.
+const MIN_DELIVER_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_DELIVER_BACKOFF: Duration = Duration::from_secs(1);
+
+/// A request queued by [`Runtime::deliver`], and how long to wait for each
+/// reply to it.
+type Delivery = (serde_json::Value, Duration);
+
+#[derive(Clone)]
+pub struct Runtime {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    node_id: String,
+    node_ids: Vec<String>,
+    next_msg_id: AtomicUsize,
+    clock: Lamport,
+    // Callers blocked in `rpc`, by the msg_id of their request.
+    pending: Mutex<HashMap<usize, mpsc::Sender<RawMessage>>>,
+    transport: Arc<dyn Transport>,
+    config: Config,
+    // Requests `deliver` queued, by destination, each queue drained by a
+    // thread of its own.
+    deliveries: Mutex<HashMap<String, mpsc::Sender<Delivery>>>,
+}
+
+impl Runtime {
+    fn new(init: &Init, transport: Arc<dyn Transport>, config: Config) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                node_id: init.node_id.clone(),
+                node_ids: init.node_ids.clone(),
+                next_msg_id: AtomicUsize::new(1),
+                clock: Lamport::new(),
+                pending: Default::default(),
+                transport,
+                config,
+                deliveries: Default::default(),
+            }),
+        }
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.inner.node_id
+    }
+
+    pub fn node_ids(&self) -> &[String] {
+        &self.inner.node_ids
+    }
+
+    /// The options the node was started with.
+    pub fn config(&self) -> &Config {
+        &self.inner.config
+    }
+
+    /// Every node in the cluster but us.
+    pub fn peers(&self) -> impl Iterator<Item = &String> {
+        self.inner
+            .node_ids
+            .iter()
+            .filter(move |&id| id != &self.inner.node_id)
+    }
+
+    fn next_msg_id(&self) -> usize {
+        self.inner.next_msg_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// This node's Lamport clock, kept up to date with every message sent
+    /// and received.
+    pub fn clock(&self) -> &Lamport {
+        &self.inner.clock
+    }
+
+    /// Ticks the clock for the send and, for another node, stamps the
+    /// message with the time.
+    fn write<P: Serialize>(&self, message: Message<P>) -> anyhow::Result<()> {
+        let time = self.inner.clock.tick();
+        let lamport = self.inner.node_ids.contains(&message.dest).then_some(time);
+        let payload = serde_json::to_value(message.body.payload).context("Serialize message")?;
+        self.inner.transport.send(&Message {
+            src: message.src,
+            dest: message.dest,
+            body: MessageBody {
+                msg_id: message.body.msg_id,
+                in_reply_to: message.body.in_reply_to,
+                lamport,
+                payload,
+            },
+        })
+    }
+
+    /// Sends a message nobody waits a reply for. Returns its msg_id.
+    pub fn send<P: Serialize>(&self, dest: &str, payload: P) -> anyhow::Result<usize> {
+        let msg_id = self.next_msg_id();
+        self.write(Message {
+            src: self.inner.node_id.clone(),
+            dest: dest.to_string(),
+            body: MessageBody {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                lamport: None,
+                payload,
+            },
+        })?;
+        Ok(msg_id)
+    }
+
+    pub fn reply<P: Serialize, Q>(&self, request: &Message<Q>, payload: P) -> anyhow::Result<()> {
+        self.write(Message {
+            src: self.inner.node_id.clone(),
+            dest: request.src.clone(),
+            body: MessageBody {
+                msg_id: Some(self.next_msg_id()),
+                in_reply_to: request.body.msg_id,
+                lamport: None,
+                payload,
+            },
+        })
+    }
+
+    pub fn reply_error<Q>(
+        &self,
+        request: &Message<Q>,
+        code: usize,
+        text: impl Into<String>,
+    ) -> anyhow::Result<()> {
+        let text = text.into();
+        self.reply(request, ErrorPayload::Error { code, text })
+    }
+
+    /// Sends a request and blocks until its reply arrives. Must not be
+    /// called from `Node::step`, which is what delivers the replies: spawn a
+    /// thread for handlers that need RPCs.
+    pub fn rpc<P, R>(&self, dest: &str, payload: P, timeout: Duration) -> Result<R, RpcError>
+    where
+        P: Serialize,
+        R: DeserializeOwned,
+    {
+        let msg_id = self.next_msg_id();
+        let (tx, rx) = mpsc::channel();
+        self.inner.pending.lock().unwrap().insert(msg_id, tx);
+        let sent = self.write(Message {
+            src: self.inner.node_id.clone(),
+            dest: dest.to_string(),
+            body: MessageBody {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                lamport: None,
+                payload,
+            },
+        });
+        if let Err(err) = sent {
+            self.inner.pending.lock().unwrap().remove(&msg_id);
+            return Err(err.into());
+        }
+        let reply = match rx.recv_timeout(timeout) {
+            Ok(reply) => reply,
+            Err(_) => {
+                self.inner.pending.lock().unwrap().remove(&msg_id);
+                return Err(RpcError::Timeout);
+            }
+        };
+        if reply
+            .body
+            .payload
+            .get("type")
+            .and_then(|kind| kind.as_str())
+            == Some("error")
+        {
+            let ErrorPayload::Error { code, text } =
+                serde_json::from_value(reply.body.payload).context("Deserialize error reply")?;
+            return Err(RpcError::Remote { code, text });
+        }
+        let reply = serde_json::from_value(reply.body.payload).context("Deserialize rpc reply")?;
+        Ok(reply)
+    }
+
+    /// Queues a request for a background thread to send, resending it every
+    /// `timeout`, with pauses growing up to a second in between, until
+    /// `dest` replies. Each destination has one queue, sent one request at a
+    /// time in order. For messages that must eventually arrive, such as
+    /// replication, whose reply carries nothing.
+    pub fn deliver<P: Serialize>(&self, dest: &str, payload: P, timeout: Duration) {
+        let payload = match serde_json::to_value(payload) {
+            Ok(payload) => payload,
+            Err(err) => {
+                eprintln!("delivery to {dest} failed: {err}");
+                return;
+            }
+        };
+        let mut deliveries = self.inner.deliveries.lock().unwrap();
+        let queue = deliveries.entry(dest.to_string()).or_insert_with(|| {
+            let (tx, rx) = mpsc::channel();
+            let (inner, dest) = (Arc::downgrade(&self.inner), dest.to_string());
+            std::thread::spawn(move || deliver_all(&inner, &dest, rx));
+            tx
+        });
+        // The thread only stops once the runtime is gone.
+        let _ = queue.send((payload, timeout));
+    }
+
+    /// Relays `request`, which a client sent to this node, to `to`, and its
+    /// answer, error or not, back to the client, on a thread of its own so
+    /// the caller does not wait. No answer within `timeout` is answered with
+    /// a timeout error.
+    pub fn forward<P>(&self, to: &str, request: Message<P>, timeout: Duration)
+    where
+        P: Serialize + Send + 'static,
+    {
+        let (runtime, to) = (self.clone(), to.to_string());
+        std::thread::spawn(move || {
+            let payload = &request.body.payload;
+            let result = match runtime.rpc::<_, serde_json::Value>(&to, payload, timeout) {
+                Ok(reply) => runtime.reply(&request, reply),
+                Err(RpcError::Remote { code, text }) => runtime.reply_error(&request, code, text),
+                Err(err) => runtime.reply_error(&request, error_code::TIMEOUT, err.to_string()),
+            };
+            if let Err(err) = result {
+                eprintln!("forwarding to {to} failed: {err:#}");
+            }
+        });
+    }
+
+    /// Hands a reply to the `rpc` call waiting for it. Gives the message back
+    /// when nobody is waiting.
+    fn route_reply(&self, message: RawMessage) -> Option<RawMessage> {
+        let waiter = message
+            .body
+            .in_reply_to
+            .and_then(|id| self.inner.pending.lock().unwrap().remove(&id));
+        match waiter {
+            Some(waiter) => {
+                // The caller may have timed out in the meantime; nothing to do then.
+                let _ = waiter.send(message);
+                None
+            }
+            None => Some(message),
+        }
+    }
+}
+
+/// Sends the requests [`Runtime::deliver`] queued for `dest` until the
+/// runtime is gone. It holds the runtime only while sending, so that the
+/// queue, which the runtime owns, closes with it.
+fn deliver_all(inner: &Weak<Inner>, dest: &str, queue: mpsc::Receiver<Delivery>) {
+    for (payload, timeout) in queue {
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+        let runtime = Runtime { inner };
+        let mut backoff = Backoff::new(MIN_DELIVER_BACKOFF, MAX_DELIVER_BACKOFF);
+        loop {
+            match runtime.rpc::<_, serde_json::Value>(dest, &payload, timeout) {
+                Ok(_) => break,
+                Err(RpcError::Timeout) => backoff.sleep(),
+                Err(err) => {
+                    eprintln!("delivery to {dest} failed: {err}");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Runs the node over the transport `--transport` names: stdin and
+/// stdout by default, as Maelstrom expects. See [`main_loop_on`].
+pub fn main_loop<N, P>() -> anyhow::Result<()>
+where
+    N: Node<P>,
+    P: DeserializeOwned,
+{
+    let config = Config::from_env()?;
+    match config.parse("transport")?.unwrap_or_default() {
+        TransportMode::Stdio => main_loop_on::<N, P>(Stdio, config),
+        TransportMode::Tcp => {
+            let transport = Tcp::from_config(&config)?;
+            main_loop_on::<N, P>(transport, config)
+        }
+    }
+}
+
+/// Reads messages from `transport`: answers the init handshake, then feeds
+/// the node, which reads its options from `config`, until the transport
+/// closes.
+pub fn main_loop_on<N, P>(transport: impl Transport + 'static, config: Config) -> anyhow::Result<()>
+where
+    N: Node<P>,
+    P: DeserializeOwned,
+{
+    let transport: Arc<dyn Transport> = Arc::new(transport);
+    let init_msg = transport
+        .recv()
+        .context("init message failed to deserialize")?
+        .context("no init message received")?;
+    let InitPayload::Init(init) = serde_json::from_value(init_msg.body.payload.clone())
+        .context("first message should be init")?
+    else {
+        bail!("first message should be init");
+    };
+    let runtime = Runtime::new(&init, transport.clone(), config);
+    let mut node = N::from_init(runtime.clone(), init).context("node initialization failed")?;
+    runtime.reply(&init_msg, InitPayload::InitOk)?;
+
+    while let Some(input) = transport.recv()? {
+        if let Some(time) = input.body.lamport {
+            runtime.clock().observe(time);
+        }
+        let Some(input) = runtime.route_reply(input) else {
+            continue;
+        };
+        let payload = match serde_json::from_value(input.body.payload.clone()) {
+            Ok(payload) => payload,
+            Err(err) => {
+                // Typically a late reply to an rpc that already timed out.
+                eprintln!(
+                    "ignoring message from {}: {err}: {}",
+                    input.src, input.body.payload
+                );
+                continue;
+            }
+        };
+        let input = Message {
+            src: input.src,
+            dest: input.dest,
+            body: MessageBody {
+                msg_id: input.body.msg_id,
+                in_reply_to: input.body.in_reply_to,
+                lamport: input.body.lamport,
+                payload,
+            },
+        };
+        node.step(input).context("Node step failed")?;
+    }
+
+    Ok(())
+}
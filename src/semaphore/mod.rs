@@ -0,0 +1,169 @@
+//! Counted semaphore: `acquire {key}` hands the requesting client one of
+//! `--semaphore-permits` (default 3) permits on `key`, or fails with
+//! `temporarily-unavailable` while they are all taken, and `release {key}`
+//! gives it back.
+//!
+//! The holders of each semaphore live in `lin-kv` under `semaphore/<key>` and
+//! only change through cas, so every node can serve every key. Permits are
+//! leases: a holder that does not release or re-acquire within
+//! `--semaphore-lease` (default 2000ms) loses its permit the next time
+//! anyone touches the semaphore.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::now_ms,
+    message::{error_code, Init, Message},
+    runtime::{Node, RpcError, Runtime},
+    services::Kv,
+};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Acquire { key: String },
+    AcquireOk,
+    Release { key: String },
+    ReleaseOk,
+}
+
+/// What `lin-kv` holds for one semaphore: each holder's lease expiry, in
+/// wall-clock millis.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(transparent)]
+struct Holders {
+    expires_ms: BTreeMap<String, u64>,
+}
+
+impl Holders {
+    /// The holders whose leases have not lapsed by `now`.
+    fn live(&self, now: u64) -> Holders {
+        Holders {
+            expires_ms: self
+                .expires_ms
+                .iter()
+                .filter(|(_, &expires)| expires > now)
+                .map(|(holder, &expires)| (holder.clone(), expires))
+                .collect(),
+        }
+    }
+}
+
+pub struct SemaphoreNode {
+    runtime: Runtime,
+    kv: Kv,
+    permits: usize,
+    lease: Duration,
+}
+
+impl Node<Payload> for SemaphoreNode {
+    fn from_init(runtime: Runtime, _init: Init) -> anyhow::Result<Self> {
+        let config = runtime.config().clone();
+        Ok(Self {
+            kv: Kv::new(runtime.clone(), "lin-kv"),
+            runtime,
+            permits: config.parse("semaphore-permits")?.unwrap_or(3),
+            lease: config
+                .millis("semaphore-lease")?
+                .unwrap_or(Duration::from_millis(2000)),
+        })
+    }
+
+    fn step(&mut self, input: Message<Payload>) -> anyhow::Result<()> {
+        let runtime = self.runtime.clone();
+        let kv = self.kv.clone();
+        let (permits, lease) = (self.permits, self.lease);
+        std::thread::spawn(move || {
+            let client = input.src.as_str();
+            let result = match input.body.payload {
+                Payload::Acquire { ref key } => {
+                    acquire(&kv, key, client, permits, lease).map(|()| Some(Payload::AcquireOk))
+                }
+                Payload::Release { ref key } => {
+                    release(&kv, key, client).map(|()| Some(Payload::ReleaseOk))
+                }
+                Payload::AcquireOk | Payload::ReleaseOk => Ok(None),
+            };
+            let result = match result {
+                Ok(Some(reply)) => runtime.reply(&input, reply),
+                Ok(None) => Ok(()),
+                Err(SemaphoreError::Refused { code, text }) => {
+                    runtime.reply_error(&input, code, text)
+                }
+                Err(SemaphoreError::Kv(err)) => {
+                    runtime.reply_error(&input, error_code::TIMEOUT, err.to_string())
+                }
+            };
+            if let Err(err) = result {
+                eprintln!("semaphore reply failed: {err:#}");
+            }
+        });
+        Ok(())
+    }
+}
+
+enum SemaphoreError {
+    /// The request itself cannot succeed right now.
+    Refused {
+        code: usize,
+        text: String,
+    },
+    Kv(RpcError),
+}
+
+impl From<RpcError> for SemaphoreError {
+    fn from(err: RpcError) -> Self {
+        SemaphoreError::Kv(err)
+    }
+}
+
+/// Swaps in the holders `update` derives from the live ones, retrying when
+/// another node changed them in between.
+fn cas_holders(
+    kv: &Kv,
+    key: &str,
+    update: impl Fn(Holders, u64) -> Result<Holders, SemaphoreError>,
+) -> Result<(), SemaphoreError> {
+    kv.try_update(format!("semaphore/{key}"), |current: &Holders| {
+        let now = now_ms();
+        update(current.live(now), now)
+    })?;
+    Ok(())
+}
+
+/// Takes a permit for `client`, or renews the lease on the one it holds.
+fn acquire(
+    kv: &Kv,
+    key: &str,
+    client: &str,
+    permits: usize,
+    lease: Duration,
+) -> Result<(), SemaphoreError> {
+    cas_holders(kv, key, |mut holders, now| {
+        if !holders.expires_ms.contains_key(client) && holders.expires_ms.len() >= permits {
+            return Err(SemaphoreError::Refused {
+                code: error_code::TEMPORARILY_UNAVAILABLE,
+                text: format!("all {permits} permits of {key} are taken"),
+            });
+        }
+        holders
+            .expires_ms
+            .insert(client.to_string(), now + lease.as_millis() as u64);
+        Ok(holders)
+    })
+}
+
+fn release(kv: &Kv, key: &str, client: &str) -> Result<(), SemaphoreError> {
+    cas_holders(kv, key, |mut holders, _| {
+        if holders.expires_ms.remove(client).is_none() {
+            return Err(SemaphoreError::Refused {
+                code: error_code::PRECONDITION_FAILED,
+                text: format!("{client} holds no permit of {key}"),
+            });
+        }
+        Ok(holders)
+    })
+}
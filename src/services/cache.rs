@@ -0,0 +1,140 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use serde_json::Value;
+
+/// How long a [`Cache`] keeps what it read.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// Nothing is cached: every read goes to the service.
+    #[default]
+    Off,
+    /// Until this node writes the key or loses a cas on it. Only safe for
+    /// keys no other node changes, or whose values never change once set.
+    On,
+    /// As `On`, and for at most this long, so changes made by other nodes
+    /// show up after that.
+    Ttl(Duration),
+}
+
+impl FromStr for CachePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "on" => Ok(Self::On),
+            _ => match s.parse() {
+                Ok(millis) => Ok(Self::Ttl(Duration::from_millis(millis))),
+                Err(_) => {
+                    bail!("unknown kv cache {s}, expected off, on or a number of milliseconds")
+                }
+            },
+        }
+    }
+}
+
+/// How many reads a [`Cache`] answered, and how many it had to pass on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheStats {
+    /// The share of reads answered from the cache, 0 before any read.
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            reads => self.hits as f64 / reads as f64,
+        }
+    }
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} hits, {} misses, {:.1}% hit rate",
+            self.hits,
+            self.misses,
+            self.hit_rate() * 100.0
+        )
+    }
+}
+
+/// Values read from or written to a key/value service, by key, so reading
+/// them again takes no round trip.
+#[derive(Debug, Default)]
+pub struct Cache {
+    policy: CachePolicy,
+    // Keys are arbitrary JSON, so they are indexed by their serialization.
+    entries: Mutex<HashMap<String, (Value, Instant)>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Cache {
+    pub fn new(policy: CachePolicy) -> Self {
+        Self {
+            policy,
+            ..Self::default()
+        }
+    }
+
+    pub fn policy(&self) -> CachePolicy {
+        self.policy
+    }
+
+    /// The value cached for `key`, counting a hit or a miss.
+    pub fn get(&self, key: &Value) -> Option<Value> {
+        let mut entries = self.entries.lock().unwrap();
+        let key = key.to_string();
+        let fresh = match (self.policy, entries.get(&key)) {
+            (CachePolicy::Off, _) | (_, None) => None,
+            (CachePolicy::On, Some((value, _))) => Some(value.clone()),
+            (CachePolicy::Ttl(ttl), Some((value, at))) => {
+                Some(value.clone()).filter(|_| at.elapsed() < ttl)
+            }
+        };
+        if fresh.is_none() {
+            entries.remove(&key);
+        }
+        let counter = match fresh {
+            Some(_) => &self.hits,
+            None => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        fresh
+    }
+
+    /// Records `value` as what `key` holds now.
+    pub fn put(&self, key: &Value, value: Value) {
+        if self.policy == CachePolicy::Off {
+            return;
+        }
+        let entry = (value, Instant::now());
+        self.entries.lock().unwrap().insert(key.to_string(), entry);
+    }
+
+    /// Forgets `key`, whose cached value turned out stale.
+    pub fn invalidate(&self, key: &Value) {
+        self.entries.lock().unwrap().remove(&key.to_string());
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{mpsc, Arc, Condvar, Mutex},
+};
+
+use anyhow::anyhow;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::runtime::RpcError;
+
+type Write = dyn Fn(&Value, &Value) -> Result<(), RpcError> + Send + Sync;
+
+/// Writes waiting for a worker. Each key appears at most once in `queue`:
+/// a write to a key already queued replaces its value, and whoever waited
+/// for the older one is answered with the newer one's outcome, which is
+/// what they would have read afterwards anyway. A key being written is not
+/// dispatched again until that write finished, so writes to one key land
+/// in the order they were made.
+#[derive(Default)]
+struct Pending {
+    queue: VecDeque<String>,
+    writes: HashMap<String, Batch>,
+    in_flight: HashSet<String>,
+}
+
+struct Batch {
+    key: Value,
+    value: Value,
+    waiters: Vec<mpsc::Sender<Result<(), RpcError>>>,
+}
+
+impl Pending {
+    /// The next queued key nobody is writing, taken off the queue.
+    fn next(&mut self) -> Option<(String, Batch)> {
+        let at = self
+            .queue
+            .iter()
+            .position(|key| !self.in_flight.contains(key))?;
+        let key = self.queue.remove(at).unwrap();
+        let batch = self.writes.remove(&key).unwrap();
+        self.in_flight.insert(key.clone());
+        Some((key, batch))
+    }
+}
+
+struct Shared {
+    pending: Mutex<Pending>,
+    /// Signalled when a write is queued or finishes.
+    changed: Condvar,
+}
+
+/// A write handed to a [`WriteCoalescer`], to wait for.
+pub struct Ticket(mpsc::Receiver<Result<(), RpcError>>);
+
+impl Ticket {
+    pub fn wait(self) -> Result<(), RpcError> {
+        self.0
+            .recv()
+            .unwrap_or_else(|_| Err(RpcError::Other(anyhow!("write coalescer stopped"))))
+    }
+}
+
+/// Pipelines writes to a key/value service: callers queue writes and a
+/// fixed pool of `window` workers sends them, so up to `window` are in
+/// flight at once whoever made them, and pending writes to the same key
+/// collapse into one.
+#[derive(Clone)]
+pub struct WriteCoalescer {
+    shared: Arc<Shared>,
+}
+
+impl WriteCoalescer {
+    /// Sends each write with `write`, from `window` threads.
+    pub fn new(
+        window: usize,
+        write: impl Fn(&Value, &Value) -> Result<(), RpcError> + Send + Sync + 'static,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            pending: Mutex::default(),
+            changed: Condvar::new(),
+        });
+        let write: Arc<Write> = Arc::new(write);
+        for _ in 0..window.max(1) {
+            let (shared, write) = (shared.clone(), write.clone());
+            std::thread::spawn(move || work(&shared, write.as_ref()));
+        }
+        Self { shared }
+    }
+
+    /// Queues a write of `value` under `key` without waiting for it.
+    pub fn submit<K: Serialize, V: Serialize>(&self, key: K, value: V) -> Ticket {
+        let (tx, rx) = mpsc::channel();
+        let encoded =
+            serde_json::to_value(key).and_then(|key| Ok((key, serde_json::to_value(value)?)));
+        let (key, value) = match encoded {
+            Ok(encoded) => encoded,
+            Err(err) => {
+                let _ = tx.send(Err(RpcError::Other(err.into())));
+                return Ticket(rx);
+            }
+        };
+        let mut pending = self.shared.pending.lock().unwrap();
+        let id = key.to_string();
+        match pending.writes.get_mut(&id) {
+            Some(batch) => {
+                batch.value = value;
+                batch.waiters.push(tx);
+            }
+            None => {
+                pending.queue.push_back(id.clone());
+                let waiters = vec![tx];
+                pending.writes.insert(
+                    id,
+                    Batch {
+                        key,
+                        value,
+                        waiters,
+                    },
+                );
+            }
+        }
+        self.shared.changed.notify_all();
+        Ticket(rx)
+    }
+
+    /// Writes `value` under `key` and waits until it is acknowledged.
+    pub fn write<K: Serialize, V: Serialize>(&self, key: K, value: V) -> Result<(), RpcError> {
+        self.submit(key, value).wait()
+    }
+}
+
+fn work(shared: &Shared, write: &Write) {
+    loop {
+        let (id, batch) = {
+            let mut pending = shared.pending.lock().unwrap();
+            loop {
+                match pending.next() {
+                    Some(next) => break next,
+                    None => pending = shared.changed.wait(pending).unwrap(),
+                }
+            }
+        };
+        let result = write(&batch.key, &batch.value);
+        for waiter in batch.waiters {
+            let _ = waiter.send(share(&result));
+        }
+        shared.pending.lock().unwrap().in_flight.remove(&id);
+        shared.changed.notify_all();
+    }
+}
+
+/// A copy of `result` for each waiter of a batch.
+fn share(result: &Result<(), RpcError>) -> Result<(), RpcError> {
+    match result {
+        Ok(()) => Ok(()),
+        Err(RpcError::Timeout) => Err(RpcError::Timeout),
+        Err(RpcError::Remote { code, text }) => Err(RpcError::Remote {
+            code: *code,
+            text: text.clone(),
+        }),
+        Err(RpcError::Other(err)) => Err(RpcError::Other(anyhow!("{err:#}"))),
+    }
+}
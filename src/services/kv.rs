@@ -0,0 +1,259 @@
+use std::{fmt, sync::Arc, time::Duration};
+
+use anyhow::anyhow;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    backoff::Backoff,
+    message::error_code,
+    runtime::{RpcError, Runtime},
+    services::cache::{Cache, CachePolicy, CacheStats},
+};
+
+/// Bounds on the wait before `try_update` tries again after losing a race.
+const MIN_BACKOFF: Duration = Duration::from_millis(5);
+const MAX_BACKOFF: Duration = Duration::from_millis(200);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum KvPayload {
+    Read {
+        key: Value,
+    },
+    ReadOk {
+        value: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    WriteOk,
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        create_if_not_exists: bool,
+    },
+    CasOk,
+}
+
+/// Why a request to a key/value service failed, telling apart the two
+/// errors callers usually act on.
+#[derive(Debug)]
+pub enum KvError {
+    /// Error 20: the key holds no value.
+    KeyDoesNotExist,
+    /// Error 22: a `cas` found another value than `from`.
+    PreconditionFailed,
+    /// Any other failure, including timeouts.
+    Rpc(RpcError),
+}
+
+impl From<RpcError> for KvError {
+    fn from(err: RpcError) -> Self {
+        match err {
+            RpcError::Remote { code, .. } if code == error_code::KEY_DOES_NOT_EXIST => {
+                KvError::KeyDoesNotExist
+            }
+            RpcError::Remote { code, .. } if code == error_code::PRECONDITION_FAILED => {
+                KvError::PreconditionFailed
+            }
+            err => KvError::Rpc(err),
+        }
+    }
+}
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvError::KeyDoesNotExist => write!(f, "key does not exist"),
+            KvError::PreconditionFailed => write!(f, "precondition failed"),
+            KvError::Rpc(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for KvError {}
+
+/// Client for Maelstrom's key/value services (`seq-kv`, `lin-kv`, `lww-kv`),
+/// which all speak the same `read`/`write`/`cas` protocol.
+///
+/// With a [`CachePolicy`] other than `off`, reads are answered from what
+/// this client last read or wrote, where it has it. A write or a cas that
+/// goes through records the new value; a cas that fails drops the key, so
+/// [`update`](Self::update) retries from a fresh read.
+#[derive(Clone)]
+pub struct Kv {
+    runtime: Runtime,
+    service: &'static str,
+    timeout: Duration,
+    cache: Arc<Cache>,
+}
+
+impl Kv {
+    pub fn new(runtime: Runtime, service: &'static str) -> Self {
+        Self {
+            runtime,
+            service,
+            timeout: Duration::from_millis(1000),
+            cache: Arc::default(),
+        }
+    }
+
+    pub fn with_cache(mut self, policy: CachePolicy) -> Self {
+        self.cache = Arc::new(Cache::new(policy));
+        self
+    }
+
+    /// How the cache did so far.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Logs the cache's [`CacheStats`] to stderr every `interval` while
+    /// they change, unless the cache is off.
+    pub fn report_cache(&self, interval: Duration) {
+        if self.cache.policy() == CachePolicy::Off {
+            return;
+        }
+        let (cache, service) = (self.cache.clone(), self.service);
+        std::thread::spawn(move || {
+            let mut last = CacheStats::default();
+            loop {
+                std::thread::sleep(interval);
+                let stats = cache.stats();
+                if stats != last {
+                    eprintln!("{service} cache: {stats}");
+                    last = stats;
+                }
+            }
+        });
+    }
+
+    fn call(&self, request: KvPayload) -> Result<KvPayload, RpcError> {
+        self.runtime.rpc(self.service, request, self.timeout)
+    }
+
+    pub fn read<K, V>(&self, key: K) -> Result<V, RpcError>
+    where
+        K: Serialize,
+        V: DeserializeOwned,
+    {
+        let key = to_value(key)?;
+        if let Some(value) = self.cache.get(&key) {
+            return from_value(value);
+        }
+        match self.call(KvPayload::Read { key: key.clone() })? {
+            KvPayload::ReadOk { value } => {
+                self.cache.put(&key, value.clone());
+                from_value(value)
+            }
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn write<K, V>(&self, key: K, value: V) -> Result<(), RpcError>
+    where
+        K: Serialize,
+        V: Serialize,
+    {
+        let key = to_value(key)?;
+        let value = to_value(value)?;
+        let request = KvPayload::Write {
+            key: key.clone(),
+            value: value.clone(),
+        };
+        // Whatever the outcome, what was cached may no longer hold.
+        self.cache.invalidate(&key);
+        match self.call(request)? {
+            KvPayload::WriteOk => {
+                self.cache.put(&key, value);
+                Ok(())
+            }
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Replaces `from` with `to`, failing with a precondition error when the
+    /// current value differs.
+    pub fn cas<K, V>(
+        &self,
+        key: K,
+        from: V,
+        to: V,
+        create_if_not_exists: bool,
+    ) -> Result<(), RpcError>
+    where
+        K: Serialize,
+        V: Serialize,
+    {
+        let (key, to) = (to_value(key)?, to_value(to)?);
+        let request = KvPayload::Cas {
+            key: key.clone(),
+            from: to_value(from)?,
+            to: to.clone(),
+            create_if_not_exists,
+        };
+        self.cache.invalidate(&key);
+        match self.call(request)? {
+            KvPayload::CasOk => {
+                self.cache.put(&key, to);
+                Ok(())
+            }
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Replaces the value under `key` with `f` of it, taking a key that does
+    /// not exist for `V::default()`: reads, computes and writes it back with
+    /// cas, and starts over while other writers win the race. Each retry
+    /// waits a random time up to a bound that doubles with every loss, so
+    /// contending nodes spread out. Returns the value written.
+    pub fn update<K, V>(&self, key: K, mut f: impl FnMut(&V) -> V) -> Result<V, RpcError>
+    where
+        K: Serialize,
+        V: Serialize + DeserializeOwned + Default,
+    {
+        self.try_update(key, |current| Ok(f(current)))
+    }
+
+    /// Like [`update`](Self::update), but `f` may refuse the value it is
+    /// given, which ends the update with its error and writes nothing.
+    pub fn try_update<K, V, E>(&self, key: K, mut f: impl FnMut(&V) -> Result<V, E>) -> Result<V, E>
+    where
+        K: Serialize,
+        V: Serialize + DeserializeOwned + Default,
+        E: From<RpcError>,
+    {
+        let mut backoff = Backoff::new(MIN_BACKOFF, MAX_BACKOFF);
+        loop {
+            let current: V = match self.read(&key) {
+                Err(RpcError::Remote { code, .. }) if code == error_code::KEY_DOES_NOT_EXIST => {
+                    V::default()
+                }
+                other => other?,
+            };
+            let next = f(&current)?;
+            match self.cas(&key, &current, &next, true) {
+                Ok(()) => return Ok(next),
+                Err(RpcError::Remote { code, .. }) if code == error_code::PRECONDITION_FAILED => {}
+                Err(err) => return Err(err.into()),
+            }
+            backoff.sleep();
+        }
+    }
+}
+
+fn to_value<T: Serialize>(value: T) -> Result<Value, RpcError> {
+    serde_json::to_value(value).map_err(|err| RpcError::Other(err.into()))
+}
+
+fn from_value<T: DeserializeOwned>(value: Value) -> Result<T, RpcError> {
+    serde_json::from_value(value).map_err(|err| RpcError::Other(err.into()))
+}
+
+fn unexpected(reply: KvPayload) -> RpcError {
+    RpcError::Other(anyhow!("unexpected kv reply {reply:?}"))
+}
@@ -0,0 +1,108 @@
+use std::time::Duration;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    runtime::Runtime,
+    services::{CachePolicy, CacheStats, Kv, KvError, WriteCoalescer},
+};
+
+/// Client for `lin-kv`, which is linearizable: every request sees the
+/// effects of every request that completed before it was sent.
+#[derive(Clone)]
+pub struct LinKv {
+    kv: Kv,
+}
+
+impl LinKv {
+    pub fn new(runtime: Runtime) -> Self {
+        Self {
+            kv: Kv::new(runtime, "lin-kv"),
+        }
+    }
+
+    /// Caches reads; see [`Kv`].
+    pub fn with_cache(self, policy: CachePolicy) -> Self {
+        Self {
+            kv: self.kv.with_cache(policy),
+        }
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.kv.cache_stats()
+    }
+
+    pub fn report_cache(&self, interval: Duration) {
+        self.kv.report_cache(interval)
+    }
+
+    /// Writes through this client, up to `window` at once; see
+    /// [`WriteCoalescer`].
+    pub fn coalescer(&self, window: usize) -> WriteCoalescer {
+        let kv = self.kv.clone();
+        WriteCoalescer::new(window, move |key, value| kv.write(key, value))
+    }
+
+    pub fn read<K, V>(&self, key: K) -> Result<V, KvError>
+    where
+        K: Serialize,
+        V: DeserializeOwned,
+    {
+        Ok(self.kv.read(key)?)
+    }
+
+    /// Reads `key`, taking a key that does not exist for `V::default()`.
+    pub fn read_or_default<K, V>(&self, key: K) -> Result<V, KvError>
+    where
+        K: Serialize,
+        V: DeserializeOwned + Default,
+    {
+        match self.read(key) {
+            Err(KvError::KeyDoesNotExist) => Ok(V::default()),
+            other => other,
+        }
+    }
+
+    pub fn write<K, V>(&self, key: K, value: V) -> Result<(), KvError>
+    where
+        K: Serialize,
+        V: Serialize,
+    {
+        Ok(self.kv.write(key, value)?)
+    }
+
+    /// Replaces `from` with `to`, failing with
+    /// [`KvError::PreconditionFailed`] when the current value differs.
+    pub fn cas<K, V>(
+        &self,
+        key: K,
+        from: V,
+        to: V,
+        create_if_not_exists: bool,
+    ) -> Result<(), KvError>
+    where
+        K: Serialize,
+        V: Serialize,
+    {
+        Ok(self.kv.cas(key, from, to, create_if_not_exists)?)
+    }
+
+    /// Replaces the value under `key` with `f` of it; see [`Kv::update`].
+    pub fn update<K, V>(&self, key: K, f: impl FnMut(&V) -> V) -> Result<V, KvError>
+    where
+        K: Serialize,
+        V: Serialize + DeserializeOwned + Default,
+    {
+        Ok(self.kv.update(key, f)?)
+    }
+
+    /// Adds `delta` to the counter under `key`, starting from 0. Returns the
+    /// value before, which no other caller gets.
+    pub fn incr_with_cas<K>(&self, key: K, delta: usize) -> Result<usize, KvError>
+    where
+        K: Serialize,
+    {
+        let after = self.update(key, |current: &usize| current + delta)?;
+        Ok(after - delta)
+    }
+}
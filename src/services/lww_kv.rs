@@ -0,0 +1,52 @@
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    runtime::Runtime,
+    services::{Kv, KvError},
+};
+
+/// Client for `lww-kv`, which stays available under partitions: each of its
+/// replicas answers on its own, and concurrent writes resolve to whichever
+/// was written last. A read may miss any recent write, so this suits state
+/// that is only kept on a best-effort basis. It offers no `cas`, as the
+/// service cannot make one atomic.
+#[derive(Clone)]
+pub struct LwwKv {
+    kv: Kv,
+}
+
+impl LwwKv {
+    pub fn new(runtime: Runtime) -> Self {
+        Self {
+            kv: Kv::new(runtime, "lww-kv"),
+        }
+    }
+
+    pub fn read<K, V>(&self, key: K) -> Result<V, KvError>
+    where
+        K: Serialize,
+        V: DeserializeOwned,
+    {
+        Ok(self.kv.read(key)?)
+    }
+
+    /// Reads `key`, taking a key that does not exist for `V::default()`.
+    pub fn read_or_default<K, V>(&self, key: K) -> Result<V, KvError>
+    where
+        K: Serialize,
+        V: DeserializeOwned + Default,
+    {
+        match self.read(key) {
+            Err(KvError::KeyDoesNotExist) => Ok(V::default()),
+            other => other,
+        }
+    }
+
+    pub fn write<K, V>(&self, key: K, value: V) -> Result<(), KvError>
+    where
+        K: Serialize,
+        V: Serialize,
+    {
+        Ok(self.kv.write(key, value)?)
+    }
+}
@@ -0,0 +1,15 @@
+//! Clients for the services Maelstrom runs next to the nodes.
+
+pub mod cache;
+pub mod coalesce;
+pub mod kv;
+pub mod lin_kv;
+pub mod lww_kv;
+pub mod seq_kv;
+
+pub use cache::{CachePolicy, CacheStats};
+pub use coalesce::WriteCoalescer;
+pub use kv::{Kv, KvError};
+pub use lin_kv::LinKv;
+pub use lww_kv::LwwKv;
+pub use seq_kv::SeqKv;
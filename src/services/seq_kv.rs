@@ -0,0 +1,79 @@
+use std::time::Duration;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    runtime::Runtime,
+    services::{CachePolicy, CacheStats, Kv, KvError},
+};
+
+/// Client for `seq-kv`, which is sequentially consistent: a `read` may
+/// return a stale value, but a `cas` only succeeds against the current one.
+#[derive(Clone)]
+pub struct SeqKv {
+    kv: Kv,
+}
+
+impl SeqKv {
+    pub fn new(runtime: Runtime) -> Self {
+        Self {
+            kv: Kv::new(runtime, "seq-kv"),
+        }
+    }
+
+    /// Caches reads; see [`Kv`].
+    pub fn with_cache(self, policy: CachePolicy) -> Self {
+        Self {
+            kv: self.kv.with_cache(policy),
+        }
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.kv.cache_stats()
+    }
+
+    pub fn report_cache(&self, interval: Duration) {
+        self.kv.report_cache(interval)
+    }
+
+    pub fn read<K, V>(&self, key: K) -> Result<V, KvError>
+    where
+        K: Serialize,
+        V: DeserializeOwned,
+    {
+        Ok(self.kv.read(key)?)
+    }
+
+    pub fn write<K, V>(&self, key: K, value: V) -> Result<(), KvError>
+    where
+        K: Serialize,
+        V: Serialize,
+    {
+        Ok(self.kv.write(key, value)?)
+    }
+
+    /// Replaces `from` with `to`, failing with
+    /// [`KvError::PreconditionFailed`] when the current value differs.
+    pub fn cas<K, V>(
+        &self,
+        key: K,
+        from: V,
+        to: V,
+        create_if_not_exists: bool,
+    ) -> Result<(), KvError>
+    where
+        K: Serialize,
+        V: Serialize,
+    {
+        Ok(self.kv.cas(key, from, to, create_if_not_exists)?)
+    }
+
+    /// Replaces the value under `key` with `f` of it; see [`Kv::update`].
+    pub fn update<K, V>(&self, key: K, f: impl FnMut(&V) -> V) -> Result<V, KvError>
+    where
+        K: Serialize,
+        V: Serialize + DeserializeOwned + Default,
+    {
+        Ok(self.kv.update(key, f)?)
+    }
+}
@@ -0,0 +1,82 @@
+//! Grow-only set workload (Maelstrom's `g-set`): every node adds to its own
+//! copy and replicates its new elements through a
+//! [`CrdtReplicator`](crate::replication::CrdtReplicator); merging is set
+//! union, so copies converge no matter how gossip is delayed, duplicated or
+//! reordered.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    crdt::GSet,
+    message::{Init, Message},
+    replication::{CrdtReplicator, Gossip},
+    runtime::{Node, Runtime},
+};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Add {
+        element: usize,
+    },
+    AddOk,
+    Read,
+    ReadOk {
+        value: Vec<usize>,
+    },
+    Replicate {
+        gossip: Gossip<GSet<usize>>,
+    },
+    /// Debugging: this node's copy, as a snapshot.
+    Dump,
+    DumpOk {
+        state: Value,
+    },
+}
+
+pub struct GSetNode {
+    runtime: Runtime,
+    set: CrdtReplicator<GSet<usize>>,
+}
+
+impl Node<Payload> for GSetNode {
+    fn from_init(runtime: Runtime, _init: Init) -> anyhow::Result<Self> {
+        let set = CrdtReplicator::mount(
+            runtime.clone(),
+            GSet::new(),
+            Duration::from_millis(300),
+            |gossip| Payload::Replicate { gossip },
+        );
+        set.persist(runtime.config(), runtime.node_id())?;
+        Ok(Self { runtime, set })
+    }
+
+    fn step(&mut self, input: Message<Payload>) -> anyhow::Result<()> {
+        match input.body.payload {
+            Payload::Add { element } => {
+                self.set.update(|set| set.insert(element));
+                self.runtime.reply(&input, Payload::AddOk)?;
+            }
+            Payload::Read => {
+                let value = self.set.read(|set| set.iter().copied().collect());
+                self.runtime.reply(&input, Payload::ReadOk { value })?;
+            }
+            Payload::Replicate { gossip } => {
+                if let Some(gossip) = self.set.receive(&input.src, gossip) {
+                    self.runtime
+                        .send(&input.src, Payload::Replicate { gossip })?;
+                }
+            }
+            Payload::Dump => {
+                let state = self.set.dump();
+                self.runtime.reply(&input, Payload::DumpOk { state })?;
+            }
+            Payload::AddOk | Payload::ReadOk { .. } | Payload::DumpOk { .. } => {}
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,267 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Instant,
+};
+
+use anyhow::Context;
+
+use crate::storage::SyncPolicy;
+
+/// Where a node keeps what it persists: byte values under string keys,
+/// which related values give a common prefix to be scanned together.
+///
+/// Each write is atomic: a killed process leaves a key with its old value
+/// or its new one. Whether a crashed machine keeps it depends on the
+/// backend, until [`flush`](Self::flush) returns.
+pub trait StorageBackend: Send + Sync {
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>>;
+
+    fn put(&self, key: &str, value: &[u8]) -> anyhow::Result<()>;
+
+    /// Removes `key`, if it is there.
+    fn delete(&self, key: &str) -> anyhow::Result<()>;
+
+    /// Writes `to` under `key` if it still holds `from`, `None` meaning it
+    /// must not exist. Returns whether it did.
+    fn cas(&self, key: &str, from: Option<&[u8]>, to: &[u8]) -> anyhow::Result<bool>;
+
+    /// Every key starting with `prefix` and its value, in key order.
+    fn scan(&self, prefix: &str) -> anyhow::Result<Vec<(String, Vec<u8>)>>;
+
+    /// Makes every write so far durable.
+    fn flush(&self) -> anyhow::Result<()>;
+}
+
+/// Keeps everything in memory, for tests and for nodes that need not
+/// survive a restart.
+#[derive(Default, Debug)]
+pub struct MemoryBackend {
+    values: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+
+impl StorageBackend for MemoryBackend {
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.values.lock().unwrap().get(key).cloned())
+    }
+
+    fn put(&self, key: &str, value: &[u8]) -> anyhow::Result<()> {
+        let mut values = self.values.lock().unwrap();
+        values.insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> anyhow::Result<()> {
+        self.values.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn cas(&self, key: &str, from: Option<&[u8]>, to: &[u8]) -> anyhow::Result<bool> {
+        let mut values = self.values.lock().unwrap();
+        if values.get(key).map(Vec::as_slice) != from {
+            return Ok(false);
+        }
+        values.insert(key.to_string(), to.to_vec());
+        Ok(true)
+    }
+
+    fn scan(&self, prefix: &str) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+        let values = self.values.lock().unwrap();
+        Ok(values
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    fn flush(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps each key in a file of its own in a directory, named after the key
+/// with every byte but ASCII letters, digits, `.`, `-` and `_` escaped as
+/// `%XX`. A write goes to a temporary file first, renamed over the key's,
+/// and reaches the disk as the [`SyncPolicy`] says: with `always`, before
+/// the write returns.
+#[derive(Debug)]
+pub struct FileBackend {
+    dir: PathBuf,
+    sync: SyncPolicy,
+    state: Mutex<Unsynced>,
+}
+
+/// Files written since the last sync.
+#[derive(Debug)]
+struct Unsynced {
+    files: Vec<PathBuf>,
+    synced: Instant,
+}
+
+/// Ends temporary file names; keys never do, as `~` is escaped.
+const TEMPORARY: &str = "~";
+
+impl FileBackend {
+    /// The backend in `dir`, created if need be.
+    pub fn open(dir: impl AsRef<Path>, sync: SyncPolicy) -> anyhow::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).with_context(|| format!("create {}", dir.display()))?;
+        Ok(Self {
+            dir,
+            sync,
+            state: Mutex::new(Unsynced {
+                files: Vec::new(),
+                synced: Instant::now(),
+            }),
+        })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(escape(key))
+    }
+
+    fn read(path: &Path) -> anyhow::Result<Option<Vec<u8>>> {
+        match fs::read(path) {
+            Ok(value) => Ok(Some(value)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("read {}", path.display())),
+        }
+    }
+
+    /// Writes `value` to `path`, with `state` locked so writes to one key
+    /// land in order.
+    fn write(&self, state: &mut Unsynced, path: PathBuf, value: &[u8]) -> anyhow::Result<()> {
+        let mut temporary = path.clone().into_os_string();
+        temporary.push(TEMPORARY);
+        let mut file =
+            File::create(&temporary).with_context(|| format!("create {}", path.display()))?;
+        file.write_all(value)?;
+        // Synced before the rename, so the key never holds a torn value.
+        match self.sync {
+            SyncPolicy::Always => file.sync_all()?,
+            _ => state.files.push(path.clone()),
+        }
+        fs::rename(&temporary, &path).with_context(|| format!("replace {}", path.display()))?;
+        self.synced(state, false)
+    }
+
+    /// Syncs the files written since the last time, and the directory
+    /// naming them, if the policy says so by now or `force` is set.
+    fn synced(&self, state: &mut Unsynced, force: bool) -> anyhow::Result<()> {
+        let due = force
+            || match self.sync {
+                SyncPolicy::Always => true,
+                SyncPolicy::Interval(every) => state.synced.elapsed() >= every,
+                SyncPolicy::Never => false,
+            };
+        if !due {
+            return Ok(());
+        }
+        for path in state.files.drain(..) {
+            match File::open(&path) {
+                Ok(file) => file.sync_all()?,
+                // Deleted since.
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(err).with_context(|| format!("sync {}", path.display())),
+            }
+        }
+        File::open(&self.dir)?.sync_all()?;
+        state.synced = Instant::now();
+        Ok(())
+    }
+}
+
+impl StorageBackend for FileBackend {
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Self::read(&self.path(key))
+    }
+
+    fn put(&self, key: &str, value: &[u8]) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        self.write(&mut state, self.path(key), value)
+    }
+
+    fn delete(&self, key: &str) -> anyhow::Result<()> {
+        let path = self.path(key);
+        let mut state = self.state.lock().unwrap();
+        match fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err).with_context(|| format!("remove {}", path.display())),
+        }
+        self.synced(&mut state, false)
+    }
+
+    fn cas(&self, key: &str, from: Option<&[u8]>, to: &[u8]) -> anyhow::Result<bool> {
+        let path = self.path(key);
+        let mut state = self.state.lock().unwrap();
+        if Self::read(&path)?.as_deref() != from {
+            return Ok(false);
+        }
+        self.write(&mut state, path, to)?;
+        Ok(true)
+    }
+
+    fn scan(&self, prefix: &str) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+        let mut pairs = Vec::new();
+        let entries =
+            fs::read_dir(&self.dir).with_context(|| format!("list {}", self.dir.display()))?;
+        for entry in entries {
+            let entry = entry?;
+            let Some(key) = entry.file_name().to_str().and_then(unescape) else {
+                continue;
+            };
+            if !key.starts_with(prefix) {
+                continue;
+            }
+            // Deleted since it was listed.
+            if let Some(value) = Self::read(&entry.path())? {
+                pairs.push((key, value));
+            }
+        }
+        pairs.sort();
+        Ok(pairs)
+    }
+
+    fn flush(&self) -> anyhow::Result<()> {
+        self.synced(&mut self.state.lock().unwrap(), true)
+    }
+}
+
+fn escape(key: &str) -> String {
+    let mut name = String::with_capacity(key.len());
+    for byte in key.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'.' | b'-' | b'_' => name.push(byte as char),
+            _ => name.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    name
+}
+
+/// The key a file is named after, or `None` for anything else in the
+/// directory, such as temporary files.
+fn unescape(name: &str) -> Option<String> {
+    if name.ends_with(TEMPORARY) {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(name.len());
+    let mut rest = name.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        match byte {
+            b'%' => {
+                let hex = std::str::from_utf8(after.get(..2)?).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &after[2..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = after;
+            }
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
@@ -0,0 +1,171 @@
+use std::{marker::PhantomData, sync::Arc};
+
+use anyhow::{bail, Context};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::storage::{crc32, StorageBackend};
+
+/// A snapshot of the state, and the number of the last record it covers.
+#[derive(Serialize, Deserialize)]
+struct Snapshot<S> {
+    seq: u64,
+    state: S,
+}
+
+/// What [`Journal::open`] found: the latest snapshot, if any, and the
+/// records appended after it, in order.
+pub struct Recovered<S, R> {
+    pub snapshot: Option<S>,
+    pub records: Vec<R>,
+    /// Records the snapshot already covered, left behind by a crash
+    /// between writing it and deleting them.
+    pub skipped: usize,
+    /// Whether the last batch failed its checksum and was set aside.
+    pub quarantined: bool,
+}
+
+/// Changes of type `R` with a snapshot of the whole state `S`, kept in a
+/// [`StorageBackend`] so that neither recovery nor the store grow with the
+/// length of the run: the snapshot under `snapshot`, and each batch of
+/// records appended under `log/` and the number of its first record, which
+/// a checkpoint deletes once the snapshot covering them is flushed. A crash
+/// in between leaves batches the snapshot already covers; their numbers
+/// tell recovery to skip them.
+///
+/// Every value is stored behind its CRC-32, four little-endian bytes, and
+/// checked on open. A bad last batch is a write a crashed machine lost,
+/// which was never acknowledged: it is moved under `quarantine/` for
+/// inspection and recovery goes on. A bad snapshot or batch before the last
+/// means the store is corrupt, and opening fails.
+pub struct Journal<S, R> {
+    backend: Arc<dyn StorageBackend>,
+    /// The number of the last record appended.
+    seq: u64,
+    /// The keys of the batches appended since the last checkpoint.
+    batches: Vec<String>,
+    /// Records appended since the last checkpoint.
+    pending: usize,
+    types: PhantomData<fn() -> (S, R)>,
+}
+
+const SNAPSHOT: &str = "snapshot";
+const LOG: &str = "log/";
+const QUARANTINE: &str = "quarantine/";
+
+/// `value` behind its checksum.
+fn checksummed(value: &impl Serialize) -> anyhow::Result<Vec<u8>> {
+    let json = serde_json::to_vec(value)?;
+    let mut bytes = crc32(&json).to_le_bytes().to_vec();
+    bytes.extend(json);
+    Ok(bytes)
+}
+
+/// The value `bytes` hold, if their checksum matches.
+fn checked<T: DeserializeOwned>(key: &str, bytes: &[u8]) -> anyhow::Result<T> {
+    let Some((crc, json)) = bytes.split_first_chunk::<4>() else {
+        bail!("{key} is too short for its checksum");
+    };
+    if u32::from_le_bytes(*crc) != crc32(json) {
+        bail!("{key} fails its checksum");
+    }
+    serde_json::from_slice(json).with_context(|| format!("corrupt {key}"))
+}
+
+impl<S, R> Journal<S, R>
+where
+    S: Serialize + DeserializeOwned,
+    R: Serialize + DeserializeOwned,
+{
+    /// Recovers what `backend` holds, and journals to it from now on.
+    pub fn open(backend: Arc<dyn StorageBackend>) -> anyhow::Result<(Self, Recovered<S, R>)> {
+        let saved: Option<Snapshot<S>> = match backend.get(SNAPSHOT)? {
+            Some(bytes) => Some(checked(SNAPSHOT, &bytes)?),
+            None => None,
+        };
+        let (seq, state) = match saved {
+            Some(saved) => (saved.seq, Some(saved.state)),
+            None => (0, None),
+        };
+        let mut last = seq;
+        let mut records = Vec::new();
+        let mut batches = Vec::new();
+        let mut skipped = 0;
+        let mut quarantined = false;
+        let saved = backend.scan(LOG)?;
+        let count = saved.len();
+        for (at, (key, bytes)) in saved.into_iter().enumerate() {
+            let first: u64 = key[LOG.len()..]
+                .parse()
+                .with_context(|| format!("bad journal key {key}"))?;
+            let batch: Vec<R> = match checked(&key, &bytes) {
+                Ok(batch) => batch,
+                Err(err) if at + 1 == count => {
+                    eprintln!("quarantining the last journal batch: {err:#}");
+                    let moved = format!("{QUARANTINE}{}", &key[LOG.len()..]);
+                    backend.put(&moved, &bytes)?;
+                    backend.delete(&key)?;
+                    quarantined = true;
+                    break;
+                }
+                Err(err) => return Err(err),
+            };
+            for (number, record) in (first..).zip(batch) {
+                match number > seq {
+                    true => records.push(record),
+                    false => skipped += 1,
+                }
+                last = last.max(number);
+            }
+            batches.push(key);
+        }
+        let journal = Self {
+            backend,
+            seq: last,
+            batches,
+            pending: records.len(),
+            types: PhantomData,
+        };
+        let recovered = Recovered {
+            snapshot: state,
+            records,
+            skipped,
+            quarantined,
+        };
+        Ok((journal, recovered))
+    }
+
+    /// Appends `records` as one batch.
+    pub fn append(&mut self, records: &[R]) -> anyhow::Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        // Zero-padded, so batches scan in order.
+        let key = format!("{LOG}{:020}", self.seq + 1);
+        self.backend.put(&key, &checksummed(&records)?)?;
+        self.batches.push(key);
+        self.seq += records.len() as u64;
+        self.pending += records.len();
+        Ok(())
+    }
+
+    /// How many records were appended since the last checkpoint.
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    /// Replaces the snapshot with `state`, which must hold every record
+    /// appended so far, and deletes the batches it covers.
+    pub fn checkpoint(&mut self, state: &S) -> anyhow::Result<()> {
+        let snapshot = Snapshot {
+            seq: self.seq,
+            state,
+        };
+        self.backend.put(SNAPSHOT, &checksummed(&snapshot)?)?;
+        self.backend.flush()?;
+        for key in self.batches.drain(..) {
+            self.backend.delete(&key)?;
+        }
+        self.pending = 0;
+        Ok(())
+    }
+}
@@ -0,0 +1,35 @@
+//! Building blocks for keeping a node's state on local disk, in a
+//! [`StorageBackend`] that tests can swap for memory, or as a tree of
+//! immutable values in `lin-kv` (see [`Thunk`]).
+//!
+//! The broadcast values (through a [`Journal`]), the kafka logs and the
+//! replicated CRDT snapshots live in a [`StorageBackend`]. Raft's term,
+//! vote and log and the sharded transactions' log stay on a [`Wal`] of
+//! their own: both only ever append and replay the whole file, which a
+//! single file does best.
+
+pub mod backend;
+pub mod checkpoint;
+pub mod root;
+pub mod thunk;
+pub mod wal;
+
+pub use backend::{FileBackend, MemoryBackend, StorageBackend};
+pub use checkpoint::{Journal, Recovered};
+pub use root::{Conflict, Resolution, Root, RootError};
+pub use thunk::{Thunk, Thunks};
+pub use wal::{Replay, SyncPolicy, Wal};
+
+/// CRC-32 (IEEE), computed bitwise: records are small and written rarely
+/// enough that a table is not worth it.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
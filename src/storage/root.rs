@@ -0,0 +1,182 @@
+use std::{fmt, marker::PhantomData, sync::Arc, time::Duration};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    backoff::Backoff,
+    message::error_code,
+    runtime::{RpcError, Runtime},
+    services::Kv,
+    storage::{Thunk, Thunks},
+};
+
+/// Bounds on the wait before an update tries again on a root that moved.
+const MIN_BACKOFF: Duration = Duration::from_millis(5);
+const MAX_BACKOFF: Duration = Duration::from_millis(200);
+
+/// A swap of the root that lost to another one, for a merge hook to settle.
+pub struct Conflict<'a, T> {
+    /// How many swaps were tried so far, this one included.
+    pub attempt: usize,
+    /// The tree `ours` was built from.
+    pub base: Option<&'a T>,
+    pub ours: &'a T,
+    /// The tree the root points to now.
+    pub theirs: Option<&'a T>,
+}
+
+/// What a merge hook makes of a [`Conflict`].
+pub enum Resolution<T> {
+    /// Swap the root from `theirs` to this tree, built from both sides,
+    /// after a backoff.
+    Merged(T),
+    /// Build again from `theirs`, after a backoff.
+    Rebuild,
+    /// Give up with [`RootError::Conflict`].
+    Abort,
+}
+
+/// Why [`Root::update`] did not swap the root.
+#[derive(Debug)]
+pub enum RootError {
+    /// The root kept moving and the merge hook gave up.
+    Conflict,
+    Kv(RpcError),
+}
+
+impl From<RpcError> for RootError {
+    fn from(err: RpcError) -> Self {
+        RootError::Kv(err)
+    }
+}
+
+impl fmt::Display for RootError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RootError::Conflict => write!(f, "the root moved"),
+            RootError::Kv(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for RootError {}
+
+/// The one mutable key of a persistent structure kept in `lin-kv`: it holds
+/// the id of the [`Thunk`] at the top of the current tree, and everything
+/// else is immutable.
+///
+/// [`update`](Self::update) reads the root, has the caller build a new tree,
+/// saves it and swaps the root to it with cas. When another update swapped
+/// the root first, a merge hook decides whether to fold both trees into
+/// one, to build again from the newer tree, or to give up.
+pub struct Root<T> {
+    kv: Kv,
+    key: String,
+    thunks: Thunks,
+    _tree: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for Root<T> {
+    fn clone(&self) -> Self {
+        Self {
+            kv: self.kv.clone(),
+            key: self.key.clone(),
+            thunks: self.thunks.clone(),
+            _tree: PhantomData,
+        }
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Root<T> {
+    /// The root under `key`, pointing into `thunks`.
+    pub fn new(runtime: Runtime, key: impl Into<String>, thunks: Thunks) -> Self {
+        Self {
+            kv: Kv::new(runtime, "lin-kv"),
+            key: key.into(),
+            thunks,
+            _tree: PhantomData,
+        }
+    }
+
+    pub fn thunks(&self) -> &Thunks {
+        &self.thunks
+    }
+
+    /// The current tree, `None` before the first update.
+    pub fn read(&self) -> Result<Option<Thunk<T>>, RpcError> {
+        match self.kv.read(&self.key) {
+            Ok(root) => Ok(Some(root)),
+            Err(RpcError::Remote { code, .. }) if code == error_code::KEY_DOES_NOT_EXIST => {
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn load(&self, tree: &Option<Thunk<T>>) -> Result<Option<Arc<T>>, RpcError> {
+        tree.as_ref().map(|tree| tree.get(&self.thunks)).transpose()
+    }
+
+    /// Points the root at `to` if it still points at `from`, saving `to`
+    /// first. Whatever `to` refers to must be saved already.
+    pub fn swap(&self, from: Option<&Thunk<T>>, to: &mut Thunk<T>) -> Result<bool, RpcError> {
+        to.save(&self.thunks)?;
+        match self.kv.cas(&self.key, from, Some(&*to), from.is_none()) {
+            Ok(()) => Ok(true),
+            Err(RpcError::Remote { code, .. })
+                if code == error_code::PRECONDITION_FAILED
+                    || code == error_code::KEY_DOES_NOT_EXIST =>
+            {
+                Ok(false)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Swaps the root to the tree `build` makes of the current one, saving
+    /// the thunks under it first, and returns what `build` returned along
+    /// with it. A `build` that returns no tree leaves the root as it is.
+    ///
+    /// When the root moved meanwhile, `merge` is handed the conflict and
+    /// what `build` returned, and settles it with a [`Resolution`].
+    pub fn update<R>(
+        &self,
+        mut build: impl FnMut(Option<&T>) -> Result<(Option<T>, R), RpcError>,
+        mut merge: impl FnMut(&R, Conflict<'_, T>) -> Resolution<T>,
+    ) -> Result<R, RootError> {
+        let mut base = self.read()?;
+        let mut attempt = 0;
+        let mut backoff = Backoff::new(MIN_BACKOFF, MAX_BACKOFF);
+        loop {
+            let (tree, result) = build(self.load(&base)?.as_deref())?;
+            let Some(tree) = tree else {
+                return Ok(result);
+            };
+            let mut ours = Thunk::new(tree);
+            loop {
+                attempt += 1;
+                if self.swap(base.as_ref(), &mut ours)? {
+                    return Ok(result);
+                }
+                let theirs = self.read()?;
+                let resolution = merge(
+                    &result,
+                    Conflict {
+                        attempt,
+                        base: self.load(&base)?.as_deref(),
+                        ours: ours.get(&self.thunks)?.as_ref(),
+                        theirs: self.load(&theirs)?.as_deref(),
+                    },
+                );
+                base = theirs;
+                match resolution {
+                    Resolution::Merged(tree) => ours = Thunk::new(tree),
+                    Resolution::Rebuild => break,
+                    Resolution::Abort => return Err(RootError::Conflict),
+                }
+                backoff.sleep();
+            }
+            backoff.sleep();
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    fmt,
+    sync::{Arc, OnceLock},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+use ulid::Ulid;
+
+use crate::{
+    runtime::{RpcError, Runtime},
+    services::{CachePolicy, Kv},
+};
+
+/// Where [`Thunk`]s are kept: one `lin-kv` key each, under a prefix.
+///
+/// A thunk is written once and never changed, so this client caches every
+/// thunk it reads or writes for good, and a node only fetches thunks other
+/// nodes wrote since it last looked.
+#[derive(Clone)]
+pub struct Thunks {
+    kv: Kv,
+    prefix: &'static str,
+}
+
+impl Thunks {
+    pub fn new(runtime: Runtime, prefix: &'static str) -> Self {
+        Self {
+            kv: Kv::new(runtime, "lin-kv").with_cache(CachePolicy::On),
+            prefix,
+        }
+    }
+
+    fn key(&self, id: &str) -> String {
+        format!("{}/{id}", self.prefix)
+    }
+}
+
+/// An immutable value stored under a generated id, which is all it
+/// serializes to: a thunk can hold other thunks, and a whole tree of them
+/// is referred to by the id of its root.
+///
+/// A thunk made with [`new`](Self::new) is only in memory until
+/// [`save`](Self::save)d; one read back from its id is fetched the first
+/// time it is [`get`](Self::get). Save a thunk before anything that refers
+/// to it, and the thunks under a root before the root pointer: a reader
+/// then never finds an id it cannot fetch. Thunks that end up referred to
+/// by nothing, as when the root pointer moved on before it could be
+/// swapped, are left behind.
+pub struct Thunk<T> {
+    id: String,
+    value: OnceLock<Arc<T>>,
+    saved: bool,
+}
+
+impl<T> Thunk<T> {
+    /// A thunk for `value` under a fresh id, not saved yet.
+    pub fn new(value: T) -> Self {
+        Self {
+            id: Ulid::new().to_string(),
+            value: OnceLock::from(Arc::new(value)),
+            saved: false,
+        }
+    }
+
+    /// The thunk saved under `id`, fetched when first needed.
+    pub fn at(id: String) -> Self {
+        Self {
+            id,
+            value: OnceLock::new(),
+            saved: true,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn is_saved(&self) -> bool {
+        self.saved
+    }
+}
+
+impl<T: DeserializeOwned> Thunk<T> {
+    /// The value, fetched from `thunks` on first use.
+    pub fn get(&self, thunks: &Thunks) -> Result<Arc<T>, RpcError> {
+        if let Some(value) = self.value.get() {
+            return Ok(value.clone());
+        }
+        let value = Arc::new(thunks.kv.read(thunks.key(&self.id))?);
+        // Another caller may have fetched it meanwhile; both read the same.
+        Ok(self.value.get_or_init(|| value).clone())
+    }
+}
+
+impl<T: Serialize> Thunk<T> {
+    /// Writes the value to `thunks`, unless it is there already.
+    pub fn save(&mut self, thunks: &Thunks) -> Result<(), RpcError> {
+        if self.saved {
+            return Ok(());
+        }
+        let value = self.value.get().expect("unsaved thunks hold their value");
+        thunks.kv.write(thunks.key(&self.id), value.as_ref())?;
+        self.saved = true;
+        Ok(())
+    }
+}
+
+impl<T> Clone for Thunk<T> {
+    fn clone(&self) -> Self {
+        Self {
+            id: self.id.clone(),
+            value: self.value.clone(),
+            saved: self.saved,
+        }
+    }
+}
+
+impl<T> fmt::Debug for Thunk<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Thunk({})", self.id)
+    }
+}
+
+impl<T> PartialEq for Thunk<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl<T> Eq for Thunk<T> {}
+
+impl<T> Serialize for Thunk<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.id.serialize(serializer)
+    }
+}
+
+impl<'de, T> Deserialize<'de> for Thunk<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Self::at)
+    }
+}
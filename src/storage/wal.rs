@@ -0,0 +1,236 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Write},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::storage::crc32;
+
+/// Bytes in front of each record: its length, then its CRC-32.
+const HEADER: usize = 8;
+/// The longest record written. A length beyond it is never a torn write.
+const MAX_RECORD: usize = 64 << 20;
+
+/// When a [`Wal`] makes its appends durable.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Before every append returns.
+    #[default]
+    Always,
+    /// At the first append once this long passed since the last sync, so a
+    /// crash of the machine loses at most about that much.
+    Interval(Duration),
+    /// Never: the operating system writes appends out in its own time. That
+    /// survives the process being killed, though not the machine.
+    Never,
+}
+
+impl FromStr for SyncPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => match s.parse() {
+                Ok(millis) => Ok(Self::Interval(Duration::from_millis(millis))),
+                Err(_) => bail!(
+                    "unknown wal sync policy {s}, \
+                     expected always, never or a number of milliseconds"
+                ),
+            },
+        }
+    }
+}
+
+/// A write-ahead log: an append-only file of records, each a JSON value
+/// behind its length and CRC-32, both four little-endian bytes.
+///
+/// On open every record is checked. A bad record at the very end is a
+/// write a crash cut short, which was never acknowledged: it is cut off,
+/// kept in a `.corrupt` file beside the log for inspection, and recovery
+/// goes on. A record claiming more bytes than the file has left, but no
+/// more than a record may hold, is taken for one too. A bad record anywhere
+/// else, or one claiming more than a record may hold, means the file is
+/// corrupt, and opening fails rather than bring the node back with state it
+/// may have promised differently.
+pub struct Wal {
+    path: PathBuf,
+    file: File,
+    sync: SyncPolicy,
+    synced: Instant,
+}
+
+impl Wal {
+    /// Opens the log at `path`, creating it if need be, and returns it with
+    /// the records it holds, to replay.
+    pub fn open<R>(path: &Path, sync: SyncPolicy) -> anyhow::Result<(Self, Replay<R>)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .with_context(|| format!("open {}", path.display()))?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)
+            .with_context(|| format!("read {}", path.display()))?;
+        // The length of the good records, where a torn one is cut off.
+        let mut good = 0;
+        let mut count = 0;
+        while good < bytes.len() {
+            match record(&bytes[good..]) {
+                Some(record) => good += HEADER + record.len(),
+                None if torn(&bytes[good..]) => break,
+                None => bail!("{} is corrupt at record {}", path.display(), count + 1),
+            }
+            count += 1;
+        }
+        if bytes.len() != good {
+            let quarantine = beside(path, "corrupt");
+            let mut corrupt = OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(&quarantine)
+                .with_context(|| format!("open {}", quarantine.display()))?;
+            corrupt.write_all(&bytes[good..])?;
+            corrupt.sync_all()?;
+            eprintln!(
+                "{}: moved a torn last record of {} bytes to {}",
+                path.display(),
+                bytes.len() - good,
+                quarantine.display()
+            );
+            bytes.truncate(good);
+            file.set_len(good as u64)?;
+            file.sync_all()?;
+        }
+        let wal = Self {
+            path: path.to_path_buf(),
+            file,
+            sync,
+            synced: Instant::now(),
+        };
+        let replay = Replay {
+            path: path.to_path_buf(),
+            bytes,
+            at: 0,
+            number: 0,
+            record: PhantomData,
+        };
+        Ok((wal, replay))
+    }
+
+    /// Appends `records`, and syncs them as the policy says.
+    pub fn append<R: Serialize>(&mut self, records: &[R]) -> anyhow::Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        self.file.write_all(&encode(records)?)?;
+        let due = match self.sync {
+            SyncPolicy::Always => true,
+            SyncPolicy::Interval(every) => self.synced.elapsed() >= every,
+            SyncPolicy::Never => false,
+        };
+        if due {
+            self.file.sync_data()?;
+            self.synced = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Replaces the log with `records`, through a temporary file synced to
+    /// disk first whatever the policy, so a crash leaves either the old log
+    /// or the new.
+    pub fn rewrite<R: Serialize>(&mut self, records: &[R]) -> anyhow::Result<()> {
+        let temporary = beside(&self.path, "tmp");
+        let mut file =
+            File::create(&temporary).with_context(|| format!("create {}", temporary.display()))?;
+        file.write_all(&encode(records)?)?;
+        file.sync_all()?;
+        fs::rename(&temporary, &self.path)
+            .with_context(|| format!("replace {}", self.path.display()))?;
+        // The rename itself is only durable once the directory is synced.
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)
+            .and_then(|dir| dir.sync_all())
+            .with_context(|| format!("sync {}", dir.display()))?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.synced = Instant::now();
+        Ok(())
+    }
+}
+
+/// The records a [`Wal`] held when it was opened, in order. Their checksums
+/// were checked on open; a record that does not decode as `R` is an error.
+pub struct Replay<R> {
+    path: PathBuf,
+    bytes: Vec<u8>,
+    at: usize,
+    number: usize,
+    record: PhantomData<R>,
+}
+
+impl<R: DeserializeOwned> Iterator for Replay<R> {
+    type Item = anyhow::Result<R>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let json = record(&self.bytes[self.at..])?;
+        self.at += HEADER + json.len();
+        self.number += 1;
+        let decoded = serde_json::from_slice(json)
+            .with_context(|| format!("{} record {}", self.path.display(), self.number));
+        Some(decoded)
+    }
+}
+
+/// `path` with `.suffix` added after its extension.
+fn beside(path: &Path, suffix: &str) -> PathBuf {
+    let mut beside = path.as_os_str().to_owned();
+    beside.push(format!(".{suffix}"));
+    PathBuf::from(beside)
+}
+
+fn encode<R: Serialize>(records: &[R]) -> anyhow::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    for record in records {
+        let json = serde_json::to_vec(record)?;
+        if json.len() > MAX_RECORD {
+            bail!("a record of {} bytes is over {MAX_RECORD}", json.len());
+        }
+        let len = json.len() as u32;
+        buffer.extend_from_slice(&len.to_le_bytes());
+        buffer.extend_from_slice(&crc32(&json).to_le_bytes());
+        buffer.extend_from_slice(&json);
+    }
+    Ok(buffer)
+}
+
+/// The record `bytes` start with, or `None` if it is cut short or its
+/// checksum is off.
+fn record(bytes: &[u8]) -> Option<&[u8]> {
+    let (len, rest) = bytes.split_first_chunk::<4>()?;
+    let (checksum, rest) = rest.split_first_chunk::<4>()?;
+    let json = rest.get(..u32::from_le_bytes(*len) as usize)?;
+    (u32::from_le_bytes(*checksum) == crc32(json)).then_some(json)
+}
+
+/// Whether the bad record `bytes` start with is the last thing in the file,
+/// as a write cut short would be, and no longer than a record can be.
+fn torn(bytes: &[u8]) -> bool {
+    match bytes.split_first_chunk::<4>() {
+        Some((len, _)) => {
+            let len = u32::from_le_bytes(*len) as usize;
+            len <= MAX_RECORD && HEADER + len >= bytes.len()
+        }
+        None => true,
+    }
+}
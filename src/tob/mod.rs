@@ -0,0 +1,119 @@
+//! Total-order broadcast: the node with the lowest id is the sequencer. It
+//! numbers every broadcast value and delivers it to every node, which apply
+//! values strictly in sequence order, so `read` returns the same list, in
+//! the same order, everywhere (a prefix of it while deliveries are still in
+//! flight).
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    message::{Init, Message},
+    runtime::{Node, Runtime},
+};
+
+const FORWARD_TIMEOUT: Duration = Duration::from_millis(1000);
+const DELIVER_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Broadcast {
+        message: usize,
+    },
+    BroadcastOk,
+    Read,
+    ReadOk {
+        messages: Vec<usize>,
+    },
+    Topology {
+        topology: HashMap<String, Vec<String>>,
+    },
+    TopologyOk,
+    /// From the sequencer: `message` is number `seq` in the total order.
+    Deliver {
+        seq: usize,
+        message: usize,
+    },
+    DeliverOk,
+}
+
+pub struct TobNode {
+    runtime: Runtime,
+    sequencer: String,
+    // Sequence number the sequencer assigns next.
+    next_seq: usize,
+    // Values in delivery order.
+    delivered: Vec<usize>,
+    // Values that arrived ahead of a gap, by sequence number.
+    pending: BTreeMap<usize, usize>,
+}
+
+impl Node<Payload> for TobNode {
+    fn from_init(runtime: Runtime, init: Init) -> anyhow::Result<Self> {
+        let sequencer = init.node_ids.iter().min().cloned().unwrap_or(init.node_id);
+        Ok(Self {
+            runtime,
+            sequencer,
+            next_seq: 0,
+            delivered: Vec::new(),
+            pending: BTreeMap::new(),
+        })
+    }
+
+    fn step(&mut self, input: Message<Payload>) -> anyhow::Result<()> {
+        match input.body.payload {
+            Payload::Broadcast { message } => {
+                if self.sequencer != self.runtime.node_id() {
+                    self.runtime
+                        .forward(&self.sequencer, input, FORWARD_TIMEOUT);
+                    return Ok(());
+                }
+                let seq = self.next_seq;
+                self.next_seq += 1;
+                self.deliver(seq, message);
+                for peer in self.runtime.peers() {
+                    let payload = Payload::Deliver { seq, message };
+                    self.runtime.deliver(peer, payload, DELIVER_TIMEOUT);
+                }
+                self.runtime.reply(&input, Payload::BroadcastOk)?;
+            }
+            Payload::Deliver { seq, message } => {
+                self.deliver(seq, message);
+                self.runtime.reply(&input, Payload::DeliverOk)?;
+            }
+            Payload::Read => {
+                let messages = self.delivered.clone();
+                self.runtime.reply(&input, Payload::ReadOk { messages })?;
+            }
+            Payload::Topology { .. } => {
+                // Every value comes from the sequencer; the topology is moot.
+                self.runtime.reply(&input, Payload::TopologyOk)?;
+            }
+            Payload::BroadcastOk
+            | Payload::ReadOk { .. }
+            | Payload::TopologyOk
+            | Payload::DeliverOk => {}
+        }
+        Ok(())
+    }
+}
+
+impl TobNode {
+    /// Buffers a sequenced value, then delivers every value that is now
+    /// next in line. Duplicates are ignored.
+    fn deliver(&mut self, seq: usize, message: usize) {
+        if seq < self.delivered.len() {
+            return;
+        }
+        self.pending.insert(seq, message);
+        while let Some(message) = self.pending.remove(&self.delivered.len()) {
+            self.delivered.push(message);
+        }
+    }
+}
@@ -0,0 +1,525 @@
+//! How messages get in and out of a node. Maelstrom talks to nodes over
+//! stdin and stdout ([`Stdio`]); [`Channel`] connects nodes within one
+//! process instead, [`Tcp`] across a real network, and any other
+//! [`Transport`] can carry the same nodes without touching their handlers.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    config::Config,
+    message::{Message, MessageBody, RawMessage},
+};
+
+/// How long a peer that could not be reached is left alone before the
+/// next attempt. The wait doubles with each failed one, up to `MAX_BACKOFF`.
+const MIN_BACKOFF: Duration = Duration::from_millis(50);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long a write may block on a connection that stopped reading before
+/// the connection is given up on.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Carries messages to and from a node. `main_loop` receives on one thread
+/// while the node sends from any number of others, so both take `&self`.
+pub trait Transport: Send + Sync {
+    /// Blocks until the next message arrives. `None` once no more will.
+    fn recv(&self) -> anyhow::Result<Option<RawMessage>>;
+
+    fn send(&self, message: &RawMessage) -> anyhow::Result<()>;
+}
+
+/// One JSON message per line, read from stdin and written to stdout.
+#[derive(Default, Debug)]
+pub struct Stdio;
+
+impl Transport for Stdio {
+    fn recv(&self) -> anyhow::Result<Option<RawMessage>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if std::io::stdin().lock().read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            if !line.trim().is_empty() {
+                break;
+            }
+        }
+        let message = serde_json::from_str(&line).context("Message input failed to deserialize")?;
+        Ok(Some(message))
+    }
+
+    fn send(&self, message: &RawMessage) -> anyhow::Result<()> {
+        let mut output = std::io::stdout().lock();
+        serde_json::to_writer(&mut output, message).context("Serialize message")?;
+        output.write_all(b"\n").context("trailing new line")?;
+        Ok(())
+    }
+}
+
+/// Messages passed over in-process channels, for running nodes side by side
+/// in one process, as tests do. Whoever holds the other ends routes them.
+#[derive(Debug)]
+pub struct Channel {
+    inbox: Mutex<mpsc::Receiver<RawMessage>>,
+    outbox: mpsc::Sender<RawMessage>,
+}
+
+impl Channel {
+    /// A transport receiving from `inbox` and sending to `outbox`.
+    pub fn new(inbox: mpsc::Receiver<RawMessage>, outbox: mpsc::Sender<RawMessage>) -> Self {
+        Self {
+            inbox: Mutex::new(inbox),
+            outbox,
+        }
+    }
+}
+
+impl Transport for Channel {
+    fn recv(&self) -> anyhow::Result<Option<RawMessage>> {
+        Ok(self.inbox.lock().unwrap().recv().ok())
+    }
+
+    fn send(&self, message: &RawMessage) -> anyhow::Result<()> {
+        self.outbox
+            .send(message.clone())
+            .map_err(|_| anyhow!("channel to {} closed", message.dest))
+    }
+}
+
+/// In-process links between nodes and clients, routed by id, for running
+/// a whole cluster in one process. Links can be cut to partition it, and a
+/// node can [`leave`](Self::leave) as if it crashed. Messages to ids that
+/// are not on the network are dropped.
+#[derive(Clone, Default)]
+pub struct Network {
+    links: Arc<Mutex<Links>>,
+}
+
+#[derive(Default)]
+struct Links {
+    /// Each id's inbox, with the generation of the endpoint reading it.
+    inboxes: HashMap<String, (usize, mpsc::Sender<RawMessage>)>,
+    generations: usize,
+    /// Pairs that cannot reach each other, either way.
+    cut: HashSet<(String, String)>,
+}
+
+impl Network {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// An endpoint for `id`, taking over from any earlier one.
+    pub fn join(&self, id: &str) -> Endpoint {
+        let (inbox, receiver) = mpsc::channel();
+        let mut links = self.links.lock().unwrap();
+        links.generations += 1;
+        let generation = links.generations;
+        links.inboxes.insert(id.to_string(), (generation, inbox));
+        Endpoint {
+            id: id.to_string(),
+            generation,
+            network: self.clone(),
+            inbox: Mutex::new(receiver),
+            next_msg_id: AtomicUsize::new(0),
+        }
+    }
+
+    /// Takes `id` off the network: its endpoint's `recv` returns `None`,
+    /// and what it still sends is dropped.
+    pub fn leave(&self, id: &str) {
+        self.links.lock().unwrap().inboxes.remove(id);
+    }
+
+    /// Drops every message between `a` and `b` until [`heal`](Self::heal).
+    pub fn cut(&self, a: &str, b: &str) {
+        let mut links = self.links.lock().unwrap();
+        links.cut.insert((a.to_string(), b.to_string()));
+        links.cut.insert((b.to_string(), a.to_string()));
+    }
+
+    /// Cuts `id` off from every other id on the network.
+    pub fn isolate(&self, id: &str) {
+        let others: Vec<String> = self.links.lock().unwrap().inboxes.keys().cloned().collect();
+        for other in others.iter().filter(|other| *other != id) {
+            self.cut(id, other);
+        }
+    }
+
+    /// Restores every link cut.
+    pub fn heal(&self) {
+        self.links.lock().unwrap().cut.clear();
+    }
+}
+
+/// One id's end of a [`Network`].
+///
+/// Besides carrying a node, an endpoint can stand in for a client or a
+/// node of its own, sending requests and replies as Maelstrom's would.
+pub struct Endpoint {
+    id: String,
+    generation: usize,
+    network: Network,
+    inbox: Mutex<mpsc::Receiver<RawMessage>>,
+    next_msg_id: AtomicUsize,
+}
+
+impl Endpoint {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Like [`recv`](Transport::recv), giving up after `timeout`.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<RawMessage> {
+        self.inbox.lock().unwrap().recv_timeout(timeout).ok()
+    }
+
+    fn write(
+        &self,
+        dest: &str,
+        in_reply_to: Option<usize>,
+        payload: impl Serialize,
+    ) -> anyhow::Result<usize> {
+        let msg_id = self.next_msg_id.fetch_add(1, Ordering::SeqCst) + 1;
+        self.send(&Message {
+            src: self.id.clone(),
+            dest: dest.to_string(),
+            body: MessageBody {
+                msg_id: Some(msg_id),
+                in_reply_to,
+                lamport: None,
+                payload: serde_json::to_value(payload)?,
+            },
+        })?;
+        Ok(msg_id)
+    }
+
+    /// Sends `payload` to `dest` and returns its msg_id.
+    pub fn request(&self, dest: &str, payload: impl Serialize) -> anyhow::Result<usize> {
+        self.write(dest, None, payload)
+    }
+
+    pub fn reply(&self, request: &RawMessage, payload: impl Serialize) -> anyhow::Result<()> {
+        self.write(&request.src, request.body.msg_id, payload)?;
+        Ok(())
+    }
+
+    /// Sends `payload` to `dest` and waits up to `timeout` for the reply,
+    /// dropping whatever else arrives meanwhile. Returns the reply's
+    /// payload, Maelstrom errors included.
+    pub fn rpc(
+        &self,
+        dest: &str,
+        payload: impl Serialize,
+        timeout: Duration,
+    ) -> anyhow::Result<Value> {
+        let msg_id = self.request(dest, payload)?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            let reply = self
+                .recv_timeout(left)
+                .with_context(|| format!("no reply from {dest}"))?;
+            if reply.body.in_reply_to == Some(msg_id) {
+                return Ok(reply.body.payload);
+            }
+        }
+    }
+}
+
+impl Transport for Endpoint {
+    fn recv(&self) -> anyhow::Result<Option<RawMessage>> {
+        Ok(self.inbox.lock().unwrap().recv().ok())
+    }
+
+    fn send(&self, message: &RawMessage) -> anyhow::Result<()> {
+        let links = self.network.links.lock().unwrap();
+        let current = links
+            .inboxes
+            .get(&self.id)
+            .map(|(generation, _)| *generation);
+        if current != Some(self.generation) {
+            return Ok(());
+        }
+        if links
+            .cut
+            .contains(&(message.src.clone(), message.dest.clone()))
+        {
+            return Ok(());
+        }
+        if let Some((_, inbox)) = links.inboxes.get(&message.dest) {
+            let _ = inbox.send(message.clone());
+        }
+        Ok(())
+    }
+}
+
+/// Which transport `main_loop` runs a node over.
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub enum TransportMode {
+    #[default]
+    Stdio,
+    Tcp,
+}
+
+impl FromStr for TransportMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "stdio" => Ok(TransportMode::Stdio),
+            "tcp" => Ok(TransportMode::Tcp),
+            _ => bail!("unknown transport {s}, expected stdio or tcp"),
+        }
+    }
+}
+
+/// Newline-delimited JSON over TCP, for running nodes as networked
+/// processes without Maelstrom.
+///
+/// Every message for this node, from peers and clients alike, arrives on
+/// connections accepted at the listening address. Messages to a peer go out
+/// over one connection of its own, dialled on first use and again whenever
+/// it breaks, backing off while the peer cannot be reached; what is sent to
+/// it meanwhile is dropped, as on a lossy network. Anyone else is answered
+/// over the latest connection it sent from. A connection that stops reading
+/// is dropped once a write to it blocks for a second.
+pub struct Tcp {
+    addr: SocketAddr,
+    inbox: Mutex<mpsc::Receiver<RawMessage>>,
+    // Feeds messages this node sends itself back into its inbox.
+    loopback: mpsc::Sender<RawMessage>,
+    // Learnt from the first message, the init, which is addressed to it.
+    node_id: OnceLock<String>,
+    peers: HashMap<String, mpsc::Sender<Vec<u8>>>,
+    clients: Arc<Mutex<HashMap<String, Client>>>,
+}
+
+/// The connection a client was last heard from, shared by every sender on
+/// it so that lines written to it do not interleave.
+type Client = Arc<Mutex<TcpStream>>;
+
+impl Tcp {
+    /// Listens on `listen` and reaches each of `peers`, node ids mapped to
+    /// `host:port` addresses, at its address.
+    pub fn bind(listen: &str, peers: HashMap<String, String>) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(listen).with_context(|| format!("listen on {listen}"))?;
+        let addr = listener.local_addr()?;
+        let (loopback, inbox) = mpsc::channel();
+        let clients = Arc::default();
+        {
+            let (inbox, clients) = (loopback.clone(), Arc::clone(&clients));
+            let peers = peers.keys().cloned().collect();
+            thread::spawn(move || accept(listener, inbox, clients, Arc::new(peers)));
+        }
+        let peers = peers
+            .into_iter()
+            .map(|(id, addr)| {
+                let (lines, queue) = mpsc::channel();
+                let peer = id.clone();
+                thread::spawn(move || connect(&peer, &addr, queue));
+                (id, lines)
+            })
+            .collect();
+        Ok(Self {
+            addr,
+            inbox: Mutex::new(inbox),
+            loopback,
+            node_id: OnceLock::new(),
+            peers,
+            clients,
+        })
+    }
+
+    /// The transport `--listen` and `--peers` describe.
+    pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+        let listen = config
+            .get("listen")
+            .context("--transport tcp needs --listen <host>:<port>")?;
+        let mut peers = HashMap::new();
+        for peer in config.list("peers") {
+            let (id, addr) = peer
+                .split_once('=')
+                .with_context(|| format!("invalid peer {peer}, expected <id>=<host>:<port>"))?;
+            peers.insert(id.to_string(), addr.to_string());
+        }
+        Self::bind(listen, peers)
+    }
+
+    /// Where it listens, with the port the system picked if asked for 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Transport for Tcp {
+    fn recv(&self) -> anyhow::Result<Option<RawMessage>> {
+        let message = self.inbox.lock().unwrap().recv().ok();
+        if let Some(message) = &message {
+            self.node_id.get_or_init(|| message.dest.clone());
+        }
+        Ok(message)
+    }
+
+    fn send(&self, message: &RawMessage) -> anyhow::Result<()> {
+        let dest = &message.dest;
+        if self.node_id.get() == Some(dest) {
+            let _ = self.loopback.send(message.clone());
+            return Ok(());
+        }
+        let mut line = serde_json::to_vec(message).context("Serialize message")?;
+        line.push(b'\n');
+        if let Some(peer) = self.peers.get(dest) {
+            // Its thread lives as long as we do.
+            let _ = peer.send(line);
+            return Ok(());
+        }
+        // Written outside the lock, so a slow client holds up only itself.
+        let Some(client) = self.clients.lock().unwrap().get(dest).cloned() else {
+            eprintln!("tcp: no connection to {dest}, dropping message");
+            return Ok(());
+        };
+        let written = client.lock().unwrap().write_all(&line);
+        if let Err(err) = written {
+            eprintln!("tcp: lost {dest}: {err}");
+            let mut clients = self.clients.lock().unwrap();
+            // Unless it reconnected meanwhile.
+            if clients
+                .get(dest)
+                .is_some_and(|latest| Arc::ptr_eq(latest, &client))
+            {
+                clients.remove(dest);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads every connection made to `listener` into `inbox`.
+fn accept(
+    listener: TcpListener,
+    inbox: mpsc::Sender<RawMessage>,
+    clients: Arc<Mutex<HashMap<String, Client>>>,
+    peers: Arc<HashSet<String>>,
+) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("tcp: accept failed: {err}");
+                continue;
+            }
+        };
+        let (inbox, clients, peers) = (inbox.clone(), clients.clone(), peers.clone());
+        thread::spawn(move || {
+            if let Err(err) = read(stream, &inbox, &clients, &peers) {
+                eprintln!("tcp: {err:#}");
+            }
+        });
+    }
+}
+
+/// Reads one connection until it closes, registering it as the way back to
+/// every sender on it that is not a peer.
+fn read(
+    stream: TcpStream,
+    inbox: &mpsc::Sender<RawMessage>,
+    clients: &Mutex<HashMap<String, Client>>,
+    peers: &HashSet<String>,
+) -> anyhow::Result<()> {
+    let from = stream.peer_addr()?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let client = Arc::new(Mutex::new(stream.try_clone()?));
+    let mut registered = HashSet::new();
+    for line in BufReader::new(stream.try_clone()?).lines() {
+        let line = line.with_context(|| format!("read from {from}"))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let message: RawMessage = serde_json::from_str(&line)
+            .with_context(|| format!("dropping connection from {from}"))?;
+        if !peers.contains(&message.src) && registered.insert(message.src.clone()) {
+            let client = Arc::clone(&client);
+            clients.lock().unwrap().insert(message.src.clone(), client);
+        }
+        if inbox.send(message).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Writes every line queued for peer `id` to it at `addr`, reconnecting as
+/// needed, until the transport is dropped.
+fn connect(id: &str, addr: &str, lines: mpsc::Receiver<Vec<u8>>) {
+    let mut stream: Option<TcpStream> = None;
+    let mut backoff = MIN_BACKOFF;
+    let mut retry_at = Instant::now();
+    for line in lines {
+        // A connection that broke is found out by writing to it; the line
+        // then goes out once more over a new one.
+        for _ in 0..2 {
+            if stream.is_none() {
+                if Instant::now() < retry_at {
+                    break;
+                }
+                match dial(addr) {
+                    Ok(connected) => {
+                        eprintln!("tcp: connected to {id} at {addr}");
+                        stream = Some(connected);
+                        backoff = MIN_BACKOFF;
+                    }
+                    Err(err) => {
+                        if backoff == MIN_BACKOFF {
+                            eprintln!("tcp: cannot reach {id} at {addr}: {err}");
+                        }
+                        retry_at = Instant::now() + backoff;
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                        break;
+                    }
+                }
+            }
+            let Some(connected) = stream.as_mut() else {
+                break;
+            };
+            match connected.write_all(&line) {
+                Ok(()) => break,
+                Err(err) => {
+                    eprintln!("tcp: lost {id} at {addr}: {err}");
+                    stream = None;
+                }
+            }
+        }
+    }
+}
+
+fn dial(addr: &str) -> io::Result<TcpStream> {
+    let mut failed = io::Error::new(io::ErrorKind::NotFound, format!("{addr} did not resolve"));
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => {
+                stream.set_nodelay(true)?;
+                stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                return Ok(stream);
+            }
+            Err(err) => failed = err,
+        }
+    }
+    Err(failed)
+}
@@ -0,0 +1,152 @@
+//! Datomic-style transactor over `lin-kv`: the database is a tree of
+//! immutable thunks and a single mutable root pointer.
+//!
+//! Every value a transaction writes is stored once under a fresh
+//! `thunk/<id>` key and never changed again. A map thunk holds the id of
+//! each key's value thunk, and the `root` key holds the id of the current
+//! map. A transaction reads the root, loads what it needs through the
+//! cache, writes thunks for its new values and a new map, then commits with
+//! one cas on the root, through [`storage::Root`](crate::storage::Root). If
+//! the root moved, another transaction committed in between: when that one
+//! changed none of the keys this one touched, this one commits after it,
+//! and otherwise aborts with `txn-conflict`; its thunks are simply never
+//! referenced.
+//!
+//! Thunks are [`storage::Thunk`](crate::storage::Thunk)s: since they never
+//! change, every node caches them forever, and a transaction only pays for
+//! thunks written since this node last saw them.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    runtime::{RpcError, Runtime},
+    storage::{Conflict, Resolution, Root, Thunk, Thunks},
+    txn::{
+        lin_kv::TxnError,
+        op::{Op, ReadValue},
+    },
+};
+
+/// Key → thunk holding its value.
+type Map = BTreeMap<usize, Thunk<ReadValue>>;
+
+/// What a transaction did to the map it ran against.
+struct Run {
+    done: Vec<Op>,
+    /// Keys it read or wrote.
+    touched: BTreeSet<usize>,
+    /// The thunks of the keys it wrote, saved.
+    written: Map,
+}
+
+#[derive(Clone)]
+pub struct DatomicTxns {
+    root: Root<Map>,
+}
+
+impl DatomicTxns {
+    pub fn new(runtime: Runtime) -> Self {
+        let thunks = Thunks::new(runtime.clone(), "thunk");
+        Self {
+            root: Root::new(runtime, "root", thunks),
+        }
+    }
+
+    /// Runs `txn` against the current map and swaps in the map it leads to.
+    /// When another transaction committed first, this one still commits
+    /// after it, on the newer map, if the other changed none of the keys
+    /// this one touched: that order gives every read the same value.
+    /// Otherwise it aborts.
+    pub fn run(&self, txn: Vec<Op>) -> Result<Vec<Op>, TxnError> {
+        let run = self.root.update(
+            |map| {
+                let map = map.cloned().unwrap_or_default();
+                let run = self.apply(&map, txn.clone())?;
+                let next = match run.written.is_empty() {
+                    true => None,
+                    false => Some(map.into_iter().chain(run.written.clone()).collect()),
+                };
+                Ok((next, run))
+            },
+            merge,
+        )?;
+        Ok(run.done)
+    }
+
+    /// Runs `txn`'s micro-ops against `map`, saving a thunk for each value
+    /// written.
+    fn apply(&self, map: &Map, txn: Vec<Op>) -> Result<Run, RpcError> {
+        // Values this transaction wrote, not yet stored as thunks.
+        let mut written: BTreeMap<usize, ReadValue> = BTreeMap::new();
+        let mut touched = BTreeSet::new();
+        let mut done = Vec::with_capacity(txn.len());
+        for op in txn {
+            match op {
+                Op::Read { key, .. } => {
+                    touched.insert(key);
+                    let value = match written.get(&key) {
+                        Some(value) => Some(value.clone()),
+                        None => self.read(map, key)?,
+                    };
+                    done.push(Op::Read { key, value });
+                }
+                Op::Write { key, value } => {
+                    touched.insert(key);
+                    written.insert(key, ReadValue::Register(value));
+                    done.push(op);
+                }
+                Op::Append { key, value } => {
+                    touched.insert(key);
+                    let mut list = match written.get(&key) {
+                        Some(ReadValue::List(list)) => list.clone(),
+                        _ => match self.read(map, key)? {
+                            Some(ReadValue::List(list)) => list,
+                            _ => Vec::new(),
+                        },
+                    };
+                    list.push(value);
+                    written.insert(key, ReadValue::List(list));
+                    done.push(op);
+                }
+            }
+        }
+        // Values are saved before the map referring to them.
+        let written = written
+            .into_iter()
+            .map(|(key, value)| {
+                let mut thunk = Thunk::new(value);
+                thunk.save(self.root.thunks())?;
+                Ok((key, thunk))
+            })
+            .collect::<Result<_, RpcError>>()?;
+        Ok(Run {
+            done,
+            touched,
+            written,
+        })
+    }
+
+    fn read(&self, map: &Map, key: usize) -> Result<Option<ReadValue>, RpcError> {
+        match map.get(&key) {
+            Some(thunk) => Ok(Some(thunk.get(self.root.thunks())?.as_ref().clone())),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Moves `run` past a transaction that committed first, if it changed none
+/// of the keys `run` touched.
+fn merge(run: &Run, conflict: Conflict<'_, Map>) -> Resolution<Map> {
+    let value = |map: Option<&Map>, key| map.and_then(|map| map.get(key)).cloned();
+    let disjoint = run
+        .touched
+        .iter()
+        .all(|key| value(conflict.base, key) == value(conflict.theirs, key));
+    match disjoint {
+        true => {
+            let theirs = conflict.theirs.cloned().unwrap_or_default();
+            Resolution::Merged(theirs.into_iter().chain(run.written.clone()).collect())
+        }
+        false => Resolution::Abort,
+    }
+}
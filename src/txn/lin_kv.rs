@@ -0,0 +1,130 @@
+//! Transactions against a database kept in `lin-kv` as a single value:
+//! a transaction reads it, runs against the copy and writes the result back
+//! with one cas. A cas that fails means another transaction committed in
+//! between, possibly writing the same keys, so the transaction is aborted
+//! with `txn-conflict` (error 30) instead of being applied over it.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    message::error_code,
+    runtime::{RpcError, Runtime},
+    services::Kv,
+    storage::RootError,
+    txn::op::{Op, ReadValue},
+};
+
+const DB: &str = "txn-db";
+
+/// Registers and lists, with no history: one transaction applies after
+/// another.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Db {
+    registers: BTreeMap<usize, usize>,
+    lists: BTreeMap<usize, Vec<usize>>,
+}
+
+impl Db {
+    /// Runs a transaction's micro-ops in order, filling in reads.
+    pub fn apply(&mut self, txn: Vec<Op>) -> Vec<Op> {
+        txn.into_iter()
+            .map(|op| match op {
+                Op::Read { key, .. } => {
+                    let value = match self.lists.get(&key) {
+                        Some(list) => Some(ReadValue::List(list.clone())),
+                        None => self.registers.get(&key).copied().map(ReadValue::Register),
+                    };
+                    Op::Read { key, value }
+                }
+                Op::Write { key, value } => {
+                    self.registers.insert(key, value);
+                    op
+                }
+                Op::Append { key, value } => {
+                    self.lists.entry(key).or_default().push(value);
+                    op
+                }
+            })
+            .collect()
+    }
+
+    /// A copy of just the registers and lists under `keys`.
+    pub fn part(&self, keys: &BTreeSet<usize>) -> Db {
+        let pick = |key: &usize| keys.contains(key);
+        Db {
+            registers: self
+                .registers
+                .iter()
+                .filter(|(key, _)| pick(key))
+                .map(|(&k, &v)| (k, v))
+                .collect(),
+            lists: self
+                .lists
+                .iter()
+                .filter(|(key, _)| pick(key))
+                .map(|(&k, v)| (k, v.clone()))
+                .collect(),
+        }
+    }
+}
+
+/// Why a transaction did not commit.
+#[derive(Debug)]
+pub enum TxnError {
+    /// Another transaction committed first; nothing was applied.
+    Conflict,
+    Kv(RpcError),
+}
+
+impl From<RpcError> for TxnError {
+    fn from(err: RpcError) -> Self {
+        TxnError::Kv(err)
+    }
+}
+
+impl From<RootError> for TxnError {
+    fn from(err: RootError) -> Self {
+        match err {
+            RootError::Conflict => TxnError::Conflict,
+            RootError::Kv(err) => TxnError::Kv(err),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct LinKvTxns {
+    kv: Kv,
+}
+
+impl LinKvTxns {
+    pub fn new(runtime: Runtime) -> Self {
+        Self {
+            kv: Kv::new(runtime, "lin-kv"),
+        }
+    }
+
+    pub fn run(&self, txn: Vec<Op>) -> Result<Vec<Op>, TxnError> {
+        let current: Db = match self.kv.read(DB) {
+            Ok(db) => db,
+            Err(RpcError::Remote { code, .. }) if code == error_code::KEY_DOES_NOT_EXIST => {
+                Db::default()
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let mut next = current.clone();
+        let done = next.apply(txn);
+        if next == current {
+            // Read-only: the read of the whole database was the snapshot.
+            return Ok(done);
+        }
+        match self.kv.cas(DB, &current, &next, true) {
+            Ok(()) => Ok(done),
+            Err(RpcError::Remote { code, .. }) if code == error_code::PRECONDITION_FAILED => {
+                Err(TxnError::Conflict)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+}
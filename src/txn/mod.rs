@@ -0,0 +1,336 @@
+//! Totally-available transactions (Gossip Glomers challenge 6) over
+//! registers (`txn-rw-register`) or lists (`txn-list-append`): every node
+//! runs transactions against its own copy and replicates the store to the
+//! others in the background through a
+//! [`CrdtReplicator`](crate::replication::CrdtReplicator).
+//!
+//! Writes are stamped with the transaction's hybrid logical time, so
+//! replicas resolve conflicting writes last-writer-wins. `--isolation` picks
+//! how a transaction runs locally. `read-uncommitted` (default) applies each
+//! micro-op to the store as it goes; `read-committed` runs against a private
+//! buffer and commits the final values together. Either way a transaction's
+//! writes replicate together, in one delta.
+//!
+//! `--txn-store lin-kv` gives up availability for serializability instead:
+//! the database lives in `lin-kv` and conflicting transactions abort (see
+//! [`lin_kv`]). `--txn-store datomic` does the same over a tree of
+//! immutable thunks, so a transaction only moves the values it touches (see
+//! [`datomic`]). `--txn-store sharded` keeps the database on the nodes
+//! themselves instead, each key on one of them, and commits transactions
+//! that span several by two-phase commit (see [`sharded`]).
+
+pub mod datomic;
+pub mod lin_kv;
+pub mod op;
+pub mod sharded;
+pub mod store;
+
+use std::{fmt, str::FromStr, time::Duration};
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    clock::Hlc,
+    message::{error_code, Init, Message},
+    replication::{CrdtReplicator, Gossip},
+    runtime::{Node, Runtime},
+};
+use datomic::DatomicTxns;
+use lin_kv::{LinKvTxns, TxnError};
+use op::Op;
+use sharded::{Decision, ShardedTxns};
+use store::Store;
+
+const REPLICATE_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Isolation {
+    #[default]
+    ReadUncommitted,
+    ReadCommitted,
+}
+
+impl FromStr for Isolation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read-uncommitted" => Ok(Self::ReadUncommitted),
+            "read-committed" => Ok(Self::ReadCommitted),
+            _ => bail!("unknown isolation {s}, expected read-uncommitted or read-committed"),
+        }
+    }
+}
+
+impl fmt::Display for Isolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Isolation::ReadUncommitted => write!(f, "read-uncommitted"),
+            Isolation::ReadCommitted => write!(f, "read-committed"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TxnStore {
+    #[default]
+    Local,
+    LinKv,
+    Datomic,
+    Sharded,
+}
+
+impl FromStr for TxnStore {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "local" => Ok(Self::Local),
+            "lin-kv" => Ok(Self::LinKv),
+            "datomic" => Ok(Self::Datomic),
+            "sharded" => Ok(Self::Sharded),
+            _ => bail!("unknown txn store {s}, expected local, lin-kv, datomic or sharded"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Txn {
+        txn: Vec<Op>,
+    },
+    TxnOk {
+        txn: Vec<Op>,
+    },
+    Replicate {
+        gossip: Gossip<Store>,
+    },
+    /// From a coordinator to a participant, with `--txn-store sharded`: the
+    /// ops of transaction `id` on the participant's keys, to vote on.
+    Prepare {
+        id: String,
+        ops: Vec<Op>,
+    },
+    PrepareOk {
+        ops: Vec<Op>,
+    },
+    Commit {
+        id: String,
+    },
+    CommitOk,
+    Abort {
+        id: String,
+    },
+    AbortOk,
+    /// From a participant in doubt to the coordinator.
+    Outcome {
+        id: String,
+    },
+    /// No `decision` while the transaction is still voting.
+    OutcomeOk {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        decision: Option<Decision>,
+    },
+    /// Debugging: this node's copy, as a snapshot.
+    Dump,
+    DumpOk {
+        state: Value,
+    },
+}
+
+/// Serializable stores: in `lin-kv`, or split between the nodes.
+#[derive(Clone)]
+enum Remote {
+    LinKv(LinKvTxns),
+    Datomic(DatomicTxns),
+    Sharded(ShardedTxns),
+}
+
+impl Remote {
+    fn run(&self, txn: Vec<Op>) -> Result<Vec<Op>, TxnError> {
+        match self {
+            Remote::LinKv(txns) => txns.run(txn),
+            Remote::Datomic(txns) => txns.run(txn),
+            Remote::Sharded(txns) => txns.run(txn),
+        }
+    }
+}
+
+pub struct TxnNode {
+    runtime: Runtime,
+    store: CrdtReplicator<Store>,
+    /// Stamps local transactions: close to wall-clock time, so the last
+    /// writer is roughly the one that wrote last, yet never behind a
+    /// replicated write.
+    clock: Hlc,
+    isolation: Isolation,
+    // Set by `--txn-store lin-kv|datomic|sharded`, replacing the local store.
+    remote: Option<Remote>,
+}
+
+impl Node<Payload> for TxnNode {
+    fn from_init(runtime: Runtime, _init: Init) -> anyhow::Result<Self> {
+        let config = runtime.config().clone();
+        let isolation = config.parse("isolation")?.unwrap_or_default();
+        let remote = match config.parse("txn-store")?.unwrap_or_default() {
+            TxnStore::Local => {
+                eprintln!("{}: txn node, isolation {isolation}", runtime.node_id());
+                None
+            }
+            TxnStore::LinKv => {
+                eprintln!("{}: txn node, serializable on lin-kv", runtime.node_id());
+                Some(Remote::LinKv(LinKvTxns::new(runtime.clone())))
+            }
+            TxnStore::Datomic => {
+                eprintln!("{}: txn node, datomic transactor", runtime.node_id());
+                Some(Remote::Datomic(DatomicTxns::new(runtime.clone())))
+            }
+            TxnStore::Sharded => {
+                eprintln!(
+                    "{}: txn node, sharded with two-phase commit",
+                    runtime.node_id()
+                );
+                Some(Remote::Sharded(ShardedTxns::mount(
+                    runtime.clone(),
+                    &config,
+                )?))
+            }
+        };
+        let store = CrdtReplicator::mount(
+            runtime.clone(),
+            Store::default(),
+            REPLICATE_INTERVAL,
+            |gossip| Payload::Replicate { gossip },
+        );
+        store.persist(&config, runtime.node_id())?;
+        let clock = Hlc::new();
+        if let Some(time) = store.read(Store::latest) {
+            clock.observe(time);
+        }
+        Ok(Self {
+            runtime: runtime.clone(),
+            store,
+            clock,
+            isolation,
+            remote,
+        })
+    }
+
+    fn step(&mut self, input: Message<Payload>) -> anyhow::Result<()> {
+        match input.body.payload {
+            Payload::Txn { .. } if self.remote.is_some() => {
+                self.run_remote(input);
+            }
+            Payload::Txn { ref txn } => {
+                let txn = match self.isolation {
+                    Isolation::ReadUncommitted => self.run_uncommitted(txn.clone()),
+                    Isolation::ReadCommitted => self.run_committed(txn.clone()),
+                };
+                self.runtime.reply(&input, Payload::TxnOk { txn })?;
+            }
+            Payload::Replicate { gossip } => {
+                if let Some(time) = gossip.state().and_then(Store::latest) {
+                    self.clock.observe(time);
+                }
+                if let Some(gossip) = self.store.receive(&input.src, gossip) {
+                    self.runtime
+                        .send(&input.src, Payload::Replicate { gossip })?;
+                }
+            }
+            Payload::Dump => {
+                let state = self.store.dump();
+                self.runtime.reply(&input, Payload::DumpOk { state })?;
+            }
+            Payload::Prepare { .. }
+            | Payload::Commit { .. }
+            | Payload::Abort { .. }
+            | Payload::Outcome { .. } => self.step_participant(input)?,
+            Payload::TxnOk { .. }
+            | Payload::PrepareOk { .. }
+            | Payload::CommitOk
+            | Payload::AbortOk
+            | Payload::OutcomeOk { .. }
+            | Payload::DumpOk { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+impl TxnNode {
+    /// Serves another node's part in a two-phase commit.
+    fn step_participant(&self, input: Message<Payload>) -> anyhow::Result<()> {
+        let Some(Remote::Sharded(txns)) = &self.remote else {
+            return Ok(());
+        };
+        let reply = match input.body.payload {
+            Payload::Prepare { ref id, ref ops } => {
+                match txns.prepare(id, &input.src, ops.clone()) {
+                    Ok(ops) => Payload::PrepareOk { ops },
+                    Err((code, text)) => return self.runtime.reply_error(&input, code, text),
+                }
+            }
+            Payload::Commit { ref id } => {
+                txns.resolve(id, Decision::Commit)?;
+                Payload::CommitOk
+            }
+            Payload::Abort { ref id } => {
+                txns.resolve(id, Decision::Abort)?;
+                Payload::AbortOk
+            }
+            Payload::Outcome { ref id } => Payload::OutcomeOk {
+                decision: txns.outcome(id),
+            },
+            _ => return Ok(()),
+        };
+        self.runtime.reply(&input, reply)
+    }
+
+    fn run_remote(&self, input: Message<Payload>) {
+        let Some(txns) = self.remote.clone() else {
+            return;
+        };
+        let runtime = self.runtime.clone();
+        std::thread::spawn(move || {
+            let Payload::Txn { ref txn } = input.body.payload else {
+                return;
+            };
+            let result = match txns.run(txn.clone()) {
+                Ok(txn) => runtime.reply(&input, Payload::TxnOk { txn }),
+                Err(TxnError::Conflict) => runtime.reply_error(
+                    &input,
+                    error_code::TXN_CONFLICT,
+                    "another transaction committed first",
+                ),
+                Err(TxnError::Kv(err)) => {
+                    runtime.reply_error(&input, error_code::TIMEOUT, err.to_string())
+                }
+            };
+            if let Err(err) = result {
+                eprintln!("txn reply failed: {err:#}");
+            }
+        });
+    }
+
+    /// Applies the whole transaction under one timestamp.
+    fn run_uncommitted(&mut self, txn: Vec<Op>) -> Vec<Op> {
+        let time = self.clock.tick();
+        let node = self.runtime.node_id();
+        self.store.update(|store| store.apply(txn, time, node))
+    }
+
+    /// Runs against a private buffer, then commits the buffered writes
+    /// together.
+    fn run_committed(&mut self, txn: Vec<Op>) -> Vec<Op> {
+        let (done, writes) = self.store.read(|store| store.execute(txn));
+        if !writes.is_empty() {
+            let time = self.clock.tick();
+            let node = self.runtime.node_id();
+            self.store.update(|store| store.apply(writes, time, node));
+        }
+        done
+    }
+}
@@ -0,0 +1,69 @@
+//! Micro-operations as Maelstrom encodes them: `[f, key, value]` arrays.
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(try_from = "(String, usize, Value)", into = "(String, usize, Value)")]
+pub enum Op {
+    /// `value` is `None` in the request and filled in when the read runs.
+    Read {
+        key: usize,
+        value: Option<ReadValue>,
+    },
+    /// Overwrites a register (txn-rw-register).
+    Write { key: usize, value: usize },
+    /// Adds to the end of a list (txn-list-append).
+    Append { key: usize, value: usize },
+}
+
+/// What a read observed: a register or a list, depending on the workload.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum ReadValue {
+    Register(usize),
+    List(Vec<usize>),
+}
+
+impl Op {
+    pub fn is_write(&self) -> bool {
+        !matches!(self, Op::Read { .. })
+    }
+}
+
+impl TryFrom<(String, usize, Value)> for Op {
+    type Error = anyhow::Error;
+
+    fn try_from((f, key, value): (String, usize, Value)) -> Result<Self, Self::Error> {
+        Ok(match f.as_str() {
+            "r" => Op::Read {
+                key,
+                value: serde_json::from_value(value)?,
+            },
+            "w" => Op::Write {
+                key,
+                value: serde_json::from_value(value)?,
+            },
+            "append" => Op::Append {
+                key,
+                value: serde_json::from_value(value)?,
+            },
+            _ => bail!("unknown micro-op {f}"),
+        })
+    }
+}
+
+impl From<Op> for (String, usize, Value) {
+    fn from(op: Op) -> Self {
+        match op {
+            Op::Read { key, value } => (
+                "r".into(),
+                key,
+                serde_json::to_value(value).expect("read values serialize"),
+            ),
+            Op::Write { key, value } => ("w".into(), key, value.into()),
+            Op::Append { key, value } => ("append".into(), key, value.into()),
+        }
+    }
+}
@@ -0,0 +1,443 @@
+//! Transactions over a database split between the nodes: each key lives on
+//! one node, its owner on a consistent hash ring (see [`crate::ring`]), and
+//! a transaction touching keys on several nodes commits atomically by
+//! two-phase commit.
+//!
+//! The node a client asks coordinates. It sends each owner, participant in
+//! the transaction, the micro-ops on its keys, in order. A participant
+//! locks those keys, runs the ops against a copy to fill in the reads,
+//! logs them as prepared and votes yes; if another transaction holds one of
+//! the keys it votes no instead, without waiting. Once every participant
+//! voted yes the coordinator logs the commit, answers the client and tells
+//! each participant, until every one acknowledged, to apply its ops and
+//! release its locks. Any no, or a participant that does not answer in
+//! time, aborts the transaction everywhere.
+//!
+//! Aborts are presumed: a coordinator only logs commits, and answers a
+//! participant that asks about a transaction it neither committed nor is
+//! still voting on that it aborted. Participants ask about a transaction
+//! left prepared for longer than [`IN_DOUBT_TIMEOUT`], as happens when the
+//! decision was lost or the coordinator restarted.
+//!
+//! With `--txn-dir`, each node keeps its log at `<dir>/<node id>.txn` and
+//! replays it on start: prepared transactions keep their locks until they
+//! are resolved, committed ones are applied, and commits not every
+//! participant acknowledged are sent again. Unset keeps it in memory only.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use crate::{
+    config::Config,
+    message::error_code,
+    ring::Ring,
+    runtime::{RpcError, Runtime},
+    storage::Wal,
+    txn::{
+        lin_kv::{Db, TxnError},
+        op::Op,
+        Payload,
+    },
+};
+
+/// How long the coordinator waits for each vote.
+const PREPARE_TIMEOUT: Duration = Duration::from_millis(1000);
+const COMMIT_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long a participant holds a prepared transaction before asking the
+/// coordinator what became of it.
+pub const IN_DOUBT_TIMEOUT: Duration = Duration::from_millis(1000);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    Commit,
+    Abort,
+}
+
+/// What a node logs before acting on it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Record {
+    /// This node voted yes on its part of a transaction.
+    Prepared {
+        id: String,
+        coordinator: String,
+        ops: Vec<Op>,
+    },
+    /// This node applied or dropped its part.
+    Resolved { id: String, decision: Decision },
+    /// This node, as coordinator, committed the transaction.
+    Committed {
+        id: String,
+        participants: BTreeSet<String>,
+    },
+    /// Every participant applied the commit.
+    Finished { id: String },
+}
+
+struct Prepared {
+    coordinator: String,
+    ops: Vec<Op>,
+    since: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    /// The keys this node owns.
+    db: Db,
+    /// Keys held by prepared transactions, with the transaction holding each.
+    locks: HashMap<usize, String>,
+    prepared: HashMap<String, Prepared>,
+    /// Transactions this node coordinates that are still collecting votes.
+    voting: HashSet<String>,
+    /// Commits decided here, with the participants yet to acknowledge them.
+    committed: HashMap<String, BTreeSet<String>>,
+    log: Option<Wal>,
+}
+
+impl State {
+    fn log(&mut self, record: Record) -> anyhow::Result<()> {
+        match &mut self.log {
+            Some(log) => log.append(&[record]),
+            None => Ok(()),
+        }
+    }
+
+    /// Replays a record logged by an earlier run.
+    fn replay(&mut self, record: Record) {
+        match record {
+            Record::Prepared {
+                id,
+                coordinator,
+                ops,
+            } => self.prepare(id, coordinator, ops),
+            Record::Resolved { id, decision } => self.resolve(&id, decision),
+            Record::Committed { id, participants } => {
+                self.committed.insert(id, participants);
+            }
+            Record::Finished { id } => {
+                self.committed.remove(&id);
+            }
+        }
+    }
+
+    fn prepare(&mut self, id: String, coordinator: String, ops: Vec<Op>) {
+        for op in &ops {
+            self.locks.insert(key(op), id.clone());
+        }
+        let since = Instant::now();
+        let prepared = Prepared {
+            coordinator,
+            ops,
+            since,
+        };
+        self.prepared.insert(id, prepared);
+    }
+
+    fn resolve(&mut self, id: &str, decision: Decision) {
+        let Some(prepared) = self.prepared.remove(id) else {
+            return;
+        };
+        self.locks.retain(|_, holder| holder != id);
+        if decision == Decision::Commit {
+            self.db.apply(prepared.ops);
+        }
+    }
+}
+
+fn key(op: &Op) -> usize {
+    match op {
+        Op::Read { key, .. } | Op::Write { key, .. } | Op::Append { key, .. } => *key,
+    }
+}
+
+#[derive(Clone)]
+pub struct ShardedTxns {
+    runtime: Runtime,
+    ring: Arc<Ring>,
+    state: Arc<Mutex<State>>,
+}
+
+impl ShardedTxns {
+    /// Recovers this node's log, if `--txn-dir` keeps one, and starts
+    /// resolving what it left open.
+    pub fn mount(runtime: Runtime, config: &Config) -> anyhow::Result<Self> {
+        let node = runtime.node_id().to_string();
+        let mut state = State::default();
+        if let Some(dir) = config.get("txn-dir") {
+            let path = Path::new(dir).join(format!("{node}.txn"));
+            let sync = config.parse("wal-sync")?.unwrap_or_default();
+            let started = Instant::now();
+            let (log, replay) = Wal::open(&path, sync)?;
+            let mut records = 0;
+            for record in replay {
+                let record =
+                    record.with_context(|| format!("{} record {records}", path.display()))?;
+                state.replay(record);
+                records += 1;
+            }
+            let (prepared, committed) = (state.prepared.len(), state.committed.len());
+            eprintln!(
+                "{node}: replayed {records} txn records in {:?}: \
+                 {prepared} prepared, {committed} commits unacknowledged",
+                started.elapsed()
+            );
+            state.log = Some(log);
+        }
+        let unfinished: Vec<String> = state.committed.keys().cloned().collect();
+        let txns = Self {
+            ring: Arc::new(Ring::new(runtime.node_ids())?),
+            runtime,
+            state: Arc::new(Mutex::new(state)),
+        };
+        for id in unfinished {
+            txns.finish(id);
+        }
+        let resolver = txns.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(IN_DOUBT_TIMEOUT / 2);
+            resolver.resolve_in_doubt();
+        });
+        Ok(txns)
+    }
+
+    /// Coordinates a transaction. Blocks on the participants, so call it
+    /// off the input thread.
+    pub fn run(&self, txn: Vec<Op>) -> Result<Vec<Op>, TxnError> {
+        let id = Ulid::new().to_string();
+        let mut parts: BTreeMap<String, Vec<(usize, Op)>> = BTreeMap::new();
+        for (at, op) in txn.into_iter().enumerate() {
+            let owner = self.ring.owner(&key(&op)).to_string();
+            parts.entry(owner).or_default().push((at, op));
+        }
+        self.state.lock().unwrap().voting.insert(id.clone());
+        let votes: Vec<(String, Result<Vec<Op>, TxnError>)> = std::thread::scope(|scope| {
+            let voters: Vec<_> = parts
+                .iter()
+                .map(|(owner, part)| {
+                    let ops = part.iter().map(|(_, op)| op.clone()).collect();
+                    let vote = scope.spawn(|| self.ask_to_prepare(&id, owner, ops));
+                    (owner.clone(), vote)
+                })
+                .collect();
+            voters
+                .into_iter()
+                .map(|(owner, vote)| (owner, vote.join().expect("voters do not panic")))
+                .collect()
+        });
+        let participants: BTreeSet<String> = parts.keys().cloned().collect();
+        let mut done: Vec<(usize, Op)> = Vec::new();
+        let mut failed = None;
+        for ((owner, vote), part) in votes.into_iter().zip(parts.values()) {
+            match vote {
+                Ok(ops) if ops.len() == part.len() => {
+                    done.extend(part.iter().map(|(at, _)| *at).zip(ops));
+                }
+                Ok(_) => {
+                    let text = format!("{owner} answered for other ops");
+                    failed = Some(TxnError::Kv(RpcError::Other(anyhow::anyhow!(text))));
+                }
+                Err(err) => failed = Some(err),
+            }
+        }
+        let decided = match failed {
+            Some(err) => Err(err),
+            None => self.commit(&id, participants.clone()),
+        };
+        if let Err(err) = decided {
+            self.state.lock().unwrap().voting.remove(&id);
+            for participant in &participants {
+                self.tell_abort(&id, participant);
+            }
+            return Err(err);
+        }
+        self.finish(id);
+        done.sort_by_key(|(at, _)| *at);
+        Ok(done.into_iter().map(|(_, op)| op).collect())
+    }
+
+    /// Logs the decision to commit: from here on the transaction commits,
+    /// whatever fails.
+    fn commit(&self, id: &str, participants: BTreeSet<String>) -> Result<(), TxnError> {
+        let mut state = self.state.lock().unwrap();
+        let record = Record::Committed {
+            id: id.to_string(),
+            participants: participants.clone(),
+        };
+        state
+            .log(record)
+            .map_err(|err| TxnError::Kv(RpcError::Other(err)))?;
+        state.voting.remove(id);
+        state.committed.insert(id.to_string(), participants);
+        Ok(())
+    }
+
+    fn ask_to_prepare(&self, id: &str, owner: &str, ops: Vec<Op>) -> Result<Vec<Op>, TxnError> {
+        if owner == self.runtime.node_id() {
+            let coordinator = owner.to_string();
+            return self.prepare(id, &coordinator, ops).map_err(|(code, text)| {
+                match code == error_code::TXN_CONFLICT {
+                    true => TxnError::Conflict,
+                    false => TxnError::Kv(RpcError::Remote { code, text }),
+                }
+            });
+        }
+        let request = Payload::Prepare {
+            id: id.to_string(),
+            ops,
+        };
+        match self.runtime.rpc(owner, request, PREPARE_TIMEOUT) {
+            Ok(Payload::PrepareOk { ops }) => Ok(ops),
+            Ok(_) => Err(TxnError::Kv(RpcError::Other(anyhow::anyhow!(
+                "{owner} answered prepare with something else"
+            )))),
+            Err(RpcError::Remote { code, .. }) if code == error_code::TXN_CONFLICT => {
+                Err(TxnError::Conflict)
+            }
+            Err(err) => Err(TxnError::Kv(err)),
+        }
+    }
+
+    /// A participant's vote on its part of transaction `id`: the ops with
+    /// their reads filled in, or the error to answer with.
+    pub fn prepare(
+        &self,
+        id: &str,
+        coordinator: &str,
+        ops: Vec<Op>,
+    ) -> Result<Vec<Op>, (usize, String)> {
+        let mut state = self.state.lock().unwrap();
+        let keys: BTreeSet<usize> = ops.iter().map(key).collect();
+        if let Some(held) = keys.iter().find(|key| state.locks.contains_key(*key)) {
+            let text = format!("key {held} is held by another transaction");
+            return Err((error_code::TXN_CONFLICT, text));
+        }
+        let done = state.db.part(&keys).apply(ops.clone());
+        let record = Record::Prepared {
+            id: id.to_string(),
+            coordinator: coordinator.to_string(),
+            ops: ops.clone(),
+        };
+        if let Err(err) = state.log(record) {
+            return Err((error_code::CRASH, format!("{err:#}")));
+        }
+        state.prepare(id.to_string(), coordinator.to_string(), ops);
+        Ok(done)
+    }
+
+    /// Applies or drops this node's part of transaction `id`, if it still
+    /// holds it prepared.
+    pub fn resolve(&self, id: &str, decision: Decision) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.prepared.contains_key(id) {
+            return Ok(());
+        }
+        let record = Record::Resolved {
+            id: id.to_string(),
+            decision,
+        };
+        state.log(record)?;
+        state.resolve(id, decision);
+        Ok(())
+    }
+
+    /// What became of transaction `id`, which this node coordinates;
+    /// `None` while it is still voting.
+    pub fn outcome(&self, id: &str) -> Option<Decision> {
+        let state = self.state.lock().unwrap();
+        match (state.committed.contains_key(id), state.voting.contains(id)) {
+            (true, _) => Some(Decision::Commit),
+            (false, true) => None,
+            (false, false) => Some(Decision::Abort),
+        }
+    }
+
+    /// Tells every participant of committed transaction `id` to apply it,
+    /// until each acknowledged, then logs it finished.
+    fn finish(&self, id: String) {
+        let txns = self.clone();
+        std::thread::spawn(move || {
+            let participants = txns.state.lock().unwrap().committed.get(&id).cloned();
+            for participant in participants.unwrap_or_default() {
+                if participant == txns.runtime.node_id() {
+                    while let Err(err) = txns.resolve(&id, Decision::Commit) {
+                        eprintln!("committing {id} failed: {err:#}");
+                        std::thread::sleep(COMMIT_TIMEOUT);
+                    }
+                    continue;
+                }
+                let commit = Payload::Commit { id: id.clone() };
+                while let Err(err) =
+                    txns.runtime
+                        .rpc::<_, Payload>(&participant, &commit, COMMIT_TIMEOUT)
+                {
+                    if !matches!(err, RpcError::Timeout) {
+                        eprintln!("committing {id} on {participant} failed: {err}");
+                        std::thread::sleep(COMMIT_TIMEOUT);
+                    }
+                }
+            }
+            let mut state = txns.state.lock().unwrap();
+            if let Err(err) = state.log(Record::Finished { id: id.clone() }) {
+                // Sent again after a restart, which participants ignore.
+                eprintln!("logging {id} finished failed: {err:#}");
+            }
+            state.committed.remove(&id);
+        });
+    }
+
+    /// Drops this node's part of aborted transaction `id` on `participant`.
+    /// A participant that misses it asks once its part is in doubt.
+    fn tell_abort(&self, id: &str, participant: &str) {
+        if participant == self.runtime.node_id() {
+            if let Err(err) = self.resolve(id, Decision::Abort) {
+                eprintln!("aborting {id} failed: {err:#}");
+            }
+            return;
+        }
+        let abort = Payload::Abort { id: id.to_string() };
+        if let Err(err) = self.runtime.send(participant, abort) {
+            eprintln!("aborting {id} on {participant} failed: {err:#}");
+        }
+    }
+
+    /// Asks the coordinators of transactions prepared here for too long
+    /// what became of them.
+    fn resolve_in_doubt(&self) {
+        let in_doubt: Vec<(String, String)> = {
+            let state = self.state.lock().unwrap();
+            let stale = state
+                .prepared
+                .iter()
+                .filter(|(_, p)| p.since.elapsed() >= IN_DOUBT_TIMEOUT);
+            stale
+                .map(|(id, p)| (id.clone(), p.coordinator.clone()))
+                .collect()
+        };
+        for (id, coordinator) in in_doubt {
+            let decision = match coordinator == self.runtime.node_id() {
+                true => self.outcome(&id),
+                false => {
+                    let request = Payload::Outcome { id: id.clone() };
+                    match self.runtime.rpc(&coordinator, request, COMMIT_TIMEOUT) {
+                        Ok(Payload::OutcomeOk { decision }) => decision,
+                        _ => None,
+                    }
+                }
+            };
+            if let Some(decision) = decision {
+                if let Err(err) = self.resolve(&id, decision) {
+                    eprintln!("resolving {id} failed: {err:#}");
+                }
+            }
+        }
+    }
+}
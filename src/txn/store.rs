@@ -0,0 +1,160 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::HlcTimestamp,
+    crdt::{CrdtMap, LwwMap, Rga, RgaId, Snapshot},
+    gossip::{DeltaCrdt, Merge},
+    txn::op::{Op, ReadValue},
+};
+
+/// This node's copy of every register and list. Registers are
+/// last-writer-wins and lists are replicated growable arrays, both keyed by
+/// the transaction's time and node. All writes of a transaction share its
+/// time, so a transaction wins or loses on every register together and
+/// write-write cycles (G0) cannot form.
+///
+/// The store is itself a delta CRDT, replicated whole: a transaction's
+/// writes land in one version, so they reach other nodes in the same delta
+/// and are merged together.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct Store {
+    registers: LwwMap<usize, usize>,
+    /// Lists by their key, as a string.
+    lists: CrdtMap<Rga<usize>>,
+}
+
+impl Store {
+    /// Runs a transaction's micro-ops in order, filling in reads. Later
+    /// reads observe the transaction's own earlier writes. `time` and `node`
+    /// stamp its writes.
+    pub fn apply(&mut self, txn: Vec<Op>, time: HlcTimestamp, node: &str) -> Vec<Op> {
+        let mut appended = 0;
+        txn.into_iter()
+            .map(|op| match op {
+                Op::Read { key, .. } => Op::Read {
+                    key,
+                    value: self.read(key),
+                },
+                Op::Write { key, value } => {
+                    self.registers.insert(key, value, time, node);
+                    op
+                }
+                Op::Append { key, value } => {
+                    let id = RgaId {
+                        time,
+                        node: node.to_string(),
+                        seq: appended,
+                    };
+                    appended += 1;
+                    self.lists.entry(&key.to_string()).append(id, value);
+                    op
+                }
+            })
+            .collect()
+    }
+
+    /// The latest time a write here was stamped with.
+    pub fn latest(&self) -> Option<HlcTimestamp> {
+        let lists = self.lists.iter().filter_map(|(_, list)| list.latest());
+        self.registers.latest().into_iter().chain(lists).max()
+    }
+
+    /// Runs a transaction against a private write buffer, leaving the store
+    /// untouched. Returns the completed micro-ops and the writes to commit:
+    /// the final value of each register and every append, in order.
+    pub fn execute(&self, txn: Vec<Op>) -> (Vec<Op>, Vec<Op>) {
+        let mut registers: HashMap<usize, usize> = HashMap::new();
+        let mut appends: Vec<(usize, usize)> = Vec::new();
+        let done = txn
+            .into_iter()
+            .map(|op| match op {
+                Op::Read { key, .. } => {
+                    let buffered: Vec<usize> = appends
+                        .iter()
+                        .filter(|&&(appended, _)| appended == key)
+                        .map(|&(_, value)| value)
+                        .collect();
+                    let value = match (self.read(key), registers.get(&key)) {
+                        (_, Some(&value)) => Some(ReadValue::Register(value)),
+                        (Some(ReadValue::List(mut list)), None) => {
+                            list.extend(buffered);
+                            Some(ReadValue::List(list))
+                        }
+                        (None, None) if !buffered.is_empty() => Some(ReadValue::List(buffered)),
+                        (value, None) => value,
+                    };
+                    Op::Read { key, value }
+                }
+                Op::Write { key, value } => {
+                    registers.insert(key, value);
+                    op
+                }
+                Op::Append { key, value } => {
+                    appends.push((key, value));
+                    op
+                }
+            })
+            .collect();
+        let writes = registers
+            .into_iter()
+            .map(|(key, value)| Op::Write { key, value })
+            .chain(
+                appends
+                    .into_iter()
+                    .map(|(key, value)| Op::Append { key, value }),
+            )
+            .collect();
+        (done, writes)
+    }
+
+    fn read(&self, key: usize) -> Option<ReadValue> {
+        if let Some(list) = self.lists.get(&key.to_string()) {
+            return Some(ReadValue::List(list.values().copied().collect()));
+        }
+        self.registers
+            .get(&key)
+            .map(|&value| ReadValue::Register(value))
+    }
+}
+
+impl Merge for Store {
+    fn merge(&mut self, other: Self) {
+        self.registers.merge(other.registers);
+        self.lists.merge(other.lists);
+    }
+}
+
+impl DeltaCrdt for Store {
+    type Version = (u64, BTreeMap<String, u64>);
+
+    fn version(&self) -> Self::Version {
+        (self.registers.version(), self.lists.version())
+    }
+
+    fn split_delta(&self, (registers, lists): &Self::Version) -> Self {
+        Self {
+            registers: self.registers.split_delta(registers),
+            lists: self.lists.split_delta(lists),
+        }
+    }
+}
+
+impl Snapshot for Store {
+    type Snapshot = (
+        <LwwMap<usize, usize> as Snapshot>::Snapshot,
+        <CrdtMap<Rga<usize>> as Snapshot>::Snapshot,
+    );
+
+    fn snapshot(&self) -> Self::Snapshot {
+        (self.registers.snapshot(), self.lists.snapshot())
+    }
+
+    fn restore((registers, lists): Self::Snapshot) -> Self {
+        Self {
+            registers: LwwMap::restore(registers),
+            lists: CrdtMap::restore(lists),
+        }
+    }
+}
@@ -0,0 +1,656 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    consensus::{Committed, Protocol},
+    message::NodeId,
+};
+
+const VIEW_CHANGE_TIMEOUT_MIN: Duration = Duration::from_millis(300);
+const VIEW_CHANGE_TIMEOUT_MAX: Duration = Duration::from_millis(600);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+/// Most operations sent to a lagging backup at once.
+const MAX_ENTRIES: usize = 100;
+
+/// An operation in the log, with the view it was first prepared in.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Entry<C> {
+    pub view: u64,
+    pub command: C,
+}
+
+/// Messages between VR replicas. Operations are numbered from 1, in log
+/// order.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum VrMessage<C> {
+    /// Asks a backup to append `entries` after operation `op`.
+    Prepare {
+        view: u64,
+        op: u64,
+        entries: Vec<Entry<C>>,
+        commit: u64,
+    },
+    /// The backup holds the primary's log up to `op`. `round` is the
+    /// heartbeat this answers, if it answers one.
+    PrepareOk {
+        view: u64,
+        op: u64,
+        round: Option<u64>,
+    },
+    /// The primary's heartbeat: operations up to `commit` are committed.
+    /// `round` confirms it still leads, for reads.
+    Commit { view: u64, commit: u64, round: u64 },
+    /// The sender gave up on the views before `view`.
+    StartViewChange { view: u64 },
+    /// Hands the new primary of `view` what the sender holds.
+    DoViewChange {
+        view: u64,
+        log: Vec<Entry<C>>,
+        last_normal_view: u64,
+        commit: u64,
+    },
+    /// The new primary's log, which every backup adopts.
+    StartView {
+        view: u64,
+        log: Vec<Entry<C>>,
+        commit: u64,
+    },
+}
+
+/// What a replica reported for a view change.
+#[derive(Clone, Debug)]
+struct Reported<C> {
+    last_normal_view: u64,
+    log: Vec<Entry<C>>,
+    commit: u64,
+}
+
+#[derive(Clone, Debug)]
+enum Role<C> {
+    Backup,
+    Primary {
+        /// The highest operation each backup holds in this view.
+        acked: HashMap<NodeId, u64>,
+        /// How many operations the log held when the view started; reads
+        /// wait until they are committed.
+        started_at: u64,
+        /// The last heartbeat round sent.
+        round: u64,
+        /// The last round each backup answered.
+        answered: HashMap<NodeId, u64>,
+        /// Reads waiting for a round to be confirmed, by id.
+        reads: Vec<(u64, u64)>,
+    },
+    /// Changing to the current view.
+    ViewChange {
+        /// Who gave up on the views before it.
+        votes: BTreeSet<NodeId>,
+        /// Whether this replica sent its `DoViewChange`.
+        sent: bool,
+        /// What each replica reported, if this one is the view's primary.
+        reports: BTreeMap<NodeId, Reported<C>>,
+    },
+}
+
+/// One Viewstamped Replication replica, without I/O, in the manner of
+/// [`Raft`](crate::raft::Raft).
+///
+/// Views are numbered, and the primary of each is fixed: the replicas take
+/// turns in the order given at `init`. In a normal view the primary appends
+/// each command to its log and prepares it on the backups; once a majority
+/// holds it, it is committed, and the next heartbeat says so. A backup that
+/// hears nothing from the primary for a timeout starts a view change. Once
+/// a majority gave up on the view, each sends the next primary its log; with
+/// a majority of them, the new primary takes the log of the latest normal
+/// view, the longest among those, and starts the view with it.
+///
+/// A backup that heard from its primary lately ignores view changes, so one
+/// cut off for a while does not depose it when it comes back, and one
+/// changing views rejoins a lower view it has not promised to give up.
+/// Reads wait for a heartbeat round a majority answered, as with Raft's read
+/// index.
+///
+/// Everything is kept in memory: there is neither checkpointing nor the
+/// recovery protocol for a replica that restarts.
+pub struct Vr<C> {
+    me: NodeId,
+    nodes: Vec<NodeId>,
+    view: u64,
+    /// The last view this replica was normal in.
+    last_normal_view: u64,
+    /// The highest view this replica started or handed its log to; it
+    /// takes no part in lower ones.
+    promised_view: u64,
+    log: Vec<Entry<C>>,
+    commit: u64,
+    last_applied: u64,
+    role: Role<C>,
+    view_change_deadline: Instant,
+    heartbeat_due: Instant,
+    heard_from_primary: Instant,
+    next_read: u64,
+    ready_reads: Vec<u64>,
+    outbox: Vec<(NodeId, VrMessage<C>)>,
+}
+
+impl<C: Clone> Vr<C> {
+    /// Starts in view 0, which the first of `node_ids` leads.
+    pub fn new(me: &str, node_ids: &[String], now: Instant) -> Self {
+        let mut vr = Self {
+            me: me.to_string(),
+            nodes: node_ids.to_vec(),
+            view: 0,
+            last_normal_view: 0,
+            promised_view: 0,
+            log: Vec::new(),
+            commit: 0,
+            last_applied: 0,
+            role: Role::Backup,
+            view_change_deadline: now + view_change_timeout(),
+            heartbeat_due: now,
+            heard_from_primary: now,
+            next_read: 0,
+            ready_reads: Vec::new(),
+            outbox: Vec::new(),
+        };
+        if *vr.primary_of(0) == vr.me {
+            vr.role = Role::Primary {
+                acked: HashMap::new(),
+                started_at: 0,
+                round: 0,
+                answered: HashMap::new(),
+                reads: Vec::new(),
+            };
+        }
+        vr
+    }
+
+    pub fn view(&self) -> u64 {
+        self.view
+    }
+
+    pub fn is_leader(&self) -> bool {
+        matches!(self.role, Role::Primary { .. })
+    }
+
+    /// The primary of the view this replica is normal in.
+    pub fn leader(&self) -> Option<&str> {
+        match self.role {
+            Role::Backup | Role::Primary { .. } => Some(self.primary_of(self.view)),
+            Role::ViewChange { .. } => None,
+        }
+    }
+
+    /// Every operation up to this one is committed.
+    pub fn committed(&self) -> u64 {
+        self.commit
+    }
+
+    /// Appends `command` to the log if this replica is the primary. Returns
+    /// its operation number and the view: it was applied if the entry
+    /// applied there is from that view.
+    pub fn propose(&mut self, command: C) -> Option<(u64, u64)> {
+        if !self.is_leader() {
+            return None;
+        }
+        let entry = Entry {
+            view: self.view,
+            command,
+        };
+        self.log.push(entry.clone());
+        let op = self.log.len() as u64;
+        self.broadcast(VrMessage::Prepare {
+            view: self.view,
+            op: op - 1,
+            entries: vec![entry],
+            commit: self.commit,
+        });
+        self.advance_commit();
+        Some((op, self.view))
+    }
+
+    /// Starts a linearizable read if this replica is the primary, returning
+    /// its id. [`take_reads`](Self::take_reads) hands the id out once a
+    /// majority confirmed the view still stands and every operation from
+    /// before it is committed. A read still waiting when the view ends is
+    /// never handed out.
+    pub fn read_index(&mut self) -> Option<u64> {
+        let Role::Primary { round, reads, .. } = &mut self.role else {
+            return None;
+        };
+        let id = self.next_read;
+        self.next_read += 1;
+        reads.push((id, *round + 1));
+        self.broadcast_heartbeat();
+        self.confirm_reads();
+        Some(id)
+    }
+
+    /// Starts a view change or sends heartbeats when they are due.
+    pub fn tick(&mut self, now: Instant) {
+        match self.role {
+            Role::Primary { .. } if now >= self.heartbeat_due => {
+                self.heartbeat_due = now + HEARTBEAT_INTERVAL;
+                self.broadcast_heartbeat();
+            }
+            Role::Primary { .. } => {}
+            Role::Backup | Role::ViewChange { .. } if now >= self.view_change_deadline => {
+                self.start_view_change(self.view + 1, now);
+            }
+            Role::Backup | Role::ViewChange { .. } => {}
+        }
+    }
+
+    pub fn handle(&mut self, from: &str, message: VrMessage<C>, now: Instant) {
+        match message {
+            VrMessage::Prepare {
+                view,
+                op,
+                entries,
+                commit,
+            } => {
+                if !self.follow(view, now) {
+                    return;
+                }
+                if op <= self.log.len() as u64 {
+                    let known = self.log.len() - op as usize;
+                    self.log.extend(entries.into_iter().skip(known));
+                }
+                self.learn_commit(commit);
+                let op = self.log.len() as u64;
+                let ok = VrMessage::PrepareOk {
+                    view,
+                    op,
+                    round: None,
+                };
+                self.send(from, ok);
+            }
+            VrMessage::PrepareOk { view, op, round } => {
+                if view != self.view {
+                    return;
+                }
+                let Role::Primary {
+                    acked, answered, ..
+                } = &mut self.role
+                else {
+                    return;
+                };
+                let held = acked.entry(from.to_string()).or_default();
+                *held = (*held).max(op);
+                if let Some(round) = round {
+                    let last = answered.entry(from.to_string()).or_default();
+                    *last = (*last).max(round);
+                    if op < self.log.len() as u64 {
+                        // Whatever it missed was lost on the way.
+                        self.send_entries(from, op);
+                    }
+                }
+                self.advance_commit();
+            }
+            VrMessage::Commit {
+                view,
+                commit,
+                round,
+            } => {
+                if !self.follow(view, now) {
+                    return;
+                }
+                self.learn_commit(commit);
+                let op = self.log.len() as u64;
+                let ok = VrMessage::PrepareOk {
+                    view,
+                    op,
+                    round: Some(round),
+                };
+                self.send(from, ok);
+            }
+            VrMessage::StartViewChange { view } => {
+                let alive = matches!(self.role, Role::Backup)
+                    && now < self.heard_from_primary + VIEW_CHANGE_TIMEOUT_MIN;
+                if view > self.view && !self.is_leader() && !alive {
+                    self.start_view_change(view, now);
+                }
+                if view != self.view {
+                    return;
+                }
+                let Role::ViewChange { votes, .. } = &mut self.role else {
+                    return;
+                };
+                votes.insert(from.to_string());
+                self.check_votes(now);
+            }
+            VrMessage::DoViewChange {
+                view,
+                log,
+                last_normal_view,
+                commit,
+            } => {
+                if *self.primary_of(view) != self.me || view < self.view {
+                    return;
+                }
+                if view > self.view {
+                    // A majority gave up on the views before, so the
+                    // current one is over anyway.
+                    self.start_view_change(view, now);
+                }
+                let Role::ViewChange { reports, .. } = &mut self.role else {
+                    return;
+                };
+                let report = Reported {
+                    last_normal_view,
+                    log,
+                    commit,
+                };
+                reports.insert(from.to_string(), report);
+                if reports.len() > self.nodes.len() / 2 {
+                    self.start_view(now);
+                }
+            }
+            VrMessage::StartView { view, log, commit } => {
+                if view < self.promised_view || self.is_normal_in(view) {
+                    return;
+                }
+                self.log = log;
+                self.become_backup(view, now);
+                self.learn_commit(commit);
+                let op = self.log.len() as u64;
+                let ok = VrMessage::PrepareOk {
+                    view,
+                    op,
+                    round: None,
+                };
+                self.send(from, ok);
+            }
+        }
+    }
+
+    /// The entries committed since the last call, in log order, with their
+    /// operation numbers.
+    pub fn take_committed(&mut self) -> Vec<(u64, Entry<C>)> {
+        let committed = (self.last_applied + 1..=self.commit)
+            .map(|op| (op, self.log[op as usize - 1].clone()))
+            .collect();
+        self.last_applied = self.commit;
+        committed
+    }
+
+    /// The reads started with [`read_index`](Self::read_index) that may
+    /// be served since the last call.
+    pub fn take_reads(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.ready_reads)
+    }
+
+    /// The messages to send, in order.
+    pub fn take_outbox(&mut self) -> Vec<(NodeId, VrMessage<C>)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    fn primary_of(&self, view: u64) -> &NodeId {
+        &self.nodes[(view % self.nodes.len() as u64) as usize]
+    }
+
+    fn is_normal_in(&self, view: u64) -> bool {
+        view == self.view && !matches!(self.role, Role::ViewChange { .. })
+    }
+
+    /// Follows the primary of `view`, which sent a prepare or heartbeat,
+    /// moving to that view if not in it yet. Returns whether it may.
+    fn follow(&mut self, view: u64, now: Instant) -> bool {
+        if view < self.promised_view {
+            return false;
+        }
+        if !self.is_normal_in(view) {
+            if view != self.last_normal_view {
+                // What was not committed may not be in that view's log;
+                // the primary sends the rest.
+                self.log.truncate(self.commit as usize);
+            }
+            self.become_backup(view, now);
+        } else if self.is_leader() {
+            return false;
+        }
+        self.heard_from_primary = now;
+        self.view_change_deadline = now + view_change_timeout();
+        true
+    }
+
+    fn become_backup(&mut self, view: u64, now: Instant) {
+        self.role = Role::Backup;
+        self.view = view;
+        self.last_normal_view = view;
+        self.promised_view = view;
+        self.heard_from_primary = now;
+        self.view_change_deadline = now + view_change_timeout();
+    }
+
+    /// Gives up on the views before `view` and tells every replica.
+    fn start_view_change(&mut self, view: u64, now: Instant) {
+        self.view = view;
+        self.role = Role::ViewChange {
+            votes: BTreeSet::from([self.me.clone()]),
+            sent: false,
+            reports: BTreeMap::new(),
+        };
+        self.view_change_deadline = now + view_change_timeout();
+        self.broadcast(VrMessage::StartViewChange { view });
+        self.check_votes(now);
+    }
+
+    /// Hands the new primary this replica's log once a majority gave up on
+    /// the views before.
+    fn check_votes(&mut self, now: Instant) {
+        let Role::ViewChange { votes, sent, .. } = &mut self.role else {
+            return;
+        };
+        if *sent || votes.len() <= self.nodes.len() / 2 {
+            return;
+        }
+        *sent = true;
+        self.promised_view = self.view;
+        let message = VrMessage::DoViewChange {
+            view: self.view,
+            log: self.log.clone(),
+            last_normal_view: self.last_normal_view,
+            commit: self.commit,
+        };
+        let primary = self.primary_of(self.view).clone();
+        match primary == self.me {
+            true => self.handle(&primary, message, now),
+            false => self.send(&primary, message),
+        }
+    }
+
+    /// Starts the view as its primary, with the log of the latest normal
+    /// view a majority reported.
+    fn start_view(&mut self, now: Instant) {
+        let Role::ViewChange { reports, .. } = std::mem::replace(&mut self.role, Role::Backup)
+        else {
+            return;
+        };
+        let commit = reports.values().map(|report| report.commit).max();
+        let latest = reports
+            .into_values()
+            .max_by_key(|report| (report.last_normal_view, report.log.len()));
+        if let Some(latest) = latest {
+            self.log = latest.log;
+        }
+        self.commit = self.commit.max(commit.unwrap_or_default());
+        self.last_normal_view = self.view;
+        self.promised_view = self.view;
+        self.role = Role::Primary {
+            acked: HashMap::new(),
+            started_at: self.log.len() as u64,
+            round: 0,
+            answered: HashMap::new(),
+            reads: Vec::new(),
+        };
+        self.broadcast(VrMessage::StartView {
+            view: self.view,
+            log: self.log.clone(),
+            commit: self.commit,
+        });
+        self.heartbeat_due = now + HEARTBEAT_INTERVAL;
+        self.advance_commit();
+    }
+
+    /// Commits what a majority of the view holds.
+    fn advance_commit(&mut self) {
+        let Role::Primary { acked, .. } = &self.role else {
+            return;
+        };
+        let mut held: Vec<u64> = self
+            .nodes
+            .iter()
+            .map(|id| match *id == self.me {
+                true => self.log.len() as u64,
+                false => acked.get(id).copied().unwrap_or_default(),
+            })
+            .collect();
+        held.sort_unstable_by(|a, b| b.cmp(a));
+        self.commit = self.commit.max(held[self.nodes.len() / 2]);
+        self.confirm_reads();
+    }
+
+    fn learn_commit(&mut self, commit: u64) {
+        self.commit = self.commit.max(commit.min(self.log.len() as u64));
+    }
+
+    /// Sends `to` the operations after `op`, up to [`MAX_ENTRIES`].
+    fn send_entries(&mut self, to: &str, op: u64) {
+        let entries = self.log[op as usize..]
+            .iter()
+            .take(MAX_ENTRIES)
+            .cloned()
+            .collect();
+        let prepare = VrMessage::Prepare {
+            view: self.view,
+            op,
+            entries,
+            commit: self.commit,
+        };
+        self.send(to, prepare);
+    }
+
+    fn broadcast_heartbeat(&mut self) {
+        let Role::Primary { round, .. } = &mut self.role else {
+            return;
+        };
+        *round += 1;
+        let heartbeat = VrMessage::Commit {
+            view: self.view,
+            commit: self.commit,
+            round: *round,
+        };
+        self.broadcast(heartbeat);
+    }
+
+    /// Readies the reads whose round a majority answered, once every
+    /// operation from before the view is committed.
+    fn confirm_reads(&mut self) {
+        let Role::Primary {
+            started_at,
+            round,
+            answered,
+            reads,
+            ..
+        } = &mut self.role
+        else {
+            return;
+        };
+        if self.commit < *started_at {
+            return;
+        }
+        let mut rounds: Vec<u64> = self
+            .nodes
+            .iter()
+            .map(|id| match *id == self.me {
+                true => *round,
+                false => answered.get(id).copied().unwrap_or_default(),
+            })
+            .collect();
+        rounds.sort_unstable_by(|a, b| b.cmp(a));
+        let confirmed = rounds[self.nodes.len() / 2];
+        reads.retain(|&(id, round)| {
+            let ready = round <= confirmed;
+            if ready {
+                self.ready_reads.push(id);
+            }
+            !ready
+        });
+    }
+
+    fn broadcast(&mut self, message: VrMessage<C>) {
+        for peer in self.nodes.iter().filter(|&peer| *peer != self.me) {
+            self.outbox.push((peer.clone(), message.clone()));
+        }
+    }
+
+    fn send(&mut self, to: &str, message: VrMessage<C>) {
+        self.outbox.push((to.to_string(), message));
+    }
+}
+
+impl<C> Protocol for Vr<C>
+where
+    C: Serialize + DeserializeOwned + Clone + Send + 'static,
+{
+    type Command = C;
+    type Message = VrMessage<C>;
+
+    fn new(me: &str, node_ids: &[String], now: Instant) -> Self {
+        Vr::new(me, node_ids, now)
+    }
+
+    fn tick(&mut self, now: Instant) {
+        Vr::tick(self, now)
+    }
+
+    fn handle(&mut self, from: &str, message: VrMessage<C>, now: Instant) {
+        Vr::handle(self, from, message, now)
+    }
+
+    fn propose(&mut self, command: C) -> Option<(u64, u64)> {
+        Vr::propose(self, command)
+    }
+
+    fn read_index(&mut self) -> Option<u64> {
+        Vr::read_index(self)
+    }
+
+    fn is_leader(&self) -> bool {
+        Vr::is_leader(self)
+    }
+
+    fn leader(&self) -> Option<&str> {
+        Vr::leader(self)
+    }
+
+    fn take_committed(&mut self) -> Vec<Committed<C>> {
+        Vr::take_committed(self)
+            .into_iter()
+            .map(|(op, entry)| Committed {
+                position: op,
+                proposal: Some(entry.view),
+                command: Some(entry.command),
+            })
+            .collect()
+    }
+
+    fn take_reads(&mut self) -> Vec<u64> {
+        Vr::take_reads(self)
+    }
+
+    fn take_outbox(&mut self) -> Vec<(NodeId, VrMessage<C>)> {
+        Vr::take_outbox(self)
+    }
+}
+
+fn view_change_timeout() -> Duration {
+    rand::thread_rng().gen_range(VIEW_CHANGE_TIMEOUT_MIN..=VIEW_CHANGE_TIMEOUT_MAX)
+}
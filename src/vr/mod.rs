@@ -0,0 +1,24 @@
+//! Viewstamped Replication: the same replicated log as [`crate::raft`],
+//! kept by a primary that changes only through numbered views.
+//!
+//! Where Raft elects whoever asks first with a log up to date, VR fixes the
+//! primary of every view in advance and has a majority agree to leave a
+//! view before the next one starts. The new primary then collects the logs
+//! of that majority and keeps the one from the latest normal view, so no
+//! log needs to be up to date to win, and no replica ever votes. In a
+//! normal view it costs one round trip to a majority per command, like
+//! Raft and Multi-Paxos.
+//!
+//! [`Vr`] is the protocol alone, without I/O or threads; [`VrServer`]
+//! mounts it on a node as a [`Consensus`](crate::consensus::Consensus)
+//! implementation, through the [`Server`](crate::consensus::Server) it
+//! shares with [`crate::paxos`]. It runs on the cluster's nodes as given at
+//! `init`, in memory only.
+
+mod core;
+
+pub use self::core::{Entry, Vr, VrMessage};
+
+/// [`Vr`] mounted on a node.
+pub type VrServer<S> =
+    crate::consensus::Server<Vr<<S as crate::consensus::StateMachine>::Command>, S>;
@@ -0,0 +1,27 @@
+//! A broadcast node refuses an in-flight limit of zero, which would never
+//! let a frame out.
+
+use std::{thread, time::Duration};
+
+use fly_distributed::{
+    broadcast::node::{BroadcastNode, Payload},
+    config::Config,
+    main_loop_on,
+    transport::Network,
+};
+use serde_json::json;
+
+#[test]
+fn an_inflight_limit_of_zero_fails_init() {
+    let network = Network::new();
+    let endpoint = network.join("n1");
+    let config = Config::default().with("gossip-inflight", 0);
+    let node = thread::spawn(move || main_loop_on::<BroadcastNode, Payload>(endpoint, config));
+    let client = network.join("c1");
+    let init = json!({ "type": "init", "node_id": "n1", "node_ids": ["n1"] });
+    client.request("n1", init).unwrap();
+
+    let err = node.join().unwrap().unwrap_err();
+    assert!(format!("{err:#}").contains("gossip-inflight"), "{err:#}");
+    assert!(client.recv_timeout(Duration::from_millis(200)).is_none());
+}
@@ -0,0 +1,28 @@
+//! Causal delivery holds operations back until what they depend on arrived.
+
+use fly_distributed::causal::CausalDelivery;
+
+#[test]
+fn operations_wait_for_their_causal_past() {
+    let mut n1 = CausalDelivery::<&str>::new("n1");
+    let mut n2 = CausalDelivery::new("n2");
+    let mut n3 = CausalDelivery::<&str>::new("n3");
+
+    let first = n1.stamp();
+    let second = n1.stamp();
+    assert_eq!(n2.receive("n1", first.clone(), "a"), vec!["a"]);
+    // n2 saw both of n1's operations before its own.
+    assert_eq!(n2.receive("n1", second.clone(), "b"), vec!["b"]);
+    let reply = n2.stamp();
+
+    // n3 gets everything in the worst order.
+    assert!(n3.receive("n2", reply, "c").is_empty());
+    assert!(n3.receive("n1", second.clone(), "b").is_empty());
+    assert_eq!(n3.pending(), 2);
+    assert_eq!(n3.receive("n1", first.clone(), "a"), vec!["a", "b", "c"]);
+    assert_eq!(n3.pending(), 0);
+
+    // Retransmissions are dropped.
+    assert!(n3.receive("n1", first, "a").is_empty());
+    assert!(n3.receive("n1", second, "b").is_empty());
+}
@@ -0,0 +1,87 @@
+//! A write coalescer keeps at most its window of writes in flight, sends
+//! pending writes to one key as one, and hands every waiter the outcome.
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use fly_distributed::{runtime::RpcError, services::WriteCoalescer};
+use serde_json::{json, Value};
+
+#[test]
+fn no_more_than_the_window_is_in_flight() {
+    let (in_flight, most) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+    let coalescer = {
+        let (in_flight, most) = (in_flight.clone(), most.clone());
+        WriteCoalescer::new(3, move |_, _| {
+            let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            most.fetch_max(now, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(20));
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        })
+    };
+    let tickets: Vec<_> = (0..12)
+        .map(|i| coalescer.submit(format!("entry/k/{i}"), i))
+        .collect();
+    for ticket in tickets {
+        ticket.wait().unwrap();
+    }
+    // Twelve writes of 20ms each, three at a time.
+    assert_eq!(most.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn pending_writes_to_a_key_collapse_into_the_last() {
+    let (started, release) = (mpsc::channel(), mpsc::channel::<()>());
+    let (started_tx, started_rx) = started;
+    let (release_tx, release_rx) = release;
+    let release_rx = Mutex::new(release_rx);
+    let written = Arc::new(Mutex::new(Vec::new()));
+    let coalescer = {
+        let written = written.clone();
+        WriteCoalescer::new(4, move |key: &Value, value: &Value| {
+            started_tx.send(()).unwrap();
+            release_rx.lock().unwrap().recv().unwrap();
+            written.lock().unwrap().push((key.clone(), value.clone()));
+            Ok(())
+        })
+    };
+    let first = coalescer.submit("x", 1);
+    started_rx.recv().unwrap();
+    // "x" is being written, so these wait, and only the last is sent.
+    let rest: Vec<_> = (2..=4).map(|i| coalescer.submit("x", i)).collect();
+    release_tx.send(()).unwrap();
+    first.wait().unwrap();
+    started_rx.recv().unwrap();
+    release_tx.send(()).unwrap();
+    for ticket in rest {
+        ticket.wait().unwrap();
+    }
+    let written = written.lock().unwrap().clone();
+    assert_eq!(
+        written,
+        vec![(json!("x"), json!(1)), (json!("x"), json!(4))]
+    );
+}
+
+#[test]
+fn every_waiter_gets_the_error() {
+    let coalescer = WriteCoalescer::new(1, |_, _| {
+        Err(RpcError::Remote {
+            code: 11,
+            text: "unavailable".into(),
+        })
+    });
+    let tickets: Vec<_> = (0..3).map(|_| coalescer.submit("x", 1)).collect();
+    for ticket in tickets {
+        match ticket.wait() {
+            Err(RpcError::Remote { code: 11, .. }) => {}
+            other => panic!("expected error 11, got {other:?}"),
+        }
+    }
+}
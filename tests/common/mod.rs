@@ -0,0 +1,38 @@
+//! Setup shared by the tests that run nodes on an in-process [`Network`].
+
+use std::{thread, time::Duration};
+
+use fly_distributed::{
+    config::Config,
+    main_loop_on,
+    transport::{Endpoint, Network},
+    Node,
+};
+use serde::de::DeserializeOwned;
+use serde_json::json;
+
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Starts `nodes` running `N` with `config`, and inits each through
+/// `client` as a member of `node_ids`. The `lin-kv` stand-in starts the
+/// same way, as `start::<LinKvNode, _>(.., &["lin-kv"], &["lin-kv"], ..)`.
+pub fn start<N, P>(
+    network: &Network,
+    client: &Endpoint,
+    nodes: &[&str],
+    node_ids: &[&str],
+    config: Config,
+) where
+    N: Node<P> + 'static,
+    P: DeserializeOwned + 'static,
+{
+    for node in nodes {
+        let endpoint = network.join(node);
+        let config = config.clone();
+        thread::spawn(move || main_loop_on::<N, P>(endpoint, config));
+    }
+    for node in nodes {
+        let init = json!({ "type": "init", "node_id": node, "node_ids": node_ids });
+        assert_eq!(client.rpc(node, init, TIMEOUT).unwrap()["type"], "init_ok");
+    }
+}
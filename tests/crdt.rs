@@ -0,0 +1,358 @@
+//! Convergence of the CRDTs in `crdt`: merging copies in any order, any
+//! number of times, gives the same state.
+
+use fly_distributed::{
+    clock::{HlcTimestamp, Stability},
+    crdt::{
+        DvvSet, GCounter, GSet, LwwMap, LwwRegister, OrSet, Orswot, PnCounter, Rga, RgaId,
+        TwoPhaseSet,
+    },
+    gossip::{DeltaCrdt, Merge},
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+fn merged<S: Merge>(mut into: S, from: S) -> S {
+    into.merge(from);
+    into
+}
+
+#[test]
+fn gset_merge_is_union_in_any_order() {
+    let a: GSet<usize> = [1, 2, 3].into_iter().collect();
+    let b: GSet<usize> = [3, 4].into_iter().collect();
+
+    let ab = merged(a.clone(), b.clone());
+    let ba = merged(b.clone(), a.clone());
+    assert_eq!(ab, ba);
+    assert_eq!(merged(ab.clone(), a), ab);
+    assert_eq!(ab.iter().copied().collect::<Vec<_>>(), vec![1, 2, 3, 4]);
+}
+
+#[test]
+fn gset_delta_since_brings_a_copy_up_to_date() {
+    let mut source: GSet<usize> = [1, 2].into_iter().collect();
+    let mut copy = source.clone();
+    let version = source.version();
+
+    source.insert(3);
+    source.insert(1);
+    let delta = source.delta_since(version);
+    assert_eq!(delta.iter().copied().collect::<Vec<_>>(), vec![3]);
+
+    copy.merge(delta);
+    assert_eq!(copy, source);
+}
+
+#[test]
+fn gset_serializes_as_a_list() {
+    let set: GSet<usize> = [2, 1].into_iter().collect();
+    let json = serde_json::to_string(&set).unwrap();
+    assert_eq!(json, "[1,2]");
+    assert_eq!(serde_json::from_str::<GSet<usize>>(&json).unwrap(), set);
+}
+
+#[test]
+fn orset_removes_what_it_saw_and_keeps_concurrent_adds() {
+    let mut a = OrSet::new();
+    a.insert("n1", 1);
+    a.insert("n1", 2);
+    let mut b = a.clone();
+
+    // n1 removes 1 while n2 adds it again concurrently.
+    a.remove("n1", &1);
+    b.insert("n2", 1);
+    b.remove("n2", &2);
+
+    let ab = merged(a.clone(), b.clone());
+    let ba = merged(b, a);
+    assert_eq!(ab.elements(), vec![1]);
+    assert_eq!(ab, ba);
+}
+
+#[test]
+fn orset_element_can_be_added_back() {
+    let mut set = OrSet::new();
+    set.insert("n1", 7);
+    assert!(set.remove("n1", &7));
+    assert!(!set.contains(&7));
+    set.insert("n1", 7);
+    assert!(set.contains(&7));
+}
+
+#[test]
+fn orset_gc_drops_stable_tombstones_for_good() {
+    let mut a = OrSet::new();
+    a.insert("n1", 1);
+    a.insert("n1", 2);
+    let stale = a.clone();
+
+    a.remove("n1", &1);
+    let b = merged(stale.clone(), a.clone());
+    let mut stability = Stability::new(&["n1".to_string(), "n2".to_string()]);
+    stability.observe("n1", a.clock().clone());
+    assert_eq!(a.gc(&stability.stable()), 0);
+
+    stability.observe("n2", b.clock().clone());
+    assert_eq!(a.gc(&stability.stable()), 1);
+    assert_eq!(a.tombstones(), 0);
+
+    // A copy from before the removal does not bring the element back.
+    a.merge(stale);
+    assert_eq!(a.elements(), vec![2]);
+}
+
+#[test]
+fn orswot_removes_what_it_saw_and_keeps_concurrent_adds() {
+    let mut a = Orswot::new();
+    a.insert("n1", 1);
+    a.insert("n1", 2);
+    let mut b = a.clone();
+
+    a.remove(&1);
+    b.insert("n2", 1);
+    b.remove(&2);
+
+    let ab = merged(a.clone(), b.clone());
+    let ba = merged(b, a);
+    assert_eq!(ab.elements(), vec![1]);
+    assert_eq!(ab, ba);
+}
+
+/// Random inserts, removes and merges over three copies: an ORSWOT answers
+/// exactly like an OR-Set put through the same operations, and copies that
+/// merged everything agree.
+#[test]
+fn orswot_behaves_like_orset() {
+    let nodes = ["n1", "n2", "n3"];
+    for seed in 0..200 {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut orsets = vec![OrSet::new(); nodes.len()];
+        let mut orswots = vec![Orswot::new(); nodes.len()];
+        for _ in 0..100 {
+            let at = rng.gen_range(0..nodes.len());
+            let element = rng.gen_range(0..5);
+            match rng.gen_range(0..3) {
+                0 => {
+                    orsets[at].insert(nodes[at], element);
+                    orswots[at].insert(nodes[at], element);
+                }
+                1 => {
+                    let removed = orsets[at].remove(nodes[at], &element);
+                    assert_eq!(orswots[at].remove(&element), removed, "seed {seed}");
+                }
+                _ => {
+                    let from = rng.gen_range(0..nodes.len());
+                    let (orset, orswot) = (orsets[from].clone(), orswots[from].clone());
+                    orsets[at].merge(orset);
+                    orswots[at].merge(orswot);
+                }
+            }
+            assert_eq!(orswots[at].elements(), orsets[at].elements(), "seed {seed}");
+        }
+        let all = orswots.iter().cloned().reduce(merged).unwrap();
+        let all_orsets = orsets.into_iter().reduce(merged).unwrap();
+        assert_eq!(all.elements(), all_orsets.elements(), "seed {seed}");
+        for copy in orswots {
+            assert_eq!(merged(copy, all.clone()), all, "seed {seed}");
+        }
+    }
+}
+
+#[test]
+fn two_phase_set_removal_is_final() {
+    let mut a = TwoPhaseSet::new();
+    assert!(a.insert(1));
+    assert!(a.insert(2));
+    let mut b = a.clone();
+
+    assert!(a.remove(&1));
+    assert!(!a.insert(1));
+    b.insert(1);
+    b.insert(3);
+
+    let ab = merged(a.clone(), b.clone());
+    assert_eq!(ab, merged(b, a));
+    assert_eq!(ab.elements(), vec![2, 3]);
+}
+
+#[test]
+fn pn_counter_sums_every_node_once() {
+    let mut a = PnCounter::default();
+    a.apply("n1", 5);
+    let mut b = a.clone();
+    a.apply("n1", -2);
+    b.apply("n2", 4);
+    b.apply("n2", -1);
+
+    let ab = merged(a.clone(), b.clone());
+    assert_eq!(ab, merged(b, a.clone()));
+    assert_eq!(merged(ab.clone(), a), ab);
+    assert_eq!(ab.value(), 6);
+    assert_eq!(ab.node_value("n2"), 3);
+}
+
+#[test]
+fn g_counter_keeps_each_nodes_largest_count() {
+    let mut a = GCounter::default();
+    a.add("n1", 2);
+    let mut b = a.clone();
+    a.add("n1", 3);
+    b.add("n2", 1);
+
+    let ab = merged(a.clone(), b.clone());
+    assert_eq!(ab, merged(b, a.clone()));
+    assert_eq!(merged(ab.clone(), a), ab);
+    assert_eq!(ab.value(), 6);
+}
+
+fn at(ms: u64) -> HlcTimestamp {
+    HlcTimestamp { ms, logical: 0 }
+}
+
+#[test]
+fn lww_register_keeps_the_latest_write_and_breaks_ties_by_node() {
+    let base = LwwRegister::new(0, at(1), "n1");
+    let mut a = base.clone();
+    let mut b = base.clone();
+    assert!(a.set(1, at(5), "n1"));
+    assert!(b.set(2, at(5), "n2"));
+    assert!(!b.set(3, at(4), "n3"));
+
+    let ab = merged(a.clone(), b.clone());
+    assert_eq!(ab, merged(b, a));
+    assert_eq!(*ab.get(), 2);
+    assert_eq!(merged(ab.clone(), base), ab);
+}
+
+#[test]
+fn lww_map_tombstones_compete_with_writes() {
+    let mut a = LwwMap::new();
+    a.insert("x", 1, at(1), "n1");
+    a.insert("y", 1, at(1), "n1");
+    let mut b = a.clone();
+
+    a.remove("x", at(3), "n1");
+    b.insert("x", 2, at(2), "n2");
+    b.remove("y", at(2), "n2");
+    a.insert("y", 3, at(4), "n1");
+
+    let ab = merged(a.clone(), b.clone());
+    assert_eq!(ab, merged(b, a));
+    assert_eq!(ab.get(&"x"), None);
+    assert_eq!(ab.iter().collect::<Vec<_>>(), vec![(&"y", &3)]);
+}
+
+#[test]
+fn lww_map_gc_drops_stable_tombstones_for_good() {
+    let mut a = LwwMap::new();
+    a.insert("x", 1, at(1), "n1");
+    let stale = a.clone();
+    a.remove("x", at(2), "n1");
+    let mut b = merged(stale.clone(), a.clone());
+    b.insert("y", 1, at(5), "n1");
+    a.merge(b.clone());
+
+    let mut stability = Stability::new(&["n1".to_string(), "n2".to_string()]);
+    stability.observe("n1", a.clock());
+    stability.observe("n2", b.clock());
+    assert_eq!(a.gc(&stability.stable()), 1);
+    assert_eq!(a.tombstones(), 0);
+
+    a.merge(stale);
+    assert_eq!(a.get(&"x"), None);
+    assert_eq!(a.iter().collect::<Vec<_>>(), vec![(&"y", &1)]);
+}
+
+#[test]
+fn lww_map_delta_survives_a_json_round_trip() {
+    let mut source = LwwMap::new();
+    source.insert(1usize, 10usize, at(1), "n1");
+    let mut copy = source.clone();
+    let version = source.version();
+
+    source.insert(2, 20, at(2), "n1");
+    source.insert(1, 11, at(3), "n2");
+    let delta = source.split_delta(&version);
+    let json = serde_json::to_value(&delta).unwrap();
+    copy.merge(serde_json::from_value(json).unwrap());
+    assert_eq!(copy, source);
+}
+
+#[test]
+fn dvv_set_keeps_concurrent_writes_and_replaces_what_the_writer_saw() {
+    let mut a = DvvSet::new();
+    a.write("n1", "x", at(1), &Default::default());
+    let mut b = a.clone();
+    let seen = a.context().clone();
+
+    // Two clients overwrite "x" on different nodes, each having read it.
+    a.write("n1", "y", at(2), &seen);
+    b.write("n2", "z", at(3), &seen);
+    let ab = merged(a.clone(), b.clone());
+    assert_eq!(ab, merged(b, a.clone()));
+    let values: Vec<_> = ab.siblings().iter().map(|s| s.value).collect();
+    assert_eq!(values, vec!["y", "z"]);
+    assert_eq!(ab.winner(), Some(&"z"));
+
+    // A write that read only "y" leaves "z" alone; one that read both
+    // resolves them.
+    let mut stale = ab.clone();
+    stale.write("n1", "y2", at(4), a.context());
+    let values: Vec<_> = stale.siblings().iter().map(|s| s.value).collect();
+    assert_eq!(values, vec!["y2", "z"]);
+    let mut resolved = ab.clone();
+    resolved.write("n1", "yz", at(4), ab.context());
+    assert_eq!(resolved.siblings().len(), 1);
+    assert_eq!(merged(resolved.clone(), ab), resolved);
+}
+
+#[test]
+fn pn_counter_delta_carries_only_changed_counts() {
+    let mut source = PnCounter::default();
+    source.apply("n1", 3);
+    source.apply("n2", -1);
+    let mut copy = source.clone();
+    let version = source.version();
+
+    source.apply("n2", 4);
+    let delta = source.split_delta(&version);
+    assert_eq!(delta.node_value("n1"), 0);
+    assert_eq!(delta.node_value("n2"), 4);
+
+    copy.merge(delta);
+    assert_eq!(copy, source);
+    assert_eq!(copy.value(), 6);
+}
+
+fn id(ms: u64, node: &str) -> RgaId {
+    RgaId {
+        time: at(ms),
+        node: node.to_string(),
+        seq: 0,
+    }
+}
+
+#[test]
+fn rga_concurrent_appends_converge() {
+    let mut a = Rga::new();
+    a.append(id(1, "n1"), 1);
+    let mut b = a.clone();
+
+    a.append(id(2, "n1"), 2);
+    a.append(id(4, "n1"), 4);
+    b.append(id(3, "n2"), 3);
+
+    let ab = merged(a.clone(), b.clone());
+    assert_eq!(ab, merged(b, a));
+    // The newer sibling of 1 comes first, followed by what was appended to it.
+    assert_eq!(ab.values().copied().collect::<Vec<_>>(), vec![1, 3, 2, 4]);
+}
+
+#[test]
+fn rga_hides_elements_until_their_predecessor_arrives() {
+    let mut list = Rga::new();
+    list.insert_after(id(2, "n1"), Some(id(1, "n1")), 2);
+    assert_eq!(list.values().count(), 0);
+    list.insert_after(id(1, "n1"), None, 1);
+    assert_eq!(list.values().copied().collect::<Vec<_>>(), vec![1, 2]);
+}
@@ -0,0 +1,70 @@
+//! `Runtime::deliver` resends a request until it is answered, and sends a
+//! destination's requests one at a time, in the order they were queued.
+
+mod common;
+
+use std::time::Duration;
+
+use common::TIMEOUT;
+use fly_distributed::{config::Config, message::Init, transport::Network, Message, Node, Runtime};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+const RESEND: Duration = Duration::from_millis(100);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    /// Delivers each of `values` to `dest`.
+    Relay {
+        dest: String,
+        values: Vec<usize>,
+    },
+    Value {
+        value: usize,
+    },
+    ValueOk,
+}
+
+struct RelayNode {
+    runtime: Runtime,
+}
+
+impl Node<Payload> for RelayNode {
+    fn from_init(runtime: Runtime, _init: Init) -> anyhow::Result<Self> {
+        Ok(Self { runtime })
+    }
+
+    fn step(&mut self, input: Message<Payload>) -> anyhow::Result<()> {
+        if let Payload::Relay { dest, values } = input.body.payload {
+            for value in values {
+                self.runtime
+                    .deliver(&dest, Payload::Value { value }, RESEND);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn deliveries_are_resent_and_kept_in_order() {
+    let network = Network::new();
+    let peer = network.join("n2");
+    let client = network.join("c1");
+    common::start::<RelayNode, _>(&network, &client, &["n1"], &["n1", "n2"], Config::default());
+
+    let relay = json!({ "type": "relay", "dest": "n2", "values": [1, 2, 3] });
+    client.request("n1", relay).unwrap();
+    // Left unanswered, the first value comes again, and nothing after it.
+    for _ in 0..3 {
+        let request = peer.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(request.body.payload["value"], 1);
+    }
+    for value in 1..=3 {
+        let request = peer.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(request.body.payload["value"], value);
+        peer.reply(&request, json!({ "type": "value_ok" })).unwrap();
+    }
+    assert!(peer.recv_timeout(RESEND * 3).is_none());
+}
@@ -0,0 +1,33 @@
+//! HyParView keeps an active peer through a lost frame or two, and only
+//! demotes it after repeated ack timeouts with no ack in between.
+
+use fly_distributed::membership::hyparview::HyParView;
+
+fn view() -> HyParView {
+    let node_ids: Vec<String> = ["n1", "n2", "n3", "n4"].map(String::from).to_vec();
+    let mut view = HyParView::new("n2", &node_ids);
+    view.join();
+    assert_eq!(view.active(), ["n1"]);
+    view
+}
+
+#[test]
+fn an_active_peer_is_demoted_after_repeated_timeouts() {
+    let mut view = view();
+    view.on_failed("n1");
+    view.on_failed("n1");
+    assert_eq!(view.active(), ["n1"]);
+    view.on_failed("n1");
+    assert!(view.active().is_empty());
+}
+
+#[test]
+fn an_ack_forgives_earlier_timeouts() {
+    let mut view = view();
+    view.on_failed("n1");
+    view.on_failed("n1");
+    view.heard_from("n1");
+    view.on_failed("n1");
+    view.on_failed("n1");
+    assert_eq!(view.active(), ["n1"]);
+}
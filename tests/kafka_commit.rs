@@ -0,0 +1,69 @@
+//! `commit_offsets` with several keys must never be half visible.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use fly_distributed::kafka::log::{LogStore, MemoryLogs};
+
+const KEYS: [&str; 3] = ["a", "b", "c"];
+
+fn keys() -> Vec<String> {
+    KEYS.iter().map(|key| key.to_string()).collect()
+}
+
+#[test]
+fn multi_key_commit_is_never_partially_observed() {
+    let logs = Arc::new(MemoryLogs::new(None));
+    let done = Arc::new(AtomicBool::new(false));
+
+    let writer = {
+        let logs = logs.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            for offset in 1..=2000 {
+                let offsets = keys().into_iter().map(|key| (key, offset)).collect();
+                logs.commit(offsets).unwrap();
+            }
+            done.store(true, Ordering::SeqCst);
+        })
+    };
+
+    while !done.load(Ordering::SeqCst) {
+        let committed = logs.committed(&keys()).unwrap();
+        let distinct: Vec<usize> = {
+            let mut values: Vec<usize> = committed.values().copied().collect();
+            values.sort();
+            values.dedup();
+            values
+        };
+        assert!(
+            committed.is_empty() || (committed.len() == KEYS.len() && distinct.len() == 1),
+            "partial commit observed: {committed:?}"
+        );
+    }
+    writer.join().unwrap();
+
+    let committed = logs.committed(&keys()).unwrap();
+    let expected: HashMap<String, usize> = keys().into_iter().map(|key| (key, 2000)).collect();
+    assert_eq!(committed, expected);
+}
+
+#[test]
+fn commit_never_moves_offsets_backwards() {
+    let logs = MemoryLogs::new(None);
+    logs.commit(HashMap::from([("a".to_string(), 5), ("b".to_string(), 2)]))
+        .unwrap();
+    logs.commit(HashMap::from([("a".to_string(), 3), ("b".to_string(), 4)]))
+        .unwrap();
+    let committed = logs.committed(&keys()).unwrap();
+    assert_eq!(
+        committed,
+        HashMap::from([("a".to_string(), 5), ("b".to_string(), 4)])
+    );
+}
@@ -0,0 +1,116 @@
+//! The lin-kv kafka store hands out every offset once and never leaves a gap
+//! that `poll` would stop at, even when a `send` fails half way, and its
+//! offset hint catches up with sends that did not wait for it.
+
+mod common;
+
+use std::{
+    collections::BTreeMap,
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::TIMEOUT;
+use fly_distributed::{
+    config::Config,
+    kafka::KafkaNode,
+    lin_kv::LinKvNode,
+    transport::{Endpoint, Network},
+};
+use serde_json::{json, Value};
+
+/// Starts a `lin-kv` service and kafka nodes `nodes` keeping their logs in it.
+fn start(network: &Network, nodes: &[&str]) -> Endpoint {
+    let client = network.join("c0");
+    let lin_kv = ["lin-kv"];
+    common::start::<LinKvNode, _>(network, &client, &lin_kv, &lin_kv, Config::default());
+    let config = Config::default().with("kafka-store", "lin-kv");
+    common::start::<KafkaNode, _>(network, &client, nodes, nodes, config);
+    client
+}
+
+fn send(client: &Endpoint, node: &str, msg: usize) -> Value {
+    let send = json!({ "type": "send", "key": "k", "msg": msg });
+    client.rpc(node, send, TIMEOUT).unwrap()
+}
+
+fn poll(client: &Endpoint, node: &str) -> Vec<(usize, usize)> {
+    let poll = json!({ "type": "poll", "offsets": { "k": 0 } });
+    let reply = client.rpc(node, poll, TIMEOUT).unwrap();
+    serde_json::from_value(reply["msgs"]["k"].clone()).unwrap_or_default()
+}
+
+#[test]
+fn concurrent_sends_get_every_offset_once() {
+    let nodes = ["n1", "n2"];
+    let network = Network::new();
+    let client = start(&network, &nodes);
+
+    let senders: Vec<_> = (0..4)
+        .map(|c| {
+            let client = network.join(&format!("c{}", c + 1));
+            thread::spawn(move || {
+                (0..10)
+                    .map(|i| {
+                        let msg = c * 100 + i;
+                        let reply = send(&client, nodes[i % nodes.len()], msg);
+                        assert_eq!(reply["type"], "send_ok", "{reply}");
+                        (reply["offset"].as_u64().unwrap() as usize, msg)
+                    })
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect();
+    let sent: BTreeMap<usize, usize> = senders
+        .into_iter()
+        .flat_map(|sender| sender.join().unwrap())
+        .collect();
+    assert_eq!(sent.len(), 40, "an offset was handed out twice");
+    let expected: Vec<(usize, usize)> = sent.into_iter().collect();
+    for node in nodes {
+        assert_eq!(poll(&client, node), expected, "{node}");
+    }
+}
+
+#[test]
+fn a_failed_send_leaves_no_gap() {
+    let nodes = ["n1", "n2"];
+    let network = Network::new();
+    let client = start(&network, &nodes);
+    assert_eq!(send(&client, "n1", 10)["offset"], 0);
+
+    network.cut("n1", "lin-kv");
+    assert_eq!(send(&client, "n1", 11)["type"], "error");
+    network.heal();
+
+    assert_eq!(send(&client, "n2", 12)["offset"], 1);
+    assert_eq!(send(&client, "n1", 13)["offset"], 2);
+    assert_eq!(poll(&client, "n1"), [(0, 10), (1, 12), (2, 13)]);
+}
+
+#[test]
+fn the_offset_hint_catches_up_with_the_sends() {
+    let network = Network::new();
+    let client = start(&network, &["n1"]);
+    let senders: Vec<_> = (0..4)
+        .map(|c| {
+            let client = network.join(&format!("c{}", c + 1));
+            thread::spawn(move || {
+                for i in 0..5 {
+                    assert_eq!(send(&client, "n1", c * 10 + i)["type"], "send_ok");
+                }
+            })
+        })
+        .collect();
+    for sender in senders {
+        sender.join().unwrap();
+    }
+    assert_eq!(poll(&client, "n1").len(), 20);
+
+    let read = json!({ "type": "read", "key": "next/k" });
+    let deadline = Instant::now() + TIMEOUT;
+    while client.rpc("lin-kv", read.clone(), TIMEOUT).unwrap()["value"] != 20 {
+        assert!(Instant::now() < deadline, "the hint never reached 20");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,206 @@
+//! The replicated kafka store hands out every offset once, keeps members'
+//! logs identical and gapless, and fences a leader that was taken over from.
+
+mod common;
+
+use std::{collections::BTreeMap, thread, time::Duration};
+
+use common::TIMEOUT;
+use fly_distributed::{
+    config::Config,
+    kafka::KafkaNode,
+    message::RawMessage,
+    ring::Ring,
+    transport::{Endpoint, Network},
+};
+use serde_json::{json, Value};
+
+/// Starts kafka nodes `nodes` of the cluster `node_ids`, copying each key
+/// to `replicas` followers.
+fn start(network: &Network, client: &Endpoint, nodes: &[&str], node_ids: &[&str], replicas: usize) {
+    let config = Config::default()
+        .with("kafka-store", "replicated")
+        .with("kafka-replicas", replicas);
+    common::start::<KafkaNode, _>(network, client, nodes, node_ids, config);
+}
+
+fn send(client: &Endpoint, node: &str, key: &str, msg: usize) -> Value {
+    let send = json!({ "type": "send", "key": key, "msg": msg });
+    client.rpc(node, send, TIMEOUT).unwrap()
+}
+
+fn poll(client: &Endpoint, node: &str, key: &str) -> Vec<(usize, usize)> {
+    let poll = json!({ "type": "poll", "offsets": { key: 0 } });
+    let reply = client.rpc(node, poll, TIMEOUT).unwrap();
+    serde_json::from_value(reply["msgs"][key].clone()).unwrap_or_default()
+}
+
+/// A key `nodes` lists in that order on the ring.
+fn key_led_by(nodes: &[&str]) -> String {
+    let ids: Vec<String> = nodes.iter().map(|node| node.to_string()).collect();
+    let ring = Ring::new(&ids).unwrap();
+    (0..)
+        .map(|i| format!("k{i}"))
+        .find(|key| ring.replicas(key, ids.len()) == ids)
+        .unwrap()
+}
+
+#[test]
+fn concurrent_sends_are_replicated_in_order_without_gaps() {
+    let nodes = ["n1", "n2", "n3"];
+    let network = Network::new();
+    let client = network.join("c0");
+    start(&network, &client, &nodes, &nodes, 2);
+
+    let senders: Vec<_> = (0..4)
+        .map(|c| {
+            let client = network.join(&format!("c{}", c + 1));
+            thread::spawn(move || {
+                (0..20)
+                    .map(|i| {
+                        let msg = c * 100 + i;
+                        let reply = send(&client, nodes[i % nodes.len()], "k", msg);
+                        assert_eq!(reply["type"], "send_ok", "{reply}");
+                        (reply["offset"].as_u64().unwrap() as usize, msg)
+                    })
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect();
+    let sent: BTreeMap<usize, usize> = senders
+        .into_iter()
+        .flat_map(|sender| sender.join().unwrap())
+        .collect();
+    assert_eq!(sent.len(), 80, "an offset was handed out twice");
+    let expected: Vec<(usize, usize)> = sent.into_iter().collect();
+    assert_eq!(expected.last().unwrap().0, 79);
+
+    for node in nodes {
+        // Followers learn that the last entry was acknowledged a moment later.
+        let mut polled = poll(&client, node, "k");
+        for _ in 0..50 {
+            if polled.len() == expected.len() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+            polled = poll(&client, node, "k");
+        }
+        assert_eq!(polled, expected, "{node}");
+    }
+}
+
+/// Reads requests to `fake` until one of type `kind`, skipping `acked`
+/// notices.
+fn expect(fake: &Endpoint, kind: &str) -> RawMessage {
+    loop {
+        let request = fake.recv_timeout(TIMEOUT).expect("no request");
+        match request.body.payload["type"].as_str() {
+            Some("acked") => continue,
+            Some(found) => {
+                assert_eq!(found, kind, "{}", request.body.payload);
+                return request;
+            }
+            None => panic!("untyped request {}", request.body.payload),
+        }
+    }
+}
+
+/// Plays the part of n2 while n1 claims `key` in `term` and appends one
+/// entry, which n2 stores or, if `refuse`, fails.
+fn follow_append(fake: &Endpoint, term: u64, next: usize, refuse: bool) -> Value {
+    let claim = expect(fake, "claim");
+    assert_eq!(claim.body.payload["term"], term);
+    let reply = json!({ "type": "claim_ok", "term": term, "leader": "n1", "next": next });
+    fake.reply(&claim, reply).unwrap();
+    let catch_up = expect(fake, "sync");
+    assert_eq!(catch_up.body.payload["term"], term);
+    fake.reply(&catch_up, json!({ "type": "sync_ok" })).unwrap();
+    let append = expect(fake, "sync");
+    if refuse {
+        let error = json!({ "type": "error", "code": 11, "text": "refused" });
+        fake.reply(&append, error).unwrap();
+    } else {
+        fake.reply(&append, json!({ "type": "sync_ok" })).unwrap();
+    }
+    append.body.payload
+}
+
+#[test]
+fn a_failed_entry_is_dropped_and_the_lead_claimed_again() {
+    let nodes = ["n1", "n2"];
+    let key = key_led_by(&nodes);
+    let network = Network::new();
+    let fake = network.join("n2");
+    let client = network.join("c1");
+    start(&network, &client, &nodes[..1], &nodes, 1);
+
+    let sending = {
+        let (client, key) = (network.join("c2"), key.clone());
+        thread::spawn(move || send(&client, "n1", &key, 10))
+    };
+    let append = follow_append(&fake, 1, 0, true);
+    assert_eq!(append["entries"], json!([[0, 10]]));
+    assert_eq!(sending.join().unwrap()["type"], "error");
+    assert!(poll(&client, "n1", &key).is_empty());
+
+    // The next send claims a new term and takes offset 0 again.
+    let sending = {
+        let (client, key) = (network.join("c3"), key.clone());
+        thread::spawn(move || send(&client, "n1", &key, 11))
+    };
+    let append = follow_append(&fake, 2, 1, false);
+    assert_eq!(append["entries"], json!([[0, 11]]));
+    assert_eq!(append["term"], 2);
+    assert_eq!(sending.join().unwrap()["offset"], 0);
+    assert_eq!(poll(&client, "n1", &key), [(0, 11)]);
+}
+
+#[test]
+fn a_leader_taken_over_from_is_fenced() {
+    let nodes = ["n1", "n2"];
+    let key = key_led_by(&nodes);
+    let network = Network::new();
+    let fake = network.join("n2");
+    let client = network.join("c1");
+    start(&network, &client, &nodes[..1], &nodes, 1);
+
+    let sending = {
+        let (client, key) = (network.join("c2"), key.clone());
+        thread::spawn(move || send(&client, "n1", &key, 10))
+    };
+    follow_append(&fake, 1, 0, false);
+    assert_eq!(sending.join().unwrap()["offset"], 0);
+
+    // n2 takes over in term 2 and appends offset 1.
+    let claim = json!({ "type": "claim", "key": key, "term": 2 });
+    let promise = fake.rpc("n1", claim, TIMEOUT).unwrap();
+    assert_eq!(
+        promise,
+        json!({ "type": "claim_ok", "term": 2, "leader": "n2", "next": 1 })
+    );
+    let sync = json!({
+        "type": "sync", "key": key, "term": 2, "from": 1, "entries": [[1, 20]], "acked": 2,
+    });
+    assert_eq!(fake.rpc("n1", sync, TIMEOUT).unwrap()["type"], "sync_ok");
+    assert_eq!(poll(&client, "n1", &key), [(0, 10), (1, 20)]);
+
+    // Entries from term 1 are refused now, and a claim of it too.
+    let stale = json!({
+        "type": "sync", "key": key, "term": 1, "from": 1, "entries": [[1, 99]], "acked": 2,
+    });
+    assert_eq!(fake.rpc("n1", stale, TIMEOUT).unwrap()["type"], "error");
+    let claim = json!({ "type": "claim", "key": key, "term": 1 });
+    assert_eq!(fake.rpc("n1", claim, TIMEOUT).unwrap()["term"], 2);
+    assert_eq!(poll(&client, "n1", &key), [(0, 10), (1, 20)]);
+
+    // n1 forwards sends to n2 from now on.
+    let sending = {
+        let (client, key) = (network.join("c3"), key.clone());
+        thread::spawn(move || send(&client, "n1", &key, 11))
+    };
+    let forwarded = expect(&fake, "send");
+    assert_eq!(forwarded.body.payload["msg"], 11);
+    let reply = json!({ "type": "send_ok", "offset": 2 });
+    fake.reply(&forwarded, reply).unwrap();
+    assert_eq!(sending.join().unwrap()["offset"], 2);
+}
@@ -0,0 +1,75 @@
+//! A segmented kafka log reads runs across segment boundaries, stops at
+//! gaps, and drops only whole segments when truncated, along with entries
+//! arriving for them later.
+
+use fly_distributed::kafka::segment::{SegmentedLog, SEGMENT_SIZE};
+
+fn offsets(entries: &[(usize, usize)]) -> Vec<usize> {
+    entries.iter().map(|&(offset, _)| offset).collect()
+}
+
+#[test]
+fn reads_run_across_segments_up_to_the_limit() {
+    let mut log = SegmentedLog::default();
+    for msg in 0..SEGMENT_SIZE + 10 {
+        assert_eq!(log.append(msg * 2), msg);
+    }
+    let entries = log.read_from(SEGMENT_SIZE - 2, 5);
+    assert_eq!(
+        offsets(&entries),
+        (SEGMENT_SIZE - 2..SEGMENT_SIZE + 3).collect::<Vec<_>>()
+    );
+    assert_eq!(entries[0].1, (SEGMENT_SIZE - 2) * 2);
+    assert_eq!(log.read_from(SEGMENT_SIZE + 8, 100).len(), 2);
+    assert!(log.read_from(SEGMENT_SIZE + 10, 100).is_empty());
+}
+
+#[test]
+fn reads_stop_at_gaps_left_by_entries_out_of_order() {
+    let mut log = SegmentedLog::default();
+    log.insert(0, 10);
+    log.insert(2, 12);
+    log.insert(2 * SEGMENT_SIZE, 20);
+    assert_eq!(offsets(&log.read_from(0, 10)), [0]);
+    log.insert(1, 11);
+    assert_eq!(offsets(&log.read_from(0, 10)), [0, 1, 2]);
+    // Appends go after the highest offset, whatever is missing below it.
+    assert_eq!(log.append(21), 2 * SEGMENT_SIZE + 1);
+    assert_eq!(
+        offsets(&log.read_from(2 * SEGMENT_SIZE, 10)),
+        [2 * SEGMENT_SIZE, 2 * SEGMENT_SIZE + 1]
+    );
+}
+
+#[test]
+fn truncation_drops_whole_segments_only() {
+    let mut log = SegmentedLog::default();
+    for msg in 0..3 * SEGMENT_SIZE {
+        log.append(msg);
+    }
+    log.truncate(SEGMENT_SIZE + 5);
+    assert!(log.read_from(0, 1).is_empty());
+    assert_eq!(offsets(&log.read_from(SEGMENT_SIZE, 1)), [SEGMENT_SIZE]);
+
+    // Truncating everything still leaves the next offset where it was.
+    log.truncate(3 * SEGMENT_SIZE);
+    assert!(log.read_from(2 * SEGMENT_SIZE, 1).is_empty());
+    assert_eq!(log.append(0), 3 * SEGMENT_SIZE);
+}
+
+#[test]
+fn entries_arriving_below_a_truncation_are_dropped() {
+    let mut log = SegmentedLog::default();
+    log.insert(2 * SEGMENT_SIZE, 20);
+    log.truncate(SEGMENT_SIZE + 5);
+    // A replicated entry from a dropped segment arrives late.
+    assert!(!log.insert(3, 13));
+    assert!(log.read_from(3, 1).is_empty());
+    assert!(log.range(0, SEGMENT_SIZE).is_empty());
+    // The segment truncation fell in still takes entries.
+    assert!(log.insert(SEGMENT_SIZE + 1, 11));
+    assert_eq!(
+        offsets(&log.read_from(SEGMENT_SIZE + 1, 1)),
+        [SEGMENT_SIZE + 1]
+    );
+}
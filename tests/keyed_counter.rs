@@ -0,0 +1,129 @@
+//! Keyed counters keep every total while `reconfigure` moves keys between
+//! nodes, and a refused handoff holds `reconfigure_ok` back until it is sent
+//! again and acknowledged.
+
+use std::{thread, time::Duration};
+
+use fly_distributed::{
+    config::Config,
+    counter::keyed::{KeyedCounterNode, Payload},
+    main_loop_on,
+    message::RawMessage,
+    ring::Ring,
+    transport::{Endpoint, Network},
+};
+use serde_json::json;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+const KEYS: usize = 20;
+
+fn start(network: &Network, nodes: &[&str]) -> Endpoint {
+    for node in nodes {
+        let endpoint = network.join(node);
+        thread::spawn(move || {
+            main_loop_on::<KeyedCounterNode, Payload>(endpoint, Config::default())
+        });
+    }
+    let client = network.join("c0");
+    for node in nodes {
+        let init = json!({ "type": "init", "node_id": node, "node_ids": nodes });
+        assert_eq!(client.rpc(node, init, TIMEOUT).unwrap()["type"], "init_ok");
+    }
+    client
+}
+
+/// Adds `delta` to every key, through the nodes in turn.
+fn add_all(client: &Endpoint, nodes: &[&str], delta: i64) {
+    for k in 0..KEYS {
+        let add = json!({ "type": "add", "key": format!("k{k}"), "delta": delta });
+        let reply = client.rpc(nodes[k % nodes.len()], add, TIMEOUT).unwrap();
+        assert_eq!(reply["type"], "add_ok", "{reply}");
+    }
+}
+
+fn assert_totals(client: &Endpoint, nodes: &[&str], total: i64) {
+    for node in nodes {
+        for k in 0..KEYS {
+            let read = json!({ "type": "read", "key": format!("k{k}") });
+            let reply = client.rpc(node, read, TIMEOUT).unwrap();
+            assert_eq!(reply["value"], total, "k{k} on {node}: {reply}");
+        }
+    }
+}
+
+fn reconfigure(client: &Endpoint, node: &str, nodes: &[&str]) -> serde_json::Value {
+    let reconfigure = json!({ "type": "reconfigure", "nodes": nodes });
+    client.rpc(node, reconfigure, TIMEOUT).unwrap()
+}
+
+#[test]
+fn totals_survive_moving_keys_between_rings() {
+    let nodes = ["n1", "n2", "n3"];
+    let network = Network::new();
+    let client = start(&network, &nodes);
+    add_all(&client, &nodes, 1);
+
+    assert_eq!(
+        reconfigure(&client, "n1", &nodes[..2])["type"],
+        "reconfigure_ok"
+    );
+    assert_totals(&client, &nodes, 1);
+    add_all(&client, &nodes, 2);
+
+    assert_eq!(reconfigure(&client, "n3", &nodes)["type"], "reconfigure_ok");
+    assert_totals(&client, &nodes, 3);
+}
+
+/// Reads the next message to `fake`, which must be handoff batch `seq`.
+fn expect_handoff(fake: &Endpoint, seq: u64, done: bool) -> RawMessage {
+    let handoff = fake.recv_timeout(TIMEOUT).expect("no handoff");
+    let payload = &handoff.body.payload;
+    assert_eq!(payload["type"], "handoff", "{payload}");
+    assert_eq!(
+        (&payload["seq"], &payload["done"]),
+        (&json!(seq), &json!(done))
+    );
+    handoff
+}
+
+#[test]
+fn a_refused_handoff_is_sent_again_before_reconfigure_ok() {
+    let nodes = ["n1", "n2"];
+    let network = Network::new();
+    let endpoint = network.join("n2");
+    thread::spawn(move || main_loop_on::<KeyedCounterNode, Payload>(endpoint, Config::default()));
+    // Plays n1, which n2 hands its keys to.
+    let fake = network.join("n1");
+    let client = network.join("c0");
+    let init = json!({ "type": "init", "node_id": "n2", "node_ids": nodes });
+    assert_eq!(client.rpc("n2", init, TIMEOUT).unwrap()["type"], "init_ok");
+    let ids: Vec<String> = nodes.iter().map(|node| node.to_string()).collect();
+    let key = (0..)
+        .map(|k| format!("k{k}"))
+        .find(|key| Ring::new(&ids).unwrap().owner(key) == "n2")
+        .unwrap();
+    let add = json!({ "type": "add", "key": key, "delta": 5 });
+    assert_eq!(client.rpc("n2", add, TIMEOUT).unwrap()["type"], "add_ok");
+
+    let request = json!({ "type": "reconfigure", "nodes": ["n1"] });
+    let reconfigure = fake.request("n2", request).unwrap();
+    let handoff = expect_handoff(&fake, 1, false);
+    assert_eq!(handoff.body.payload["totals"], json!({ key.as_str(): 5 }));
+    let refused = json!({ "type": "error", "code": 11, "text": "busy" });
+    fake.reply(&handoff, refused).unwrap();
+
+    // The same batch comes again, and the key still reads its total meanwhile.
+    let handoff = expect_handoff(&fake, 1, false);
+    let read = json!({ "type": "read", "key": key });
+    assert_eq!(client.rpc("n2", read, TIMEOUT).unwrap()["value"], 5);
+    fake.reply(&handoff, json!({ "type": "handoff_ok" }))
+        .unwrap();
+
+    // n2 answers only once its last batch was acknowledged.
+    let last = expect_handoff(&fake, 2, true);
+    assert!(fake.recv_timeout(Duration::from_millis(200)).is_none());
+    fake.reply(&last, json!({ "type": "handoff_ok" })).unwrap();
+    let answer = fake.recv_timeout(TIMEOUT).expect("no answer");
+    assert_eq!(answer.body.in_reply_to, Some(reconfigure));
+    assert_eq!(answer.body.payload["type"], "reconfigure_ok");
+}
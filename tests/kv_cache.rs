@@ -0,0 +1,57 @@
+//! A read-through cache answers from what was put in it until it is
+//! invalidated or its ttl runs out, and counts hits and misses.
+
+use std::time::Duration;
+
+use fly_distributed::services::{
+    cache::Cache,
+    CachePolicy::{self, Off, On, Ttl},
+    CacheStats,
+};
+use serde_json::json;
+
+#[test]
+fn cached_values_are_hits_until_invalidated() {
+    let cache = Cache::new(On);
+    assert_eq!(cache.get(&json!("committed")), None);
+    cache.put(&json!("committed"), json!({"k1": 3}));
+    assert_eq!(cache.get(&json!("committed")), Some(json!({"k1": 3})));
+    cache.invalidate(&json!("committed"));
+    assert_eq!(cache.get(&json!("committed")), None);
+    assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 2 });
+    assert!((cache.stats().hit_rate() - 1.0 / 3.0).abs() < 1e-9);
+}
+
+#[test]
+fn keys_are_compared_as_json() {
+    let cache = Cache::new(On);
+    cache.put(&json!(1), json!("number"));
+    assert_eq!(cache.get(&json!("1")), None);
+    assert_eq!(cache.get(&json!(1)), Some(json!("number")));
+}
+
+#[test]
+fn entries_expire_after_the_ttl() {
+    let cache = Cache::new(Ttl(Duration::from_millis(20)));
+    cache.put(&json!("next/k1"), json!(4));
+    assert_eq!(cache.get(&json!("next/k1")), Some(json!(4)));
+    std::thread::sleep(Duration::from_millis(30));
+    assert_eq!(cache.get(&json!("next/k1")), None);
+}
+
+#[test]
+fn an_off_cache_keeps_nothing() {
+    let cache = Cache::new(Off);
+    cache.put(&json!("next/k1"), json!(4));
+    assert_eq!(cache.get(&json!("next/k1")), None);
+    assert_eq!(cache.stats().hit_rate(), 0.0);
+}
+
+#[test]
+fn policies_parse() {
+    assert_eq!("off".parse::<CachePolicy>().unwrap(), Off);
+    assert_eq!("on".parse::<CachePolicy>().unwrap(), On);
+    let ttl = "250".parse::<CachePolicy>().unwrap();
+    assert_eq!(ttl, Ttl(Duration::from_millis(250)));
+    assert!("forever".parse::<CachePolicy>().is_err());
+}
@@ -0,0 +1,85 @@
+//! In `quorum` mode a read at `r` sees every write acknowledged at `w`
+//! whenever `r + w > n`, and a write a replica missed is handed off to it
+//! once it answers again.
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::TIMEOUT;
+use fly_distributed::{
+    config::Config,
+    lin_kv::LinKvNode,
+    message::RawMessage,
+    transport::{Endpoint, Network},
+};
+use serde_json::{json, Value};
+const NODES: [&str; 3] = ["n1", "n2", "n3"];
+
+/// Starts `nodes` in quorum mode with three replicas, and inits them as
+/// [`NODES`].
+fn start(network: &Network, nodes: &[&str]) -> Endpoint {
+    let config = Config::default().with("kv-mode", "quorum");
+    let client = network.join("c1");
+    common::start::<LinKvNode, _>(network, &client, nodes, &NODES, config);
+    client
+}
+
+fn write(client: &Endpoint, node: &str, value: i64) -> Value {
+    let write = json!({ "type": "write", "key": "x", "value": value });
+    client.rpc(node, write, TIMEOUT).unwrap()
+}
+
+fn read(client: &Endpoint, node: &str, r: usize) -> Value {
+    let read = json!({ "type": "read", "key": "x", "r": r });
+    client.rpc(node, read, TIMEOUT).unwrap()
+}
+
+/// Reads messages to `fake` until one of type `kind`, skipping the
+/// anti-entropy exchanges and every other request.
+fn expect(fake: &Endpoint, kind: &str) -> RawMessage {
+    loop {
+        let message = fake.recv_timeout(TIMEOUT).expect("no message");
+        if message.body.payload["type"] == kind {
+            return message;
+        }
+    }
+}
+
+#[test]
+fn a_read_quorum_sees_the_latest_write() {
+    let network = Network::new();
+    let client = start(&network, &NODES);
+    assert_eq!(write(&client, "n1", 1)["type"], "write_ok");
+
+    // n3 misses the second write, yet any two replicas include one that
+    // took it.
+    network.cut("n1", "n3");
+    network.cut("n2", "n3");
+    assert_eq!(write(&client, "n1", 2)["type"], "write_ok");
+    network.heal();
+    assert_eq!(read(&client, "n3", 2)["value"], 2);
+    assert_eq!(read(&client, "n3", 3)["value"], 2);
+}
+
+#[test]
+fn a_missed_write_is_handed_off_once_the_replica_answers() {
+    let network = Network::new();
+    let client = start(&network, &NODES[..2]);
+    // Plays n3, which misses the write.
+    let fake = network.join("n3");
+    assert_eq!(write(&client, "n1", 1)["type"], "write_ok");
+    let put = expect(&fake, "put");
+    assert_eq!(put.body.payload["versioned"]["value"], 1);
+
+    // n1 holds the write for n3 and offers it until n3 takes it.
+    let handoff = expect(&fake, "handoff");
+    assert_eq!(handoff.body.payload["hints"]["\"x\""]["value"], 1);
+    let handoff = expect(&fake, "handoff");
+    fake.reply(&handoff, json!({ "type": "handoff_ok" }))
+        .unwrap();
+    let quiet = Instant::now() + Duration::from_millis(1500);
+    while let Some(message) = fake.recv_timeout(quiet.saturating_duration_since(Instant::now())) {
+        assert_ne!(message.body.payload["type"], "handoff");
+    }
+}
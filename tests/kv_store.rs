@@ -0,0 +1,140 @@
+//! Keys written with an expiry read as missing once it passed, every copy
+//! applying the same commands evicts the same keys, and scans list keys in
+//! order; writes conditional on a key's version fail once it moved on.
+
+use fly_distributed::{
+    consensus::StateMachine,
+    crdt::Snapshot,
+    lin_kv::store::{KvCommand, KvStore},
+    message::error_code,
+};
+use serde_json::{json, Value};
+
+fn write(key: &str, value: i64, expires: Option<u64>, at: u64) -> KvCommand {
+    KvCommand::Write {
+        key: json!(key),
+        value: json!(value),
+        expires,
+        at,
+    }
+}
+
+fn read(store: &KvStore, key: &str, now: u64) -> Result<Value, usize> {
+    let read = store.read(&json!(key), now);
+    read.map(|(value, _)| value).map_err(|(code, _)| code)
+}
+
+#[test]
+fn an_expired_key_reads_as_missing() {
+    let mut store = KvStore::default();
+    store.apply(write("a", 1, Some(100), 0)).unwrap();
+    store.apply(write("b", 2, None, 0)).unwrap();
+    assert_eq!(read(&store, "a", 99), Ok(json!(1)));
+    assert_eq!(read(&store, "a", 100), Err(error_code::KEY_DOES_NOT_EXIST));
+    assert_eq!(read(&store, "b", u64::MAX), Ok(json!(2)));
+    // Writing again without one clears the expiry.
+    store.apply(write("a", 3, None, 50)).unwrap();
+    assert!(!store.due(u64::MAX));
+    assert_eq!(read(&store, "a", 1000), Ok(json!(3)));
+}
+
+#[test]
+fn commands_sweep_at_the_time_they_carry() {
+    let mut store = KvStore::default();
+    store.apply(write("a", 1, Some(100), 0)).unwrap();
+    let cas = |at| KvCommand::Cas {
+        key: json!("a"),
+        from: json!(1),
+        to: json!(2),
+        create_if_not_exists: false,
+        at,
+    };
+    // A cas keeps the expiry, and fails once the key is gone.
+    store.apply(cas(50)).unwrap();
+    assert!(store.due(100));
+    let failed = store.apply(cas(100)).unwrap_err();
+    assert_eq!(failed.0, error_code::KEY_DOES_NOT_EXIST);
+    assert!(!store.due(u64::MAX));
+
+    store.apply(write("b", 1, Some(200), 0)).unwrap();
+    store.apply(KvCommand::Sweep { at: 199 }).unwrap();
+    assert!(store.due(200));
+    store.apply(KvCommand::Sweep { at: 200 }).unwrap();
+    assert!(store.snapshot().values.is_empty());
+}
+
+#[test]
+fn expiries_survive_a_snapshot() {
+    let mut store = KvStore::default();
+    store.apply(write("a", 1, Some(100), 0)).unwrap();
+    store.apply(write("b", 2, None, 0)).unwrap();
+    let json = serde_json::to_string(&store.snapshot()).unwrap();
+    let mut restored = KvStore::restore(serde_json::from_str(&json).unwrap());
+    assert_eq!(restored.sweep(100), 1);
+    assert_eq!(read(&restored, "a", 0), Err(error_code::KEY_DOES_NOT_EXIST));
+    assert_eq!(read(&restored, "b", 0), Ok(json!(2)));
+}
+
+#[test]
+fn scans_list_a_range_in_key_order() {
+    let mut store = KvStore::default();
+    store.apply(write("x", 0, None, 0)).unwrap();
+    for key in [10, 9, 2, 100, 1] {
+        store.write(&json!(key), json!(key * 2), None);
+    }
+    store.write(&json!(3), json!(6), Some(50));
+    let keys = |pairs: Vec<(Value, Value)>| -> Vec<Value> {
+        pairs.into_iter().map(|(key, _)| key).collect()
+    };
+    let all = store.scan(None, None, None, 0);
+    assert_eq!(all[0], (json!(1), json!(2)));
+    // Numbers before strings, and in numeric order.
+    assert_eq!(
+        keys(all),
+        [
+            json!(1),
+            json!(2),
+            json!(3),
+            json!(9),
+            json!(10),
+            json!(100),
+            json!("x")
+        ]
+    );
+    let range = store.scan(Some(&json!(2)), Some(&json!(100)), None, 50);
+    assert_eq!(keys(range), [json!(2), json!(9), json!(10)]);
+    let limited = store.scan(Some(&json!(5)), None, Some(2), 0);
+    assert_eq!(keys(limited), [json!(9), json!(10)]);
+    assert!(store
+        .scan(Some(&json!(9)), Some(&json!(2)), None, 0)
+        .is_empty());
+}
+
+#[test]
+fn writes_bump_versions_and_stale_ones_are_refused() {
+    let mut store = KvStore::default();
+    let write_if = |version, value| KvCommand::WriteIfVersion {
+        key: json!("a"),
+        value: json!(value),
+        version,
+        expires: None,
+        at: 0,
+    };
+    let stale = store.apply(write_if(1, 1)).unwrap_err();
+    assert_eq!(stale.0, error_code::PRECONDITION_FAILED);
+    assert_eq!(store.apply(write_if(0, 1)), Ok(1));
+    assert_eq!(store.apply(write("a", 2, None, 0)), Ok(2));
+    assert_eq!(store.cas(&json!("a"), &json!(2), json!(3), false), Ok(3));
+    let stale = store.apply(write_if(2, 4)).unwrap_err();
+    assert_eq!(stale.0, error_code::PRECONDITION_FAILED);
+    assert_eq!(store.apply(write_if(3, 4)), Ok(4));
+    assert_eq!(store.read(&json!("a"), 0), Ok((json!(4), 4)));
+
+    // Versions survive a snapshot, and start over once a key expired.
+    store.apply(write("b", 1, Some(10), 0)).unwrap();
+    let json = serde_json::to_string(&store.snapshot()).unwrap();
+    let mut restored = KvStore::restore(serde_json::from_str(&json).unwrap());
+    assert_eq!(restored.read(&json!("a"), 0), Ok((json!(4), 4)));
+    restored.sweep(10);
+    assert_eq!(restored.write(&json!("b"), json!(2), None), 1);
+}
@@ -0,0 +1,132 @@
+//! `Kv::update` keeps every change racing writers make to a key, and
+//! `Kv::try_update` writes nothing when its function refuses the value.
+
+mod common;
+
+use std::thread;
+
+use common::TIMEOUT;
+use fly_distributed::{
+    config::Config,
+    lin_kv::LinKvNode,
+    message::{error_code, Init},
+    runtime::RpcError,
+    services::Kv,
+    transport::{Endpoint, Network},
+    Message, Node, Runtime,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    /// Adds `delta` to the counter, unless that takes it past `limit`.
+    Add {
+        delta: u64,
+        #[serde(default)]
+        limit: Option<u64>,
+    },
+    AddOk {
+        value: u64,
+    },
+    Read,
+    ReadOk {
+        value: u64,
+    },
+}
+
+struct CounterNode {
+    runtime: Runtime,
+    kv: Kv,
+}
+
+impl Node<Payload> for CounterNode {
+    fn from_init(runtime: Runtime, _init: Init) -> anyhow::Result<Self> {
+        Ok(Self {
+            kv: Kv::new(runtime.clone(), "lin-kv"),
+            runtime,
+        })
+    }
+
+    fn step(&mut self, input: Message<Payload>) -> anyhow::Result<()> {
+        let (runtime, kv) = (self.runtime.clone(), self.kv.clone());
+        thread::spawn(move || {
+            let result = match input.body.payload {
+                Payload::Add { delta, limit: None } => kv
+                    .update("counter", |current: &u64| current + delta)
+                    .map(|value| Payload::AddOk { value }),
+                Payload::Add {
+                    delta,
+                    limit: Some(limit),
+                } => kv
+                    .try_update("counter", |current: &u64| match current + delta {
+                        value if value > limit => Err(RpcError::Remote {
+                            code: error_code::PRECONDITION_FAILED,
+                            text: format!("{value} is past {limit}"),
+                        }),
+                        value => Ok(value),
+                    })
+                    .map(|value| Payload::AddOk { value }),
+                Payload::Read => kv
+                    .update("counter", |current: &u64| *current)
+                    .map(|value| Payload::ReadOk { value }),
+                _ => return,
+            };
+            let _ = match result {
+                Ok(reply) => runtime.reply(&input, reply),
+                Err(RpcError::Remote { code, text }) => runtime.reply_error(&input, code, text),
+                Err(err) => runtime.reply_error(&input, error_code::TIMEOUT, err.to_string()),
+            };
+        });
+        Ok(())
+    }
+}
+
+fn start(network: &Network, nodes: &[&str]) -> Endpoint {
+    let client = network.join("c0");
+    let lin_kv = ["lin-kv"];
+    common::start::<LinKvNode, _>(network, &client, &lin_kv, &lin_kv, Config::default());
+    common::start::<CounterNode, _>(network, &client, nodes, nodes, Config::default());
+    client
+}
+
+#[test]
+fn racing_updates_keep_every_change() {
+    let nodes = ["n1", "n2", "n3"];
+    let network = Network::new();
+    let client = start(&network, &nodes);
+
+    let clients: Vec<_> = (0..6)
+        .map(|c| {
+            let client = network.join(&format!("c{}", c + 1));
+            let node = nodes[c % nodes.len()];
+            thread::spawn(move || {
+                for _ in 0..10 {
+                    let add = json!({ "type": "add", "delta": 1 });
+                    let reply = client.rpc(node, add, TIMEOUT).unwrap();
+                    assert_eq!(reply["type"], "add_ok", "{reply}");
+                }
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+    let reply = client.rpc("n1", json!({ "type": "read" }), TIMEOUT);
+    assert_eq!(reply.unwrap()["value"], 60);
+}
+
+#[test]
+fn a_refused_update_writes_nothing() {
+    let network = Network::new();
+    let client = start(&network, &["n1"]);
+
+    let add = json!({ "type": "add", "delta": 3, "limit": 5 });
+    assert_eq!(client.rpc("n1", add.clone(), TIMEOUT).unwrap()["value"], 3);
+    let reply = client.rpc("n1", add, TIMEOUT).unwrap();
+    assert_eq!(reply["code"], error_code::PRECONDITION_FAILED, "{reply}");
+    let reply = client.rpc("n1", json!({ "type": "read" }), TIMEOUT);
+    assert_eq!(reply.unwrap()["value"], 3);
+}
@@ -0,0 +1,118 @@
+//! A lock's value only takes writes fenced by the current grant's token:
+//! stale and future tokens are refused, and holders taking turns never lose
+//! each other's writes.
+
+mod common;
+
+use std::{thread, time::Duration};
+
+use common::TIMEOUT;
+use fly_distributed::{
+    config::Config,
+    lin_kv::LinKvNode,
+    lock::LockNode,
+    transport::{Endpoint, Network},
+};
+use serde_json::{json, Value};
+
+/// Starts a `lin-kv` service and lock nodes `nodes` with grants lasting
+/// `lease`.
+fn start(network: &Network, nodes: &[&str], lease: Duration) -> Endpoint {
+    let client = network.join("c0");
+    let lin_kv = ["lin-kv"];
+    common::start::<LinKvNode, _>(network, &client, &lin_kv, &lin_kv, Config::default());
+    let config = Config::default().with("lock-lease", lease.as_millis());
+    common::start::<LockNode, _>(network, &client, nodes, nodes, config);
+    client
+}
+
+fn lock(client: &Endpoint, node: &str) -> Value {
+    client
+        .rpc(node, json!({ "type": "lock", "key": "x" }), TIMEOUT)
+        .unwrap()
+}
+
+fn write(client: &Endpoint, node: &str, value: Value, token: &Value) -> Value {
+    let write = json!({ "type": "write", "key": "x", "value": value, "token": token });
+    client.rpc(node, write, TIMEOUT).unwrap()
+}
+
+fn read(client: &Endpoint, node: &str) -> Value {
+    client
+        .rpc(node, json!({ "type": "read", "key": "x" }), TIMEOUT)
+        .unwrap()
+}
+
+#[test]
+fn stale_and_future_tokens_are_refused() {
+    let network = Network::new();
+    let c1 = start(&network, &["n1", "n2"], Duration::from_millis(100));
+    let c2 = network.join("c2");
+
+    let first = lock(&c1, "n1")["token"].clone();
+    assert_eq!(write(&c1, "n1", json!(1), &first)["type"], "write_ok");
+
+    // c1 pauses past its lease and c2 takes the lock over.
+    thread::sleep(Duration::from_millis(200));
+    let second = lock(&c2, "n2")["token"].clone();
+    assert_eq!(second, first.as_u64().unwrap() + 1);
+    assert_eq!(write(&c1, "n1", json!(2), &first)["code"], 22);
+    let future = json!(second.as_u64().unwrap() + 1);
+    assert_eq!(write(&c1, "n2", json!(3), &future)["code"], 22);
+    assert_eq!(read(&c1, "n2")["value"], 1);
+
+    assert_eq!(write(&c2, "n2", json!(4), &second)["type"], "write_ok");
+    assert_eq!(read(&c1, "n1")["value"], 4);
+
+    // Once unlocked, not even the last token writes.
+    let unlock = json!({ "type": "unlock", "key": "x", "token": second });
+    assert_eq!(c2.rpc("n1", unlock, TIMEOUT).unwrap()["type"], "unlock_ok");
+    assert_eq!(write(&c2, "n1", json!(5), &second)["code"], 22);
+    assert_eq!(read(&c2, "n1")["value"], 4);
+}
+
+#[test]
+fn holders_taking_turns_keep_every_write() {
+    let nodes = ["n1", "n2", "n3"];
+    let network = Network::new();
+    let client = start(&network, &nodes, Duration::from_secs(5));
+    let first = lock(&client, "n1")["token"].clone();
+    assert_eq!(write(&client, "n1", json!(0), &first)["type"], "write_ok");
+    let unlock = json!({ "type": "unlock", "key": "x", "token": first });
+    assert_eq!(
+        client.rpc("n1", unlock, TIMEOUT).unwrap()["type"],
+        "unlock_ok"
+    );
+
+    // Every client increments the value under the lock, so a write that
+    // slipped past the fence would lose an increment.
+    let clients: Vec<_> = (0..4)
+        .map(|c| {
+            let client = network.join(&format!("c{}", c + 1));
+            let node = nodes[c % nodes.len()];
+            thread::spawn(move || {
+                for _ in 0..5 {
+                    let token = loop {
+                        let reply = lock(&client, node);
+                        if reply["type"] == "lock_ok" {
+                            break reply["token"].clone();
+                        }
+                        thread::sleep(Duration::from_millis(5));
+                    };
+                    let value = read(&client, node)["value"].as_u64().unwrap();
+                    let reply = write(&client, node, json!(value + 1), &token);
+                    assert_eq!(reply["type"], "write_ok", "{reply}");
+                    let unlock = json!({ "type": "unlock", "key": "x", "token": token });
+                    assert_eq!(
+                        client.rpc(node, unlock, TIMEOUT).unwrap()["type"],
+                        "unlock_ok"
+                    );
+                }
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+    assert_eq!(read(&client, "n2")["value"], 20);
+}
@@ -0,0 +1,31 @@
+//! Merkle trees locate differing keys by walking down from the root.
+
+use fly_distributed::merkle::{leaf_of, MerkleTree, DEPTH};
+
+#[test]
+fn trees_differ_only_along_the_path_to_a_changed_key() {
+    let keys: Vec<String> = (0..200).map(|i| format!("key-{i}")).collect();
+    let ours = MerkleTree::build(keys.iter().map(|key| (key.as_str(), 1)));
+    let same = MerkleTree::build(keys.iter().rev().map(|key| (key.as_str(), 1)));
+    assert_eq!(ours, same);
+
+    let changed = "key-42";
+    let theirs = MerkleTree::build(
+        keys.iter()
+            .map(|key| (key.as_str(), if key == changed { 2 } else { 1 })),
+    );
+    let mut index = 0;
+    for level in 0..=DEPTH {
+        assert_ne!(ours.hash(level, index), theirs.hash(level, index));
+        if level < DEPTH {
+            let [left, right] = MerkleTree::children(index);
+            let differ: Vec<usize> = [left, right]
+                .into_iter()
+                .filter(|&child| ours.hash(level + 1, child) != theirs.hash(level + 1, child))
+                .collect();
+            assert_eq!(differ.len(), 1);
+            index = differ[0];
+        }
+    }
+    assert_eq!(index, leaf_of(changed));
+}
@@ -0,0 +1,239 @@
+//! Multi-Paxos servers wired together in memory, with a simulated clock
+//! and partitions, decide the same command in every slot.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::{Duration, Instant},
+};
+
+use fly_distributed::paxos::{Decree, Paxos};
+
+struct Cluster {
+    servers: BTreeMap<String, Paxos<u64>>,
+    /// Commands each server applied, in order.
+    applied: BTreeMap<String, Vec<u64>>,
+    /// Every decree any server learned, by slot.
+    decided: BTreeMap<u64, Decree<u64>>,
+    /// Reads each server found ready to serve.
+    reads: BTreeMap<String, Vec<u64>>,
+    /// Servers cut off from all the others.
+    isolated: BTreeSet<String>,
+    now: Instant,
+}
+
+impl Cluster {
+    fn new(size: usize) -> Self {
+        let ids: Vec<String> = (1..=size).map(|n| format!("n{n}")).collect();
+        let now = Instant::now();
+        Self {
+            servers: ids
+                .iter()
+                .map(|id| (id.clone(), Paxos::new(id, &ids, now)))
+                .collect(),
+            applied: ids.iter().map(|id| (id.clone(), Vec::new())).collect(),
+            decided: BTreeMap::new(),
+            reads: ids.iter().map(|id| (id.clone(), Vec::new())).collect(),
+            isolated: BTreeSet::new(),
+            now,
+        }
+    }
+
+    /// Advances the clock by `duration`, 10ms at a time, delivering every
+    /// message sent along the way.
+    fn run(&mut self, duration: Duration) {
+        let end = self.now + duration;
+        while self.now < end {
+            self.now += Duration::from_millis(10);
+            for paxos in self.servers.values_mut() {
+                paxos.tick(self.now);
+            }
+            self.deliver();
+        }
+    }
+
+    fn deliver(&mut self) {
+        loop {
+            let mut messages = Vec::new();
+            for (id, paxos) in &mut self.servers {
+                for (to, message) in paxos.take_outbox() {
+                    messages.push((id.clone(), to, message));
+                }
+            }
+            if messages.is_empty() {
+                break;
+            }
+            for (from, to, message) in messages {
+                if self.isolated.contains(&from) || self.isolated.contains(&to) {
+                    continue;
+                }
+                self.servers
+                    .get_mut(&to)
+                    .unwrap()
+                    .handle(&from, message, self.now);
+            }
+        }
+        for (id, paxos) in &mut self.servers {
+            for (slot, decree) in paxos.take_decided() {
+                // No two servers ever learn different decrees for a slot.
+                let first = self.decided.entry(slot).or_insert(decree.clone());
+                assert_eq!(*first, decree, "slot {slot} decided twice");
+                if let Decree::Apply { command, .. } = decree {
+                    self.applied.get_mut(id).unwrap().push(command);
+                }
+            }
+            self.reads.get_mut(id).unwrap().extend(paxos.take_reads());
+        }
+    }
+
+    fn leaders(&self) -> Vec<String> {
+        self.servers
+            .iter()
+            .filter(|(id, paxos)| paxos.is_leader() && !self.isolated.contains(*id))
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    fn leader(&self) -> String {
+        let leaders = self.leaders();
+        assert_eq!(leaders.len(), 1, "expected one leader, got {leaders:?}");
+        leaders[0].clone()
+    }
+
+    fn propose(&mut self, on: &str, command: u64) -> Option<(u64, u64)> {
+        self.servers.get_mut(on).unwrap().propose(command)
+    }
+}
+
+#[test]
+fn a_leader_is_elected_and_stays() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(Duration::from_secs(2));
+    let leader = cluster.leader();
+    let ballot = cluster.servers[&leader].ballot().clone();
+    cluster.run(Duration::from_secs(2));
+    assert_eq!(cluster.leader(), leader);
+    assert_eq!(*cluster.servers[&leader].ballot(), ballot);
+}
+
+#[test]
+fn commands_are_decided_in_the_same_order_everywhere() {
+    let mut cluster = Cluster::new(5);
+    cluster.run(Duration::from_secs(2));
+    let leader = cluster.leader();
+    for command in 1..=20 {
+        assert!(cluster.propose(&leader, command).is_some());
+    }
+    let follower = cluster.servers.keys().find(|&id| *id != leader).unwrap();
+    assert_eq!(cluster.propose(&follower.clone(), 99), None);
+    cluster.run(Duration::from_millis(500));
+    let expected: Vec<u64> = (1..=20).collect();
+    for (id, applied) in &cluster.applied {
+        assert_eq!(applied, &expected, "{id}");
+    }
+}
+
+#[test]
+fn a_leader_without_a_majority_decides_nothing() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(Duration::from_secs(2));
+    let old = cluster.leader();
+    cluster.isolated.insert(old.clone());
+    cluster.propose(&old, 1);
+    cluster.run(Duration::from_secs(2));
+    assert!(cluster.applied[&old].is_empty());
+
+    // The others carry on under a higher ballot.
+    let new = cluster.leader();
+    assert_ne!(new, old);
+    assert!(cluster.servers[&new].ballot() > cluster.servers[&old].ballot());
+    cluster.propose(&new, 2);
+    cluster.run(Duration::from_millis(500));
+
+    // Healed, the old leader follows, and its command never decides.
+    cluster.isolated.clear();
+    cluster.run(Duration::from_secs(1));
+    assert_eq!(cluster.leader(), new);
+    for (id, applied) in &cluster.applied {
+        assert_eq!(applied, &vec![2], "{id}");
+    }
+}
+
+#[test]
+fn a_new_leader_decides_what_a_majority_accepted() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(Duration::from_secs(2));
+    let old = cluster.leader();
+    // Accepted by a majority, but the leader dies before telling anyone
+    // it was chosen.
+    cluster.propose(&old, 7);
+    let mut accepts = cluster.servers.get_mut(&old).unwrap().take_outbox();
+    let (to, accept) = accepts.remove(0);
+    cluster
+        .servers
+        .get_mut(&to)
+        .unwrap()
+        .handle(&old, accept, cluster.now);
+    cluster.servers.get_mut(&to).unwrap().take_outbox();
+    cluster.isolated.insert(old.clone());
+
+    cluster.run(Duration::from_secs(2));
+    let new = cluster.leader();
+    assert_ne!(new, old);
+    for (id, applied) in &cluster.applied {
+        if *id != old {
+            assert_eq!(applied, &vec![7], "{id}");
+        }
+    }
+}
+
+#[test]
+fn a_server_that_missed_decisions_catches_up() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(Duration::from_secs(2));
+    let leader = cluster.leader();
+    let behind = cluster
+        .servers
+        .keys()
+        .find(|&id| *id != leader)
+        .unwrap()
+        .clone();
+    cluster.isolated.insert(behind.clone());
+    for command in 1..=250 {
+        cluster.propose(&leader, command);
+    }
+    cluster.run(Duration::from_millis(200));
+    assert!(cluster.applied[&behind].is_empty());
+
+    cluster.isolated.clear();
+    cluster.run(Duration::from_secs(2));
+    assert_eq!(cluster.leader(), leader);
+    let expected: Vec<u64> = (1..=250).collect();
+    assert_eq!(cluster.applied[&behind], expected);
+}
+
+#[test]
+fn reads_wait_for_a_majority_to_confirm_the_leader() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(Duration::from_secs(2));
+    let leader = cluster.leader();
+    let read = cluster.servers.get_mut(&leader).unwrap().read_index();
+    let read = read.unwrap();
+    cluster.deliver();
+    assert_eq!(cluster.reads[&leader], vec![read]);
+
+    cluster.isolated.insert(leader.clone());
+    let stale = cluster.servers.get_mut(&leader).unwrap().read_index();
+    cluster.run(Duration::from_secs(1));
+    assert!(!cluster.reads[&leader].contains(&stale.unwrap()));
+}
+
+#[test]
+fn a_lone_server_decides_on_its_own() {
+    let mut cluster = Cluster::new(1);
+    cluster.run(Duration::from_secs(1));
+    cluster.propose("n1", 1);
+    let read = cluster.servers.get_mut("n1").unwrap().read_index();
+    cluster.deliver();
+    assert_eq!(cluster.applied["n1"], vec![1]);
+    assert_eq!(cluster.reads["n1"], vec![read.unwrap()]);
+}
@@ -0,0 +1,80 @@
+//! A subscriber hears every message published to its topic through any
+//! node, once each, and nothing published to other topics, including what
+//! a node publishes after it restarted.
+
+mod common;
+
+use std::{collections::BTreeSet, time::Duration};
+
+use common::TIMEOUT;
+use fly_distributed::{
+    config::Config,
+    pubsub::PubSubNode,
+    transport::{Endpoint, Network},
+};
+use serde_json::json;
+const NODES: [&str; 2] = ["n1", "n2"];
+
+fn start(network: &Network, client: &Endpoint, node: &str) {
+    common::start::<PubSubNode, _>(network, client, &[node], &NODES, Config::default());
+}
+
+fn publish(client: &Endpoint, node: &str, msg: u64) {
+    let publish = json!({ "type": "publish", "topic": "t", "msg": msg });
+    let reply = client.rpc(node, publish, TIMEOUT).unwrap();
+    assert_eq!(reply["type"], "publish_ok");
+}
+
+fn subscribe(network: &Network, node: &str) -> Endpoint {
+    let subscriber = network.join("c2");
+    let subscribe = json!({ "type": "subscribe", "topic": "t" });
+    let reply = subscriber.rpc(node, subscribe, TIMEOUT).unwrap();
+    assert_eq!(reply["type"], "subscribe_ok");
+    subscriber
+}
+
+#[test]
+fn subscribers_hear_every_message_once() {
+    let network = Network::new();
+    let publisher = network.join("c1");
+    for node in NODES {
+        start(&network, &publisher, node);
+    }
+    let subscriber = subscribe(&network, "n1");
+
+    for (i, node) in ["n1", "n2", "n2"].iter().enumerate() {
+        publish(&publisher, node, i as u64);
+    }
+    let publish = json!({ "type": "publish", "topic": "other", "msg": 9 });
+    publisher.rpc("n2", publish, TIMEOUT).unwrap();
+
+    let mut heard = BTreeSet::new();
+    for _ in 0..3 {
+        let deliver = subscriber.recv_timeout(TIMEOUT).expect("no delivery");
+        assert_eq!(deliver.body.payload["topic"], "t");
+        assert!(heard.insert(deliver.body.payload["msg"].as_u64().unwrap()));
+    }
+    assert_eq!(heard, BTreeSet::from([0, 1, 2]));
+    assert!(subscriber
+        .recv_timeout(Duration::from_millis(600))
+        .is_none());
+}
+
+#[test]
+fn a_restarted_publisher_does_not_reuse_message_ids() {
+    let network = Network::new();
+    let publisher = network.join("c1");
+    for node in NODES {
+        start(&network, &publisher, node);
+    }
+    let subscriber = subscribe(&network, "n2");
+    publish(&publisher, "n1", 0);
+    let deliver = subscriber.recv_timeout(TIMEOUT).expect("no delivery");
+    assert_eq!(deliver.body.payload["msg"], 0);
+
+    network.leave("n1");
+    start(&network, &publisher, "n1");
+    publish(&publisher, "n1", 1);
+    let deliver = subscriber.recv_timeout(TIMEOUT).expect("no delivery");
+    assert_eq!(deliver.body.payload["msg"], 1);
+}
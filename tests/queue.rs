@@ -0,0 +1,83 @@
+//! The queue hands each message to one consumer at a time: a claim only
+//! its holder can ack, and one left unacked past the visibility timeout
+//! lapses so another consumer gets the message.
+
+mod common;
+
+use std::{collections::BTreeSet, thread, time::Duration};
+
+use common::TIMEOUT;
+use fly_distributed::{
+    config::Config,
+    lin_kv::LinKvNode,
+    queue::QueueNode,
+    transport::{Endpoint, Network},
+};
+use serde_json::{json, Value};
+const NODES: [&str; 2] = ["n1", "n2"];
+
+/// Starts a `lin-kv` service and queue nodes whose claims last `visibility`.
+fn start(network: &Network, visibility: Duration) -> Endpoint {
+    let client = network.join("c0");
+    let lin_kv = ["lin-kv"];
+    common::start::<LinKvNode, _>(network, &client, &lin_kv, &lin_kv, Config::default());
+    let config = Config::default().with("queue-visibility", visibility.as_millis());
+    common::start::<QueueNode, _>(network, &client, &NODES, &NODES, config);
+    client
+}
+
+fn enqueue(client: &Endpoint, node: &str, msg: Value) -> Value {
+    let enqueue = json!({ "type": "enqueue", "msg": msg });
+    client.rpc(node, enqueue, TIMEOUT).unwrap()
+}
+
+fn dequeue(client: &Endpoint, node: &str) -> Value {
+    client
+        .rpc(node, json!({ "type": "dequeue" }), TIMEOUT)
+        .unwrap()
+}
+
+fn ack(client: &Endpoint, node: &str, id: &Value) -> Value {
+    let ack = json!({ "type": "ack", "id": id });
+    client.rpc(node, ack, TIMEOUT).unwrap()
+}
+
+#[test]
+fn every_message_goes_to_one_consumer() {
+    let network = Network::new();
+    let c1 = start(&network, Duration::from_secs(5));
+    let c2 = network.join("c2");
+    for (i, node) in ["n1", "n2", "n1"].iter().enumerate() {
+        assert_eq!(enqueue(&c1, node, json!(i))["type"], "enqueue_ok");
+    }
+
+    let mut seen = BTreeSet::new();
+    for (client, node) in [(&c1, "n1"), (&c2, "n2"), (&c1, "n2")] {
+        let reply = dequeue(client, node);
+        assert_eq!(reply["type"], "dequeue_ok", "{reply}");
+        assert!(seen.insert(reply["msg"].as_u64().unwrap()));
+        assert_eq!(ack(client, node, &reply["id"])["type"], "ack_ok");
+    }
+    assert_eq!(seen, BTreeSet::from([0, 1, 2]));
+    assert_eq!(dequeue(&c2, "n1")["code"], 20);
+}
+
+#[test]
+fn an_unacked_claim_lapses_to_another_consumer() {
+    let network = Network::new();
+    let c1 = start(&network, Duration::from_millis(100));
+    let c2 = network.join("c2");
+    enqueue(&c1, "n1", json!("x"));
+
+    let claimed = dequeue(&c1, "n1");
+    assert_eq!(claimed["msg"], "x");
+    // Held by c1, it is neither handed out again nor acked by c2.
+    assert_eq!(dequeue(&c2, "n2")["code"], 20);
+    assert_eq!(ack(&c2, "n2", &claimed["id"])["code"], 22);
+
+    thread::sleep(Duration::from_millis(200));
+    let reclaimed = dequeue(&c2, "n2");
+    assert_eq!(reclaimed["id"], claimed["id"]);
+    assert_eq!(ack(&c1, "n1", &claimed["id"])["code"], 22);
+    assert_eq!(ack(&c2, "n2", &claimed["id"])["type"], "ack_ok");
+}
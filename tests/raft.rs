@@ -0,0 +1,584 @@
+//! Raft servers wired together in memory, with a simulated clock and
+//! partitions, elect one leader and agree on the committed log.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::{Duration, Instant},
+};
+
+use fly_distributed::raft::{Command, Entry, Raft, RaftMessage, Timing};
+
+struct Cluster {
+    servers: BTreeMap<String, Raft<u64>>,
+    /// Commands each server applied, in order; a snapshot is the JSON of
+    /// these.
+    applied: BTreeMap<String, Vec<u64>>,
+    /// Reads each server found ready to serve.
+    reads: BTreeMap<String, Vec<u64>>,
+    /// Servers cut off from all the others.
+    isolated: BTreeSet<String>,
+    leaders_by_term: BTreeMap<u64, String>,
+    now: Instant,
+}
+
+impl Cluster {
+    fn new(size: usize) -> Self {
+        Self::with(size, |raft| raft)
+    }
+
+    /// A cluster of servers set up by `configure`.
+    fn with(size: usize, configure: impl Fn(Raft<u64>) -> Raft<u64>) -> Self {
+        let ids: Vec<String> = (1..=size).map(|n| format!("n{n}")).collect();
+        let now = Instant::now();
+        Self {
+            servers: ids
+                .iter()
+                .map(|id| (id.clone(), configure(Raft::new(id, &ids, now))))
+                .collect(),
+            applied: ids.iter().map(|id| (id.clone(), Vec::new())).collect(),
+            reads: ids.iter().map(|id| (id.clone(), Vec::new())).collect(),
+            isolated: BTreeSet::new(),
+            leaders_by_term: BTreeMap::new(),
+            now,
+        }
+    }
+
+    /// Advances the clock by `duration`, 10ms at a time, delivering every
+    /// message sent along the way.
+    fn run(&mut self, duration: Duration) {
+        let end = self.now + duration;
+        while self.now < end {
+            self.now += Duration::from_millis(10);
+            for raft in self.servers.values_mut() {
+                raft.tick(self.now);
+            }
+            self.deliver();
+            self.check_election_safety();
+        }
+    }
+
+    /// At most one server ever leads in a term.
+    fn check_election_safety(&mut self) {
+        for (id, raft) in &self.servers {
+            if raft.is_leader() {
+                let leader = self
+                    .leaders_by_term
+                    .entry(raft.term())
+                    .or_insert(id.clone());
+                assert_eq!(leader, id, "two leaders in term {}", raft.term());
+            }
+        }
+    }
+
+    fn deliver(&mut self) {
+        loop {
+            let mut messages = Vec::new();
+            for (id, raft) in &mut self.servers {
+                for (to, message) in raft.take_outbox() {
+                    messages.push((id.clone(), to, message));
+                }
+            }
+            if messages.is_empty() {
+                break;
+            }
+            for (from, to, message) in messages {
+                if self.isolated.contains(&from) || self.isolated.contains(&to) {
+                    continue;
+                }
+                self.servers
+                    .get_mut(&to)
+                    .unwrap()
+                    .handle(&from, message, self.now);
+            }
+        }
+        for (id, raft) in &mut self.servers {
+            if let Some(snapshot) = raft.take_installed() {
+                self.applied
+                    .insert(id.clone(), serde_json::from_str(&snapshot.data).unwrap());
+            }
+            for (_, entry) in raft.take_committed() {
+                if let Command::Apply { command } = entry.command {
+                    self.applied.get_mut(id).unwrap().push(command);
+                }
+            }
+            self.reads.get_mut(id).unwrap().extend(raft.take_reads());
+        }
+    }
+
+    fn leaders(&self) -> Vec<String> {
+        self.servers
+            .iter()
+            .filter(|(id, raft)| raft.is_leader() && !self.isolated.contains(*id))
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    fn leader(&self) -> String {
+        let leaders = self.leaders();
+        assert_eq!(leaders.len(), 1, "expected one leader, got {leaders:?}");
+        leaders[0].clone()
+    }
+
+    /// Has every server snapshot what it applied and compact its log.
+    fn compact(&mut self) {
+        for (id, raft) in &mut self.servers {
+            let data = serde_json::to_string(&self.applied[id]).unwrap();
+            raft.compact(raft.commit_index(), data);
+        }
+    }
+
+    fn reconfigure(&mut self, on: &str, voters: &[&str]) {
+        let voters = voters.iter().map(|id| id.to_string()).collect();
+        self.servers
+            .get_mut(on)
+            .unwrap()
+            .reconfigure(voters)
+            .unwrap();
+        self.deliver();
+    }
+
+    fn voters(&self, id: &str) -> Vec<String> {
+        let membership = self.servers[id].membership();
+        assert!(!membership.is_joint(), "{id} is mid-change");
+        membership.voters.iter().cloned().collect()
+    }
+
+    fn propose(&mut self, on: &str, command: u64) {
+        assert!(self.servers.get_mut(on).unwrap().propose(command).is_some());
+        self.deliver();
+    }
+}
+
+#[test]
+fn commands_commit_in_the_same_order_everywhere() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(Duration::from_secs(2));
+    let leader = cluster.leader();
+    for command in 1..=5 {
+        cluster.propose(&leader, command);
+    }
+    cluster.run(Duration::from_millis(300));
+    for applied in cluster.applied.values() {
+        assert_eq!(applied, &vec![1, 2, 3, 4, 5]);
+    }
+    // Followers refuse proposals.
+    let follower = cluster
+        .servers
+        .keys()
+        .find(|&id| *id != leader)
+        .unwrap()
+        .clone();
+    let raft = cluster.servers.get_mut(&follower).unwrap();
+    assert_eq!(raft.propose(9), None);
+    assert_eq!(raft.leader(), Some(leader.as_str()));
+}
+
+#[test]
+fn a_leader_without_a_majority_commits_nothing() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(Duration::from_secs(2));
+    let old = cluster.leader();
+    let committed = cluster.servers[&old].commit_index();
+    cluster.isolated.insert(old.clone());
+    cluster.propose(&old, 7);
+    cluster.run(Duration::from_secs(1));
+    assert_eq!(cluster.servers[&old].commit_index(), committed);
+    assert!(cluster.applied[&old].is_empty());
+}
+
+#[test]
+fn followers_are_repaired_to_the_leaders_log() {
+    let mut cluster = Cluster::new(5);
+    cluster.run(Duration::from_secs(2));
+    let old = cluster.leader();
+    cluster.propose(&old, 1);
+    cluster.run(Duration::from_millis(300));
+
+    // The old leader keeps appending while cut off; none of it commits.
+    cluster.isolated.insert(old.clone());
+    for command in 100..110 {
+        cluster.propose(&old, command);
+    }
+    cluster.run(Duration::from_secs(2));
+    let new = cluster.leader();
+    assert_ne!(new, old);
+    for command in 2..=150 {
+        cluster.propose(&new, command);
+    }
+
+    // Healed, the old leader steps down and its uncommitted entries are
+    // overwritten, even though it needs more than one batch.
+    cluster.isolated.clear();
+    cluster.run(Duration::from_secs(2));
+    assert_eq!(cluster.leader(), new);
+    let expected: Vec<u64> = (1..=150).collect();
+    for (id, applied) in &cluster.applied {
+        assert_eq!(applied, &expected, "{id}");
+    }
+    let last = cluster.servers[&new].log().last_index();
+    for raft in cluster.servers.values() {
+        assert_eq!(raft.log().last_index(), last);
+        assert_eq!(raft.commit_index(), last);
+    }
+}
+
+#[test]
+fn a_follower_behind_the_compacted_log_catches_up_from_a_snapshot() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(Duration::from_secs(2));
+    let leader = cluster.leader();
+    let behind = cluster
+        .servers
+        .keys()
+        .find(|&id| *id != leader)
+        .unwrap()
+        .clone();
+    cluster.isolated.insert(behind.clone());
+    // Enough commands that the snapshot takes several pieces.
+    for command in 1..=3000 {
+        cluster.propose(&leader, command);
+    }
+    cluster.run(Duration::from_millis(300));
+    cluster.compact();
+    assert!(cluster.servers[&leader].log().get(1).is_none());
+    cluster.propose(&leader, 3001);
+
+    cluster.isolated.clear();
+    cluster.run(Duration::from_secs(2));
+    let expected: Vec<u64> = (1..=3001).collect();
+    for (id, applied) in &cluster.applied {
+        assert_eq!(applied, &expected, "{id}");
+    }
+    let raft = &cluster.servers[&behind];
+    assert_eq!(
+        raft.snapshot().map(|snapshot| snapshot.index),
+        cluster.servers[&leader]
+            .snapshot()
+            .map(|snapshot| snapshot.index)
+    );
+    assert_eq!(raft.commit_index(), cluster.servers[&leader].commit_index());
+}
+
+#[test]
+fn a_removed_server_no_longer_counts_or_stands() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(Duration::from_secs(2));
+    let leader = cluster.leader();
+    let ids: Vec<String> = cluster.servers.keys().cloned().collect();
+    let removed = ids.iter().find(|&id| *id != leader).unwrap().clone();
+    let kept: Vec<&str> = ids
+        .iter()
+        .filter(|&id| *id != removed)
+        .map(String::as_str)
+        .collect();
+    cluster.reconfigure(&leader, &kept);
+    cluster.run(Duration::from_millis(300));
+    for id in &ids {
+        assert_eq!(cluster.voters(id), kept, "{id}");
+    }
+
+    // Two of two commit without it, and it never calls an election.
+    cluster.isolated.insert(removed.clone());
+    let term = cluster.servers[&removed].term();
+    cluster.propose(&leader, 1);
+    cluster.run(Duration::from_secs(2));
+    assert_eq!(cluster.leader(), leader);
+    assert_eq!(cluster.applied[&leader], vec![1]);
+    assert_eq!(cluster.servers[&removed].term(), term);
+    let raft = cluster.servers.get_mut(&leader).unwrap();
+    assert!(raft.reconfigure(BTreeSet::new()).is_err());
+}
+
+#[test]
+fn a_leader_that_removes_itself_steps_down() {
+    let mut cluster = Cluster::new(5);
+    cluster.run(Duration::from_secs(2));
+    let old = cluster.leader();
+    let rest: Vec<String> = cluster
+        .servers
+        .keys()
+        .filter(|&id| *id != old)
+        .cloned()
+        .collect();
+    let rest_ids: Vec<&str> = rest.iter().map(String::as_str).collect();
+    cluster.reconfigure(&old, &rest_ids);
+    cluster.run(Duration::from_secs(2));
+    let new = cluster.leader();
+    assert_ne!(new, old);
+    assert_eq!(cluster.voters(&old), rest);
+    cluster.propose(&new, 1);
+    cluster.run(Duration::from_millis(300));
+    for id in &rest {
+        assert_eq!(cluster.applied[id], vec![1], "{id}");
+    }
+}
+
+#[test]
+fn a_joint_change_needs_a_majority_of_the_new_voters_too() {
+    let mut cluster = Cluster::new(5);
+    cluster.run(Duration::from_secs(2));
+    let leader = cluster.leader();
+    let mut others = cluster.servers.keys().filter(|&id| *id != leader).cloned();
+    let (a, b, c, d) = (
+        others.next().unwrap(),
+        others.next().unwrap(),
+        others.next().unwrap(),
+        others.next().unwrap(),
+    );
+    // Shrink to three, then move to a set the leader shares one server
+    // with, whose other two are cut off.
+    cluster.reconfigure(&leader, &[&leader, &a, &b]);
+    cluster.run(Duration::from_millis(300));
+    cluster.isolated.extend([c.clone(), d.clone()]);
+    cluster.reconfigure(&leader, &[&leader, &c, &d]);
+    cluster.propose(&leader, 1);
+    cluster.run(Duration::from_secs(2));
+    assert!(cluster.servers[&leader].membership().is_joint());
+    assert!(cluster.applied[&leader].is_empty());
+
+    cluster.isolated.clear();
+    cluster.run(Duration::from_secs(2));
+    let leader = cluster.leader();
+    let mut expected = vec![leader.clone(), c.clone(), d.clone()];
+    expected.sort();
+    assert_eq!(cluster.voters(&leader), expected);
+    assert_eq!(cluster.applied[&leader], vec![1]);
+}
+
+#[test]
+fn reads_wait_for_a_quorum_to_confirm_the_leader() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(Duration::from_secs(2));
+    let leader = cluster.leader();
+    let now = cluster.now;
+    let read = cluster.servers.get_mut(&leader).unwrap().read_index(now);
+    let read = read.unwrap();
+    cluster.deliver();
+    assert_eq!(cluster.reads[&leader], vec![read]);
+
+    let follower = cluster
+        .servers
+        .keys()
+        .find(|&id| *id != leader)
+        .unwrap()
+        .clone();
+    let raft = cluster.servers.get_mut(&follower).unwrap();
+    assert_eq!(raft.read_index(now), None);
+
+    // Cut off, the old leader never confirms it still leads.
+    cluster.isolated.insert(leader.clone());
+    let raft = cluster.servers.get_mut(&leader).unwrap();
+    let stale = raft.read_index(now).unwrap();
+    cluster.run(Duration::from_secs(1));
+    assert!(!cluster.reads[&leader].contains(&stale));
+}
+
+#[test]
+fn a_lease_serves_reads_without_a_round_until_it_runs_out() {
+    let mut cluster = Cluster::with(3, |raft| raft.with_leases(true));
+    cluster.run(Duration::from_secs(2));
+    let leader = cluster.leader();
+    let now = cluster.now;
+    let raft = cluster.servers.get_mut(&leader).unwrap();
+    raft.take_outbox();
+    let read = raft.read_index(now).unwrap();
+    assert_eq!(raft.take_reads(), vec![read]);
+    assert!(raft.take_outbox().is_empty());
+
+    cluster.isolated.insert(leader.clone());
+    cluster.run(Duration::from_millis(400));
+    let now = cluster.now;
+    let raft = cluster.servers.get_mut(&leader).unwrap();
+    raft.read_index(now).unwrap();
+    assert!(raft.take_reads().is_empty());
+}
+
+#[test]
+fn followers_ignore_candidates_while_the_leader_is_alive() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(Duration::from_secs(2));
+    let leader = cluster.leader();
+    let term = cluster.servers[&leader].term();
+    let mut ids = cluster.servers.keys().filter(|&id| *id != leader);
+    let (follower, candidate) = (ids.next().unwrap().clone(), ids.next().unwrap().clone());
+    let request = RaftMessage::RequestVote {
+        term: term + 5,
+        last_log_index: 1000,
+        last_log_term: term,
+    };
+    let now = cluster.now;
+    let raft = cluster.servers.get_mut(&follower).unwrap();
+    raft.handle(&candidate, request, now);
+    assert_eq!(raft.term(), term);
+    assert!(raft.take_outbox().is_empty());
+}
+
+#[test]
+fn a_server_rejoining_after_a_partition_does_not_depose_the_leader() {
+    let mut cluster = Cluster::new(5);
+    cluster.run(Duration::from_secs(2));
+    let leader = cluster.leader();
+    let term = cluster.servers[&leader].term();
+    let cut_off = cluster
+        .servers
+        .keys()
+        .find(|&id| *id != leader)
+        .unwrap()
+        .clone();
+
+    // Alone, it keeps asking for pre-votes, and none come.
+    cluster.isolated.insert(cut_off.clone());
+    cluster.run(Duration::from_secs(5));
+    assert_eq!(cluster.servers[&cut_off].term(), term);
+
+    cluster.isolated.clear();
+    cluster.propose(&leader, 1);
+    cluster.run(Duration::from_secs(1));
+    assert_eq!(cluster.leader(), leader);
+    for (id, raft) in &cluster.servers {
+        assert_eq!(raft.term(), term, "{id}");
+        assert_eq!(cluster.applied[id], vec![1], "{id}");
+    }
+}
+
+#[test]
+fn timing_needs_several_heartbeats_per_election_timeout() {
+    let ms = Duration::from_millis;
+    assert!(Timing::new(ms(150), ms(300), ms(50)).is_ok());
+    assert!(Timing::new(ms(150), ms(300), ms(60)).is_err());
+    assert!(Timing::new(ms(300), ms(150), ms(50)).is_err());
+    assert!(Timing::new(ms(150), ms(300), ms(0)).is_err());
+}
+
+#[test]
+fn shorter_timeouts_elect_a_leader_sooner() {
+    let ms = Duration::from_millis;
+    let timing = Timing::new(ms(30), ms(150), ms(10)).unwrap();
+    let mut cluster = Cluster::with(3, |raft| raft.with_timing(timing));
+    // Under the default shortest election timeout alone.
+    cluster.run(ms(280));
+    let leader = cluster.leader();
+
+    // Heartbeats come often enough to keep it in the lead.
+    cluster.run(Duration::from_secs(2));
+    assert_eq!(cluster.leader(), leader);
+}
+
+#[test]
+fn a_learner_gets_the_log_but_neither_votes_nor_stands() {
+    let ids: Vec<String> = (1..=4).map(|n| format!("n{n}")).collect();
+    let learners = BTreeSet::from(["n4".to_string()]);
+    let mut cluster = Cluster::with(4, |raft| {
+        raft.with_roles(&ids, learners.clone(), BTreeSet::new())
+            .unwrap()
+    });
+    cluster.run(Duration::from_secs(2));
+    let leader = cluster.leader();
+    cluster.propose(&leader, 1);
+    cluster.run(Duration::from_millis(300));
+    assert_eq!(cluster.applied["n4"], vec![1]);
+
+    // Two of the three voters commit without the learner, but it does not
+    // stand in for a second one.
+    let mut voters = ids[..3].iter().filter(|&id| *id != leader);
+    cluster.isolated.insert(voters.next().unwrap().clone());
+    cluster.propose(&leader, 2);
+    cluster.run(Duration::from_millis(300));
+    assert_eq!(cluster.applied[&leader], vec![1, 2]);
+    cluster.isolated.insert(voters.next().unwrap().clone());
+    cluster.propose(&leader, 3);
+    cluster.run(Duration::from_secs(2));
+    assert_eq!(cluster.applied[&leader], vec![1, 2]);
+    assert!(cluster.leaders_by_term.values().all(|id| id != "n4"));
+}
+
+#[test]
+fn a_witness_votes_but_holds_no_data_and_never_leads() {
+    let ids: Vec<String> = (1..=3).map(|n| format!("n{n}")).collect();
+    let witnesses = BTreeSet::from(["n3".to_string()]);
+    let mut cluster = Cluster::with(3, |raft| {
+        raft.with_roles(&ids, BTreeSet::new(), witnesses.clone())
+            .unwrap()
+    });
+    cluster.run(Duration::from_secs(2));
+    let old = cluster.leader();
+    for command in 1..=3 {
+        cluster.propose(&old, command);
+    }
+    cluster.run(Duration::from_millis(300));
+    assert!(cluster.applied["n3"].is_empty());
+    let witness = &cluster.servers["n3"];
+    assert_eq!(witness.commit_index(), cluster.servers[&old].commit_index());
+    for index in 1..=witness.log().last_index() {
+        let entry = witness.log().get(index).unwrap();
+        assert!(!matches!(entry.command, Command::Apply { .. }));
+    }
+
+    // Its vote elects the other data holder once the leader is cut off.
+    cluster.isolated.insert(old.clone());
+    cluster.run(Duration::from_secs(2));
+    let new = cluster.leader();
+    assert_ne!(new, old);
+    cluster.propose(&new, 4);
+    cluster.run(Duration::from_millis(300));
+    assert_eq!(cluster.applied[&new], vec![1, 2, 3, 4]);
+    assert!(cluster.leaders_by_term.values().all(|id| id != "n3"));
+}
+
+#[test]
+fn roles_must_fit_the_cluster() {
+    let ids: Vec<String> = (1..=2).map(|n| format!("n{n}")).collect();
+    let roles = |learners: &[&str], witnesses: &[&str]| {
+        let set = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect();
+        Raft::<u64>::new("n1", &ids, Instant::now()).with_roles(&ids, set(learners), set(witnesses))
+    };
+    assert!(roles(&["n2"], &[]).is_ok());
+    assert!(roles(&["n5"], &[]).is_err());
+    assert!(roles(&["n2"], &["n2"]).is_err());
+    assert!(roles(&["n2"], &["n1"]).is_err());
+    assert!(roles(&[], &["n1", "n2"]).is_err());
+}
+
+/// An append from `n1` in term 1 of `commands` after `prev_log_index`.
+fn append(prev_log_index: u64, commands: &[u64], leader_commit: u64) -> RaftMessage<u64> {
+    RaftMessage::AppendEntries {
+        term: 1,
+        prev_log_index,
+        prev_log_term: if prev_log_index == 0 { 0 } else { 1 },
+        entries: commands
+            .iter()
+            .map(|&command| Entry {
+                term: 1,
+                command: Command::Apply { command },
+            })
+            .collect(),
+        leader_commit,
+        round: 0,
+    }
+}
+
+fn applied(raft: &mut Raft<u64>) -> Vec<u64> {
+    raft.take_committed()
+        .into_iter()
+        .filter_map(|(_, entry)| match entry.command {
+            Command::Apply { command } => Some(command),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn a_stale_append_does_not_move_the_commit_index_back() {
+    let ids: Vec<String> = ["n1", "n2", "n3"].map(String::from).to_vec();
+    let now = Instant::now();
+    let mut follower = Raft::new("n2", &ids, now);
+    follower.handle("n1", append(0, &[1, 2, 3], 3), now);
+    assert_eq!(applied(&mut follower), [1, 2, 3]);
+
+    // An append the leader sent earlier arrives late, after the leader
+    // committed further.
+    follower.handle("n1", append(0, &[1], 4), now);
+    assert_eq!(follower.commit_index(), 3);
+    follower.handle("n1", append(3, &[4], 4), now);
+    assert_eq!(applied(&mut follower), [4]);
+}
@@ -0,0 +1,158 @@
+//! A Raft server restarted from its storage file keeps its term, vote and
+//! log, drops a torn last write and refuses a corrupt file.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use fly_distributed::{
+    raft::{Command, Raft, RaftMessage, Storage},
+    storage::{SyncPolicy, Wal},
+};
+
+fn ids() -> Vec<String> {
+    ["n1", "n2", "n3"].map(String::from).to_vec()
+}
+
+fn file(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fly-raft-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    let _ = fs::remove_file(&path);
+    path
+}
+
+/// Persists what `raft` changed.
+fn sync(storage: &mut Storage, raft: &mut Raft<u64>) {
+    storage.write(&raft.take_journal()).unwrap();
+}
+
+/// A lone server that elected itself and appended `commands`.
+fn leader_with(path: &Path, commands: &[u64]) -> Raft<u64> {
+    let (mut storage, durable) = Storage::open::<u64>(path, SyncPolicy::Always).unwrap();
+    let now = Instant::now();
+    let mut raft = Raft::recover("n1", &["n1".to_string()], now, durable);
+    raft.tick(now + Duration::from_secs(1));
+    assert!(raft.is_leader());
+    for &command in commands {
+        raft.propose(command).unwrap();
+    }
+    sync(&mut storage, &mut raft);
+    raft
+}
+
+#[test]
+fn a_restarted_server_recovers_its_term_and_log() {
+    let path = file("recover.raft");
+    let before = leader_with(&path, &[1, 2, 3]);
+
+    let (_, durable) = Storage::open::<u64>(&path, SyncPolicy::Always).unwrap();
+    assert_eq!(durable.term, before.term());
+    assert_eq!(durable.voted_for.as_deref(), Some("n1"));
+    assert_eq!(durable.log.last_index(), 4);
+    let commands: Vec<_> = (1..=4)
+        .map(|index| durable.log.get(index).unwrap().command.clone())
+        .collect();
+    assert_eq!(
+        commands,
+        vec![
+            Command::Noop,
+            Command::Apply { command: 1 },
+            Command::Apply { command: 2 },
+            Command::Apply { command: 3 },
+        ]
+    );
+
+    // The next election after the restart is for a later term.
+    let after = leader_with(&path, &[]);
+    assert_eq!(after.term(), before.term() + 1);
+    assert_eq!(after.log().last_index(), 5);
+}
+
+#[test]
+fn a_restarted_server_does_not_vote_twice_in_a_term() {
+    let path = file("vote.raft");
+    let now = Instant::now();
+    let request = RaftMessage::RequestVote {
+        term: 1,
+        last_log_index: 0,
+        last_log_term: 0,
+    };
+
+    let (mut storage, durable) = Storage::open::<u64>(&path, SyncPolicy::Always).unwrap();
+    let mut raft = Raft::recover("n1", &ids(), now, durable);
+    raft.handle("n2", request.clone(), now);
+    sync(&mut storage, &mut raft);
+    assert!(matches!(
+        raft.take_outbox()[..],
+        [(_, RaftMessage::Vote { granted: true, .. })]
+    ));
+
+    let (_, durable) = Storage::open::<u64>(&path, SyncPolicy::Always).unwrap();
+    let mut raft = Raft::recover("n1", &ids(), now, durable);
+    raft.handle("n3", request, now);
+    assert!(matches!(
+        raft.take_outbox()[..],
+        [(_, RaftMessage::Vote { granted: false, .. })]
+    ));
+}
+
+#[test]
+fn a_torn_last_record_is_dropped() {
+    let path = file("torn.raft");
+    leader_with(&path, &[1, 2]);
+    let whole = fs::read(&path).unwrap();
+    // The start of another record, its length and checksum and a few bytes
+    // after them, made it to disk.
+    let mut torn = whole.clone();
+    torn.extend_from_slice(&whole[..12]);
+    fs::write(&path, &torn).unwrap();
+
+    let (_, durable) = Storage::open::<u64>(&path, SyncPolicy::Always).unwrap();
+    assert_eq!(durable.log.last_index(), 3);
+    assert_eq!(fs::read(&path).unwrap(), whole);
+
+    // Writes after recovery land on a clean line.
+    leader_with(&path, &[3]);
+    let (_, durable) = Storage::open::<u64>(&path, SyncPolicy::Always).unwrap();
+    assert_eq!(durable.log.last_index(), 5);
+}
+
+#[test]
+fn a_corrupt_record_before_the_end_fails_recovery() {
+    let path = file("corrupt.raft");
+    leader_with(&path, &[1, 2]);
+    let mut bytes = fs::read(&path).unwrap();
+    let flipped = bytes.iter().position(|&byte| byte == b'{').unwrap() + 1;
+    bytes[flipped] ^= 0x20;
+    fs::write(&path, &bytes).unwrap();
+
+    let err = Storage::open::<u64>(&path, SyncPolicy::Always)
+        .err()
+        .unwrap();
+    assert!(format!("{err:#}").contains("corrupt"), "{err:#}");
+}
+
+#[test]
+fn compaction_rewrites_the_file_around_the_snapshot() {
+    let path = file("compact.raft");
+    let mut raft = leader_with(&path, &[1, 2, 3]);
+    let (mut storage, _) = Storage::open::<u64>(&path, SyncPolicy::Always).unwrap();
+    raft.take_committed();
+    raft.compact(3, "[1,2]".to_string());
+    raft.propose(4).unwrap();
+    raft.take_journal();
+    storage.rewrite(&raft.records()).unwrap();
+
+    let (_, durable) = Storage::open::<u64>(&path, SyncPolicy::Always).unwrap();
+    let snapshot = durable.snapshot.unwrap();
+    assert_eq!((snapshot.index, snapshot.data.as_str()), (3, "[1,2]"));
+    assert_eq!(durable.log.snapshot_index(), 3);
+    assert_eq!(durable.log.last_index(), 5);
+    assert!(durable.log.get(3).is_none());
+    // The state, the snapshot and the two entries after it.
+    let (_, records) = Wal::open::<serde_json::Value>(&path, SyncPolicy::Always).unwrap();
+    assert_eq!(records.count(), 4);
+}
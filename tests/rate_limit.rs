@@ -0,0 +1,78 @@
+//! A bucket grants tokens up to its capacity, refuses a request it cannot
+//! fill however large, and refills over time at the configured rate, through
+//! whichever node its tokens were granted.
+
+mod common;
+
+use std::{thread, time::Duration};
+
+use common::TIMEOUT;
+use fly_distributed::{
+    config::Config,
+    message::error_code,
+    rate_limit::RateLimitNode,
+    ring::Ring,
+    transport::{Endpoint, Network},
+};
+use serde_json::{json, Value};
+
+/// Starts rate limiters `nodes` holding 3 tokens per key, refilled at
+/// `refill` tokens per second.
+fn start(network: &Network, nodes: &[&str], refill: f64) -> Endpoint {
+    let config = Config::default()
+        .with("rate-limit-capacity", 3)
+        .with("rate-limit-refill", refill);
+    let client = network.join("c1");
+    common::start::<RateLimitNode, _>(network, &client, nodes, nodes, config);
+    client
+}
+
+fn acquire_on(client: &Endpoint, node: &str, tokens: u64) -> Value {
+    let acquire = json!({ "type": "acquire", "key": "k", "tokens": tokens });
+    client.rpc(node, acquire, TIMEOUT).unwrap()
+}
+
+fn acquire(client: &Endpoint, tokens: u64) -> Value {
+    acquire_on(client, "n1", tokens)["granted"].clone()
+}
+
+#[test]
+fn tokens_are_granted_up_to_the_capacity() {
+    let network = Network::new();
+    let client = start(&network, &["n1"], 0.0);
+    assert_eq!(acquire(&client, 2), true);
+    assert_eq!(acquire(&client, 2), false);
+    assert_eq!(acquire(&client, 1), true);
+    assert_eq!(acquire(&client, 1), false);
+    // Too many to add to the ones in use at all.
+    assert_eq!(acquire(&client, u64::MAX / 2), false);
+    // Too many to count at all.
+    let reply = acquire_on(&client, "n1", u64::MAX);
+    assert_eq!(reply["code"], error_code::MALFORMED_REQUEST, "{reply}");
+}
+
+#[test]
+fn a_drained_bucket_refills() {
+    let network = Network::new();
+    let client = start(&network, &["n1"], 10.0);
+    assert_eq!(acquire(&client, 3), true);
+    assert_eq!(acquire(&client, 1), false);
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(acquire(&client, 1), true);
+}
+
+#[test]
+fn a_bucket_used_through_one_node_refills_at_the_full_rate() {
+    let nodes = ["n1", "n2", "n3"];
+    let network = Network::new();
+    let client = start(&network, &nodes, 10.0);
+    let ids: Vec<String> = nodes.map(String::from).to_vec();
+    let owner = Ring::new(&ids).unwrap().owner("k").to_string();
+    let node = nodes.into_iter().find(|&node| node != owner).unwrap();
+
+    assert_eq!(acquire_on(&client, node, 3)["granted"], true);
+    assert_eq!(acquire_on(&client, node, 1)["granted"], false);
+    // At a third of the rate, two tokens at most would be back by now.
+    thread::sleep(Duration::from_millis(800));
+    assert_eq!(acquire_on(&client, node, 3)["granted"], true);
+}
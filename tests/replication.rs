@@ -0,0 +1,53 @@
+//! A CRDT replicator counts an ack that arrives after later rounds went
+//! out, rather than sending the peer its whole state every round.
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::TIMEOUT;
+use fly_distributed::{
+    config::Config,
+    message::RawMessage,
+    set::GSetNode,
+    transport::{Endpoint, Network},
+};
+use serde_json::json;
+
+/// The next gossip `fake` hears from n1, skipping everything else.
+fn gossip(fake: &Endpoint, timeout: Duration) -> Option<RawMessage> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let message = fake.recv_timeout(deadline.saturating_duration_since(Instant::now()))?;
+        if message.body.payload["type"] == "replicate" {
+            return Some(message);
+        }
+    }
+}
+
+#[test]
+fn a_late_ack_still_counts() {
+    let network = Network::new();
+    // Plays n2, which acks slower than one gossip round.
+    let fake = network.join("n2");
+    let client = network.join("c1");
+    common::start::<GSetNode, _>(&network, &client, &["n1"], &["n1", "n2"], Config::default());
+    let add = json!({ "type": "add", "element": 1 });
+    assert_eq!(client.rpc("n1", add, TIMEOUT).unwrap()["type"], "add_ok");
+
+    let first = gossip(&fake, TIMEOUT).expect("no gossip");
+    assert_eq!(first.body.payload["gossip"]["kind"], "snapshot");
+    let second = gossip(&fake, TIMEOUT).expect("no second round");
+    assert_eq!(second.body.payload["gossip"]["kind"], "snapshot");
+    let seq = first.body.payload["gossip"]["seq"].clone();
+    let ack = json!({ "type": "replicate", "gossip": { "kind": "ack", "seq": seq } });
+    fake.request("n1", ack).unwrap();
+
+    // n2 has everything now: at most a digest follows, never the state.
+    while let Some(message) = gossip(&fake, Duration::from_secs(1)) {
+        assert_eq!(
+            message.body.payload["gossip"]["kind"], "digest",
+            "{message:?}"
+        );
+    }
+}
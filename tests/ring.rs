@@ -0,0 +1,87 @@
+//! A consistent hash ring gives every node the same answer from the same
+//! ids, spreads keys over the nodes, and moves only the keys of a node that
+//! leaves. A ring with no nodes is refused.
+
+use std::collections::{BTreeSet, HashMap};
+
+use fly_distributed::ring::Ring;
+
+fn ids(count: usize) -> Vec<String> {
+    (1..=count).map(|n| format!("n{n}")).collect()
+}
+
+#[test]
+fn an_empty_ring_is_refused() {
+    assert!(Ring::new(&[]).is_err());
+    assert!(Ring::new(&ids(0)).is_err());
+}
+
+#[test]
+fn the_order_of_the_ids_does_not_matter() {
+    let mut reversed = ids(5);
+    reversed.reverse();
+    let (ring, other) = (Ring::new(&ids(5)).unwrap(), Ring::new(&reversed).unwrap());
+    for key in 0..100 {
+        assert_eq!(ring.owner(&key), other.owner(&key));
+        assert_eq!(ring.replicas(&key, 3), other.replicas(&key, 3));
+    }
+}
+
+#[test]
+fn replicas_are_distinct_and_start_at_the_owner() {
+    let ring = Ring::new(&ids(5)).unwrap();
+    for key in 0..100 {
+        let replicas = ring.replicas(&key, 3);
+        assert_eq!(replicas[0], ring.owner(&key));
+        assert_eq!(replicas.iter().collect::<BTreeSet<_>>().len(), 3);
+    }
+    assert_eq!(ring.replicas("key", 9).len(), 5);
+}
+
+#[test]
+fn keys_spread_and_only_those_of_a_leaving_node_move() {
+    let ring = Ring::new(&ids(5)).unwrap();
+    let smaller = Ring::new(&ids(4)).unwrap();
+    let mut owned: HashMap<String, usize> = HashMap::new();
+    for key in 0..5000 {
+        let owner = ring.owner(&key);
+        *owned.entry(owner.to_string()).or_default() += 1;
+        if owner != "n5" {
+            assert_eq!(smaller.owner(&key), owner);
+        }
+    }
+    assert!(
+        owned.values().all(|&keys| (500..1500).contains(&keys)),
+        "{owned:?}"
+    );
+}
+
+#[test]
+fn neighbors_are_the_nodes_sharing_a_replica_set() {
+    let ring = Ring::new(&ids(5)).unwrap();
+    let neighbors = ring.neighbors("n1", 2);
+    for key in 0..1000 {
+        let replicas = ring.replicas(&key, 2);
+        if replicas.iter().any(|node| node == "n1") {
+            let other = replicas.iter().find(|node| *node != "n1").unwrap();
+            assert!(neighbors.contains(other));
+        }
+    }
+    assert!(!neighbors.contains(&"n1".to_string()));
+    assert!(ring.neighbors("n1", 1).is_empty());
+}
+
+#[test]
+fn moves_cover_every_key_that_changes_owner() {
+    let ring = Ring::new(&ids(4)).unwrap();
+    let larger = Ring::new(&ids(5)).unwrap();
+    let moves = ring.moves(&larger);
+    for key in 0..5000 {
+        let (from, to) = (ring.owner(&key), larger.owner(&key));
+        if from != to {
+            assert!(moves.contains(&(from.to_string(), to.to_string())));
+        }
+    }
+    assert!(moves.iter().all(|(_, to)| to == "n5"), "{moves:?}");
+    assert!(ring.moves(&ring).is_empty());
+}
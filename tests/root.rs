@@ -0,0 +1,158 @@
+//! `Root::update` swaps the root to the tree built from the current one,
+//! and when another update moved the root first its merge hook settles the
+//! conflict: a merged tree is swapped in, a rebuild starts over from the
+//! newer tree, and an abort leaves the other update's tree in place.
+
+mod common;
+
+use std::thread;
+
+use common::TIMEOUT;
+use fly_distributed::{
+    config::Config,
+    lin_kv::LinKvNode,
+    message::{error_code, Init},
+    storage::{Resolution, Root, RootError, Thunk, Thunks},
+    transport::{Endpoint, Network},
+    Message, Node, Runtime,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    /// Appends `value` to the list, racing an append of `value + 100` that
+    /// lands while the first build is under way, and settles the conflict
+    /// as `resolve` says.
+    Append {
+        value: u64,
+        resolve: String,
+    },
+    AppendOk {
+        builds: usize,
+        attempts: Vec<usize>,
+    },
+    Read,
+    ReadOk {
+        values: Vec<u64>,
+    },
+}
+
+struct ListNode {
+    runtime: Runtime,
+    root: Root<Vec<u64>>,
+}
+
+impl Node<Payload> for ListNode {
+    fn from_init(runtime: Runtime, _init: Init) -> anyhow::Result<Self> {
+        let thunks = Thunks::new(runtime.clone(), "thunk");
+        Ok(Self {
+            root: Root::new(runtime.clone(), "root", thunks),
+            runtime,
+        })
+    }
+
+    fn step(&mut self, input: Message<Payload>) -> anyhow::Result<()> {
+        let (runtime, root) = (self.runtime.clone(), self.root.clone());
+        thread::spawn(move || {
+            let reply = match input.body.payload.clone() {
+                Payload::Append { value, resolve } => append(&root, value, &resolve),
+                Payload::Read => read(&root),
+                _ => return,
+            };
+            let _ = match reply {
+                Ok(reply) => runtime.reply(&input, reply),
+                Err(err) => runtime.reply_error(&input, error_code::TXN_CONFLICT, err.to_string()),
+            };
+        });
+        Ok(())
+    }
+}
+
+fn append(root: &Root<Vec<u64>>, value: u64, resolve: &str) -> Result<Payload, RootError> {
+    let mut attempts = Vec::new();
+    let mut builds = 0;
+    root.update(
+        |list| {
+            builds += 1;
+            let list = list.cloned().unwrap_or_default();
+            if builds == 1 {
+                let base = root.read()?;
+                let mut theirs = Thunk::new([list.clone(), vec![value + 100]].concat());
+                assert!(root.swap(base.as_ref(), &mut theirs)?);
+            }
+            Ok((Some([list, vec![value]].concat()), ()))
+        },
+        |(), conflict| {
+            attempts.push(conflict.attempt);
+            match resolve {
+                "merge" => {
+                    let theirs = conflict.theirs.cloned().unwrap_or_default();
+                    Resolution::Merged([theirs, vec![value]].concat())
+                }
+                "rebuild" => Resolution::Rebuild,
+                _ => Resolution::Abort,
+            }
+        },
+    )?;
+    Ok(Payload::AppendOk { builds, attempts })
+}
+
+fn read(root: &Root<Vec<u64>>) -> Result<Payload, RootError> {
+    let values = match root.read()? {
+        Some(tree) => tree.get(root.thunks())?.as_ref().clone(),
+        None => Vec::new(),
+    };
+    Ok(Payload::ReadOk { values })
+}
+
+fn start(network: &Network) -> Endpoint {
+    let client = network.join("c1");
+    let lin_kv = ["lin-kv"];
+    common::start::<LinKvNode, _>(network, &client, &lin_kv, &lin_kv, Config::default());
+    common::start::<ListNode, _>(network, &client, &["n1"], &["n1"], Config::default());
+    client
+}
+
+fn append_with(client: &Endpoint, value: u64, resolve: &str) -> Value {
+    let append = json!({ "type": "append", "value": value, "resolve": resolve });
+    client.rpc("n1", append, TIMEOUT).unwrap()
+}
+
+fn values(client: &Endpoint) -> Value {
+    client
+        .rpc("n1", json!({ "type": "read" }), TIMEOUT)
+        .unwrap()["values"]
+        .clone()
+}
+
+#[test]
+fn a_merge_swaps_in_a_tree_built_from_both_sides() {
+    let network = Network::new();
+    let client = start(&network);
+    assert_eq!(values(&client), json!([]));
+    let reply = append_with(&client, 1, "merge");
+    // The racing append moved the root, and both made it.
+    assert_eq!(reply["attempts"], json!([1]), "{reply}");
+    assert_eq!(values(&client), json!([101, 1]));
+}
+
+#[test]
+fn a_rebuild_starts_over_from_the_newer_tree() {
+    let network = Network::new();
+    let client = start(&network);
+    let reply = append_with(&client, 1, "rebuild");
+    assert_eq!(reply["builds"], 2, "{reply}");
+    assert_eq!(values(&client), json!([101, 1]));
+}
+
+#[test]
+fn an_abort_keeps_the_other_update() {
+    let network = Network::new();
+    let client = start(&network);
+    let reply = append_with(&client, 1, "abort");
+    assert_eq!(reply["code"], error_code::TXN_CONFLICT, "{reply}");
+    assert_eq!(values(&client), json!([101]));
+}
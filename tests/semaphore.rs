@@ -0,0 +1,69 @@
+//! A semaphore hands out at most its permits at once, takes back a permit
+//! only from its holder, and gives the permit of a holder whose lease
+//! lapsed to the next client that asks.
+
+mod common;
+
+use std::{thread, time::Duration};
+
+use common::TIMEOUT;
+use fly_distributed::{
+    config::Config,
+    lin_kv::LinKvNode,
+    semaphore::SemaphoreNode,
+    transport::{Endpoint, Network},
+};
+use serde_json::{json, Value};
+const NODES: [&str; 2] = ["n1", "n2"];
+
+/// Starts a `lin-kv` service and semaphore nodes with two permits per key,
+/// leased for `lease`.
+fn start(network: &Network, lease: Duration) {
+    let client = network.join("c0");
+    let lin_kv = ["lin-kv"];
+    common::start::<LinKvNode, _>(network, &client, &lin_kv, &lin_kv, Config::default());
+    let config = Config::default()
+        .with("semaphore-permits", 2)
+        .with("semaphore-lease", lease.as_millis());
+    common::start::<SemaphoreNode, _>(network, &client, &NODES, &NODES, config);
+}
+
+fn acquire(client: &Endpoint, node: &str) -> Value {
+    let acquire = json!({ "type": "acquire", "key": "s" });
+    client.rpc(node, acquire, TIMEOUT).unwrap()
+}
+
+fn release(client: &Endpoint, node: &str) -> Value {
+    let release = json!({ "type": "release", "key": "s" });
+    client.rpc(node, release, TIMEOUT).unwrap()
+}
+
+#[test]
+fn permits_are_handed_out_until_they_run_out() {
+    let network = Network::new();
+    start(&network, Duration::from_secs(5));
+    let clients: Vec<_> = (1..=3).map(|c| network.join(&format!("c{c}"))).collect();
+
+    assert_eq!(acquire(&clients[0], "n1")["type"], "acquire_ok");
+    assert_eq!(acquire(&clients[1], "n2")["type"], "acquire_ok");
+    assert_eq!(acquire(&clients[2], "n1")["code"], 11);
+    // Acquiring again only renews the permit held.
+    assert_eq!(acquire(&clients[0], "n2")["type"], "acquire_ok");
+
+    assert_eq!(release(&clients[2], "n2")["code"], 22);
+    assert_eq!(release(&clients[0], "n2")["type"], "release_ok");
+    assert_eq!(acquire(&clients[2], "n1")["type"], "acquire_ok");
+}
+
+#[test]
+fn a_lapsed_lease_frees_its_permit() {
+    let network = Network::new();
+    start(&network, Duration::from_millis(100));
+    let clients: Vec<_> = (1..=3).map(|c| network.join(&format!("c{c}"))).collect();
+
+    assert_eq!(acquire(&clients[0], "n1")["type"], "acquire_ok");
+    assert_eq!(acquire(&clients[1], "n1")["type"], "acquire_ok");
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(acquire(&clients[2], "n2")["type"], "acquire_ok");
+    assert_eq!(release(&clients[0], "n2")["code"], 22);
+}
@@ -0,0 +1,121 @@
+//! CRDT snapshots restore copies that answer and merge like the originals.
+
+use std::{fmt::Debug, fs};
+
+use fly_distributed::{
+    clock::{HlcTimestamp, Stability, VectorClock},
+    crdt::{
+        snapshot, DvvSet, GSet, LwwMap, OrSet, Orswot, PnCounter, Rga, RgaId, Snapshot, TwoPhaseSet,
+    },
+    gossip::{DeltaCrdt, Merge},
+    storage::{FileBackend, SyncPolicy},
+};
+
+fn at(ms: u64) -> HlcTimestamp {
+    HlcTimestamp { ms, logical: 0 }
+}
+
+/// Restores `state` from its snapshot, through JSON.
+fn round_trip<C: Snapshot>(state: &C) -> C {
+    let json = serde_json::to_string(&state.snapshot()).unwrap();
+    C::restore(serde_json::from_str(&json).unwrap())
+}
+
+fn assert_restores<C: Snapshot + PartialEq + Debug>(state: C) {
+    assert_eq!(round_trip(&state), state);
+}
+
+#[test]
+fn every_crdt_restores_from_its_snapshot() {
+    assert_restores(GSet::from_iter([3, 1, 2]));
+
+    let mut counter = PnCounter::default();
+    counter.apply("n1", 5);
+    counter.apply("n2", -2);
+    assert_restores(counter);
+
+    let mut map = LwwMap::new();
+    map.insert(1, "a".to_string(), at(1), "n1");
+    map.insert(2, "b".to_string(), at(2), "n2");
+    map.remove(2, at(3), "n1");
+    assert_restores(map);
+
+    let mut orset = OrSet::new();
+    orset.insert("n1", 1);
+    orset.insert("n2", 1);
+    orset.insert("n1", 2);
+    orset.remove("n1", &2);
+    assert_restores(orset);
+
+    let mut orswot = Orswot::new();
+    orswot.insert("n1", 1);
+    orswot.insert("n1", 2);
+    orswot.remove(&1);
+    assert_restores(orswot);
+
+    let mut two_phase = TwoPhaseSet::new();
+    two_phase.insert(1);
+    two_phase.insert(2);
+    two_phase.remove(&1);
+    assert_restores(two_phase);
+
+    let mut rga = Rga::new();
+    let id = |ms| RgaId {
+        time: at(ms),
+        node: "n1".to_string(),
+        seq: 0,
+    };
+    rga.append(id(1), 'a');
+    rga.append(id(2), 'b');
+    // Its predecessor has not arrived: kept, though hidden.
+    rga.insert_after(id(4), Some(id(3)), 'd');
+    let restored = round_trip(&rga);
+    assert_eq!(restored, rga);
+    assert_eq!(restored.values().collect::<String>(), "ab");
+
+    let mut register = DvvSet::new();
+    register.write("n1", "x".to_string(), at(1), &VectorClock::new());
+    assert_restores(register);
+}
+
+#[test]
+fn restored_copies_hand_out_everything_in_their_next_delta() {
+    let set = round_trip(&GSet::from_iter([1, 2]));
+    assert_eq!(set.split_delta(&0), set);
+}
+
+#[test]
+fn snapshots_keep_what_collected_tombstones_stood_for() {
+    let mut a = OrSet::new();
+    a.insert("n1", 1);
+    let stale = a.clone();
+    a.remove("n1", &1);
+
+    let mut stability = Stability::new(&["n1".to_string()]);
+    stability.observe("n1", a.clock().clone());
+    assert_eq!(a.gc(&stability.stable()), 1);
+
+    // The stable clock is local to the copy and not on the wire, but the
+    // snapshot has it: a stale copy does not bring the element back.
+    let mut restored = round_trip(&a);
+    restored.merge(stale);
+    assert!(restored.elements().is_empty());
+}
+
+#[test]
+fn saved_snapshots_load_back_and_corruption_is_reported() {
+    let dir = std::env::temp_dir().join(format!("fly-snapshot-{}", std::process::id()));
+    let backend = FileBackend::open(&dir, SyncPolicy::Always).unwrap();
+
+    assert_eq!(
+        snapshot::load::<GSet<usize>>(&backend, "n1.json").unwrap(),
+        None
+    );
+    let set = GSet::from_iter([1, 2, 3]);
+    snapshot::save(&set, &backend, "n1.json").unwrap();
+    assert_eq!(snapshot::load(&backend, "n1.json").unwrap(), Some(set));
+
+    fs::write(dir.join("n1.json"), "[1, 2").unwrap();
+    assert!(snapshot::load::<GSet<usize>>(&backend, "n1.json").is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,191 @@
+//! The in-memory and file-backed storage backends answer alike, and what is
+//! persisted through them comes back on recovery: a journal from its
+//! snapshot and the batches after it, setting aside a corrupt last batch,
+//! kafka logs with their committed offsets and without what retention
+//! dropped, and broadcast values without the ones that expired.
+
+use std::{
+    collections::HashMap,
+    fs,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use fly_distributed::{
+    broadcast::{topology::TopologyMode, BroadcastStore, Origin},
+    kafka::log::Logs,
+    membership::MembershipMode,
+    storage::{FileBackend, Journal, MemoryBackend, StorageBackend, SyncPolicy},
+};
+
+fn file_backend(name: &str) -> FileBackend {
+    let dir = std::env::temp_dir().join(format!("fly-backend-{}-{name}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    FileBackend::open(&dir, SyncPolicy::Never).unwrap()
+}
+
+fn backends(name: &str) -> Vec<Arc<dyn StorageBackend>> {
+    vec![
+        Arc::new(MemoryBackend::default()),
+        Arc::new(file_backend(name)),
+    ]
+}
+
+#[test]
+fn backends_get_put_cas_scan_and_delete_alike() {
+    for backend in backends("ops") {
+        assert_eq!(backend.get("a").unwrap(), None);
+        backend.put("a", b"1").unwrap();
+        assert_eq!(backend.get("a").unwrap(), Some(b"1".to_vec()));
+
+        assert!(!backend.cas("a", Some(b"2"), b"3").unwrap());
+        assert!(backend.cas("a", Some(b"1"), b"3").unwrap());
+        assert!(!backend.cas("b", Some(b"1"), b"1").unwrap());
+        assert!(backend.cas("b", None, b"4").unwrap());
+        assert!(!backend.cas("b", None, b"5").unwrap());
+
+        // Keys with bytes a file name cannot hold, sorted among the others.
+        backend.put("log/2", b"y").unwrap();
+        backend.put("log/10", b"x").unwrap();
+        backend.put("logs", b"z").unwrap();
+        let scanned = backend.scan("log/").unwrap();
+        let keys: Vec<&str> = scanned.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, ["log/10", "log/2"]);
+
+        backend.delete("log/10").unwrap();
+        backend.delete("missing").unwrap();
+        assert_eq!(
+            backend.scan("log/").unwrap(),
+            [("log/2".to_string(), b"y".to_vec())]
+        );
+        backend.flush().unwrap();
+    }
+}
+
+#[test]
+fn a_journal_recovers_its_snapshot_and_the_batches_after_it() {
+    for backend in backends("journal") {
+        let open = || Journal::<Vec<u64>, u64>::open(backend.clone()).unwrap();
+        let (mut journal, recovered) = open();
+        assert_eq!(recovered.snapshot, None);
+        journal.append(&[1, 2]).unwrap();
+        journal.append(&[3]).unwrap();
+        assert_eq!(journal.pending(), 3);
+
+        let (mut journal, recovered) = open();
+        assert_eq!(recovered.records, [1, 2, 3]);
+        journal.checkpoint(&vec![1, 2, 3]).unwrap();
+        journal.append(&[4]).unwrap();
+
+        let (_, recovered) = open();
+        assert_eq!(recovered.snapshot, Some(vec![1, 2, 3]));
+        assert_eq!(recovered.records, [4]);
+        assert_eq!(recovered.skipped, 0);
+        // Only the batch after the checkpoint is left.
+        assert_eq!(backend.scan("log/").unwrap().len(), 1);
+    }
+}
+
+#[test]
+fn a_journal_skips_batches_its_snapshot_covers() {
+    let backend: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::default());
+    let open = || Journal::<Vec<u64>, u64>::open(backend.clone()).unwrap();
+    let (mut journal, _) = open();
+    journal.append(&[1, 2]).unwrap();
+    let batches = backend.scan("log/").unwrap();
+    journal.checkpoint(&vec![1, 2]).unwrap();
+
+    // A crash after the snapshot but before the batches were deleted.
+    for (key, value) in &batches {
+        backend.put(key, value).unwrap();
+    }
+    let (mut journal, recovered) = open();
+    assert_eq!(recovered.snapshot, Some(vec![1, 2]));
+    assert!(recovered.records.is_empty());
+    assert_eq!(recovered.skipped, 2);
+    journal.append(&[3]).unwrap();
+    assert_eq!(open().1.records, [3]);
+}
+
+#[test]
+fn a_corrupt_last_batch_is_quarantined_and_an_earlier_one_refused() {
+    let backend: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::default());
+    let open = || Journal::<Vec<u64>, u64>::open(backend.clone());
+    let (mut journal, _) = open().unwrap();
+    journal.append(&[1]).unwrap();
+    journal.append(&[2]).unwrap();
+    let batches = backend.scan("log/").unwrap();
+    let (last, bytes) = batches.last().unwrap();
+    let mut damaged = bytes.clone();
+    *damaged.last_mut().unwrap() ^= 0x01;
+    backend.put(last, &damaged).unwrap();
+
+    let (_, recovered) = open().unwrap();
+    assert!(recovered.quarantined);
+    assert_eq!(recovered.records, [1]);
+    assert_eq!(backend.scan("log/").unwrap().len(), 1);
+    assert_eq!(backend.scan("quarantine/").unwrap().len(), 1);
+
+    // Damage before the last batch is not a lost write but corruption.
+    let (mut journal, _) = open().unwrap();
+    journal.append(&[3]).unwrap();
+    let (first, bytes) = &backend.scan("log/").unwrap()[0];
+    backend.put(first, &bytes[..bytes.len() - 1]).unwrap();
+    let err = open().err().unwrap();
+    assert!(format!("{err:#}").contains("checksum"), "{err:#}");
+}
+
+#[test]
+fn kafka_logs_come_back_from_their_backend() {
+    let backend: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::default());
+    let mut logs = Logs::with_retention(Some(0));
+    logs.persist(backend.clone()).unwrap();
+    for msg in 0..1030 {
+        logs.append("k/1", msg);
+    }
+    logs.insert("k2", 3, 7);
+    logs.commit(HashMap::from([("k/1".to_string(), 1025)]));
+
+    let mut recovered = Logs::with_retention(Some(0));
+    recovered.persist(backend.clone()).unwrap();
+    assert_eq!(
+        recovered.read_from("k/1", 1024, 2),
+        [(1024, 1024), (1025, 1025)]
+    );
+    assert_eq!(recovered.read_from("k2", 3, 10), [(3, 7)]);
+    assert_eq!(recovered.append("k/1", 9), 1030);
+    let keys = ["k/1".to_string()];
+    assert_eq!(
+        recovered.committed(&keys),
+        HashMap::from([("k/1".to_string(), 1025)])
+    );
+    // The first segment of `k/1` was dropped, on disk as in memory.
+    assert_eq!(backend.scan("entry/k/1/").unwrap().len(), 1030 - 1024 + 1);
+}
+
+#[test]
+fn expired_broadcast_values_stay_expired_after_a_restart() {
+    let ttl = Duration::from_millis(100);
+    let store = || {
+        BroadcastStore::new(
+            Some(ttl),
+            TopologyMode::Full,
+            100,
+            MembershipMode::Full,
+            false,
+        )
+    };
+    for backend in backends("broadcast") {
+        let before = store();
+        before.persist_to(backend.clone(), "n1").unwrap();
+        before.insert([1, 2], Origin::Journal);
+        before.expire(Instant::now() + ttl);
+        before.insert([3], Origin::Journal);
+
+        let after = store();
+        after.persist_to(backend, "n1").unwrap();
+        assert_eq!(after.all(), [3]);
+        // Nor does a late gossip frame bring them back.
+        assert!(after.insert([1, 2], Origin::Peer("n2".into())).is_empty());
+    }
+}
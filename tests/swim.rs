@@ -0,0 +1,86 @@
+//! SWIM suspects a member that misses its probes, declares it dead once the
+//! suspicion times out, and takes it back once it refutes either claim.
+
+use std::time::{Duration, Instant};
+
+use fly_distributed::membership::swim::{Status, Swim, Update};
+
+const SUSPICION_TIMEOUT: Duration = Duration::from_secs(3);
+
+fn cluster() -> (Swim, Swim) {
+    let ids: Vec<String> = ["n1", "n2", "n3"].iter().map(|id| id.to_string()).collect();
+    (Swim::new("n1", &ids), Swim::new("n2", &ids))
+}
+
+fn claim(status: Status, incarnation: u64) -> Update {
+    Update {
+        node: "n2".to_string(),
+        status,
+        incarnation,
+    }
+}
+
+/// Piggybacks until nothing is left to send.
+fn drain(swim: &mut Swim) -> Vec<Update> {
+    let mut sent = Vec::new();
+    loop {
+        let updates = swim.piggyback();
+        if updates.is_empty() {
+            return sent;
+        }
+        sent.extend(updates);
+    }
+}
+
+#[test]
+fn a_failed_probe_makes_a_suspect_that_refutes_it() {
+    let (mut n1, mut n2) = cluster();
+    n1.on_probe_failed("n2");
+    assert_eq!(n1.view()["n2"], Status::Suspect);
+    let updates = n1.piggyback();
+    assert_eq!(updates, [claim(Status::Suspect, 0)]);
+
+    n2.apply_all(updates);
+    assert_eq!(n2.incarnation(), 1);
+    let refutation = n2.piggyback();
+    assert_eq!(refutation, [claim(Status::Alive, 1)]);
+    n1.apply_all(refutation);
+    assert_eq!(n1.view()["n2"], Status::Alive);
+
+    // An older suspicion does not undo the refutation.
+    n1.apply_all(vec![claim(Status::Suspect, 0)]);
+    assert_eq!(n1.view()["n2"], Status::Alive);
+}
+
+#[test]
+fn a_suspect_is_declared_dead_after_the_timeout() {
+    let (mut n1, _) = cluster();
+    n1.on_probe_failed("n2");
+    n1.expire(Instant::now() + SUSPICION_TIMEOUT / 2);
+    assert_eq!(n1.view()["n2"], Status::Suspect);
+    n1.expire(Instant::now() + SUSPICION_TIMEOUT);
+    assert_eq!(n1.view()["n2"], Status::Dead);
+    assert!(drain(&mut n1).contains(&claim(Status::Dead, 0)));
+}
+
+#[test]
+fn a_member_declared_dead_by_mistake_comes_back() {
+    let (mut n1, mut n2) = cluster();
+    n1.on_probe_failed("n2");
+    n1.expire(Instant::now() + SUSPICION_TIMEOUT);
+    // Every retransmission of the death went elsewhere.
+    drain(&mut n1);
+
+    // n2 acks at the incarnation it was declared dead at, which does not
+    // revive it, but sends it the claim to refute.
+    n1.on_ack("n2", 0);
+    assert_eq!(n1.view()["n2"], Status::Dead);
+    let updates = n1.piggyback();
+    assert!(updates.contains(&claim(Status::Dead, 0)));
+
+    n2.apply_all(updates);
+    assert_eq!(n2.incarnation(), 1);
+    n1.on_ack("n2", n2.incarnation());
+    assert_eq!(n1.view()["n2"], Status::Alive);
+    assert!(drain(&mut n1).contains(&claim(Status::Alive, 1)));
+}
@@ -0,0 +1,26 @@
+//! A thunk stands for its value by id alone: a tree of thunks serializes as
+//! ids, and reads back as thunks already saved, to fetch on first use.
+
+use std::collections::BTreeMap;
+
+use fly_distributed::storage::Thunk;
+use serde_json::json;
+
+#[test]
+fn new_thunks_get_fresh_ids_and_wait_to_be_saved() {
+    let (a, b) = (Thunk::new(1), Thunk::new(1));
+    assert_ne!(a.id(), b.id());
+    assert!(!a.is_saved());
+}
+
+#[test]
+fn a_tree_serializes_as_the_ids_of_its_thunks() {
+    let value = Thunk::new(vec![1, 2]);
+    let map = BTreeMap::from([(7usize, value.clone())]);
+    let encoded = serde_json::to_value(&map).unwrap();
+    assert_eq!(encoded, json!({ "7": value.id() }));
+
+    let decoded: BTreeMap<usize, Thunk<Vec<usize>>> = serde_json::from_value(encoded).unwrap();
+    assert_eq!(decoded[&7], value);
+    assert!(decoded[&7].is_saved());
+}
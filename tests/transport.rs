@@ -0,0 +1,196 @@
+//! A node runs the same over in-process channels and TCP as over stdin and
+//! stdout: the init handshake, replies, and Lamport times for other nodes.
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+use fly_distributed::{
+    config::Config,
+    main_loop_on,
+    message::Init,
+    message::RawMessage,
+    transport::{Channel, Network, Tcp, Transport},
+    Message, Node, Runtime,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    Echo { echo: String },
+    EchoOk { echo: String },
+}
+
+struct EchoNode {
+    runtime: Runtime,
+}
+
+impl Node<Payload> for EchoNode {
+    fn from_init(runtime: Runtime, _init: Init) -> anyhow::Result<Self> {
+        Ok(Self { runtime })
+    }
+
+    fn step(&mut self, input: Message<Payload>) -> anyhow::Result<()> {
+        match input.body.payload {
+            Payload::Echo { ref echo } => {
+                let echo = echo.clone();
+                self.runtime.reply(&input, Payload::EchoOk { echo })
+            }
+            Payload::EchoOk { .. } => Ok(()),
+        }
+    }
+}
+
+fn message(src: &str, dest: &str, body: Value) -> RawMessage {
+    serde_json::from_value(json!({ "src": src, "dest": dest, "body": body })).unwrap()
+}
+
+/// Starts an echo node over channels, returning its inbox and outbox.
+fn start() -> (mpsc::Sender<RawMessage>, mpsc::Receiver<RawMessage>) {
+    let (inbox, node_inbox) = mpsc::channel();
+    let (node_outbox, outbox) = mpsc::channel();
+    thread::spawn(move || {
+        main_loop_on::<EchoNode, Payload>(Channel::new(node_inbox, node_outbox), Config::default())
+            .unwrap()
+    });
+    (inbox, outbox)
+}
+
+fn next(outbox: &mpsc::Receiver<RawMessage>) -> RawMessage {
+    outbox.recv_timeout(Duration::from_secs(5)).unwrap()
+}
+
+#[test]
+fn init_then_echo() {
+    let (inbox, outbox) = start();
+    let init = json!({ "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1", "n2"] });
+    inbox.send(message("c1", "n1", init)).unwrap();
+    let init_ok = next(&outbox);
+    assert_eq!((init_ok.src.as_str(), init_ok.dest.as_str()), ("n1", "c1"));
+    assert_eq!(init_ok.body.in_reply_to, Some(1));
+    assert_eq!(init_ok.body.payload["type"], "init_ok");
+
+    let echo = json!({ "type": "echo", "msg_id": 2, "echo": "hello" });
+    inbox.send(message("c1", "n1", echo)).unwrap();
+    let echo_ok = next(&outbox);
+    assert_eq!(echo_ok.body.in_reply_to, Some(2));
+    assert_eq!(
+        echo_ok.body.payload,
+        json!({ "type": "echo_ok", "echo": "hello" })
+    );
+    // Clients get no Lamport time; other nodes do.
+    assert_eq!(echo_ok.body.lamport, None);
+
+    let echo = json!({ "type": "echo", "msg_id": 3, "echo": "peer" });
+    inbox.send(message("n2", "n1", echo)).unwrap();
+    assert!(next(&outbox).body.lamport.is_some());
+}
+
+#[test]
+fn the_loop_ends_when_the_inbox_closes() {
+    let (inbox, node_inbox) = mpsc::channel();
+    let (node_outbox, outbox) = mpsc::channel();
+    let init = json!({ "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"] });
+    inbox.send(message("c1", "n1", init)).unwrap();
+    drop(inbox);
+    main_loop_on::<EchoNode, Payload>(Channel::new(node_inbox, node_outbox), Config::default())
+        .unwrap();
+    assert_eq!(next(&outbox).body.payload["type"], "init_ok");
+}
+
+fn write_line(stream: &mut TcpStream, message: &RawMessage) {
+    let mut line = serde_json::to_vec(message).unwrap();
+    line.push(b'\n');
+    stream.write_all(&line).unwrap();
+}
+
+fn read_line(reader: &mut impl BufRead) -> RawMessage {
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    serde_json::from_str(&line).unwrap()
+}
+
+#[test]
+fn clients_are_answered_over_tcp_and_peers_reconnected() {
+    let peer = TcpListener::bind("127.0.0.1:0").unwrap();
+    peer.set_nonblocking(true).unwrap();
+    let peers = HashMap::from([("n2".to_string(), peer.local_addr().unwrap().to_string())]);
+    let transport = Tcp::bind("127.0.0.1:0", peers).unwrap();
+    let addr = transport.local_addr();
+    thread::spawn(move || main_loop_on::<EchoNode, Payload>(transport, Config::default()).unwrap());
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut replies = BufReader::new(client.try_clone().unwrap());
+    let init = json!({ "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1", "n2"] });
+    write_line(&mut client, &message("c1", "n1", init));
+    assert_eq!(read_line(&mut replies).body.payload["type"], "init_ok");
+    let echo = json!({ "type": "echo", "msg_id": 2, "echo": "hello" });
+    write_line(&mut client, &message("c1", "n1", echo));
+    let echo_ok = read_line(&mut replies);
+    assert_eq!(
+        (echo_ok.dest.as_str(), echo_ok.body.in_reply_to),
+        ("c1", Some(2))
+    );
+
+    // Replies to n2 go to its own address, whichever connection asked, and
+    // keep arriving after it drops the connection they came over.
+    let mut connections = 0;
+    for msg_id in 3.. {
+        assert!(msg_id < 200, "n2 never heard back");
+        let echo = json!({ "type": "echo", "msg_id": msg_id, "echo": "peer" });
+        write_line(&mut client, &message("n2", "n1", echo));
+        thread::sleep(Duration::from_millis(20));
+        let Ok((stream, _)) = peer.accept() else {
+            continue;
+        };
+        stream.set_nonblocking(false).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let reply = read_line(&mut BufReader::new(stream));
+        assert_eq!(reply.dest, "n2");
+        assert_eq!(reply.body.payload["type"], "echo_ok");
+        connections += 1;
+        if connections == 2 {
+            break;
+        }
+    }
+}
+
+#[test]
+fn a_network_routes_by_id_until_cut_or_left() {
+    let network = Network::new();
+    let client = network.join("c1");
+    let node = {
+        let endpoint = network.join("n1");
+        thread::spawn(move || main_loop_on::<EchoNode, Payload>(endpoint, Config::default()))
+    };
+    let init = json!({ "type": "init", "node_id": "n1", "node_ids": ["n1"] });
+    let reply = client.rpc("n1", init, Duration::from_secs(5)).unwrap();
+    assert_eq!(reply["type"], "init_ok");
+
+    network.cut("c1", "n1");
+    let echo = json!({ "type": "echo", "msg_id": 2, "echo": "lost" });
+    client.send(&message("c1", "n1", echo)).unwrap();
+    assert!(client.recv_timeout(Duration::from_millis(100)).is_none());
+
+    network.heal();
+    let echo = json!({ "type": "echo", "msg_id": 3, "echo": "found" });
+    client.send(&message("c1", "n1", echo)).unwrap();
+    let reply = client.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(reply.body.payload["echo"], "found");
+
+    network.leave("n1");
+    node.join().unwrap().unwrap();
+}
@@ -0,0 +1,191 @@
+//! Sharded transactions commit on every participant or none: a participant
+//! that finds a key locked votes no and the transaction aborts, and a
+//! prepared part survives a restart until its coordinator, restarted or
+//! not, says what became of it.
+
+mod common;
+
+use std::{fs, path::PathBuf, thread, time::Duration};
+
+use common::TIMEOUT;
+use fly_distributed::{
+    config::Config,
+    message::RawMessage,
+    ring::Ring,
+    transport::{Endpoint, Network},
+    txn::TxnNode,
+};
+use serde_json::{json, Value};
+const NODES: [&str; 2] = ["n1", "n2"];
+
+fn dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fly-txn-{}-{name}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Starts `node` on the network and inits it as one of [`NODES`].
+fn start(network: &Network, client: &Endpoint, node: &str, config: &Config) {
+    common::start::<TxnNode, _>(network, client, &[node], &NODES, config.clone());
+}
+
+fn sharded() -> Config {
+    Config::default().with("txn-store", "sharded")
+}
+
+/// A key that `node` owns.
+fn key_on(node: &str, skip: usize) -> usize {
+    let ids: Vec<String> = NODES.iter().map(|node| node.to_string()).collect();
+    let ring = Ring::new(&ids).unwrap();
+    (0..)
+        .filter(|key| ring.owner(key) == node)
+        .nth(skip)
+        .unwrap()
+}
+
+fn txn(client: &Endpoint, node: &str, ops: Value) -> Value {
+    let txn = json!({ "type": "txn", "txn": ops });
+    client.rpc(node, txn, TIMEOUT).unwrap()
+}
+
+/// Reads messages to `fake` until one of type `kind`, skipping the
+/// replication every node gossips.
+fn expect(fake: &Endpoint, kind: &str) -> RawMessage {
+    loop {
+        let message = fake.recv_timeout(TIMEOUT).expect("no message");
+        match message.body.payload["type"].as_str() {
+            Some("replicate") => continue,
+            found => {
+                assert_eq!(found, Some(kind), "{}", message.body.payload);
+                return message;
+            }
+        }
+    }
+}
+
+#[test]
+fn a_transaction_commits_on_every_participant() {
+    let network = Network::new();
+    let client = network.join("c1");
+    for node in NODES {
+        start(&network, &client, node, &sharded());
+    }
+    let (a, b) = (key_on("n1", 0), key_on("n2", 0));
+
+    let reply = txn(
+        &client,
+        "n1",
+        json!([["w", a, 1], ["w", b, 2], ["r", b, null]]),
+    );
+    assert_eq!(reply["txn"], json!([["w", a, 1], ["w", b, 2], ["r", b, 2]]));
+    // Participants may apply the commit a moment after the client heard.
+    thread::sleep(Duration::from_millis(100));
+    let reply = txn(&client, "n2", json!([["r", a, null], ["r", b, null]]));
+    assert_eq!(reply["txn"], json!([["r", a, 1], ["r", b, 2]]));
+}
+
+#[test]
+fn a_participant_voting_no_aborts_the_transaction() {
+    let network = Network::new();
+    let client = network.join("c1");
+    start(&network, &client, "n1", &sharded());
+    let fake = network.join("n2");
+    let (a, b) = (key_on("n1", 0), key_on("n2", 0));
+
+    // n2 votes no, so n1 drops its part and tells n2 to drop its own.
+    let running = {
+        let client = network.join("c2");
+        thread::spawn(move || txn(&client, "n1", json!([["w", a, 1], ["w", b, 2]])))
+    };
+    let prepare = expect(&fake, "prepare");
+    let conflict = json!({ "type": "error", "code": 30, "text": "b is locked" });
+    fake.reply(&prepare, conflict).unwrap();
+    assert_eq!(running.join().unwrap()["code"], 30);
+    assert_eq!(
+        expect(&fake, "abort").body.payload["id"],
+        prepare.body.payload["id"]
+    );
+    let reply = txn(&client, "n1", json!([["r", a, null]]));
+    assert_eq!(reply["txn"], json!([["r", a, null]]));
+
+    // A key prepared by n2's transaction makes n1 vote no in turn.
+    let prepare = json!({ "type": "prepare", "id": "t1", "ops": [["w", a, 5]] });
+    assert_eq!(
+        fake.rpc("n1", prepare, TIMEOUT).unwrap()["type"],
+        "prepare_ok"
+    );
+    assert_eq!(txn(&client, "n1", json!([["w", a, 6]]))["code"], 30);
+    let abort = json!({ "type": "abort", "id": "t1" });
+    assert_eq!(fake.rpc("n1", abort, TIMEOUT).unwrap()["type"], "abort_ok");
+    let reply = txn(&client, "n1", json!([["w", a, 6]]));
+    assert_eq!(reply["type"], "txn_ok", "{reply}");
+}
+
+#[test]
+fn a_prepared_part_is_replayed_and_resolved_by_its_coordinator() {
+    let network = Network::new();
+    let client = network.join("c1");
+    let config = sharded().with("txn-dir", dir("participant").display());
+    start(&network, &client, "n1", &config);
+    let fake = network.join("n2");
+    let a = key_on("n1", 0);
+
+    let prepare = json!({ "type": "prepare", "id": "t1", "ops": [["w", a, 7]] });
+    assert_eq!(
+        fake.rpc("n1", prepare, TIMEOUT).unwrap()["type"],
+        "prepare_ok"
+    );
+
+    // Restarted, n1 still holds the key for t1.
+    network.leave("n1");
+    start(&network, &client, "n1", &config);
+    assert_eq!(txn(&client, "n1", json!([["w", a, 8]]))["code"], 30);
+
+    // Left in doubt, it asks the coordinator, which committed t1.
+    let outcome = expect(&fake, "outcome");
+    assert_eq!(outcome.body.payload["id"], "t1");
+    let commit = json!({ "type": "outcome_ok", "decision": "commit" });
+    fake.reply(&outcome, commit).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let reply = txn(&client, "n1", json!([["r", a, null]]));
+    assert_eq!(reply["txn"], json!([["r", a, 7]]));
+}
+
+#[test]
+fn a_restarted_coordinator_resends_its_commits_and_answers_for_them() {
+    let network = Network::new();
+    let client = network.join("c1");
+    let config = sharded().with("txn-dir", dir("coordinator").display());
+    start(&network, &client, "n1", &config);
+    let fake = network.join("n2");
+    let (a, b) = (key_on("n1", 0), key_on("n2", 0));
+
+    let running = {
+        let client = network.join("c2");
+        thread::spawn(move || txn(&client, "n1", json!([["w", a, 1], ["w", b, 2]])))
+    };
+    let prepare = expect(&fake, "prepare");
+    let id = prepare.body.payload["id"].clone();
+    let vote = json!({ "type": "prepare_ok", "ops": [["w", b, 2]] });
+    fake.reply(&prepare, vote).unwrap();
+    assert_eq!(running.join().unwrap()["type"], "txn_ok");
+    // n2 never acknowledges the commit before n1 restarts.
+    assert_eq!(expect(&fake, "commit").body.payload["id"], id);
+    network.leave("n1");
+
+    // Restarted, n1 still answers for the commit while n2 has not
+    // acknowledged it, and sends it again.
+    start(&network, &client, "n1", &config);
+    let outcome = json!({ "type": "outcome", "id": id });
+    let reply = fake.rpc("n1", outcome, TIMEOUT).unwrap();
+    assert_eq!(reply["decision"], "commit");
+    let outcome = json!({ "type": "outcome", "id": "unknown" });
+    let reply = fake.rpc("n1", outcome, TIMEOUT).unwrap();
+    assert_eq!(reply["decision"], "abort");
+    let commit = expect(&fake, "commit");
+    assert_eq!(commit.body.payload["id"], id);
+    fake.reply(&commit, json!({ "type": "commit_ok" })).unwrap();
+    let reply = txn(&client, "n1", json!([["r", a, null]]));
+    assert_eq!(reply["txn"], json!([["r", a, 1]]));
+}
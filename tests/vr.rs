@@ -0,0 +1,258 @@
+//! Viewstamped Replication replicas wired together in memory, with a
+//! simulated clock and partitions, commit the same operations in order.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::{Duration, Instant},
+};
+
+use fly_distributed::vr::{Entry, Vr};
+
+struct Cluster {
+    servers: BTreeMap<String, Vr<u64>>,
+    /// Commands each replica applied, in order.
+    applied: BTreeMap<String, Vec<u64>>,
+    /// Every entry any replica committed, by operation number.
+    committed: BTreeMap<u64, Entry<u64>>,
+    /// Reads each replica found ready to serve.
+    reads: BTreeMap<String, Vec<u64>>,
+    /// Replicas cut off from all the others.
+    isolated: BTreeSet<String>,
+    now: Instant,
+}
+
+impl Cluster {
+    fn new(size: usize) -> Self {
+        let ids: Vec<String> = (1..=size).map(|n| format!("n{n}")).collect();
+        let now = Instant::now();
+        Self {
+            servers: ids
+                .iter()
+                .map(|id| (id.clone(), Vr::new(id, &ids, now)))
+                .collect(),
+            applied: ids.iter().map(|id| (id.clone(), Vec::new())).collect(),
+            committed: BTreeMap::new(),
+            reads: ids.iter().map(|id| (id.clone(), Vec::new())).collect(),
+            isolated: BTreeSet::new(),
+            now,
+        }
+    }
+
+    /// Advances the clock by `duration`, 10ms at a time, delivering every
+    /// message sent along the way.
+    fn run(&mut self, duration: Duration) {
+        let end = self.now + duration;
+        while self.now < end {
+            self.now += Duration::from_millis(10);
+            for vr in self.servers.values_mut() {
+                vr.tick(self.now);
+            }
+            self.deliver();
+        }
+    }
+
+    fn deliver(&mut self) {
+        loop {
+            let mut messages = Vec::new();
+            for (id, vr) in &mut self.servers {
+                for (to, message) in vr.take_outbox() {
+                    messages.push((id.clone(), to, message));
+                }
+            }
+            if messages.is_empty() {
+                break;
+            }
+            for (from, to, message) in messages {
+                if self.isolated.contains(&from) || self.isolated.contains(&to) {
+                    continue;
+                }
+                self.servers
+                    .get_mut(&to)
+                    .unwrap()
+                    .handle(&from, message, self.now);
+            }
+        }
+        for (id, vr) in &mut self.servers {
+            for (op, entry) in vr.take_committed() {
+                // No two replicas ever commit different entries for an op.
+                let first = self.committed.entry(op).or_insert(entry.clone());
+                assert_eq!(*first, entry, "op {op} committed twice");
+                self.applied.get_mut(id).unwrap().push(entry.command);
+            }
+            self.reads.get_mut(id).unwrap().extend(vr.take_reads());
+        }
+    }
+
+    fn leaders(&self) -> Vec<String> {
+        self.servers
+            .iter()
+            .filter(|(id, vr)| vr.is_leader() && !self.isolated.contains(*id))
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    fn leader(&self) -> String {
+        let leaders = self.leaders();
+        assert_eq!(leaders.len(), 1, "expected one primary, got {leaders:?}");
+        leaders[0].clone()
+    }
+
+    fn propose(&mut self, on: &str, command: u64) -> Option<(u64, u64)> {
+        self.servers.get_mut(on).unwrap().propose(command)
+    }
+
+    fn backup(&self, leader: &str) -> String {
+        self.servers
+            .keys()
+            .find(|&id| id != leader)
+            .unwrap()
+            .clone()
+    }
+}
+
+#[test]
+fn the_first_replica_leads_view_zero_and_keeps_it() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(Duration::from_secs(2));
+    assert_eq!(cluster.leader(), "n1");
+    for vr in cluster.servers.values() {
+        assert_eq!(vr.view(), 0);
+        assert_eq!(vr.leader(), Some("n1"));
+    }
+}
+
+#[test]
+fn commands_are_committed_in_the_same_order_everywhere() {
+    let mut cluster = Cluster::new(5);
+    cluster.run(Duration::from_millis(100));
+    let leader = cluster.leader();
+    for command in 1..=20 {
+        assert!(cluster.propose(&leader, command).is_some());
+    }
+    let backup = cluster.backup(&leader);
+    assert_eq!(cluster.propose(&backup, 99), None);
+    cluster.run(Duration::from_millis(500));
+    let expected: Vec<u64> = (1..=20).collect();
+    for (id, applied) in &cluster.applied {
+        assert_eq!(applied, &expected, "{id}");
+    }
+}
+
+#[test]
+fn a_primary_without_a_majority_commits_nothing() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(Duration::from_millis(100));
+    let old = cluster.leader();
+    cluster.isolated.insert(old.clone());
+    cluster.propose(&old, 1);
+    cluster.run(Duration::from_secs(2));
+    assert!(cluster.applied[&old].is_empty());
+
+    // The others carry on in a later view, led by another replica.
+    let new = cluster.leader();
+    assert_ne!(new, old);
+    assert!(cluster.servers[&new].view() > cluster.servers[&old].view());
+    cluster.propose(&new, 2);
+    cluster.run(Duration::from_millis(500));
+
+    // Healed, the old primary joins the new view, dropping its command.
+    cluster.isolated.clear();
+    cluster.run(Duration::from_secs(1));
+    assert_eq!(cluster.leader(), new);
+    for (id, applied) in &cluster.applied {
+        assert_eq!(applied, &vec![2], "{id}");
+    }
+}
+
+#[test]
+fn a_view_change_keeps_what_a_majority_prepared() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(Duration::from_millis(100));
+    let old = cluster.leader();
+    // Prepared on a majority, but the primary dies before committing it.
+    cluster.propose(&old, 7);
+    let mut prepares = cluster.servers.get_mut(&old).unwrap().take_outbox();
+    let (to, prepare) = prepares.remove(0);
+    cluster
+        .servers
+        .get_mut(&to)
+        .unwrap()
+        .handle(&old, prepare, cluster.now);
+    cluster.servers.get_mut(&to).unwrap().take_outbox();
+    cluster.isolated.insert(old.clone());
+
+    cluster.run(Duration::from_secs(2));
+    let new = cluster.leader();
+    assert_ne!(new, old);
+    for (id, applied) in &cluster.applied {
+        if *id != old {
+            assert_eq!(applied, &vec![7], "{id}");
+        }
+    }
+}
+
+#[test]
+fn a_backup_that_missed_operations_catches_up() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(Duration::from_millis(100));
+    let leader = cluster.leader();
+    let behind = cluster.backup(&leader);
+    cluster.isolated.insert(behind.clone());
+    for command in 1..=250 {
+        cluster.propose(&leader, command);
+    }
+    cluster.run(Duration::from_millis(200));
+    assert!(cluster.applied[&behind].is_empty());
+
+    cluster.isolated.clear();
+    cluster.run(Duration::from_secs(2));
+    assert_eq!(cluster.leader(), leader);
+    let expected: Vec<u64> = (1..=250).collect();
+    assert_eq!(cluster.applied[&behind], expected);
+}
+
+#[test]
+fn a_backup_rejoining_after_a_partition_does_not_change_the_view() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(Duration::from_millis(100));
+    let leader = cluster.leader();
+    let view = cluster.servers[&leader].view();
+    let cut_off = cluster.backup(&leader);
+    cluster.isolated.insert(cut_off.clone());
+    cluster.run(Duration::from_secs(3));
+    assert!(cluster.servers[&cut_off].view() > view);
+
+    cluster.isolated.clear();
+    cluster.run(Duration::from_secs(1));
+    assert_eq!(cluster.leader(), leader);
+    for vr in cluster.servers.values() {
+        assert_eq!(vr.view(), view);
+        assert_eq!(vr.leader(), Some(leader.as_str()));
+    }
+}
+
+#[test]
+fn reads_wait_for_a_majority_to_confirm_the_primary() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(Duration::from_millis(100));
+    let leader = cluster.leader();
+    let read = cluster.servers.get_mut(&leader).unwrap().read_index();
+    let read = read.unwrap();
+    cluster.deliver();
+    assert_eq!(cluster.reads[&leader], vec![read]);
+
+    cluster.isolated.insert(leader.clone());
+    let stale = cluster.servers.get_mut(&leader).unwrap().read_index();
+    cluster.run(Duration::from_secs(1));
+    assert!(!cluster.reads[&leader].contains(&stale.unwrap()));
+}
+
+#[test]
+fn a_lone_replica_commits_on_its_own() {
+    let mut cluster = Cluster::new(1);
+    cluster.propose("n1", 1);
+    let read = cluster.servers.get_mut("n1").unwrap().read_index();
+    cluster.deliver();
+    assert_eq!(cluster.applied["n1"], vec![1]);
+    assert_eq!(cluster.reads["n1"], vec![read.unwrap()]);
+}
@@ -0,0 +1,91 @@
+//! A write-ahead log hands back what was appended, across rewrites, cuts
+//! off a torn last record into a quarantine file and refuses a corrupt one
+//! before the end.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use fly_distributed::storage::{SyncPolicy, Wal};
+
+/// The length and checksum in front of each record.
+const HEADER: usize = 8;
+
+fn file(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fly-wal-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    let _ = fs::remove_file(&path);
+    path
+}
+
+fn replay(path: &Path) -> anyhow::Result<Vec<String>> {
+    let (_, records) = Wal::open(path, SyncPolicy::Never)?;
+    records.collect()
+}
+
+#[test]
+fn records_come_back_in_order_after_appends_and_rewrites() {
+    let path = file("order.wal");
+    let (mut wal, records) = Wal::open::<String>(&path, SyncPolicy::Always).unwrap();
+    assert_eq!(records.count(), 0);
+    wal.append(&["a", "b"]).unwrap();
+    wal.append(&["c"]).unwrap();
+    assert_eq!(replay(&path).unwrap(), ["a", "b", "c"]);
+
+    wal.rewrite(&["c"]).unwrap();
+    wal.append(&["d"]).unwrap();
+    assert_eq!(replay(&path).unwrap(), ["c", "d"]);
+}
+
+#[test]
+fn a_torn_tail_is_quarantined_and_a_bad_record_before_it_is_corruption() {
+    let path = file("torn.wal");
+    let quarantine = path.with_extension("wal.corrupt");
+    let _ = fs::remove_file(&quarantine);
+    let (mut wal, _) = Wal::open::<String>(&path, SyncPolicy::Always).unwrap();
+    wal.append(&["first", "second"]).unwrap();
+    let whole = fs::read(&path).unwrap();
+
+    // Half of a third record, like the second, made it to disk.
+    let second = &whole[HEADER + "\"first\"".len()..];
+    let mut torn = whole.clone();
+    torn.extend_from_slice(&second[..second.len() / 2]);
+    fs::write(&path, &torn).unwrap();
+    assert_eq!(replay(&path).unwrap(), ["first", "second"]);
+    assert_eq!(fs::read(&path).unwrap(), whole);
+    assert_eq!(fs::read(&quarantine).unwrap(), torn[whole.len()..]);
+
+    // Damage to the first record leaves the second behind it.
+    let mut corrupt = whole.clone();
+    corrupt[10] ^= 0x20;
+    fs::write(&path, &corrupt).unwrap();
+    let err = replay(&path).err().unwrap();
+    assert!(
+        format!("{err:#}").contains("corrupt at record 1"),
+        "{err:#}"
+    );
+
+    // A length no record can have is damage too, not a write cut short,
+    // though it runs past the end of the file.
+    let mut corrupt = whole;
+    corrupt[3] = 0xff;
+    fs::write(&path, &corrupt).unwrap();
+    let err = replay(&path).err().unwrap();
+    assert!(
+        format!("{err:#}").contains("corrupt at record 1"),
+        "{err:#}"
+    );
+}
+
+#[test]
+fn sync_policies_parse() {
+    assert_eq!("always".parse::<SyncPolicy>().unwrap(), SyncPolicy::Always);
+    assert_eq!("never".parse::<SyncPolicy>().unwrap(), SyncPolicy::Never);
+    assert_eq!(
+        "50".parse::<SyncPolicy>().unwrap(),
+        SyncPolicy::Interval(std::time::Duration::from_millis(50))
+    );
+    assert!("sometimes".parse::<SyncPolicy>().is_err());
+}